@@ -0,0 +1,55 @@
+//! Generates the gRPC server and client of `proto/market_data.proto`.
+//! The messages are written by hand in `src/proto.rs`, so no `protoc` is needed to build.
+use tonic_build::manual::{Builder, Method, MethodBuilder, Service};
+
+fn method(name: &str, route_name: &str, input: &str, output: &str) -> MethodBuilder {
+    Method::builder()
+        .name(name)
+        .route_name(route_name)
+        .input_type(format!("crate::proto::{}", input))
+        .output_type(format!("crate::proto::{}", output))
+        .codec_path("tonic::codec::ProstCodec")
+}
+
+fn main() {
+    println!("cargo:rerun-if-changed=build.rs");
+    let service = Service::builder()
+        .name("MarketData")
+        .package("binance")
+        .method(
+            method(
+                "get_order_book",
+                "GetOrderBook",
+                "GetOrderBookRequest",
+                "OrderBook",
+            )
+            .build(),
+        )
+        .method(method("get_book_at", "GetBookAt", "GetBookAtRequest", "OrderBook").build())
+        .method(
+            method(
+                "get_recent_trades",
+                "GetRecentTrades",
+                "GetRecentTradesRequest",
+                "RecentTrades",
+            )
+            .build(),
+        )
+        .method(
+            method("stream_trades", "StreamTrades", "StreamRequest", "Trade")
+                .server_streaming()
+                .build(),
+        )
+        .method(
+            method(
+                "stream_book_updates",
+                "StreamBookUpdates",
+                "StreamRequest",
+                "DepthUpdate",
+            )
+            .server_streaming()
+            .build(),
+        )
+        .build();
+    Builder::new().compile(&[service]);
+}
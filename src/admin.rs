@@ -0,0 +1,281 @@
+//! HTTP admin api, to look into a running gatherer and act on it without restarting it.
+//!
+//! - `GET /status`: a summary of the connections, subscriptions, buffers and uploads.
+//! - `GET /connections` and `GET /subscriptions`: the live connections and the streams subscribed to.
+//! - `GET /books/{symbol}?depth=N`: the top `N` levels of the local book, 10 by default, 0 for all of them.
+//! - `GET /buffers`: the records queued per sink and stream, waiting to be written.
+//! - `POST /flush`: writes what is queued and flushes every sink.
+//! - `POST /upload`: closes the open files and queues them for upload, instead of waiting for the next rotation.
+//! - `POST /symbols/{symbol}` and `DELETE /symbols/{symbol}`: subscribes to the streams of a symbol, or
+//!   unsubscribes from them, on the live connection.
+//! - `GET /metrics`: the [`Metrics`] in the Prometheus text format, when given.
+//!
+//! Every other response is JSON. The api has no authentication, so it listens on localhost by default.
+use std::net::SocketAddr;
+use std::sync::Arc;
+
+use axum::{
+    extract::{Path, Query, State},
+    http::{header, StatusCode},
+    response::{IntoResponse, Response},
+    routing::{get, post},
+    Json, Router,
+};
+use chrono::{DateTime, Utc};
+use log::info;
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+use tokio::{net::TcpListener, sync::mpsc};
+
+use crate::{
+    binance::{
+        models::orderbook::{OrderBooksRWL, PriceSize},
+        websocket::{
+            control::{ConnectionControl, ConnectionStatus},
+            requests::Stream,
+        },
+    },
+    data_manager::{upload_now, UploadRequest},
+    metrics::Metrics,
+    sinks::bus::{EventBus, StreamStats},
+    upload_queue::UploadQueue,
+};
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct AdminConfig {
+    pub address: SocketAddr,
+    /// Levels per side returned by `/books` when the request does not say.
+    pub book_depth: usize,
+}
+impl Default for AdminConfig {
+    fn default() -> Self {
+        Self {
+            address: SocketAddr::from(([127, 0, 0, 1], 9300)),
+            book_depth: 10,
+        }
+    }
+}
+
+/// The parts of the process the api reports on and controls.
+pub struct AdminApi {
+    config: AdminConfig,
+    control: Arc<ConnectionControl>,
+    orderbooks: OrderBooksRWL,
+    bus: Arc<EventBus>,
+    uploads: Arc<UploadQueue>,
+    metrics: Option<Arc<Metrics>>,
+    upload_requests: Option<mpsc::Sender<UploadRequest>>,
+}
+impl AdminApi {
+    pub fn new(
+        config: AdminConfig,
+        control: Arc<ConnectionControl>,
+        orderbooks: OrderBooksRWL,
+        bus: Arc<EventBus>,
+        uploads: Arc<UploadQueue>,
+    ) -> Self {
+        Self {
+            config,
+            control,
+            orderbooks,
+            bus,
+            uploads,
+            metrics: None,
+            upload_requests: None,
+        }
+    }
+    pub fn with_metrics(mut self, metrics: Arc<Metrics>) -> Self {
+        self.metrics = Some(metrics);
+        self
+    }
+    /// Serves `POST /upload` by sending the requests to the task rotating the files.
+    pub fn with_upload_requests(mut self, requests: mpsc::Sender<UploadRequest>) -> Self {
+        self.upload_requests = Some(requests);
+        self
+    }
+    pub fn config(&self) -> &AdminConfig {
+        &self.config
+    }
+    pub fn router(self: Arc<Self>) -> Router {
+        let router = match self.metrics.is_some() {
+            true => Router::new().route("/metrics", get(metrics)),
+            false => Router::new(),
+        };
+        router
+            .route("/status", get(status))
+            .route("/connections", get(connections))
+            .route("/subscriptions", get(subscriptions))
+            .route("/books/:symbol", get(book))
+            .route("/buffers", get(buffers))
+            .route("/flush", post(flush))
+            .route("/upload", post(upload))
+            .route("/symbols/:symbol", post(add_symbol).delete(remove_symbol))
+            .with_state(self)
+    }
+    /// Serves the api on `config.address` until the server fails.
+    pub async fn run(self: Arc<Self>) -> std::io::Result<()> {
+        let listener = TcpListener::bind(self.config.address).await?;
+        self.serve(listener).await
+    }
+    /// Serves the api on `listener` until the server fails.
+    pub async fn serve(self: Arc<Self>, listener: TcpListener) -> std::io::Result<()> {
+        info!("Serving the admin api on {}", listener.local_addr()?);
+        axum::Server::from_tcp(listener.into_std()?)
+            .map_err(std::io::Error::other)?
+            .serve(self.router().into_make_service())
+            .await
+            .map_err(std::io::Error::other)
+    }
+}
+
+type ApiState = State<Arc<AdminApi>>;
+
+fn names(streams: &[Stream]) -> Vec<String> {
+    streams.iter().map(|stream| stream.to_string()).collect()
+}
+
+fn buffered(stats: &[StreamStats]) -> u64 {
+    stats.iter().map(|stats| stats.queued).sum()
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Status {
+    pub connections: Vec<ConnectionStatus>,
+    pub subscriptions: usize,
+    pub books: usize,
+    /// Records queued in the sinks, waiting to be written.
+    pub buffered: u64,
+    pub uploads_pending: usize,
+}
+
+async fn status(State(api): ApiState) -> Json<Status> {
+    Json(Status {
+        connections: api.control.connections(),
+        subscriptions: api.control.streams().len(),
+        books: api.orderbooks.read().await.len(),
+        buffered: buffered(&api.bus.stats()),
+        uploads_pending: api.uploads.pending(),
+    })
+}
+
+async fn connections(State(api): ApiState) -> Json<Vec<ConnectionStatus>> {
+    Json(api.control.connections())
+}
+
+async fn subscriptions(State(api): ApiState) -> Json<Vec<String>> {
+    Json(names(&api.control.streams()))
+}
+
+#[derive(Debug, Deserialize)]
+struct BookQuery {
+    depth: Option<usize>,
+}
+
+/// The top levels of a local book.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct BookView {
+    pub symbol: String,
+    pub first_update_id: i64,
+    pub last_update_id: i64,
+    pub time: DateTime<Utc>,
+    pub is_valid: bool,
+    pub bids: Vec<PriceSize>,
+    pub asks: Vec<PriceSize>,
+}
+
+async fn book(
+    State(api): ApiState,
+    Path(symbol): Path<String>,
+    Query(query): Query<BookQuery>,
+) -> Response {
+    let symbol = symbol.to_uppercase();
+    let depth = match query.depth.unwrap_or(api.config.book_depth) {
+        0 => usize::MAX,
+        depth => depth,
+    };
+    let books = api.orderbooks.read().await;
+    match books.get(&symbol).and_then(|books| books.first()) {
+        Some(book) => Json(BookView {
+            symbol,
+            first_update_id: book.first_update_id,
+            last_update_id: book.last_update_id,
+            time: book.time,
+            is_valid: book.is_valid,
+            bids: book.bids.iter().take(depth).cloned().collect(),
+            asks: book.asks.iter().take(depth).cloned().collect(),
+        })
+        .into_response(),
+        None => (
+            StatusCode::NOT_FOUND,
+            Json(json!({"error": format!("No local book for {}", symbol)})),
+        )
+            .into_response(),
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Buffers {
+    pub buffered: u64,
+    pub streams: Vec<StreamStats>,
+}
+
+async fn buffers(State(api): ApiState) -> Json<Buffers> {
+    let streams = api.bus.stats();
+    Json(Buffers {
+        buffered: buffered(&streams),
+        streams,
+    })
+}
+
+async fn flush(State(api): ApiState) -> Json<Buffers> {
+    api.bus.flush().await;
+    buffers(State(api)).await
+}
+
+async fn upload(State(api): ApiState) -> Response {
+    let queued = match &api.upload_requests {
+        Some(requests) => upload_now(requests).await,
+        None => None,
+    };
+    match queued {
+        Some(queued) => Json(json!({"queued": queued})).into_response(),
+        None => (
+            StatusCode::SERVICE_UNAVAILABLE,
+            Json(json!({"error": "No task rotating the files"})),
+        )
+            .into_response(),
+    }
+}
+
+async fn add_symbol(State(api): ApiState, Path(symbol): Path<String>) -> Response {
+    if api.control.streams().is_empty() {
+        return (
+            StatusCode::CONFLICT,
+            Json(json!({"error": "No stream subscribed to take the kinds of streams from"})),
+        )
+            .into_response();
+    }
+    let added = api.control.add_symbol(&symbol);
+    Json(json!({"subscribed": names(&added)})).into_response()
+}
+
+async fn remove_symbol(
+    State(api): ApiState,
+    Path(symbol): Path<String>,
+) -> Json<serde_json::Value> {
+    let removed = api.control.remove_symbol(&symbol);
+    // The book would no longer be kept up to date.
+    api.orderbooks.write().await.remove(&symbol.to_uppercase());
+    Json(json!({"unsubscribed": names(&removed)}))
+}
+
+async fn metrics(State(api): ApiState) -> Response {
+    match &api.metrics {
+        Some(metrics) => (
+            [(header::CONTENT_TYPE, "text/plain; version=0.0.4")],
+            metrics.render().await,
+        )
+            .into_response(),
+        None => StatusCode::NOT_FOUND.into_response(),
+    }
+}
@@ -0,0 +1,558 @@
+//! An in-process fake of the Binance websocket and REST apis, so connections, books and sinks can be
+//! tested end-to-end without network.
+//!
+//! The exchange serves combined streams on `/stream?streams=...`, answers `SUBSCRIBE` requests, pings its
+//! clients and closes every connection once it reaches its lifetime, like the daily disconnect.
+//! Events are generated by a synthetic market whose depth updates chain their update ids, and the
+//! `depth` and `exchangeInfo` REST endpoints are served from the same books.
+//! Alternatively, a list of recorded frames can be served verbatim.
+use std::collections::{BTreeMap, HashMap};
+use std::net::SocketAddr;
+use std::sync::{
+    atomic::{AtomicUsize, Ordering},
+    Arc, Mutex,
+};
+use std::time::Duration;
+
+use chrono::Utc;
+use futures_util::{SinkExt, StreamExt};
+use log::{debug, info, warn};
+use rust_decimal::Decimal;
+use serde_json::{json, Value};
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt},
+    net::{TcpListener, TcpStream},
+    sync::broadcast,
+    task::JoinHandle,
+};
+use tokio_tungstenite::tungstenite::{
+    handshake::server::{Request, Response},
+    protocol::{frame::coding::CloseCode, CloseFrame},
+    Message,
+};
+
+use super::{
+    constants::Symbol,
+    models::orderbook::{OrderbookMessage, PriceSize},
+    websocket::router::StreamKind,
+};
+
+/// Behaviour of a [`MockExchange`].
+#[derive(Debug, Clone)]
+pub struct MockConfig {
+    /// Symbols listed by `exchangeInfo`, each gets its own synthetic market.
+    pub symbols: Vec<Symbol>,
+    /// Frames sent in order to every connection instead of generated events, one per `event_interval`.
+    pub fixtures: Option<Vec<String>>,
+    /// How often the market moves, every move produces one event on each stream of a symbol.
+    pub event_interval: Duration,
+    pub ping_interval: Duration,
+    /// Connections are closed by the server once they are this old.
+    pub connection_lifetime: Duration,
+    /// Levels on each side of the initial books.
+    pub depth: usize,
+    pub seed: u64,
+    /// Added to the local clock for the server time, to simulate a host clock that drifted.
+    pub clock_offset_ms: i64,
+}
+impl Default for MockConfig {
+    fn default() -> Self {
+        Self {
+            symbols: vec!["BTCUSDT".to_string()],
+            fixtures: None,
+            event_interval: Duration::from_millis(100),
+            ping_interval: Duration::from_secs(180),
+            connection_lifetime: Duration::from_secs(24 * 3600),
+            depth: 20,
+            seed: 42,
+            clock_offset_ms: 0,
+        }
+    }
+}
+
+/// Prices and quantities are kept as integer ticks.
+const PRICE_SCALE: u32 = 2;
+const QUANTITY_SCALE: u32 = 3;
+/// Levels generated for partial depth streams, trimmed to what each stream asks for.
+const PARTIAL_DEPTH_LEVELS: usize = 20;
+
+/// A payload produced by the market, sent on every stream of its symbol and kind.
+#[derive(Debug, Clone)]
+struct MarketEvent {
+    symbol: String,
+    kind: StreamKind,
+    data: Value,
+}
+
+struct SymbolMarket {
+    symbol: Symbol,
+    update_id: i64,
+    trade_id: i64,
+    bids: BTreeMap<i64, i64>,
+    asks: BTreeMap<i64, i64>,
+    rng: u64,
+}
+impl SymbolMarket {
+    fn new(symbol: &str, depth: usize, seed: u64) -> Self {
+        let mid = 2_000_000;
+        let levels = depth as i64;
+        Self {
+            symbol: symbol.to_string(),
+            update_id: 1000,
+            trade_id: 0,
+            bids: (1..=levels).map(|offset| (mid - offset, 1000)).collect(),
+            asks: (1..=levels).map(|offset| (mid + offset, 1000)).collect(),
+            rng: seed.max(1),
+        }
+    }
+    fn next_random(&mut self) -> u64 {
+        self.rng ^= self.rng << 13;
+        self.rng ^= self.rng >> 7;
+        self.rng ^= self.rng << 17;
+        self.rng
+    }
+    fn mid(&self) -> i64 {
+        match (self.bids.keys().next_back(), self.asks.keys().next()) {
+            (Some(bid), Some(ask)) => (bid + ask) / 2,
+            (Some(bid), None) => bid + 1,
+            (None, Some(ask)) => ask - 1,
+            (None, None) => 2_000_000,
+        }
+    }
+    /// Changes a few levels around the mid price, each change consuming one update id.
+    fn next_depth_update(&mut self) -> OrderbookMessage {
+        let previous = self.update_id;
+        let mid = self.mid();
+        let mut bids = BTreeMap::new();
+        let mut asks = BTreeMap::new();
+        for _ in 0..1 + self.next_random() % 3 {
+            let offset = 1 + (self.next_random() % 10) as i64;
+            let quantity = (self.next_random() % 3000) as i64;
+            let (book, changes, price) = if self.next_random() & 1 == 0 {
+                (&mut self.bids, &mut bids, mid - offset)
+            } else {
+                (&mut self.asks, &mut asks, mid + offset)
+            };
+            if quantity == 0 {
+                book.remove(&price);
+            } else {
+                book.insert(price, quantity);
+            }
+            changes.insert(price, quantity);
+            self.update_id += 1;
+        }
+        OrderbookMessage {
+            event_type: "depthUpdate".to_string(),
+            time: Utc::now(),
+            symbol: self.symbol.clone(),
+            first_update_id: previous + 1,
+            last_update_id: self.update_id,
+            bids: levels(bids.iter().rev(), usize::MAX),
+            asks: levels(asks.iter(), usize::MAX),
+            prev_last_update_id: Some(previous),
+        }
+    }
+    fn next_trade(&mut self) -> Value {
+        self.trade_id += 1;
+        let buyer_is_maker = self.next_random() & 1 == 0;
+        let price = if buyer_is_maker {
+            self.bids.keys().next_back().copied()
+        } else {
+            self.asks.keys().next().copied()
+        }
+        .unwrap_or_else(|| self.mid());
+        let quantity = 1 + (self.next_random() % 2000) as i64;
+        let now = Utc::now().timestamp_millis();
+        json!({
+            "e": "trade",
+            "E": now,
+            "T": now,
+            "s": self.symbol,
+            "t": self.trade_id,
+            "p": Decimal::new(price, PRICE_SCALE).to_string(),
+            "q": Decimal::new(quantity, QUANTITY_SCALE).to_string(),
+            "X": "MARKET",
+            "m": buyer_is_maker,
+        })
+    }
+    fn book_ticker(&self) -> Value {
+        let (bid, bid_size) = self
+            .bids
+            .iter()
+            .next_back()
+            .map_or((0, 0), |(p, q)| (*p, *q));
+        let (ask, ask_size) = self.asks.iter().next().map_or((0, 0), |(p, q)| (*p, *q));
+        json!({
+            "e": "bookTicker",
+            "u": self.update_id,
+            "s": self.symbol,
+            "b": Decimal::new(bid, PRICE_SCALE).to_string(),
+            "B": Decimal::new(bid_size, QUANTITY_SCALE).to_string(),
+            "a": Decimal::new(ask, PRICE_SCALE).to_string(),
+            "A": Decimal::new(ask_size, QUANTITY_SCALE).to_string(),
+        })
+    }
+    /// The top `limit` levels, as sent by partial depth streams.
+    fn partial_depth(&self, limit: usize) -> OrderbookMessage {
+        OrderbookMessage {
+            event_type: "depthUpdate".to_string(),
+            time: Utc::now(),
+            symbol: self.symbol.clone(),
+            first_update_id: self.update_id,
+            last_update_id: self.update_id,
+            bids: levels(self.bids.iter().rev(), limit),
+            asks: levels(self.asks.iter(), limit),
+            prev_last_update_id: Some(self.update_id),
+        }
+    }
+    /// The body of the REST depth endpoint.
+    fn snapshot(&self, limit: usize) -> Value {
+        let now = Utc::now().timestamp_millis();
+        let book = self.partial_depth(limit);
+        json!({
+            "lastUpdateId": self.update_id,
+            "E": now,
+            "T": now,
+            "bids": book.bids.iter().map(|l| [l.price.to_string(), l.size.to_string()]).collect::<Vec<_>>(),
+            "asks": book.asks.iter().map(|l| [l.price.to_string(), l.size.to_string()]).collect::<Vec<_>>(),
+        })
+    }
+}
+
+fn levels<'a>(levels: impl Iterator<Item = (&'a i64, &'a i64)>, limit: usize) -> Vec<PriceSize> {
+    levels
+        .take(limit)
+        .map(|(price, quantity)| PriceSize {
+            price: Decimal::new(*price, PRICE_SCALE),
+            size: Decimal::new(*quantity, QUANTITY_SCALE),
+        })
+        .collect()
+}
+
+struct Shared {
+    config: MockConfig,
+    markets: Mutex<HashMap<Symbol, SymbolMarket>>,
+    events: broadcast::Sender<MarketEvent>,
+    connections: AtomicUsize,
+    tasks: Mutex<Vec<JoinHandle<()>>>,
+}
+impl Shared {
+    /// Moves every market once and publishes the resulting events.
+    fn tick(&self) {
+        let mut markets = self.markets.lock().unwrap();
+        for market in markets.values_mut() {
+            let symbol = market.symbol.to_lowercase();
+            let update = market.next_depth_update();
+            let events = [
+                (StreamKind::Trade, market.next_trade()),
+                (
+                    StreamKind::DepthUpdate,
+                    serde_json::to_value(update).unwrap(),
+                ),
+                (StreamKind::BookTicker, market.book_ticker()),
+                (
+                    StreamKind::PartialDepth,
+                    serde_json::to_value(market.partial_depth(PARTIAL_DEPTH_LEVELS)).unwrap(),
+                ),
+            ];
+            for (kind, data) in events {
+                // Nobody listening is not an error.
+                _ = self.events.send(MarketEvent {
+                    symbol: symbol.clone(),
+                    kind,
+                    data,
+                });
+            }
+        }
+    }
+}
+
+/// A fake exchange listening on a local port, stopped when dropped.
+pub struct MockExchange {
+    addr: SocketAddr,
+    shared: Arc<Shared>,
+}
+impl MockExchange {
+    /// Binds to an ephemeral port on localhost and starts serving.
+    pub async fn start(config: MockConfig) -> std::io::Result<Self> {
+        let listener = TcpListener::bind("127.0.0.1:0").await?;
+        let addr = listener.local_addr()?;
+        let markets = config
+            .symbols
+            .iter()
+            .enumerate()
+            .map(|(i, symbol)| {
+                let market = SymbolMarket::new(symbol, config.depth, config.seed + i as u64);
+                (symbol.clone(), market)
+            })
+            .collect();
+        let (events, _) = broadcast::channel(4096);
+        let shared = Arc::new(Shared {
+            config,
+            markets: Mutex::new(markets),
+            events,
+            connections: AtomicUsize::new(0),
+            tasks: Mutex::new(Vec::new()),
+        });
+        let market_task = tokio::spawn({
+            let shared = shared.clone();
+            async move {
+                let mut interval = tokio::time::interval(shared.config.event_interval);
+                loop {
+                    interval.tick().await;
+                    shared.tick();
+                }
+            }
+        });
+        let accept_task = tokio::spawn(accept(listener, shared.clone()));
+        shared
+            .tasks
+            .lock()
+            .unwrap()
+            .extend([market_task, accept_task]);
+        info!("Mock exchange listening on {}", addr);
+        Ok(Self { addr, shared })
+    }
+    pub fn ws_base_url(&self) -> String {
+        format!("ws://{}", self.addr)
+    }
+    pub fn http_base_url(&self) -> String {
+        format!("http://{}", self.addr)
+    }
+    /// Websocket connections accepted so far.
+    pub fn connections(&self) -> usize {
+        self.shared.connections.load(Ordering::SeqCst)
+    }
+    /// Last update id of the book of `symbol`, as the REST depth endpoint would report it.
+    pub fn last_update_id(&self, symbol: &str) -> Option<i64> {
+        let markets = self.shared.markets.lock().unwrap();
+        markets.get(symbol).map(|market| market.update_id)
+    }
+}
+impl Drop for MockExchange {
+    fn drop(&mut self) {
+        for task in self.shared.tasks.lock().unwrap().drain(..) {
+            task.abort();
+        }
+    }
+}
+
+async fn accept(listener: TcpListener, shared: Arc<Shared>) {
+    loop {
+        match listener.accept().await {
+            Ok((stream, peer)) => {
+                debug!("Mock exchange accepted {}", peer);
+                let task = tokio::spawn(serve_client(stream, shared.clone()));
+                let mut tasks = shared.tasks.lock().unwrap();
+                tasks.retain(|task| !task.is_finished());
+                tasks.push(task);
+            }
+            Err(e) => warn!("Mock exchange failed to accept a connection: {}", e),
+        }
+    }
+}
+
+/// Peeks at the request head to tell websocket upgrades from plain REST requests.
+async fn serve_client(stream: TcpStream, shared: Arc<Shared>) {
+    let mut head = vec![0u8; 8192];
+    let head = loop {
+        let read = match stream.peek(&mut head).await {
+            Ok(0) | Err(_) => return,
+            Ok(read) => read,
+        };
+        if head[..read].windows(4).any(|w| w == b"\r\n\r\n") || read == head.len() {
+            break String::from_utf8_lossy(&head[..read]).to_ascii_lowercase();
+        }
+        tokio::time::sleep(Duration::from_millis(1)).await;
+    };
+    if head.contains("upgrade: websocket") {
+        serve_websocket(stream, shared).await;
+    } else {
+        serve_rest(stream, shared).await;
+    }
+}
+
+async fn serve_websocket(stream: TcpStream, shared: Arc<Shared>) {
+    let mut uri = String::new();
+    // The error type is set by tungstenite.
+    #[allow(clippy::result_large_err)]
+    let callback = |request: &Request, response: Response| {
+        uri = request.uri().to_string();
+        Ok(response)
+    };
+    let socket = match tokio_tungstenite::accept_hdr_async(stream, callback).await {
+        Ok(socket) => socket,
+        Err(e) => {
+            warn!("Mock exchange websocket handshake failed: {}", e);
+            return;
+        }
+    };
+    shared.connections.fetch_add(1, Ordering::SeqCst);
+    let mut streams = query(&uri, "streams")
+        .map(|streams| streams.split('/').map(str::to_string).collect::<Vec<_>>())
+        .unwrap_or_default();
+    let (mut sender, mut receiver) = socket.split();
+    let mut events = shared.events.subscribe();
+    let mut fixtures = shared.config.fixtures.clone().map(Vec::into_iter);
+    let mut fixture_interval = tokio::time::interval(shared.config.event_interval);
+    let mut ping_interval = tokio::time::interval(shared.config.ping_interval);
+    ping_interval.tick().await;
+    let lifetime = tokio::time::sleep(shared.config.connection_lifetime);
+    tokio::pin!(lifetime);
+    loop {
+        let outgoing = tokio::select! {
+            _ = &mut lifetime => {
+                let close = CloseFrame {
+                    code: CloseCode::Normal,
+                    reason: "connection lifetime reached".into(),
+                };
+                _ = sender.send(Message::Close(Some(close))).await;
+                return;
+            }
+            _ = ping_interval.tick() => vec![Message::Ping(b"mock".to_vec())],
+            _ = fixture_interval.tick(), if fixtures.is_some() => {
+                fixtures.as_mut().unwrap().next().map(Message::Text).into_iter().collect()
+            }
+            event = events.recv(), if fixtures.is_none() => match event {
+                Ok(event) => streams
+                    .iter()
+                    .filter(|stream| subscribed(stream, &event))
+                    .map(|stream| Message::Text(json!({"stream": stream, "data": payload(stream, &event)}).to_string()))
+                    .collect(),
+                Err(broadcast::error::RecvError::Lagged(missed)) => {
+                    warn!("Mock exchange client lagging, {} events skipped", missed);
+                    vec![]
+                }
+                Err(broadcast::error::RecvError::Closed) => return,
+            },
+            message = receiver.next() => match message {
+                Some(Ok(Message::Text(text))) => handle_request(&text, &mut streams).into_iter().collect(),
+                Some(Ok(Message::Ping(payload))) => vec![Message::Pong(payload)],
+                Some(Ok(Message::Close(_))) | Some(Err(_)) | None => return,
+                Some(Ok(_)) => vec![],
+            },
+        };
+        for message in outgoing {
+            if sender.send(message).await.is_err() {
+                return;
+            }
+        }
+    }
+}
+
+fn subscribed(stream: &str, event: &MarketEvent) -> bool {
+    stream.split('@').next() == Some(event.symbol.as_str())
+        && StreamKind::from_stream_name(stream) == event.kind
+}
+
+/// The data of an event as sent on `stream`, partial depth streams only get the levels they ask for.
+fn payload(stream: &str, event: &MarketEvent) -> Value {
+    let mut data = event.data.clone();
+    if event.kind == StreamKind::PartialDepth {
+        let limit = stream
+            .split('@')
+            .nth(1)
+            .and_then(|kind| kind["depth".len()..].parse::<usize>().ok())
+            .unwrap_or(PARTIAL_DEPTH_LEVELS);
+        for side in ["b", "a"] {
+            if let Some(levels) = data[side].as_array_mut() {
+                levels.truncate(limit);
+            }
+        }
+    }
+    data
+}
+
+/// Applies `SUBSCRIBE` and `UNSUBSCRIBE` requests and returns the response.
+fn handle_request(text: &str, streams: &mut Vec<String>) -> Option<Message> {
+    let request = serde_json::from_str::<Value>(text).ok()?;
+    let params = request["params"].as_array().cloned().unwrap_or_default();
+    let params = params.iter().filter_map(Value::as_str);
+    let response = match request["method"].as_str() {
+        Some("SUBSCRIBE") => {
+            for stream in params {
+                if !streams.iter().any(|s| s == stream) {
+                    streams.push(stream.to_string());
+                }
+            }
+            json!({"result": null, "id": request["id"]})
+        }
+        Some("UNSUBSCRIBE") => {
+            let params = params.collect::<Vec<_>>();
+            streams.retain(|stream| !params.contains(&stream.as_str()));
+            json!({"result": null, "id": request["id"]})
+        }
+        Some("LIST_SUBSCRIPTIONS") => json!({"result": streams, "id": request["id"]}),
+        _ => json!({"error": {"code": 2, "msg": "Invalid request"}, "id": request["id"]}),
+    };
+    Some(Message::Text(response.to_string()))
+}
+
+fn query(uri: &str, name: &str) -> Option<String> {
+    let url = url::Url::parse(&format!("http://localhost{}", uri)).ok()?;
+    url.query_pairs()
+        .find(|(key, _)| key == name)
+        .map(|(_, value)| value.into_owned())
+}
+
+async fn serve_rest(mut stream: TcpStream, shared: Arc<Shared>) {
+    let mut head = Vec::new();
+    let mut buffer = [0u8; 1024];
+    while !head.windows(4).any(|w| w == b"\r\n\r\n") {
+        match stream.read(&mut buffer).await {
+            Ok(0) | Err(_) => return,
+            Ok(read) => head.extend_from_slice(&buffer[..read]),
+        }
+    }
+    let head = String::from_utf8_lossy(&head);
+    let target = head.split_whitespace().nth(1).unwrap_or("/");
+    let path = target.split('?').next().unwrap_or(target);
+    let server_time = Utc::now().timestamp_millis() + shared.config.clock_offset_ms;
+    let (status, body) = if path.ends_with("/depth") {
+        depth(target, &shared)
+    } else if path.ends_with("/time") {
+        ("200 OK", json!({ "serverTime": server_time }))
+    } else if path.ends_with("/exchangeInfo") {
+        let symbols = shared
+            .config
+            .symbols
+            .iter()
+            .map(|symbol| json!({"symbol": symbol, "status": "TRADING"}))
+            .collect::<Vec<_>>();
+        let info = json!({
+            "timezone": "UTC",
+            "serverTime": server_time,
+            "symbols": symbols,
+        });
+        ("200 OK", info)
+    } else {
+        ("404 Not Found", json!({"code": -1, "msg": "Not found"}))
+    };
+    let body = body.to_string();
+    let response = format!(
+        "HTTP/1.1 {}\r\nContent-Type: application/json\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+        status,
+        body.len(),
+        body
+    );
+    _ = stream.write_all(response.as_bytes()).await;
+    _ = stream.shutdown().await;
+}
+
+fn depth(target: &str, shared: &Shared) -> (&'static str, Value) {
+    let invalid = (
+        "400 Bad Request",
+        json!({"code": -1121, "msg": "Invalid symbol."}),
+    );
+    let symbol = match query(target, "symbol") {
+        Some(symbol) => symbol,
+        None => return invalid,
+    };
+    let limit = query(target, "limit")
+        .and_then(|limit| limit.parse().ok())
+        .unwrap_or(500);
+    match shared.markets.lock().unwrap().get(&symbol) {
+        Some(market) => ("200 OK", market.snapshot(limit)),
+        None => invalid,
+    }
+}
@@ -1,4 +1,10 @@
+//! Everything that talks to Binance: endpoints, payload models, REST snapshots and websocket streams,
+//! the offset of the local clock to the exchange's in [`time_sync`], plus a local `mock` of the exchange for tests,
+//! built for them or with the `mock` feature.
 pub mod constants;
+#[cfg(any(test, feature = "mock"))]
+pub mod mock;
 pub mod rest;
+pub mod time_sync;
 pub mod websocket;
 pub mod models;
\ No newline at end of file
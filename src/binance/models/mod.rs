@@ -1,3 +1,4 @@
+//! Payloads received from Binance and the local orderbook built from them.
 pub mod orderbook;
 pub mod trades;
 pub mod book_ticker;
\ No newline at end of file
@@ -8,6 +8,7 @@ use serde_with::{serde_as, TimestampMilliSeconds};
 use std::collections::HashMap;
 use std::sync::Arc;
 use tokio::sync::RwLock;
+/// The most recent books of each symbol, newest first.
 pub type OrderBooksRWL = Arc<RwLock<HashMap<String, Vec<OrderBook>>>>;
 use rayon::prelude::*;
 
@@ -16,12 +17,15 @@ pub fn new_orderbooks_rwl() -> OrderBooksRWL {
     Arc::new(RwLock::new(HashMap::new()))
 }
 use rust_decimal::Decimal;
+/// A single level of a book.
 #[derive(Debug, Clone, PartialEq, Serialize, Deserialize, Eq)]
 pub struct PriceSize {
     pub price: Decimal,
     pub size: Decimal,
 }
 
+/// A local orderbook, built from a first update and kept up to date with the following diffs.
+/// `is_valid` turns false as soon as an update does not follow the previous one.
 #[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
 pub struct OrderBook {
     pub bids: Vec<PriceSize>,
@@ -42,6 +46,7 @@ impl OrderBook {
             first_update_id: update.first_update_id,
         }
     }
+    /// Applies a diff, levels with a zero size are removed.
     pub fn update(mut self, update: OrderbookMessage) -> Self {
         //Check that the order of updates is whats expected, different process for spot and futures.
         let orderly = match update.prev_last_update_id {
@@ -84,8 +89,9 @@ impl OrderBook {
         self
     }
 }
+/// A `depthUpdate` stream event. `prev_last_update_id` is only sent by futures.
 #[serde_as]
-#[derive(Default, Debug, Clone, PartialEq, Deserialize)]
+#[derive(Default, Debug, Clone, PartialEq, Serialize, Deserialize)]
 #[serde(rename_all = "camelCase")]
 pub struct OrderbookMessage {
     #[serde(rename = "e")]
@@ -108,13 +114,37 @@ pub struct OrderbookMessage {
     #[serde(rename = "pu")]
     pub prev_last_update_id: Option<i64>,
 }
+impl OrderbookMessage {
+    /// One row per changed level, bids first, each with the update ids.
+    pub fn to_csv_format(&self) -> Vec<UpdateCSVFormat> {
+        let row = UpdateCSVFormat {
+            timestamp: self.time,
+            first_update_id: Some(self.first_update_id),
+            last_update_id: Some(self.last_update_id),
+            prev_last_update_id: self.prev_last_update_id,
+            ..Default::default()
+        };
+        UpdateCSVFormat::from_levels(row, &self.bids, &self.asks)
+    }
+}
 mod orderbook_serde {
     use rust_decimal::Decimal;
-    use serde::{self, Deserialize, Deserializer};
+    use serde::{self, de::Error, Deserialize, Deserializer, Serializer};
     use std::str::FromStr;
 
     use super::PriceSize;
 
+    pub fn serialize<S>(levels: &[PriceSize], serializer: S) -> Result<S::Ok, S::Error>
+    where
+        S: Serializer,
+    {
+        serializer.collect_seq(
+            levels
+                .iter()
+                .map(|level| [level.price.to_string(), level.size.to_string()]),
+        )
+    }
+
     pub fn deserialize<'de, D>(deserializer: D) -> Result<Vec<PriceSize>, D::Error>
     where
         D: Deserializer<'de>,
@@ -122,18 +152,43 @@ mod orderbook_serde {
         let s: Vec<Vec<String>> = Vec::deserialize(deserializer)?;
         let mut v = Vec::new();
         for item in s {
+            let [price, size] = item.as_slice() else {
+                return Err(D::Error::invalid_length(item.len(), &"a price and a size"));
+            };
             v.push(PriceSize {
-                price: Decimal::from_str(&item[0]).unwrap(),
-                size: Decimal::from_str(&item[1]).unwrap(),
+                price: Decimal::from_str(price).map_err(D::Error::custom)?,
+                size: Decimal::from_str(size).map_err(D::Error::custom)?,
             });
         }
         Ok(v)
     }
 }
 
+/// A row of a book history or snapshot file.
+/// The update ids are those of the update or snapshot the level is from, files written before they were added
+/// have none.
 #[derive(Default, Debug, Clone, PartialEq, Serialize, Deserialize)]
 pub struct UpdateCSVFormat {
     pub timestamp: DateTime<Utc>,
     pub price: Decimal,
     pub quantity: Decimal,
+    #[serde(rename = "U", default)]
+    pub first_update_id: Option<i64>,
+    #[serde(rename = "u", default)]
+    pub last_update_id: Option<i64>,
+    #[serde(rename = "pu", default)]
+    pub prev_last_update_id: Option<i64>,
+}
+impl UpdateCSVFormat {
+    /// One row per level, bids first, `row` giving the timestamp and ids.
+    pub fn from_levels(row: Self, bids: &[PriceSize], asks: &[PriceSize]) -> Vec<Self> {
+        bids.iter()
+            .chain(asks)
+            .map(|level| Self {
+                price: level.price,
+                quantity: level.size,
+                ..row.clone()
+            })
+            .collect()
+    }
 }
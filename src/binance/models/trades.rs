@@ -4,35 +4,40 @@ use serde::Serialize;
 use serde_with::{serde_as, TimestampMilliSeconds};
 use chrono::Utc;
 use rust_decimal::Decimal;
+
+use crate::binance::time_sync::ServerClock;
+/// A `trade` stream event.
+/// Serialized with descriptive field names, which are also accepted when deserializing.
 #[serde_as]
 #[derive(Default, Debug, Clone, PartialEq, Serialize, Deserialize)]
 #[serde(rename_all = "camelCase")]
 #[serde(default)]
 pub struct Trade {
-    #[serde(rename(deserialize = "e"))]
+    #[serde(rename(deserialize = "e"), alias = "eventType")]
     pub event_type: String,
-    #[serde(rename(deserialize = "E"))]
+    #[serde(rename(deserialize = "E"), alias = "eventTime")]
     #[serde_as(as = "TimestampMilliSeconds")]
     pub event_time: DateTime<Utc>,
-    #[serde(rename(deserialize = "T"))]
+    #[serde(rename(deserialize = "T"), alias = "tradeTime")]
     #[serde_as(as = "TimestampMilliSeconds")]
     pub trade_time: DateTime<Utc>,
-    #[serde(rename(deserialize = "s"))]
+    #[serde(rename(deserialize = "s"), alias = "symbol")]
     pub symbol: String,
-    #[serde(rename(deserialize = "t"))]
+    #[serde(rename(deserialize = "t"), alias = "tradeId")]
     pub trade_id: i64,
-    #[serde(rename(deserialize = "p"))]
+    #[serde(rename(deserialize = "p"), alias = "price")]
     #[serde(with = "rust_decimal::serde::str")]
     pub price: Decimal,
-    #[serde(rename(deserialize = "q"))]
+    #[serde(rename(deserialize = "q"), alias = "quantity")]
     #[serde(with = "rust_decimal::serde::str")]
     pub quantity: Decimal,
-    #[serde(rename(deserialize = "X"))]
+    #[serde(rename(deserialize = "X"), alias = "x")]
     pub x: Option<String>,
-    #[serde(rename(deserialize = "m"))]
+    #[serde(rename(deserialize = "m"), alias = "buyerIsTheMarketMaker")]
     pub buyer_is_the_market_maker: bool,
 }
 impl Trade {
+    /// Side of the taker.
     pub fn side(&self) -> String {
         if self.buyer_is_the_market_maker {
             "SELL".to_string()
@@ -40,12 +45,14 @@ impl Trade {
             "BUY".to_string()
         }
     }
-    pub fn calculate_receipt_delay(&self) -> i64 {
-        let now = Utc::now();
-        let delay = now - self.trade_time;
+    /// Milliseconds elapsed between the trade and `received`, a local time, in the exchange clock as estimated by
+    /// `clock`, or in the local clock without one, which may drift from the exchange clock.
+    pub fn calculate_receipt_delay(&self, received: DateTime<Utc>, clock: Option<&ServerClock>) -> i64 {
+        let received = clock.map_or(received, |clock| clock.correct(received));
+        let delay = received - self.trade_time;
         delay.num_milliseconds()
     }
-    pub fn get_data(&self) {
-        println!("{} {} ${} ms delay={}", self.symbol,self.side(),self.price*self.quantity,self.calculate_receipt_delay());
+    pub fn get_data(&self, clock: Option<&ServerClock>) {
+        log::debug!("{} {} ${} ms delay={}", self.symbol,self.side(),self.price*self.quantity,self.calculate_receipt_delay(Utc::now(), clock));
     }
 }
\ No newline at end of file
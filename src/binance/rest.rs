@@ -1,5 +1,5 @@
 
-use super::models::orderbook::PriceSize;
+use super::models::orderbook::{PriceSize, UpdateCSVFormat};
 
 
 use chrono::DateTime;
@@ -8,7 +8,7 @@ use serde::Deserialize;
 use serde::Serialize;
 
 
-/// Retrieves an orderbook snapshot from the REST API. If an endpoint fails, it will try the next one.
+// Retrieves an orderbook snapshot from the REST API. If an endpoint fails, it will try the next one.
 // pub async fn get_orderbook(
 //     client: Client,
 //     symbol: &str,
@@ -41,7 +41,7 @@ use serde::Serialize;
 //     }
 //     Ok(RestOrderBook::default())
 // }
-/// Gets all the orderbooks at the same time from the REST API.
+// Gets all the orderbooks at the same time from the REST API.
 // pub async fn get_orderbooks(
 //     request: DataRequest,
 //     limit: u32,
@@ -155,6 +155,7 @@ use serde::Serialize;
 //     }
 // }
 
+/// An orderbook snapshot as returned by the REST depth endpoint.
 #[derive(Debug, Clone, PartialEq, Serialize, Deserialize,Default)]
 #[serde(rename_all = "camelCase")]
 #[serde(default)]
@@ -166,6 +167,18 @@ pub struct RestOrderBook {
     pub received_ts: DateTime<Utc>,
 }
 
+impl RestOrderBook {
+    /// One row per level, bids first, stamped with the time the snapshot was received and its update id.
+    pub fn to_csv_format(&self) -> Vec<UpdateCSVFormat> {
+        let row = UpdateCSVFormat {
+            timestamp: self.received_ts,
+            last_update_id: Some(self.last_update_id),
+            ..Default::default()
+        };
+        UpdateCSVFormat::from_levels(row, &self.bids, &self.asks)
+    }
+}
+
 pub fn get_ts() -> DateTime<Utc> {
     Utc::now()
 }
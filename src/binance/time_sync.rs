@@ -0,0 +1,188 @@
+//! Estimates the offset of the local clock to Binance's, from the server time of the rest api, so receive times
+//! and latencies stay meaningful when the host clock drifts.
+//!
+//! Each sample times a `/time` request and assumes the server read its clock halfway through the round trip, as
+//! NTP does. The longer the round trip, the less accurate the sample, so the estimate is the sample with the
+//! shortest round trip among the last few.
+use std::collections::VecDeque;
+use std::sync::Mutex;
+use std::time::Duration;
+
+use chrono::{DateTime, TimeZone, Utc};
+use log::{debug, error, info};
+use serde::{Deserialize, Serialize};
+use tokio::time::Instant;
+
+use super::websocket::requests::{BinanceAssetType, FuturesType};
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct TimeSyncConfig {
+    /// The `/time` endpoint polled.
+    pub url: String,
+    pub interval: Duration,
+    /// Samples the estimate is picked from, the most recent ones.
+    pub samples: usize,
+    pub timeout: Duration,
+}
+impl TimeSyncConfig {
+    /// Polls the first rest endpoint of `asset_type`.
+    pub fn new(asset_type: &BinanceAssetType) -> Self {
+        let base_url = asset_type
+            .get_http_base_url_list()
+            .into_iter()
+            .next()
+            .unwrap_or_default();
+        Self {
+            url: format!("{}{}", base_url, time_path(asset_type)),
+            interval: Duration::from_secs(30),
+            samples: 8,
+            timeout: Duration::from_secs(5),
+        }
+    }
+    /// Polls the same path on `base_url`, e.g. a local mock exchange.
+    pub fn with_base_url(mut self, base_url: &str) -> Self {
+        if let Ok(url) = url::Url::parse(&self.url) {
+            self.url = format!("{}{}", base_url.trim_end_matches('/'), url.path());
+        }
+        self
+    }
+}
+
+fn time_path(asset_type: &BinanceAssetType) -> &'static str {
+    match asset_type {
+        BinanceAssetType::Spot => "/api/v3/time",
+        BinanceAssetType::Futures(FuturesType::USDMargined) => "/fapi/v1/time",
+        BinanceAssetType::Futures(FuturesType::CoinMargined) => "/dapi/v1/time",
+        BinanceAssetType::Options => "/eapi/v1/time",
+    }
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct ServerTime {
+    server_time: i64,
+}
+
+#[derive(Debug, Clone, Copy)]
+struct Sample {
+    offset: chrono::Duration,
+    rtt: chrono::Duration,
+    measured_at: DateTime<Utc>,
+}
+
+/// The offset of the exchange clock to the local one: exchange time = local time + offset.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct ClockEstimate {
+    pub offset_ms: f64,
+    /// Round trip of the request the offset was measured with, which bounds its error to half of it.
+    pub rtt_ms: f64,
+    pub measured_at: DateTime<Utc>,
+}
+impl ClockEstimate {
+    fn new(sample: &Sample) -> Self {
+        let ms = |duration: chrono::Duration| duration.num_microseconds().unwrap_or(0) as f64 / 1e3;
+        Self {
+            offset_ms: ms(sample.offset),
+            rtt_ms: ms(sample.rtt),
+            measured_at: sample.measured_at,
+        }
+    }
+    pub fn offset(&self) -> chrono::Duration {
+        chrono::Duration::microseconds((self.offset_ms * 1e3).round() as i64)
+    }
+}
+
+/// Polls the server time and keeps the estimated offset of the local clock.
+pub struct ServerClock {
+    config: TimeSyncConfig,
+    client: reqwest::Client,
+    samples: Mutex<VecDeque<Sample>>,
+    estimate: Mutex<Option<ClockEstimate>>,
+}
+impl ServerClock {
+    pub fn new(config: TimeSyncConfig) -> Self {
+        Self {
+            client: reqwest::Client::builder()
+                .timeout(config.timeout)
+                .build()
+                .unwrap(),
+            config,
+            samples: Mutex::new(VecDeque::new()),
+            estimate: Mutex::new(None),
+        }
+    }
+    pub fn config(&self) -> &TimeSyncConfig {
+        &self.config
+    }
+    /// The current estimate, `None` until the server time was read once.
+    pub fn estimate(&self) -> Option<ClockEstimate> {
+        self.estimate.lock().unwrap().clone()
+    }
+    /// `local` in the exchange clock, unchanged without an estimate.
+    pub fn correct(&self, local: DateTime<Utc>) -> DateTime<Utc> {
+        match self.estimate() {
+            Some(estimate) => local + estimate.offset(),
+            None => local,
+        }
+    }
+    /// The current time of the exchange clock.
+    pub fn now(&self) -> DateTime<Utc> {
+        self.correct(Utc::now())
+    }
+    /// Reads the server time once, and returns the estimate it leads to.
+    pub async fn sync(&self) -> Result<ClockEstimate, reqwest::Error> {
+        let sent = Utc::now();
+        let start = Instant::now();
+        let server_time = self
+            .client
+            .get(&self.config.url)
+            .send()
+            .await?
+            .error_for_status()?
+            .json::<ServerTime>()
+            .await?;
+        let rtt = chrono::Duration::from_std(start.elapsed()).unwrap_or(chrono::Duration::zero());
+        let server_time = Utc
+            .timestamp_millis_opt(server_time.server_time)
+            .single()
+            .unwrap_or(sent);
+        let sample = Sample {
+            offset: server_time - (sent + rtt / 2),
+            rtt,
+            measured_at: sent,
+        };
+        debug!(
+            "Server time sample: offset {}ms, rtt {}ms",
+            sample.offset.num_milliseconds(),
+            sample.rtt.num_milliseconds()
+        );
+        let mut samples = self.samples.lock().unwrap();
+        samples.push_back(sample);
+        while samples.len() > self.config.samples.max(1) {
+            samples.pop_front();
+        }
+        let best = samples.iter().min_by_key(|sample| sample.rtt).unwrap();
+        let estimate = ClockEstimate::new(best);
+        *self.estimate.lock().unwrap() = Some(estimate.clone());
+        Ok(estimate)
+    }
+    /// Reads the server time every `config.interval`.
+    pub async fn run(&self) {
+        let mut interval = tokio::time::interval(self.config.interval);
+        loop {
+            interval.tick().await;
+            let first = self.estimate().is_none();
+            match self.sync().await {
+                Ok(estimate) if first => info!(
+                    "Local clock is {:.1}ms behind the exchange, rtt {:.1}ms",
+                    estimate.offset_ms, estimate.rtt_ms
+                ),
+                Ok(_) => {}
+                Err(e) => error!(
+                    "Error reading the server time from {}: {}",
+                    self.config.url, e
+                ),
+            }
+        }
+    }
+}
@@ -1,46 +1,60 @@
-use chrono::Duration;
+use chrono::{Duration, Utc};
 use futures_util::{
     stream::{SplitSink, SplitStream},
     SinkExt, StreamExt,
 };
 use log::{debug, error, info, warn};
-use serde_json::{Map, Value};
-use std::sync::Arc;
+use std::sync::{
+    atomic::{AtomicU64, Ordering},
+    Arc,
+};
 use tokio::{
     net::TcpStream,
-    sync::{Notify, RwLock},
+    sync::{broadcast, Notify},
 };
 use tokio_tungstenite::{tungstenite::Message, MaybeTlsStream, WebSocketStream};
 
-use crate::binance::{
-    models::{orderbook::OrderBooksRWL, trades::Trade},
-    websocket::handlers::book_ticker::handle_book_ticker,
-};
-
 use super::{
-    handlers::{depth_update::handle_depth_update_message, trades::handle_trades},
+    control::{ConnectionControl, SubscriptionChange},
+    handlers::{EventHandlers, RawFrame},
     requests::DataRequest,
+    router::{route, Frame, StreamEvent},
+    watchdog::{LivenessConfig, Watchdog},
 };
 
 type OutgoingSocket = SplitSink<WebSocketStream<MaybeTlsStream<TcpStream>>, Message>;
 type IncomingSocket = SplitStream<WebSocketStream<MaybeTlsStream<TcpStream>>>;
+
+/// Source of the ids handed to [`RawFrame`]s, shared by every connection of the process.
+static NEXT_CONNECTION_ID: AtomicU64 = AtomicU64::new(1);
+
 /// Establishes a websocket connection to Binance and persists it for the duration of the program.
 /// If disconnected, it will attempt to reconnect uo to 5 times at an ever-increasing interval up to 26 seconds.
 /// It will try a max of 5 times before exiting the program.
+/// Each connection is supervised by a [`Watchdog`] which forces a reconnection when it goes silent.
+/// Every event received is dispatched to each of the `handlers`, in order.
 pub async fn establish_and_persist(
     requests: DataRequest,
-    orderbooks_rwl: OrderBooksRWL,
-    trade_updates_rwl: Arc<RwLock<Vec<Trade>>>,
+    handlers: EventHandlers,
+    liveness: LivenessConfig,
+) {
+    establish_and_persist_with_control(
+        Arc::new(ConnectionControl::new(requests)),
+        handlers,
+        liveness,
+    )
+    .await
+}
+/// Like [`establish_and_persist`], with the subscriptions kept in `control` so they can be changed at runtime.
+pub async fn establish_and_persist_with_control(
+    control: Arc<ConnectionControl>,
+    handlers: EventHandlers,
+    liveness: LivenessConfig,
 ) {
+    let handlers = Arc::new(handlers);
     let mut bad_attempts = 0;
     loop {
-        if establish(
-            requests.clone(),
-            orderbooks_rwl.clone(),
-            trade_updates_rwl.clone(),
-        )
-        .await
-        {
+        if establish(control.clone(), handlers.clone(), liveness.clone()).await {
             bad_attempts += 1;
         } else {
             bad_attempts = 0;
@@ -53,28 +67,48 @@ pub async fn establish_and_persist(
     }
 }
 /// Establishes a single websocket connection to Binance. Returns true if there was an error.
+/// A connection that delivered data before ending, such as the daily disconnect, is not one.
 async fn establish(
-    request: DataRequest,
-    orderbooks_rwl: OrderBooksRWL,
-    trade_updates_rwl: Arc<RwLock<Vec<Trade>>>,
+    control: Arc<ConnectionControl>,
+    handlers: Arc<EventHandlers>,
+    liveness: LivenessConfig,
 ) -> bool {
-    for endpoint in request.get_ws_urls().iter() {
+    let endpoints = control.request().get_ws_urls().len();
+    for index in 0..endpoints {
+        // Taken again on every attempt, so a change made meanwhile is either in the request or in `changes`.
+        let (request, changes) = control.watch();
+        let endpoint = &request.get_ws_urls()[index];
         info!("Attempting WS connection to {}", endpoint);
         match tokio_tungstenite::connect_async(endpoint).await {
             Ok((stream, response)) => {
                 info!("Connected to {endpoint} status: {}", response.status());
                 let (sender, receiver) = stream.split();
                 let ping_pong = Arc::new(Notify::new());
+                let watchdog = Arc::new(Watchdog::new(&request, liveness.clone()));
+                let connection_id = NEXT_CONNECTION_ID.fetch_add(1, Ordering::Relaxed);
+                control.connected(connection_id, endpoint);
+                for handler in handlers.iter() {
+                    handler.on_connect(connection_id, endpoint).await;
+                }
                 tokio::select! {
-                    _= process_incoming_message(receiver, ping_pong.clone(),orderbooks_rwl.clone(),trade_updates_rwl.clone()) => {
+                    _= process_incoming_message(receiver, connection_id, ping_pong.clone(),watchdog.clone(),handlers.clone()) => {
                         error!("Incoming message processing failed");
-                        return true;
                     }
-                    _= process_outgoing_message(sender, ping_pong.clone(),request.clone()) => {
+                    _= process_outgoing_message(sender, ping_pong.clone(),watchdog.clone(),request.clone(),changes) => {
                         error!("Outgoing message processing failed");
-                        return true;
                     }
+                    stall = watchdog.monitor() => {
+                        error!("Connection to {endpoint} stalled: {stall:?}");
+                        for handler in handlers.iter() {
+                            handler.on_stall(&stall).await;
+                        }
+                    }
+                }
+                control.disconnected(connection_id);
+                for handler in handlers.iter() {
+                    handler.on_disconnect(connection_id, endpoint).await;
                 }
+                return !watchdog.data_received();
             }
             Err(e) => {
                 error!("{:?}", e);
@@ -86,91 +120,120 @@ async fn establish(
 }
 
 async fn process_incoming_message(
-    receiver: IncomingSocket,
+    mut receiver: IncomingSocket,
+    connection_id: u64,
     ping_pong: Arc<Notify>,
-    orderbooks_rwl: OrderBooksRWL,
-    trade_updates_rwl: Arc<RwLock<Vec<Trade>>>,
+    watchdog: Arc<Watchdog>,
+    handlers: Arc<EventHandlers>,
 ) {
-    receiver
-        .for_each(|message| async {
-            match message {
-                Ok(text_message) => match text_message {
-                    Message::Text(text_message) => {
-                        debug!("Received message: {}", text_message);
-                        match serde_json::from_str::<Map<String, Value>>(&text_message) {
-                            Ok(unrouted_message) => match unrouted_message.contains_key("data") {
-                                true => match unrouted_message["data"]["e"].as_str().unwrap() {
-                                    "depthUpdate" => {
-                                        handle_depth_update_message(
-                                            unrouted_message["data"].clone(),
-                                            orderbooks_rwl.clone(),
-                                        )
-                                        .await;
-                                    }
-                                    "trade" => {
-                                        handle_trades(
-                                            unrouted_message["data"].clone(),
-                                            trade_updates_rwl.clone(),
-                                        )
-                                        .await;
-                                    }
-                                    "bookTicker" => {
-                                        handle_book_ticker(unrouted_message["data"].clone()).await;
-                                    }
-                                    _ => {
-                                        debug!("Unrecognized message: {:?}", unrouted_message);
-                                    }
-                                },
-                                false => {
-                                    if unrouted_message.keys().len() == 2
-                                        && unrouted_message.contains_key("result")
-                                        && unrouted_message["result"].is_null()
-                                    {
-                                        info!(
-                                            "Successfully subscribed to request id {}",
-                                            unrouted_message["id"]
-                                        );
-                                    } else {
-                                        warn!("Unrecognized message: {:?}", unrouted_message);
-                                    }
-                                }
-                            },
-                            Err(e) => {
-                                error!("Error parsing message: {:?}", e);
-                            }
+    while let Some(message) = receiver.next().await {
+        match message {
+            Ok(text_message) => match text_message {
+                Message::Text(text_message) => {
+                    let received = Utc::now();
+                    debug!("Received message: {}", text_message);
+                    let routed = route(&text_message);
+                    match &routed {
+                        Ok(frame) => {
+                            watchdog.record(frame.stream());
+                            dispatch(frame, &handlers).await;
+                        }
+                        Err(e) => {
+                            watchdog.record(None);
+                            error!("Error parsing message: {:?} {}", e, text_message);
                         }
                     }
-                    Message::Binary(_) => {
-                        warn!("Binary message received");
-                    }
-                    Message::Ping(_) => {
-                        info!("Received ping");
-                        ping_pong.notify_one();
-                    }
-                    Message::Pong(_) => {}
-                    Message::Close(cf) => {
-                        warn!("Close received {cf:?}");
-                        return;
-                    }
-                    Message::Frame(_) => {
-                        warn!("Frame received");
+                    let raw = RawFrame {
+                        received,
+                        connection_id,
+                        frame: routed.as_ref().ok(),
+                        text: &text_message,
+                    };
+                    for handler in handlers.iter() {
+                        handler.on_frame(&raw).await;
                     }
-                },
-                Err(e) => {
-                    warn!("Error receiving message: {:?}", e);
+                }
+                Message::Binary(_) => {
+                    watchdog.record(None);
+                    warn!("Binary message received");
+                }
+                Message::Ping(_) => {
+                    watchdog.record(None);
+                    info!("Received ping");
+                    ping_pong.notify_one();
+                }
+                Message::Pong(_) => {
+                    watchdog.record(None);
+                    debug!("Received pong");
+                }
+                Message::Close(cf) => {
+                    warn!("Close received {cf:?}");
                     return;
                 }
+                Message::Frame(_) => {
+                    warn!("Frame received");
+                }
+            },
+            Err(e) => {
+                warn!("Error receiving message: {:?}", e);
+                return;
+            }
+        }
+    }
+}
+
+/// Hands the events of a routed frame to every handler.
+pub async fn dispatch(frame: &Frame<'_>, handlers: &EventHandlers) {
+    match frame {
+        Frame::Event { event, .. } => match event {
+            StreamEvent::DepthUpdate(update) => {
+                for handler in handlers.iter() {
+                    handler.on_depth(update).await;
+                }
             }
-        })
-        .await;
+            StreamEvent::Trade(trade) => {
+                for handler in handlers.iter() {
+                    handler.on_trade(trade).await;
+                }
+            }
+            StreamEvent::BookTicker(ticker) => {
+                for handler in handlers.iter() {
+                    handler.on_book_ticker(ticker).await;
+                }
+            }
+            StreamEvent::PartialDepth { symbol, book } => {
+                for handler in handlers.iter() {
+                    handler.on_partial_depth(symbol, book).await;
+                }
+            }
+        },
+        Frame::Unrouted { stream, data } => {
+            debug!("Unrecognized message on {}: {}", stream, data);
+            for handler in handlers.iter() {
+                handler.on_unrouted(stream, data).await;
+            }
+        }
+        Frame::Response { id, error: None } => {
+            info!("Successfully subscribed to request id {:?}", id);
+        }
+        Frame::Response {
+            id,
+            error: Some(error),
+        } => {
+            warn!("Request id {:?} failed: {}", id, error);
+        }
+    }
 }
 
 async fn process_outgoing_message(
     mut sender: OutgoingSocket,
     ping_pong: Arc<tokio::sync::Notify>,
+    watchdog: Arc<Watchdog>,
     request: DataRequest,
+    mut changes: broadcast::Receiver<SubscriptionChange>,
 ) {
     let sub_message = request.get_subscribe_message();
+    let mut request_id = 1;
     match sender.send(Message::Text(sub_message.clone())).await {
         Ok(_) => {
             info!("Sent message {}", sub_message.clone());
@@ -179,14 +242,55 @@ async fn process_outgoing_message(
             error!("Error {:?} sending {}", e, sub_message);
         }
     }
+    let mut ping_interval = tokio::time::interval(watchdog.config().ping_interval);
     loop {
-        ping_pong.notified().await;
-        match sender.send(Message::Pong(vec![])).await {
-            Ok(_) => {
-                info!("Sent pong");
+        tokio::select! {
+            _ = ping_pong.notified() => {
+                match sender.send(Message::Pong(vec![])).await {
+                    Ok(_) => {
+                        info!("Sent pong");
+                    }
+                    Err(e) => {
+                        error!("{:?}", e);
+                    }
+                }
             }
-            Err(e) => {
-                error!("{:?}", e);
+            change = changes.recv() => {
+                let change = match change {
+                    Ok(change) => change,
+                    Err(e) => {
+                        warn!("Subscription changes lost ({}), reconnecting", e);
+                        return;
+                    }
+                };
+                for stream in change.streams() {
+                    match change {
+                        SubscriptionChange::Subscribe(_) => watchdog.track(stream),
+                        SubscriptionChange::Unsubscribe(_) => watchdog.untrack(stream),
+                    }
+                }
+                request_id += 1;
+                let message = change.message(request_id);
+                match sender.send(Message::Text(message.clone())).await {
+                    Ok(_) => {
+                        info!("Sent message {}", message);
+                    }
+                    Err(e) => {
+                        error!("Error {:?} sending {}", e, message);
+                        return;
+                    }
+                }
+            }
+            _ = ping_interval.tick() => {
+                match sender.send(Message::Ping(vec![])).await {
+                    Ok(_) => {
+                        debug!("Sent ping");
+                    }
+                    Err(e) => {
+                        error!("Error sending ping {:?}", e);
+                        return;
+                    }
+                }
             }
         }
     }
@@ -0,0 +1,151 @@
+//! Runtime view and control of a persisted connection: which connection is up and what it is subscribed to.
+//!
+//! Subscription changes are sent to the live connection as `SUBSCRIBE` and `UNSUBSCRIBE` requests, and kept in
+//! the request the following connections are established with.
+use std::collections::BTreeMap;
+use std::sync::Mutex;
+
+use chrono::{DateTime, Utc};
+use log::info;
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+use tokio::sync::broadcast;
+
+use super::requests::{DataRequest, Stream};
+
+/// A change of the subscriptions, to apply to the live connection.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum SubscriptionChange {
+    Subscribe(Vec<Stream>),
+    Unsubscribe(Vec<Stream>),
+}
+impl SubscriptionChange {
+    pub fn streams(&self) -> &[Stream] {
+        match self {
+            SubscriptionChange::Subscribe(streams) => streams,
+            SubscriptionChange::Unsubscribe(streams) => streams,
+        }
+    }
+    /// The request sent to Binance for this change.
+    pub fn message(&self, id: u64) -> String {
+        let method = match self {
+            SubscriptionChange::Subscribe(_) => "SUBSCRIBE",
+            SubscriptionChange::Unsubscribe(_) => "UNSUBSCRIBE",
+        };
+        json!({"method": method, "params": names(self.streams()), "id": id}).to_string()
+    }
+}
+
+fn names(streams: &[Stream]) -> Vec<String> {
+    streams.iter().map(|stream| stream.to_string()).collect()
+}
+
+/// A connection currently established.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ConnectionStatus {
+    pub connection_id: u64,
+    pub endpoint: String,
+    pub connected_at: DateTime<Utc>,
+}
+
+/// Shared between the connection task and whatever inspects or changes its subscriptions at runtime.
+pub struct ConnectionControl {
+    request: Mutex<DataRequest>,
+    connections: Mutex<BTreeMap<u64, ConnectionStatus>>,
+    changes: broadcast::Sender<SubscriptionChange>,
+}
+impl ConnectionControl {
+    pub fn new(request: DataRequest) -> Self {
+        let (changes, _) = broadcast::channel(64);
+        Self {
+            request: Mutex::new(request),
+            connections: Mutex::new(BTreeMap::new()),
+            changes,
+        }
+    }
+    /// The request with every change applied so far.
+    pub fn request(&self) -> DataRequest {
+        self.request.lock().unwrap().clone()
+    }
+    pub fn streams(&self) -> Vec<Stream> {
+        self.request.lock().unwrap().streams.clone()
+    }
+    pub fn connections(&self) -> Vec<ConnectionStatus> {
+        self.connections.lock().unwrap().values().cloned().collect()
+    }
+    /// Subscribes to the streams not subscribed yet, and returns them.
+    pub fn subscribe(&self, streams: Vec<Stream>) -> Vec<Stream> {
+        let mut request = self.request.lock().unwrap();
+        let mut added: Vec<Stream> = Vec::new();
+        for stream in streams {
+            if !request.streams.contains(&stream) && !added.contains(&stream) {
+                added.push(stream);
+            }
+        }
+        if !added.is_empty() {
+            info!("Subscribing to {}", names(&added).join(","));
+            request.streams.extend(added.iter().cloned());
+            // Without a live connection, the next one subscribes from the request.
+            _ = self
+                .changes
+                .send(SubscriptionChange::Subscribe(added.clone()));
+        }
+        added
+    }
+    /// Unsubscribes from the streams currently subscribed, and returns them.
+    pub fn unsubscribe(&self, streams: Vec<Stream>) -> Vec<Stream> {
+        let mut request = self.request.lock().unwrap();
+        let removed = request
+            .streams
+            .iter()
+            .filter(|stream| streams.contains(stream))
+            .cloned()
+            .collect::<Vec<Stream>>();
+        if !removed.is_empty() {
+            info!("Unsubscribing from {}", names(&removed).join(","));
+            request.streams.retain(|stream| !removed.contains(stream));
+            _ = self
+                .changes
+                .send(SubscriptionChange::Unsubscribe(removed.clone()));
+        }
+        removed
+    }
+    /// Subscribes `symbol` to every kind of stream subscribed for the other symbols.
+    pub fn add_symbol(&self, symbol: &str) -> Vec<Stream> {
+        let symbol = symbol.to_uppercase();
+        let streams = self
+            .streams()
+            .iter()
+            .map(|stream| stream.with_symbol(&symbol))
+            .collect();
+        self.subscribe(streams)
+    }
+    /// Unsubscribes from every stream of `symbol`.
+    pub fn remove_symbol(&self, symbol: &str) -> Vec<Stream> {
+        let symbol = symbol.to_uppercase();
+        let streams = self
+            .streams()
+            .into_iter()
+            .filter(|stream| stream.get_symbol() == symbol)
+            .collect();
+        self.unsubscribe(streams)
+    }
+    /// The current request, and the changes made after it was taken.
+    pub(crate) fn watch(&self) -> (DataRequest, broadcast::Receiver<SubscriptionChange>) {
+        let request = self.request.lock().unwrap();
+        (request.clone(), self.changes.subscribe())
+    }
+    pub(crate) fn connected(&self, connection_id: u64, endpoint: &str) {
+        self.connections.lock().unwrap().insert(
+            connection_id,
+            ConnectionStatus {
+                connection_id,
+                endpoint: endpoint.to_string(),
+                connected_at: Utc::now(),
+            },
+        );
+    }
+    pub(crate) fn disconnected(&self, connection_id: u64) {
+        self.connections.lock().unwrap().remove(&connection_id);
+    }
+}
@@ -1,16 +1,16 @@
-use log::error;
-use serde_json::Value;
+use async_trait::async_trait;
+use log::debug;
 
 use crate::binance::models::book_ticker::BookTicker;
 
+use super::EventHandler;
 
-pub async fn handle_book_ticker(message: Value) {
-    match serde_json::from_value::<BookTicker>(message) {
-        Ok(ticker) => {
-            println!("{:#?}", ticker);
-        }
-        Err(e) => {
-            error!("Error parsing message: {:?}", e);
-        }
+/// Logs every book ticker received, at the debug level.
+pub struct BookTickerPrinter;
+
+#[async_trait]
+impl EventHandler for BookTickerPrinter {
+    async fn on_book_ticker(&self, ticker: &BookTicker) {
+        debug!("{:?}", ticker);
     }
-}
\ No newline at end of file
+}
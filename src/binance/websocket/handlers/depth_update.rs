@@ -1,31 +1,94 @@
-use log::error;
+use std::collections::{HashMap, HashSet};
+use std::sync::Mutex;
+
+use async_trait::async_trait;
+use log::warn;
 use rayon::prelude::{IntoParallelRefIterator, ParallelIterator};
-use serde_json::Value;
+use crate::binance::constants::Symbol;
 use crate::binance::models::orderbook::{OrderBooksRWL, OrderbookMessage, OrderBook};
+use crate::binance::websocket::router::{stream_symbol, StreamKind};
 
-pub async fn handle_depth_update_message(message: Value, orderbooks_rwl: OrderBooksRWL) {
-    match serde_json::from_value::<OrderbookMessage>(message) {
-        Ok(update) => {
-            let books = orderbooks_rwl.read().await.clone();
-            match books.contains_key(&update.symbol) {
-                true => {
-                    let mut all_books = books.get(&update.symbol).unwrap().clone();
-                    let latest_updated = all_books.par_iter().max_by_key(|book| book.last_update_id).unwrap().clone().update(update.clone());
-                    all_books.insert(0,latest_updated.clone());
-                    all_books.truncate(100);
-                    orderbooks_rwl.write().await.insert(update.symbol.clone(), all_books);
-                },
-                false => {
-                    let mut vec = Vec::with_capacity(100);
-                    let book = OrderBook::new_from_update(update.clone());
-                    vec.push(book);
-                    orderbooks_rwl.write().await.insert(update.symbol.clone(),vec );
-                },
-            }
-            
+use super::{EventHandler, RawFrame};
+
+/// The depth diff streams each open connection delivers, learnt from its frames.
+#[derive(Debug, Default)]
+pub struct DepthConnections {
+    streams: Mutex<HashMap<u64, HashSet<String>>>,
+}
+impl DepthConnections {
+    /// Notes the stream of `frame` if it is a depth diff stream.
+    pub fn observe(&self, frame: &RawFrame<'_>) {
+        let Some(stream) = frame.stream() else { return };
+        if StreamKind::from_stream_name(stream) != StreamKind::DepthUpdate {
+            return;
         }
-        Err(e) => {
-            error!("Error parsing message: {:?}", e);
+        let mut streams = self.streams.lock().unwrap();
+        let served = streams.entry(frame.connection_id).or_default();
+        if !served.contains(stream) {
+            served.insert(stream.to_string());
         }
     }
+    /// Forgets `connection_id`, and returns the symbols whose depth it delivered and no other open connection does.
+    pub fn disconnected(&self, connection_id: u64) -> Vec<Symbol> {
+        let mut streams = self.streams.lock().unwrap();
+        let Some(served) = streams.remove(&connection_id) else { return Vec::new() };
+        let still_served: HashSet<Symbol> = streams.values().flatten().map(|stream| stream_symbol(stream)).collect();
+        let mut symbols: Vec<Symbol> = served.iter().map(|stream| stream_symbol(stream)).filter(|symbol| !still_served.contains(symbol)).collect();
+        symbols.sort();
+        symbols.dedup();
+        symbols
+    }
+}
+
+/// Maintains the local orderbooks from the depth diff streams.
+pub struct OrderBookMaintainer {
+    pub orderbooks_rwl: OrderBooksRWL,
+    connections: DepthConnections,
+}
+impl OrderBookMaintainer {
+    pub fn new(orderbooks_rwl: OrderBooksRWL) -> Self {
+        Self { orderbooks_rwl, connections: DepthConnections::default() }
+    }
+    pub async fn apply_update(&self, update: &OrderbookMessage) {
+        let books = self.orderbooks_rwl.read().await.clone();
+        match books.contains_key(&update.symbol) {
+            true => {
+                let mut all_books = books.get(&update.symbol).unwrap().clone();
+                let latest_updated = all_books.par_iter().max_by_key(|book| book.last_update_id).unwrap().clone().update(update.clone());
+                all_books.insert(0,latest_updated.clone());
+                all_books.truncate(100);
+                self.orderbooks_rwl.write().await.insert(update.symbol.clone(), all_books);
+            },
+            false => {
+                let mut vec = Vec::with_capacity(100);
+                let book = OrderBook::new_from_update(update.clone());
+                vec.push(book);
+                self.orderbooks_rwl.write().await.insert(update.symbol.clone(),vec );
+            },
+        }
+    }
+    /// Drops the local books of `symbols` so they are rebuilt from the next update received.
+    pub async fn resync_books(&self, symbols: &[Symbol]) {
+        let mut books = self.orderbooks_rwl.write().await;
+        for symbol in symbols {
+            if books.remove(symbol).is_some() {
+                warn!("Discarded local orderbook for {} pending resync", symbol);
+            }
+        }
+    }
+}
+
+#[async_trait]
+impl EventHandler for OrderBookMaintainer {
+    async fn on_depth(&self, update: &OrderbookMessage) {
+        self.apply_update(update).await;
+    }
+    /// Updates were missed while disconnected, so the books fed by the connection can no longer be trusted.
+    async fn on_disconnect(&self, connection_id: u64, _endpoint: &str) {
+        let symbols = self.connections.disconnected(connection_id);
+        self.resync_books(&symbols).await;
+    }
+    async fn on_frame(&self, frame: &RawFrame<'_>) {
+        self.connections.observe(frame);
+    }
 }
@@ -1,3 +1,59 @@
+use std::sync::Arc;
+
+use async_trait::async_trait;
+use chrono::{DateTime, Utc};
+use serde_json::value::RawValue;
+
+use crate::binance::{
+    models::{book_ticker::BookTicker, orderbook::OrderbookMessage, trades::Trade},
+    rest::RestOrderBook,
+};
+
+use super::{router::Frame, watchdog::Stall};
+
 pub mod depth_update;
-pub mod trades;
-pub mod book_ticker;
\ No newline at end of file
+pub mod book_ticker;
+
+/// Consumer of everything received on a websocket connection.
+/// Every method does nothing by default, so implementors only override the events they care about.
+/// Handlers are called one after the other for each event, so they should not block for long.
+#[allow(unused_variables)]
+#[async_trait]
+pub trait EventHandler: Send + Sync {
+    /// Connection `connection_id` to `endpoint` was established and the subscription is about to be sent.
+    async fn on_connect(&self, connection_id: u64, endpoint: &str) {}
+    /// Connection `connection_id` ended, the events of its streams may have been missed until they are
+    /// received on another connection.
+    async fn on_disconnect(&self, connection_id: u64, endpoint: &str) {}
+    /// The watchdog detected a silent connection or stream, it is followed by `on_disconnect`.
+    async fn on_stall(&self, stall: &Stall) {}
+    async fn on_trade(&self, trade: &Trade) {}
+    async fn on_depth(&self, update: &OrderbookMessage) {}
+    async fn on_partial_depth(&self, symbol: &str, book: &RestOrderBook) {}
+    async fn on_book_ticker(&self, ticker: &BookTicker) {}
+    /// A payload on a stream the router does not know how to parse.
+    async fn on_unrouted(&self, stream: &str, data: &RawValue) {}
+    /// A text frame exactly as received, including those the router could not parse.
+    /// Called once every handler has been given the events of the frame.
+    async fn on_frame(&self, frame: &RawFrame<'_>) {}
+}
+
+/// A text frame as it came off the socket, along with where and when it was received.
+#[derive(Debug, Clone, Copy)]
+pub struct RawFrame<'a> {
+    pub received: DateTime<Utc>,
+    /// Increases with every connection established by the process.
+    pub connection_id: u64,
+    /// The frame as routed before being dispatched, `None` when it could not be parsed.
+    pub frame: Option<&'a Frame<'a>>,
+    pub text: &'a str,
+}
+impl<'a> RawFrame<'a> {
+    /// Stream name from the envelope, `None` for responses to requests and unparsable frames.
+    pub fn stream(&self) -> Option<&'a str> {
+        self.frame.and_then(|frame| frame.stream())
+    }
+}
+
+/// The set of handlers a connection dispatches its events to.
+pub type EventHandlers = Vec<Arc<dyn EventHandler>>;
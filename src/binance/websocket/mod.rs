@@ -1,3 +1,8 @@
+//! Websocket connection management, stream requests, frame routing, event handlers and frame recording.
 pub mod connection;
+pub mod control;
 pub mod requests;
 pub mod handlers;
+pub mod recorder;
+pub mod router;
+pub mod watchdog;
@@ -0,0 +1,300 @@
+//! Recording of the raw frames received on websocket connections, and replay of the recordings.
+//!
+//! [`FrameRecorder`] writes every frame with its receive time to bzip2 files on a thread of its own, so the
+//! connections only queue a copy and never wait on the disk. [`replay_file`] feeds a recording back through the
+//! router and the handlers as if it were received again.
+
+use std::fs::File;
+use std::io::{BufRead, BufReader, BufWriter, Write};
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::sync::{
+    atomic::{AtomicBool, Ordering},
+    mpsc::{sync_channel, Receiver, SyncSender, TrySendError},
+};
+use std::time::Duration;
+
+use async_trait::async_trait;
+use bzip2::{read::MultiBzDecoder, write::BzEncoder, Compression};
+use chrono::{DateTime, TimeZone, Utc};
+use log::{error, info, warn};
+use tokio::{sync::oneshot, time::Instant};
+
+use crate::disk_guard::LoadShedder;
+
+use super::{
+    connection::dispatch,
+    handlers::{EventHandler, EventHandlers, RawFrame},
+    router::route,
+};
+
+struct Recording {
+    connection_id: u64,
+    path: PathBuf,
+    encoder: BzEncoder<BufWriter<File>>,
+}
+impl Recording {
+    fn start(folder: &Path, received: DateTime<Utc>, connection_id: u64) -> std::io::Result<Self> {
+        std::fs::create_dir_all(folder)?;
+        let path = folder.join(format!(
+            "{}_{:06}.frames.bz2",
+            received.format("%Y%m%dT%H%M%S%.9f"),
+            connection_id
+        ));
+        let file = BufWriter::new(File::create(&path)?);
+        info!("Recording frames to {}", path.display());
+        Ok(Recording {
+            connection_id,
+            path,
+            encoder: BzEncoder::new(file, Compression::default()),
+        })
+    }
+    fn finish(self) {
+        match self.encoder.finish().and_then(|mut writer| writer.flush()) {
+            Ok(_) => info!("Succesfully Recorded file {}", self.path.display()),
+            Err(e) => error!("Error finishing recording {}: {}", self.path.display(), e),
+        }
+    }
+}
+
+enum Command {
+    Record {
+        received: DateTime<Utc>,
+        connection_id: u64,
+        text: String,
+    },
+    /// Finishes the current file, then replies if asked to.
+    Finish(Option<oneshot::Sender<()>>),
+}
+
+/// Writes the frames it is sent until the recorder is dropped, finishing the current file then.
+fn write_frames(folder: PathBuf, commands: Receiver<Command>) {
+    let mut current: Option<Recording> = None;
+    for command in commands {
+        match command {
+            Command::Record {
+                received,
+                connection_id,
+                text,
+            } => {
+                if current
+                    .as_ref()
+                    .is_some_and(|recording| recording.connection_id != connection_id)
+                {
+                    current.take().unwrap().finish();
+                }
+                if current.is_none() {
+                    match Recording::start(&folder, received, connection_id) {
+                        Ok(recording) => current = Some(recording),
+                        Err(e) => {
+                            error!("Error recording frame: {}", e);
+                            continue;
+                        }
+                    }
+                }
+                let recording = current.as_mut().unwrap();
+                if let Err(e) = writeln!(
+                    recording.encoder,
+                    "{}\t{}\t{}",
+                    received.timestamp_nanos(),
+                    connection_id,
+                    text.replace('\n', " ")
+                ) {
+                    error!("Error recording frame: {}", e);
+                }
+            }
+            Command::Finish(reply) => {
+                if let Some(recording) = current.take() {
+                    recording.finish();
+                }
+                if let Some(reply) = reply {
+                    _ = reply.send(());
+                }
+            }
+        }
+    }
+    if let Some(recording) = current {
+        recording.finish();
+    }
+}
+
+/// Records every text frame received, with its local receive time in nanoseconds, into bzip2 files.
+/// Each connection gets its own file in `folder`, finished when the connection ends.
+/// Lines are `{received_ns}\t{connection_id}\t{frame}`, and can be fed back with [`replay_file`].
+/// Frames are compressed on a thread of their own, so handling them only queues a copy. While that thread lags
+/// `capacity` frames behind, or while degraded, e.g. when the disk is almost full, frames are not recorded.
+pub struct FrameRecorder {
+    commands: SyncSender<Command>,
+    paused: AtomicBool,
+    /// Whether frames are being dropped because the queue is full, to only log it once.
+    lagging: AtomicBool,
+}
+impl FrameRecorder {
+    pub fn new(folder: impl Into<PathBuf>) -> Self {
+        Self::with_capacity(folder, 100_000)
+    }
+    pub fn with_capacity(folder: impl Into<PathBuf>, capacity: usize) -> Self {
+        let folder = folder.into();
+        let (commands, received) = sync_channel(capacity);
+        std::thread::Builder::new()
+            .name("frame-recorder".to_string())
+            .spawn(move || write_frames(folder, received))
+            .unwrap();
+        Self {
+            commands,
+            paused: AtomicBool::new(false),
+            lagging: AtomicBool::new(false),
+        }
+    }
+    pub fn record(&self, frame: &RawFrame<'_>) {
+        if self.paused.load(Ordering::Relaxed) {
+            return;
+        }
+        let command = Command::Record {
+            received: frame.received,
+            connection_id: frame.connection_id,
+            text: frame.text.to_string(),
+        };
+        match self.commands.try_send(command) {
+            Ok(()) => self.lagging.store(false, Ordering::Relaxed),
+            Err(TrySendError::Full(_)) => {
+                if !self.lagging.swap(true, Ordering::Relaxed) {
+                    warn!("Recording lags behind, frames are dropped");
+                }
+            }
+            Err(TrySendError::Disconnected(_)) => error!("Error recording frame: recorder stopped"),
+        }
+    }
+    /// Finishes the current file once the frames queued before are written, so it can be read back in full.
+    pub async fn finish(&self) {
+        let (reply, finished) = oneshot::channel();
+        let commands = self.commands.clone();
+        // The queue may be full, and waiting for room must not block a worker of the runtime.
+        let sent = tokio::task::spawn_blocking(move || commands.send(Command::Finish(Some(reply))));
+        if matches!(sent.await, Ok(Ok(()))) {
+            _ = finished.await;
+        }
+    }
+}
+impl LoadShedder for FrameRecorder {
+    fn set_degraded(&self, degraded: bool) {
+        self.paused.store(degraded, Ordering::Relaxed);
+        if degraded {
+            warn!("Recording paused");
+            if let Err(TrySendError::Full(command)) = self.commands.try_send(Command::Finish(None))
+            {
+                // Waits for room on a thread of its own rather than blocking the caller.
+                let commands = self.commands.clone();
+                std::thread::spawn(move || _ = commands.send(command));
+            }
+        }
+    }
+}
+
+#[async_trait]
+impl EventHandler for FrameRecorder {
+    async fn on_disconnect(&self, _connection_id: u64, _endpoint: &str) {
+        self.finish().await;
+    }
+    async fn on_frame(&self, frame: &RawFrame<'_>) {
+        self.record(frame);
+    }
+}
+
+/// Pace at which recorded frames are fed back.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ReplaySpeed {
+    /// Frames are spaced as they were received.
+    Original,
+    /// Frames are spaced as they were received, divided by the given factor.
+    Accelerated(f64),
+    /// No waiting between frames.
+    AsFastAsPossible,
+}
+impl FromStr for ReplaySpeed {
+    type Err = String;
+    /// Parses `original`, `max`, or an acceleration factor such as `10`.
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "original" => Ok(ReplaySpeed::Original),
+            "max" => Ok(ReplaySpeed::AsFastAsPossible),
+            factor => match factor.parse::<f64>() {
+                Ok(factor) if factor > 0.0 => Ok(ReplaySpeed::Accelerated(factor)),
+                _ => Err(format!("Invalid replay speed {s}")),
+            },
+        }
+    }
+}
+
+/// Feeds a file written by [`FrameRecorder`] through the router and `handlers`, exactly like live connections:
+/// `on_connect` before the first frame of each recorded connection, the events and `on_frame` of every frame,
+/// then `on_disconnect` for every connection once the file is done.
+/// Returns the number of frames replayed.
+pub async fn replay_file(
+    path: &Path,
+    handlers: &EventHandlers,
+    speed: ReplaySpeed,
+) -> std::io::Result<u64> {
+    let reader = BufReader::new(MultiBzDecoder::new(File::open(path)?));
+    let endpoint = format!("replay://{}", path.display());
+    let mut connections = Vec::new();
+    let mut start: Option<(DateTime<Utc>, Instant)> = None;
+    let mut replayed = 0;
+    for line in reader.lines() {
+        let line = line?;
+        let (received, connection_id, text) = match parse_line(&line) {
+            Some(parsed) => parsed,
+            None => {
+                warn!("Skipping malformed line in {}", path.display());
+                continue;
+            }
+        };
+        let factor = match speed {
+            ReplaySpeed::Original => Some(1.0),
+            ReplaySpeed::Accelerated(factor) => Some(factor),
+            ReplaySpeed::AsFastAsPossible => None,
+        };
+        if let Some(factor) = factor {
+            let (first_received, started) = *start.get_or_insert((received, Instant::now()));
+            let offset = (received - first_received).to_std().unwrap_or_default();
+            tokio::time::sleep_until(
+                started + Duration::from_secs_f64(offset.as_secs_f64() / factor),
+            )
+            .await;
+        }
+        if !connections.contains(&connection_id) {
+            connections.push(connection_id);
+            for handler in handlers.iter() {
+                handler.on_connect(connection_id, &endpoint).await;
+            }
+        }
+        let routed = route(text);
+        match &routed {
+            Ok(frame) => dispatch(frame, handlers).await,
+            Err(e) => error!("Error parsing message: {:?} {}", e, text),
+        }
+        let raw = RawFrame {
+            received,
+            connection_id,
+            frame: routed.as_ref().ok(),
+            text,
+        };
+        for handler in handlers.iter() {
+            handler.on_frame(&raw).await;
+        }
+        replayed += 1;
+    }
+    for connection_id in connections {
+        for handler in handlers.iter() {
+            handler.on_disconnect(connection_id, &endpoint).await;
+        }
+    }
+    Ok(replayed)
+}
+
+fn parse_line(line: &str) -> Option<(DateTime<Utc>, u64, &str)> {
+    let mut parts = line.splitn(3, '\t');
+    let received = Utc.timestamp_nanos(parts.next()?.parse().ok()?);
+    let connection_id = parts.next()?.parse().ok()?;
+    Some((received, connection_id, parts.next()?))
+}
@@ -4,13 +4,17 @@ use serde::Serialize;
 use serde::Deserialize;
 use serde_json::json;
 
+use crate::binance::constants::COIN_M_BASE_HTTP_ENDPOINT;
 use crate::binance::constants::COIN_M_BASE_WS_ENDPOINT;
 
+use crate::binance::constants::OPTIONS_BASE_HTTP_ENDPOINT;
 use crate::binance::constants::OPTIONS_BASE_WS_ENDPOINT;
 
+use crate::binance::constants::SPOT_BASE_HTTP_ENDPOINTS;
 use crate::binance::constants::SPOT_BASE_WS_ENDPOINTS;
 use crate::binance::constants::Symbol;
 
+use crate::binance::constants::USDT_M_BASE_HTTP_ENDPOINT;
 use crate::binance::constants::USDT_M_BASE_WS_ENDPOINTS;
 
 #[derive(Serialize, Deserialize, Debug,Clone)]
@@ -19,6 +23,7 @@ pub enum FuturesType {
     CoinMargined,
 }
 
+/// The Binance market to connect to, its `Display` is used as a prefix in file names.
 #[derive(Serialize, Deserialize, Debug,Clone)]
 pub enum BinanceAssetType {
     Spot,
@@ -29,43 +34,32 @@ impl BinanceAssetType {
     pub fn get_ws_base_url_list(&self) -> Vec<String> {
         match self {
             BinanceAssetType::Spot => {
-                return SPOT_BASE_WS_ENDPOINTS.iter().map(|endpoint| endpoint.to_string()).collect();
+                SPOT_BASE_WS_ENDPOINTS.iter().map(|endpoint| endpoint.to_string()).collect()
             }
             BinanceAssetType::Futures(futures_type) => {
                 match futures_type {
                     FuturesType::USDMargined => {
-                        return USDT_M_BASE_WS_ENDPOINTS.iter().map(|endpoint| endpoint.to_string()).collect();
+                        USDT_M_BASE_WS_ENDPOINTS.iter().map(|endpoint| endpoint.to_string()).collect()
                     }
                     FuturesType::CoinMargined => {
-                        return COIN_M_BASE_WS_ENDPOINT.iter().map(|endpoint| endpoint.to_string()).collect();
+                        COIN_M_BASE_WS_ENDPOINT.iter().map(|endpoint| endpoint.to_string()).collect()
                     }
                 }
             }
             BinanceAssetType::Options => {
-                return OPTIONS_BASE_WS_ENDPOINT.iter().map(|endpoint| endpoint.to_string()).collect();
+                OPTIONS_BASE_WS_ENDPOINT.iter().map(|endpoint| endpoint.to_string()).collect()
             }
         }
     }
-    // pub fn get_http_base_url_list(&self) -> Vec<String> {
-    //     match self {
-    //         BinanceAssetType::Spot => {
-    //             return SPOT_BASE_HTTP_ENDPOINTS.iter().map(|endpoint| endpoint.to_string()).collect();
-    //         }
-    //         BinanceAssetType::Futures(futures_type) => {
-    //             match futures_type {
-    //                 FuturesType::USDMargined => {
-    //                     return USDT_M_BASE_HTTP_ENDPOINT.iter().map(|endpoint| endpoint.to_string()).collect();
-    //                 }
-    //                 FuturesType::CoinMargined => {
-    //                     return COIN_M_BASE_HTTP_ENDPOINT.iter().map(|endpoint| endpoint.to_string()).collect();
-    //                 }
-    //             }
-    //         }
-    //         BinanceAssetType::Options => {
-    //             return OPTIONS_BASE_HTTP_ENDPOINT.iter().map(|endpoint| endpoint.to_string()).collect();
-    //         }
-    //     }
-    // }
+    pub fn get_http_base_url_list(&self) -> Vec<String> {
+        let endpoints: &[&str] = match self {
+            BinanceAssetType::Spot => &SPOT_BASE_HTTP_ENDPOINTS,
+            BinanceAssetType::Futures(FuturesType::USDMargined) => &USDT_M_BASE_HTTP_ENDPOINT,
+            BinanceAssetType::Futures(FuturesType::CoinMargined) => &COIN_M_BASE_HTTP_ENDPOINT,
+            BinanceAssetType::Options => &OPTIONS_BASE_HTTP_ENDPOINT,
+        };
+        endpoints.iter().map(|endpoint| endpoint.to_string()).collect()
+    }
 }
 impl Display for BinanceAssetType {
     fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
@@ -91,27 +85,45 @@ impl Display for BinanceAssetType {
 }
 
 
-#[derive(Serialize, Deserialize, Debug,Clone)]
+/// A stream of a single symbol. Depth streams carry their update speed in milliseconds.
+#[derive(Serialize, Deserialize, Debug,Clone,PartialEq,Eq)]
 pub enum Stream {
     Depth(Symbol,i32),
     Trade(Symbol),
     BookTicker(Symbol),
 }
-// impl Stream {
-//     pub fn get_symbol(&self) -> Symbol {
-//         match self {
-//             Stream::Depth(symbol,_) => {
-//                 symbol.clone()
-//             }
-//             Stream::Trade(symbol) => {
-//                 symbol.clone()
-//             }
-//             Stream::BookTicker(symbol) => {
-//                 symbol.clone()
-//             }
-//         }
-//     }
-// }
+impl Stream {
+    pub fn get_symbol(&self) -> Symbol {
+        match self {
+            Stream::Depth(symbol,_) => {
+                symbol.clone()
+            }
+            Stream::Trade(symbol) => {
+                symbol.clone()
+            }
+            Stream::BookTicker(symbol) => {
+                symbol.clone()
+            }
+        }
+    }
+    /// The same stream of another symbol.
+    pub fn with_symbol(&self, symbol: &str) -> Stream {
+        match self {
+            Stream::Depth(_,update_speed) => Stream::Depth(symbol.to_string(),*update_speed),
+            Stream::Trade(_) => Stream::Trade(symbol.to_string()),
+            Stream::BookTicker(_) => Stream::BookTicker(symbol.to_string()),
+        }
+    }
+    /// The interval at which Binance pushes this stream, if it is pushed on a fixed cadence.
+    /// Event driven streams such as trades and book tickers return `None`.
+    pub fn expected_interval(&self) -> Option<std::time::Duration> {
+        match self {
+            Stream::Depth(_,update_speed) => Some(std::time::Duration::from_millis(*update_speed as u64)),
+            Stream::Trade(_) => None,
+            Stream::BookTicker(_) => None,
+        }
+    }
+}
 impl Display for Stream {
     fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
         match self {
@@ -124,20 +136,33 @@ impl Display for Stream {
 
 
 
+/// The streams to subscribe to on a single connection.
 #[derive(Serialize, Deserialize, Debug,Clone)]
 pub struct DataRequest {
     pub asset_type: BinanceAssetType,
     pub streams: Vec<Stream>,
+    /// Replaces the endpoints of `asset_type`, e.g. to connect to a local mock exchange.
+    #[serde(default)]
+    pub ws_base_urls: Option<Vec<String>>,
 }
 impl DataRequest {
     pub fn new(asset_type: BinanceAssetType, streams: Vec<Stream>) -> Self {
-        Self { asset_type, streams }
+        Self { asset_type, streams, ws_base_urls: None }
+    }
+    pub fn with_ws_base_urls(mut self, ws_base_urls: Vec<String>) -> Self {
+        self.ws_base_urls = Some(ws_base_urls);
+        self
     }
+    /// Combined stream urls, one per base endpoint, in order of preference.
     pub fn get_ws_urls(&self) -> Vec<String> {
         let individual_streams = self.streams.iter().map(|stream| stream.to_string()).collect::<Vec<String>>();
         let combined_streams = individual_streams.join("/");
         let path = format!("/stream?streams={}",combined_streams);
-        return self.asset_type.get_ws_base_url_list().iter().map(|base_url| url::Url::parse(&format!("{}{}",base_url,path)).unwrap().to_string()).collect();
+        let base_urls = match &self.ws_base_urls {
+            Some(base_urls) => base_urls.clone(),
+            None => self.asset_type.get_ws_base_url_list(),
+        };
+        base_urls.iter().map(|base_url| url::Url::parse(&format!("{}{}",base_url,path)).unwrap().to_string()).collect()
     }
     pub fn get_subscribe_message(&self) -> String {
         let individual_streams = self.streams.iter().map(|stream| stream.to_string()).collect::<Vec<String>>();
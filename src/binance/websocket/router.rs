@@ -0,0 +1,159 @@
+use std::borrow::Cow;
+
+use chrono::{DateTime, Utc};
+use serde::Deserialize;
+use serde_json::value::RawValue;
+
+use crate::binance::{
+    constants::Symbol,
+    models::{book_ticker::BookTicker, orderbook::OrderbookMessage, trades::Trade},
+    rest::RestOrderBook,
+};
+
+/// The kind of payload carried by a stream, derived from its name, e.g. `btcusdt@depth@100ms`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum StreamKind {
+    Trade,
+    DepthUpdate,
+    PartialDepth,
+    BookTicker,
+    Unknown,
+}
+impl StreamKind {
+    pub fn from_stream_name(stream: &str) -> Self {
+        match stream.split('@').nth(1) {
+            Some("trade") => StreamKind::Trade,
+            Some("bookTicker") => StreamKind::BookTicker,
+            Some("depth") => StreamKind::DepthUpdate,
+            Some(kind)
+                if kind.len() > "depth".len()
+                    && kind.starts_with("depth")
+                    && kind["depth".len()..].chars().all(|c| c.is_ascii_digit()) =>
+            {
+                StreamKind::PartialDepth
+            }
+            _ => StreamKind::Unknown,
+        }
+    }
+}
+
+/// The symbol of a stream, uppercase as in the models.
+pub fn stream_symbol(stream: &str) -> Symbol {
+    stream.split('@').next().unwrap_or_default().to_uppercase()
+}
+
+/// A stream payload deserialized into its model.
+#[derive(Debug, Clone, PartialEq)]
+pub enum StreamEvent {
+    Trade(Trade),
+    DepthUpdate(OrderbookMessage),
+    /// Top levels of the book. Spot payloads carry no symbol, so it is taken from the stream name.
+    PartialDepth {
+        symbol: Symbol,
+        book: RestOrderBook,
+    },
+    BookTicker(BookTicker),
+}
+impl StreamEvent {
+    /// Exchange time of the event, book tickers do not carry one.
+    pub fn event_time(&self) -> Option<DateTime<Utc>> {
+        match self {
+            StreamEvent::Trade(trade) => Some(trade.event_time),
+            StreamEvent::DepthUpdate(update) => Some(update.time),
+            StreamEvent::PartialDepth { book, .. } => Some(book.received_ts),
+            StreamEvent::BookTicker(_) => None,
+        }
+    }
+}
+
+/// A single websocket text frame after routing.
+#[derive(Debug)]
+pub enum Frame<'a> {
+    /// A payload from a combined stream, parsed according to its stream name.
+    Event {
+        stream: Cow<'a, str>,
+        event: StreamEvent,
+        /// The payload as received.
+        data: &'a RawValue,
+    },
+    /// A payload on a stream this router does not know how to parse, left untouched.
+    Unrouted {
+        stream: Cow<'a, str>,
+        data: &'a RawValue,
+    },
+    /// The answer to a request sent on the connection, such as `SUBSCRIBE`.
+    Response {
+        id: Option<i64>,
+        error: Option<&'a RawValue>,
+    },
+}
+impl Frame<'_> {
+    pub fn stream(&self) -> Option<&str> {
+        match self {
+            Frame::Event { stream, .. } | Frame::Unrouted { stream, .. } => Some(stream),
+            Frame::Response { .. } => None,
+        }
+    }
+}
+
+/// Raw shape shared by combined stream payloads and request responses.
+/// Fields are borrowed from the frame so the payload is only parsed once, straight into its model.
+#[derive(Deserialize)]
+struct Envelope<'a> {
+    #[serde(borrow)]
+    stream: Option<Cow<'a, str>>,
+    #[serde(borrow)]
+    data: Option<&'a RawValue>,
+    id: Option<i64>,
+    #[serde(borrow)]
+    error: Option<&'a RawValue>,
+}
+
+/// Futures partial depth streams use the diff layout while spot ones use the REST snapshot layout.
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum PartialDepthPayload {
+    Futures(OrderbookMessage),
+    Spot(RestOrderBook),
+}
+
+/// Parses a text frame into a [`Frame`], dispatching on the stream name rather than the payload contents.
+pub fn route(text: &str) -> Result<Frame<'_>, serde_json::Error> {
+    let envelope = serde_json::from_str::<Envelope>(text)?;
+    let (stream, data) = match (envelope.stream, envelope.data) {
+        (Some(stream), Some(data)) => (stream, data),
+        _ => {
+            return Ok(Frame::Response {
+                id: envelope.id,
+                error: envelope.error,
+            })
+        }
+    };
+    let event = match StreamKind::from_stream_name(&stream) {
+        StreamKind::Trade => StreamEvent::Trade(serde_json::from_str(data.get())?),
+        StreamKind::DepthUpdate => StreamEvent::DepthUpdate(serde_json::from_str(data.get())?),
+        StreamKind::BookTicker => StreamEvent::BookTicker(serde_json::from_str(data.get())?),
+        StreamKind::PartialDepth => match serde_json::from_str(data.get())? {
+            PartialDepthPayload::Futures(update) => StreamEvent::PartialDepth {
+                symbol: update.symbol,
+                book: RestOrderBook {
+                    last_update_id: update.last_update_id,
+                    bids: update.bids,
+                    asks: update.asks,
+                    // The event time of the exchange, as for the other futures streams.
+                    received_ts: update.time,
+                },
+            },
+            PartialDepthPayload::Spot(book) => StreamEvent::PartialDepth {
+                symbol: stream_symbol(&stream),
+                book,
+            },
+        },
+        StreamKind::Unknown => return Ok(Frame::Unrouted { stream, data }),
+    };
+    Ok(Frame::Event {
+        stream,
+        event,
+        data,
+    })
+}
@@ -0,0 +1,162 @@
+use std::collections::HashMap;
+use std::sync::{
+    atomic::{AtomicBool, Ordering},
+    Mutex,
+};
+use std::time::Duration;
+
+use log::{debug, warn};
+use tokio::time::Instant;
+
+use super::requests::{DataRequest, Stream};
+
+/// Thresholds used to decide when a connection or one of its streams has gone silent.
+#[derive(Debug, Clone)]
+pub struct LivenessConfig {
+    /// How often a client ping is sent to the server.
+    pub ping_interval: Duration,
+    /// Maximum time without any frame (data, ping or pong) before the connection is considered dead.
+    pub connection_timeout: Duration,
+    /// A stream is stalled once it has been silent for this many multiples of its expected cadence.
+    pub cadence_multiplier: u32,
+    /// Lower bound for a stream timeout, so fast streams are not flagged on ordinary jitter.
+    pub min_stream_timeout: Duration,
+    /// How often the watchdog checks the recorded activity.
+    pub check_interval: Duration,
+}
+impl Default for LivenessConfig {
+    fn default() -> Self {
+        Self {
+            ping_interval: Duration::from_secs(30),
+            connection_timeout: Duration::from_secs(60),
+            cadence_multiplier: 20,
+            min_stream_timeout: Duration::from_secs(10),
+            check_interval: Duration::from_secs(1),
+        }
+    }
+}
+
+/// Why the watchdog gave up on a connection.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Stall {
+    /// Nothing at all was received for the given duration.
+    Connection(Duration),
+    /// The listed streams went silent while the connection itself was still alive.
+    Streams(Vec<Stream>),
+}
+
+struct StreamLiveness {
+    stream: Stream,
+    last_seen: Instant,
+    timeout: Duration,
+}
+
+/// Tracks the last time something was received on a connection and on each of its streams.
+/// Streams without a fixed cadence (trades, book tickers) are only covered by the connection timeout.
+pub struct Watchdog {
+    config: LivenessConfig,
+    connection_last_seen: Mutex<Instant>,
+    streams: Mutex<HashMap<String, StreamLiveness>>,
+    data_received: AtomicBool,
+}
+impl Watchdog {
+    pub fn new(request: &DataRequest, config: LivenessConfig) -> Self {
+        let watchdog = Self {
+            config,
+            connection_last_seen: Mutex::new(Instant::now()),
+            streams: Mutex::new(HashMap::new()),
+            data_received: AtomicBool::new(false),
+        };
+        for stream in request.streams.iter() {
+            watchdog.track(stream);
+        }
+        watchdog
+    }
+    /// Starts supervising a stream subscribed to after the connection was established.
+    pub fn track(&self, stream: &Stream) {
+        if let Some(interval) = stream.expected_interval() {
+            let timeout =
+                (interval * self.config.cadence_multiplier).max(self.config.min_stream_timeout);
+            self.streams.lock().unwrap().insert(
+                stream.to_string(),
+                StreamLiveness {
+                    stream: stream.clone(),
+                    last_seen: Instant::now(),
+                    timeout,
+                },
+            );
+        }
+    }
+    /// Stops supervising a stream that was unsubscribed from, so its silence is not a stall.
+    pub fn untrack(&self, stream: &Stream) {
+        self.streams.lock().unwrap().remove(&stream.to_string());
+    }
+    pub fn config(&self) -> &LivenessConfig {
+        &self.config
+    }
+    /// Records activity on the connection, and on the named stream if there is one.
+    pub fn record(&self, stream: Option<&str>) {
+        let now = Instant::now();
+        *self.connection_last_seen.lock().unwrap() = now;
+        if let Some(stream) = stream {
+            self.data_received.store(true, Ordering::Relaxed);
+            if let Some(liveness) = self.streams.lock().unwrap().get_mut(stream) {
+                liveness.last_seen = now;
+            }
+        }
+    }
+    /// Whether a frame of any stream has been recorded, as opposed to only pings or responses.
+    pub fn data_received(&self) -> bool {
+        self.data_received.load(Ordering::Relaxed)
+    }
+    /// Returns a stall if the connection or any stream has been silent longer than allowed.
+    pub fn check(&self) -> Option<Stall> {
+        let now = Instant::now();
+        let silent_for = now - *self.connection_last_seen.lock().unwrap();
+        if silent_for > self.config.connection_timeout {
+            return Some(Stall::Connection(silent_for));
+        }
+        let stalled = self
+            .streams
+            .lock()
+            .unwrap()
+            .values()
+            .filter(|liveness| now - liveness.last_seen > liveness.timeout)
+            .map(|liveness| liveness.stream.clone())
+            .collect::<Vec<Stream>>();
+        if stalled.is_empty() {
+            None
+        } else {
+            Some(Stall::Streams(stalled))
+        }
+    }
+    /// Resolves once a stall has been detected.
+    pub async fn monitor(&self) -> Stall {
+        let mut interval = tokio::time::interval(self.config.check_interval);
+        loop {
+            interval.tick().await;
+            if let Some(stall) = self.check() {
+                match &stall {
+                    Stall::Connection(silent_for) => {
+                        warn!(
+                            "Nothing received for {:?}, forcing reconnection",
+                            silent_for
+                        );
+                    }
+                    Stall::Streams(streams) => {
+                        let names = streams
+                            .iter()
+                            .map(|s| s.to_string())
+                            .collect::<Vec<String>>();
+                        warn!(
+                            "Streams silent past their threshold: {}, forcing reconnection",
+                            names.join(",")
+                        );
+                    }
+                }
+                return stall;
+            }
+            debug!("Watchdog check passed");
+        }
+    }
+}
@@ -0,0 +1,412 @@
+//! Embedded websocket server re-broadcasting the normalized streams to internal consumers, so they do not each
+//! need their own connection to Binance.
+//!
+//! The protocol mirrors Binance's: clients send `SUBSCRIBE`, `UNSUBSCRIBE` and `LIST_SUBSCRIPTIONS` requests
+//! with stream names such as `btcusdt@trade`, `btcusdt@depth` or `btcusdt@bookTicker`, and receive
+//! `{"stream":..,"data":..}` messages holding the normalized records.
+//! A `depth` subscription first gets the local book of the symbol, as a `depthSnapshot` event whose update ids
+//! are both its last update id, then the diffs that follow it. When the local book is not valid yet, or was
+//! dropped after a disconnect, the snapshot is sent as soon as it is rebuilt.
+//!
+//! Every client has a bounded queue of outgoing messages. A client that lets it fill up is disconnected as a
+//! slow consumer instead of holding back the others.
+use std::collections::{hash_map::Entry, HashMap};
+use std::net::SocketAddr;
+use std::sync::{
+    atomic::{AtomicU64, Ordering},
+    Arc,
+};
+
+use async_trait::async_trait;
+use futures_util::{SinkExt, StreamExt};
+use log::{debug, info, warn};
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+use tokio::{
+    net::{TcpListener, TcpStream},
+    sync::{mpsc, Mutex},
+};
+use tokio_tungstenite::tungstenite::{
+    protocol::{frame::coding::CloseCode, CloseFrame},
+    Message,
+};
+
+use crate::{
+    binance::{
+        models::{
+            book_ticker::BookTicker,
+            orderbook::{OrderBooksRWL, OrderbookMessage},
+            trades::Trade,
+        },
+        rest::RestOrderBook,
+        websocket::{
+            handlers::{depth_update::DepthConnections, EventHandler, RawFrame},
+            router::{stream_symbol, StreamEvent, StreamKind},
+        },
+    },
+    sinks::{encoding::normalized_json, Record},
+};
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct BroadcastConfig {
+    pub address: SocketAddr,
+    /// Messages queued for a client before it is disconnected as a slow consumer.
+    pub client_buffer: usize,
+}
+impl Default for BroadcastConfig {
+    fn default() -> Self {
+        Self {
+            address: SocketAddr::from(([0, 0, 0, 0], 9400)),
+            client_buffer: 10_000,
+        }
+    }
+}
+
+struct Client {
+    sender: mpsc::Sender<String>,
+    /// Subscribed stream names. For depth streams, the last update id the client has, once it got a snapshot.
+    subscriptions: HashMap<String, Option<i64>>,
+}
+impl Client {
+    /// Queues `message`, false when the client is too far behind to take it.
+    fn send(&self, message: String) -> bool {
+        self.sender.try_send(message).is_ok()
+    }
+}
+
+/// Serves the websocket clients and feeds them the events it handles. Register it as an [`EventHandler`]
+/// after the [`crate::binance::websocket::handlers::depth_update::OrderBookMaintainer`] of `orderbooks`,
+/// so the local books are up to date when a diff is broadcast.
+pub struct Broadcaster {
+    config: BroadcastConfig,
+    orderbooks: OrderBooksRWL,
+    clients: Mutex<HashMap<u64, Client>>,
+    next_client_id: AtomicU64,
+    connections: DepthConnections,
+}
+impl Broadcaster {
+    pub fn new(config: BroadcastConfig, orderbooks: OrderBooksRWL) -> Self {
+        Self {
+            config,
+            orderbooks,
+            clients: Mutex::new(HashMap::new()),
+            next_client_id: AtomicU64::new(1),
+            connections: DepthConnections::default(),
+        }
+    }
+    pub fn config(&self) -> &BroadcastConfig {
+        &self.config
+    }
+    pub async fn clients(&self) -> usize {
+        self.clients.lock().await.len()
+    }
+    /// Accepts clients on `config.address`, forever.
+    pub async fn run(self: Arc<Self>) -> std::io::Result<()> {
+        let listener = TcpListener::bind(self.config.address).await?;
+        self.serve(listener).await;
+        Ok(())
+    }
+    /// Accepts clients on `listener`, forever.
+    pub async fn serve(self: Arc<Self>, listener: TcpListener) {
+        if let Ok(address) = listener.local_addr() {
+            info!("Broadcasting streams on {}", address);
+        }
+        loop {
+            match listener.accept().await {
+                Ok((stream, peer)) => {
+                    debug!("Broadcast client connected from {}", peer);
+                    tokio::spawn(self.clone().serve_client(stream, peer));
+                }
+                Err(e) => warn!("Error accepting a broadcast client: {}", e),
+            }
+        }
+    }
+    async fn serve_client(self: Arc<Self>, stream: TcpStream, peer: SocketAddr) {
+        let socket = match tokio_tungstenite::accept_async(stream).await {
+            Ok(socket) => socket,
+            Err(e) => {
+                warn!("Broadcast handshake with {} failed: {}", peer, e);
+                return;
+            }
+        };
+        let (mut sender, mut receiver) = socket.split();
+        let (queue, mut queued) = mpsc::channel(self.config.client_buffer.max(1));
+        let id = self.next_client_id.fetch_add(1, Ordering::SeqCst);
+        self.clients.lock().await.insert(
+            id,
+            Client {
+                sender: queue,
+                subscriptions: HashMap::new(),
+            },
+        );
+        loop {
+            tokio::select! {
+                message = queued.recv() => match message {
+                    Some(text) => {
+                        if sender.send(Message::Text(text)).await.is_err() {
+                            break;
+                        }
+                    }
+                    // Dropped by the broadcaster, the client could not keep up.
+                    None => {
+                        warn!("Disconnecting slow broadcast client {}", peer);
+                        let close = CloseFrame {
+                            code: CloseCode::Policy,
+                            reason: "slow consumer".into(),
+                        };
+                        _ = sender.send(Message::Close(Some(close))).await;
+                        return;
+                    }
+                },
+                message = receiver.next() => match message {
+                    Some(Ok(Message::Text(text))) => self.handle_request(id, &text).await,
+                    Some(Ok(Message::Ping(payload))) => {
+                        if sender.send(Message::Pong(payload)).await.is_err() {
+                            break;
+                        }
+                    }
+                    Some(Ok(Message::Close(_))) | Some(Err(_)) | None => break,
+                    Some(Ok(_)) => {}
+                },
+            }
+        }
+        debug!("Broadcast client {} disconnected", peer);
+        self.clients.lock().await.remove(&id);
+    }
+    /// Applies a request of client `id` and queues the response, followed by the snapshots of new depth streams.
+    async fn handle_request(&self, id: u64, text: &str) {
+        let request = serde_json::from_str::<Value>(text).unwrap_or_default();
+        let params = request["params"]
+            .as_array()
+            .map(|params| {
+                params
+                    .iter()
+                    .filter_map(Value::as_str)
+                    .map(str::to_string)
+                    .collect::<Vec<_>>()
+            })
+            .unwrap_or_default();
+        let mut clients = self.clients.lock().await;
+        let client = match clients.get_mut(&id) {
+            Some(client) => client,
+            None => return,
+        };
+        let mut new_depth_streams = Vec::new();
+        let response = match request["method"].as_str() {
+            Some("SUBSCRIBE") => match params.iter().find(|stream| !supported(stream)) {
+                Some(stream) => error_response(&request, &format!("Unsupported stream {}", stream)),
+                None => {
+                    for stream in params {
+                        if let Entry::Vacant(entry) = client.subscriptions.entry(stream) {
+                            if StreamKind::from_stream_name(entry.key()) == StreamKind::DepthUpdate
+                            {
+                                new_depth_streams.push(entry.key().clone());
+                            }
+                            entry.insert(None);
+                        }
+                    }
+                    json!({"result": null, "id": request["id"]})
+                }
+            },
+            Some("UNSUBSCRIBE") => {
+                for stream in params.iter() {
+                    client.subscriptions.remove(stream);
+                }
+                json!({"result": null, "id": request["id"]})
+            }
+            Some("LIST_SUBSCRIPTIONS") => {
+                let mut streams = client.subscriptions.keys().collect::<Vec<_>>();
+                streams.sort();
+                json!({"result": streams, "id": request["id"]})
+            }
+            _ => error_response(&request, "Invalid request"),
+        };
+        if !client.send(response.to_string()) {
+            clients.remove(&id);
+            return;
+        }
+        drop(clients);
+        // The books are read with the clients unlocked, like in `broadcast_depth`.
+        let mut snapshots = Vec::new();
+        for stream in new_depth_streams {
+            if let Some(snapshot) = self.snapshot(&stream_symbol(&stream)).await {
+                snapshots.push((stream, snapshot));
+            }
+        }
+        let mut clients = self.clients.lock().await;
+        let client = match clients.get_mut(&id) {
+            Some(client) => client,
+            None => return,
+        };
+        let mut alive = true;
+        for (stream, (last_update_id, snapshot)) in snapshots {
+            // Unless a depth update synced it in the meantime.
+            if let Some(synced_to @ None) = client.subscriptions.get_mut(&stream) {
+                alive &= client.sender.try_send(message(&stream, &snapshot)).is_ok();
+                *synced_to = Some(last_update_id);
+            }
+        }
+        if !alive {
+            clients.remove(&id);
+        }
+    }
+    /// The local book of `symbol` as a depth event, when it is valid.
+    async fn snapshot(&self, symbol: &str) -> Option<(i64, Value)> {
+        let books = self.orderbooks.read().await;
+        let book = books.get(symbol)?.first().filter(|book| book.is_valid)?;
+        let snapshot = OrderbookMessage {
+            event_type: "depthSnapshot".to_string(),
+            time: book.time,
+            symbol: symbol.to_string(),
+            first_update_id: book.last_update_id,
+            last_update_id: book.last_update_id,
+            bids: book.bids.clone(),
+            asks: book.asks.clone(),
+            prev_last_update_id: None,
+        };
+        Some((book.last_update_id, serde_json::to_value(snapshot).ok()?))
+    }
+    /// Queues `record` for every client subscribed to a stream of `kind` for its symbol,
+    /// dropping the clients that are too far behind.
+    async fn broadcast(&self, kind: StreamKind, record: Record) {
+        let symbol = record.symbol();
+        let mut clients = self.clients.lock().await;
+        let mut data = None;
+        let mut slow = Vec::new();
+        for (id, client) in clients.iter() {
+            for stream in client.subscriptions.keys() {
+                if stream_symbol(stream) != *symbol || StreamKind::from_stream_name(stream) != kind
+                {
+                    continue;
+                }
+                if data.is_none() {
+                    match normalized_json(&record, None) {
+                        Ok(value) => data = Some(value),
+                        Err(e) => {
+                            warn!("Error serializing {:?} to broadcast: {}", kind, e);
+                            return;
+                        }
+                    }
+                }
+                if !client.send(message(stream, data.as_ref().unwrap())) {
+                    slow.push(*id);
+                }
+            }
+        }
+        for id in slow {
+            clients.remove(&id);
+        }
+    }
+    /// Like [`Self::broadcast`], except clients get a snapshot first and only the diffs that follow it.
+    async fn broadcast_depth(&self, update: &OrderbookMessage) {
+        let symbol = &update.symbol;
+        let is_depth = |stream: &str| {
+            stream_symbol(stream) == *symbol
+                && StreamKind::from_stream_name(stream) == StreamKind::DepthUpdate
+        };
+        let unsynced = self.clients.lock().await.values().any(|client| {
+            client
+                .subscriptions
+                .iter()
+                .any(|(stream, synced_to)| synced_to.is_none() && is_depth(stream))
+        });
+        // Taken before locking the clients, so they are never locked while waiting for the books.
+        let snapshot = match unsynced {
+            true => self.snapshot(symbol).await,
+            false => None,
+        };
+        let mut clients = self.clients.lock().await;
+        let mut diff = None;
+        let mut slow = Vec::new();
+        for (id, client) in clients.iter_mut() {
+            for (stream, synced_to) in client.subscriptions.iter_mut() {
+                if !is_depth(stream) {
+                    continue;
+                }
+                let sent = match synced_to {
+                    // The book already holds the update, so it replaces the diff.
+                    // Clients that subscribed since the snapshot was taken get it with the next update.
+                    None => match &snapshot {
+                        Some((last_update_id, snapshot)) => {
+                            *synced_to = Some(*last_update_id);
+                            client.sender.try_send(message(stream, snapshot)).is_ok()
+                        }
+                        None => true,
+                    },
+                    Some(last_update_id) if update.last_update_id <= *last_update_id => true,
+                    Some(last_update_id) => {
+                        *last_update_id = update.last_update_id;
+                        let diff = diff.get_or_insert_with(|| {
+                            serde_json::to_value(update).unwrap_or_default()
+                        });
+                        client.sender.try_send(message(stream, diff)).is_ok()
+                    }
+                };
+                if !sent {
+                    slow.push(*id);
+                }
+            }
+        }
+        for id in slow {
+            clients.remove(&id);
+        }
+    }
+    /// Depth clients of `symbols` get a new snapshot once their books are rebuilt, as diffs were missed.
+    async fn resync_depth(&self, symbols: &[String]) {
+        if symbols.is_empty() {
+            return;
+        }
+        for client in self.clients.lock().await.values_mut() {
+            for (stream, synced_to) in client.subscriptions.iter_mut() {
+                if StreamKind::from_stream_name(stream) == StreamKind::DepthUpdate
+                    && symbols.contains(&stream_symbol(stream))
+                {
+                    *synced_to = None;
+                }
+            }
+        }
+    }
+}
+
+/// Streams of the kinds the gatherer handles.
+fn supported(stream: &str) -> bool {
+    !stream_symbol(stream).is_empty() && StreamKind::from_stream_name(stream) != StreamKind::Unknown
+}
+
+fn message(stream: &str, data: &Value) -> String {
+    json!({"stream": stream, "data": data}).to_string()
+}
+
+fn error_response(request: &Value, message: &str) -> Value {
+    json!({"error": {"code": 2, "msg": message}, "id": request["id"]})
+}
+
+#[async_trait]
+impl EventHandler for Broadcaster {
+    async fn on_disconnect(&self, connection_id: u64, _endpoint: &str) {
+        self.resync_depth(&self.connections.disconnected(connection_id))
+            .await;
+    }
+    async fn on_trade(&self, trade: &Trade) {
+        self.broadcast(StreamKind::Trade, Record::Trade(trade.clone()))
+            .await;
+    }
+    async fn on_depth(&self, update: &OrderbookMessage) {
+        self.broadcast_depth(update).await;
+    }
+    async fn on_partial_depth(&self, symbol: &str, book: &RestOrderBook) {
+        let event = StreamEvent::PartialDepth {
+            symbol: symbol.to_string(),
+            book: book.clone(),
+        };
+        self.broadcast(StreamKind::PartialDepth, Record::from_event(event))
+            .await;
+    }
+    async fn on_book_ticker(&self, ticker: &BookTicker) {
+        self.broadcast(StreamKind::BookTicker, Record::BookTicker(ticker.clone()))
+            .await;
+    }
+    async fn on_frame(&self, frame: &RawFrame<'_>) {
+        self.connections.observe(frame);
+    }
+}
@@ -0,0 +1,401 @@
+//! An in-process stand-in for S3, so uploads can be tested end-to-end through [`S3Bucket`] without network.
+//!
+//! It serves path-style requests for single and multipart uploads, `ListParts` and `HeadObject`,
+//! checks the `Content-MD5` of every body and computes ETags the way S3 does.
+//! Requests can be made to fail to exercise retries. Signatures are not checked.
+use std::collections::{BTreeMap, HashMap};
+use std::net::SocketAddr;
+use std::sync::{
+    atomic::{AtomicU64, AtomicUsize, Ordering},
+    Arc, Mutex,
+};
+
+use aws_sdk_s3::{
+    config::{retry::RetryConfig, Builder},
+    Client, Credentials, Region,
+};
+use base64::{engine::general_purpose::STANDARD, Engine};
+use log::debug;
+use md5::{Digest, Md5};
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt},
+    net::{TcpListener, TcpStream},
+    task::JoinHandle,
+};
+
+use super::S3Bucket;
+
+/// An object held by a [`MockS3`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct StoredObject {
+    pub data: Vec<u8>,
+    /// Quoted, as returned by S3.
+    pub etag: String,
+    pub metadata: HashMap<String, String>,
+}
+
+struct MultipartUpload {
+    path: String,
+    metadata: HashMap<String, String>,
+    parts: BTreeMap<i32, (Vec<u8>, String)>,
+}
+
+#[derive(Default)]
+struct Shared {
+    objects: Mutex<HashMap<String, StoredObject>>,
+    uploads: Mutex<HashMap<String, MultipartUpload>>,
+    next_upload_id: AtomicU64,
+    parts_received: AtomicUsize,
+    /// Requests to let through before failing, and requests to fail.
+    failures: Mutex<(usize, usize)>,
+}
+
+pub struct MockS3 {
+    addr: SocketAddr,
+    shared: Arc<Shared>,
+    task: JoinHandle<()>,
+}
+impl MockS3 {
+    /// Binds to an ephemeral port on localhost and starts serving.
+    pub async fn start() -> std::io::Result<Self> {
+        let listener = TcpListener::bind("127.0.0.1:0").await?;
+        let addr = listener.local_addr()?;
+        let shared = Arc::new(Shared::default());
+        let task = tokio::spawn({
+            let shared = shared.clone();
+            async move {
+                while let Ok((stream, _)) = listener.accept().await {
+                    tokio::spawn(serve_client(stream, shared.clone()));
+                }
+            }
+        });
+        Ok(Self { addr, shared, task })
+    }
+    pub fn endpoint(&self) -> String {
+        format!("http://{}", self.addr)
+    }
+    /// A bucket of this store, with dummy credentials and without the retries of the sdk.
+    pub fn bucket(&self, name: &str) -> S3Bucket {
+        let config = Builder::new()
+            .region(Region::new("us-east-1"))
+            .credentials_provider(Credentials::new("mock", "mock", None, None, "mock"))
+            .endpoint_url(self.endpoint())
+            .force_path_style(true)
+            .retry_config(RetryConfig::disabled())
+            .build();
+        S3Bucket::new(Client::from_conf(config), name)
+    }
+    pub fn object(&self, bucket: &str, key: &str) -> Option<StoredObject> {
+        let path = format!("/{}/{}", bucket, key);
+        self.shared.objects.lock().unwrap().get(&path).cloned()
+    }
+    /// Lets `successes` requests through, then answers the next `failures` ones with a 500.
+    pub fn fail_after(&self, successes: usize, failures: usize) {
+        *self.shared.failures.lock().unwrap() = (successes, failures);
+    }
+    /// Counts the parts received by `UploadPart` requests.
+    pub fn parts_received(&self) -> usize {
+        self.shared.parts_received.load(Ordering::SeqCst)
+    }
+}
+impl Drop for MockS3 {
+    fn drop(&mut self) {
+        self.task.abort();
+    }
+}
+
+struct Request {
+    method: String,
+    path: String,
+    query: HashMap<String, String>,
+    headers: HashMap<String, String>,
+    body: Vec<u8>,
+}
+
+struct Response {
+    status: &'static str,
+    headers: Vec<(String, String)>,
+    body: String,
+}
+impl Response {
+    fn new(status: &'static str) -> Self {
+        Self {
+            status,
+            headers: Vec::new(),
+            body: String::new(),
+        }
+    }
+    fn error(status: &'static str, code: &str) -> Self {
+        let mut response = Self::new(status);
+        response.body = format!(
+            "<?xml version=\"1.0\" encoding=\"UTF-8\"?>\n<Error><Code>{}</Code><Message>{}</Message></Error>",
+            code, code
+        );
+        response
+    }
+    fn header(mut self, name: &str, value: impl Into<String>) -> Self {
+        self.headers.push((name.to_string(), value.into()));
+        self
+    }
+    fn xml(mut self, body: String) -> Self {
+        self.body = format!("<?xml version=\"1.0\" encoding=\"UTF-8\"?>\n{}", body);
+        self
+    }
+}
+
+async fn read_request(stream: &mut TcpStream) -> Option<Request> {
+    let mut received = Vec::new();
+    let mut buffer = [0u8; 8192];
+    let head_end = loop {
+        if let Some(end) = received.windows(4).position(|w| w == b"\r\n\r\n") {
+            break end;
+        }
+        match stream.read(&mut buffer).await {
+            Ok(0) | Err(_) => return None,
+            Ok(read) => received.extend_from_slice(&buffer[..read]),
+        }
+    };
+    let head = String::from_utf8_lossy(&received[..head_end]).to_string();
+    let mut lines = head.lines();
+    let mut request_line = lines.next()?.split_whitespace();
+    let method = request_line.next()?.to_string();
+    let url = url::Url::parse(&format!("http://localhost{}", request_line.next()?)).ok()?;
+    let headers = lines
+        .filter_map(|line| line.split_once(':'))
+        .map(|(name, value)| (name.trim().to_ascii_lowercase(), value.trim().to_string()))
+        .collect::<HashMap<_, _>>();
+    if headers.get("expect").map(String::as_str) == Some("100-continue") {
+        stream
+            .write_all(b"HTTP/1.1 100 Continue\r\n\r\n")
+            .await
+            .ok()?;
+    }
+    let length = headers
+        .get("content-length")
+        .and_then(|length| length.parse().ok())
+        .unwrap_or(0);
+    let mut body = received.split_off(head_end + 4);
+    while body.len() < length {
+        match stream.read(&mut buffer).await {
+            Ok(0) | Err(_) => return None,
+            Ok(read) => body.extend_from_slice(&buffer[..read]),
+        }
+    }
+    Some(Request {
+        method,
+        path: url.path().to_string(),
+        query: url.query_pairs().into_owned().collect(),
+        headers,
+        body,
+    })
+}
+
+async fn serve_client(mut stream: TcpStream, shared: Arc<Shared>) {
+    let request = match read_request(&mut stream).await {
+        Some(request) => request,
+        None => return,
+    };
+    debug!(
+        "Mock S3 {} {} {:?}",
+        request.method, request.path, request.query
+    );
+    let response = if shared.should_fail() {
+        Response::error("500 Internal Server Error", "InternalError")
+    } else {
+        shared.handle(request)
+    };
+    let mut raw = format!("HTTP/1.1 {}\r\nConnection: close\r\n", response.status);
+    // A HEAD response carries the length of the object instead of its own.
+    if !response
+        .headers
+        .iter()
+        .any(|(name, _)| name == "Content-Length")
+    {
+        raw.push_str(&format!("Content-Length: {}\r\n", response.body.len()));
+    }
+    for (name, value) in response.headers.iter() {
+        raw.push_str(&format!("{}: {}\r\n", name, value));
+    }
+    raw.push_str("\r\n");
+    raw.push_str(&response.body);
+    _ = stream.write_all(raw.as_bytes()).await;
+    _ = stream.shutdown().await;
+}
+
+fn quoted_md5(data: &[u8]) -> String {
+    format!("\"{}\"", hex::encode(Md5::digest(data)))
+}
+
+fn metadata(headers: &HashMap<String, String>) -> HashMap<String, String> {
+    headers
+        .iter()
+        .filter_map(|(name, value)| {
+            name.strip_prefix("x-amz-meta-")
+                .map(|name| (name.to_string(), value.clone()))
+        })
+        .collect()
+}
+
+impl Shared {
+    fn should_fail(&self) -> bool {
+        let mut failures = self.failures.lock().unwrap();
+        if failures.0 > 0 {
+            failures.0 -= 1;
+            false
+        } else if failures.1 > 0 {
+            failures.1 -= 1;
+            true
+        } else {
+            false
+        }
+    }
+    fn handle(&self, request: Request) -> Response {
+        if let Some(content_md5) = request.headers.get("content-md5") {
+            if STANDARD.encode(Md5::digest(&request.body)) != *content_md5 {
+                return Response::error("400 Bad Request", "BadDigest");
+            }
+        }
+        let upload_id = request.query.get("uploadId").cloned();
+        match (request.method.as_str(), upload_id) {
+            ("PUT", None) => {
+                let etag = quoted_md5(&request.body);
+                let object = StoredObject {
+                    data: request.body,
+                    etag: etag.clone(),
+                    metadata: metadata(&request.headers),
+                };
+                self.objects.lock().unwrap().insert(request.path, object);
+                Response::new("200 OK").header("ETag", etag)
+            }
+            ("POST", None) if request.query.contains_key("uploads") => {
+                let upload_id = format!(
+                    "upload-{}",
+                    self.next_upload_id.fetch_add(1, Ordering::SeqCst)
+                );
+                let upload = MultipartUpload {
+                    path: request.path.clone(),
+                    metadata: metadata(&request.headers),
+                    parts: BTreeMap::new(),
+                };
+                self.uploads
+                    .lock()
+                    .unwrap()
+                    .insert(upload_id.clone(), upload);
+                Response::new("200 OK").xml(format!(
+                    "<InitiateMultipartUploadResult><Key>{}</Key><UploadId>{}</UploadId></InitiateMultipartUploadResult>",
+                    request.path, upload_id
+                ))
+            }
+            ("HEAD", None) | ("GET", None) => match self.objects.lock().unwrap().get(&request.path)
+            {
+                Some(object) => {
+                    let mut response = Response::new("200 OK").header("ETag", &object.etag);
+                    for (name, value) in object.metadata.iter() {
+                        response = response.header(&format!("x-amz-meta-{}", name), value);
+                    }
+                    if request.method == "GET" {
+                        response.body = String::from_utf8_lossy(&object.data).to_string();
+                    } else {
+                        response = response.header("Content-Length", object.data.len().to_string());
+                    }
+                    response
+                }
+                None if request.method == "HEAD" => Response::new("404 Not Found"),
+                None => Response::error("404 Not Found", "NoSuchKey"),
+            },
+            (method, Some(upload_id)) => {
+                let mut uploads = self.uploads.lock().unwrap();
+                let upload = match uploads.get_mut(&upload_id) {
+                    Some(upload) if upload.path == request.path => upload,
+                    _ => return Response::error("404 Not Found", "NoSuchUpload"),
+                };
+                match method {
+                    "PUT" => {
+                        let number = match request
+                            .query
+                            .get("partNumber")
+                            .and_then(|number| number.parse().ok())
+                        {
+                            Some(number) => number,
+                            None => return Response::error("400 Bad Request", "InvalidArgument"),
+                        };
+                        let etag = quoted_md5(&request.body);
+                        upload.parts.insert(number, (request.body, etag.clone()));
+                        self.parts_received.fetch_add(1, Ordering::SeqCst);
+                        Response::new("200 OK").header("ETag", etag)
+                    }
+                    "GET" => {
+                        let parts = upload
+                            .parts
+                            .iter()
+                            .map(|(number, (data, etag))| {
+                                format!(
+                                    "<Part><PartNumber>{}</PartNumber><ETag>{}</ETag><Size>{}</Size></Part>",
+                                    number,
+                                    etag.replace('"', "&quot;"),
+                                    data.len()
+                                )
+                            })
+                            .collect::<String>();
+                        Response::new("200 OK").xml(format!(
+                            "<ListPartsResult><UploadId>{}</UploadId><IsTruncated>false</IsTruncated>{}</ListPartsResult>",
+                            upload_id, parts
+                        ))
+                    }
+                    "POST" => {
+                        let requested = completed_parts(&String::from_utf8_lossy(&request.body));
+                        let mut data = Vec::new();
+                        let mut digests = Vec::new();
+                        for (number, etag) in requested.iter() {
+                            match upload.parts.get(number) {
+                                Some((part, part_etag)) if part_etag == etag => {
+                                    data.extend_from_slice(part);
+                                    digests.extend_from_slice(&Md5::digest(part));
+                                }
+                                _ => return Response::error("400 Bad Request", "InvalidPart"),
+                            }
+                        }
+                        let etag = format!(
+                            "\"{}-{}\"",
+                            hex::encode(Md5::digest(&digests)),
+                            requested.len()
+                        );
+                        let upload = uploads.remove(&upload_id).unwrap();
+                        let object = StoredObject {
+                            data,
+                            etag: etag.clone(),
+                            metadata: upload.metadata,
+                        };
+                        self.objects.lock().unwrap().insert(upload.path, object);
+                        Response::new("200 OK").xml(format!(
+                            "<CompleteMultipartUploadResult><ETag>{}</ETag></CompleteMultipartUploadResult>",
+                            etag.replace('"', "&quot;")
+                        ))
+                    }
+                    "DELETE" => {
+                        uploads.remove(&upload_id);
+                        Response::new("204 No Content")
+                    }
+                    _ => Response::error("405 Method Not Allowed", "MethodNotAllowed"),
+                }
+            }
+            _ => Response::error("405 Method Not Allowed", "MethodNotAllowed"),
+        }
+    }
+}
+
+/// Part numbers and ETags listed by a `CompleteMultipartUpload` body.
+fn completed_parts(body: &str) -> Vec<(i32, String)> {
+    let element = |part: &str, name: &str| {
+        let start = part.find(&format!("<{}>", name))? + name.len() + 2;
+        let end = part[start..].find(&format!("</{}>", name))? + start;
+        Some(part[start..end].replace("&quot;", "\""))
+    };
+    body.split("<Part>")
+        .skip(1)
+        .filter_map(|part| {
+            let number = element(part, "PartNumber")?.parse().ok()?;
+            Some((number, element(part, "ETag")?))
+        })
+        .collect()
+}
@@ -0,0 +1,255 @@
+//! Object storage the outputs are uploaded to, see [`crate::upload_queue`] for how uploads are driven.
+use std::collections::HashMap;
+
+use async_trait::async_trait;
+use aws_sdk_s3::{
+    model::{CompletedMultipartUpload, CompletedPart},
+    types::ByteStream,
+    Client,
+};
+
+#[cfg(any(test, feature = "mock"))]
+pub mod mock;
+
+pub type StoreError = Box<dyn std::error::Error + Send + Sync>;
+
+/// A part of a multipart upload already received by the store.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct UploadedPart {
+    pub number: i32,
+    pub etag: String,
+}
+
+/// What the store holds under a key.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ObjectInfo {
+    pub size: u64,
+    pub etag: String,
+    pub metadata: HashMap<String, String>,
+}
+
+/// The subset of the S3 api needed to upload files and check them once uploaded.
+/// `content_md5` is the base64 MD5 of the body, which the store rejects if it does not match.
+/// ETags are returned as sent by the store, usually quoted.
+#[async_trait]
+pub trait ObjectStore: Send + Sync {
+    async fn put_object(
+        &self,
+        key: &str,
+        body: Vec<u8>,
+        content_md5: &str,
+        metadata: &HashMap<String, String>,
+    ) -> Result<String, StoreError>;
+    /// Returns the upload id.
+    async fn create_multipart_upload(
+        &self,
+        key: &str,
+        metadata: &HashMap<String, String>,
+    ) -> Result<String, StoreError>;
+    async fn upload_part(
+        &self,
+        key: &str,
+        upload_id: &str,
+        number: i32,
+        body: Vec<u8>,
+        content_md5: &str,
+    ) -> Result<String, StoreError>;
+    /// Returns `None` if the upload no longer exists, because it was completed or aborted.
+    async fn list_parts(
+        &self,
+        key: &str,
+        upload_id: &str,
+    ) -> Result<Option<Vec<UploadedPart>>, StoreError>;
+    async fn complete_multipart_upload(
+        &self,
+        key: &str,
+        upload_id: &str,
+        parts: &[UploadedPart],
+    ) -> Result<String, StoreError>;
+    /// Returns `None` if there is no object under `key`.
+    async fn head_object(&self, key: &str) -> Result<Option<ObjectInfo>, StoreError>;
+}
+
+///https://github.com/awslabs/aws-sdk-rust
+/// An S3 bucket, or a bucket of any S3-compatible store when an endpoint is given.
+/// The client is built once and shared by all uploads.
+pub struct S3Bucket {
+    client: Client,
+    bucket: String,
+}
+impl S3Bucket {
+    pub fn new(client: Client, bucket: &str) -> Self {
+        Self {
+            client,
+            bucket: bucket.to_string(),
+        }
+    }
+    /// Environment variables: AWS_ACCESS_KEY_ID, AWS_SECRET_ACCESS_KEY, and AWS_REGION are req'd.
+    /// With an `endpoint`, such as a MinIO server, requests use path-style addressing.
+    pub async fn from_env(bucket: &str, endpoint: Option<&str>) -> Self {
+        let shared_config = aws_config::load_from_env().await;
+        let mut config = aws_sdk_s3::config::Builder::from(&shared_config);
+        if let Some(endpoint) = endpoint {
+            config = config.endpoint_url(endpoint).force_path_style(true);
+        }
+        Self::new(Client::from_conf(config.build()), bucket)
+    }
+}
+
+#[async_trait]
+impl ObjectStore for S3Bucket {
+    async fn put_object(
+        &self,
+        key: &str,
+        body: Vec<u8>,
+        content_md5: &str,
+        metadata: &HashMap<String, String>,
+    ) -> Result<String, StoreError> {
+        let output = self
+            .client
+            .put_object()
+            .bucket(&self.bucket)
+            .key(key)
+            .content_md5(content_md5)
+            .set_metadata(Some(metadata.clone()))
+            .body(ByteStream::from(body))
+            .send()
+            .await?;
+        Ok(output.e_tag().unwrap_or_default().to_string())
+    }
+    async fn create_multipart_upload(
+        &self,
+        key: &str,
+        metadata: &HashMap<String, String>,
+    ) -> Result<String, StoreError> {
+        let output = self
+            .client
+            .create_multipart_upload()
+            .bucket(&self.bucket)
+            .key(key)
+            .set_metadata(Some(metadata.clone()))
+            .send()
+            .await?;
+        output
+            .upload_id()
+            .map(str::to_string)
+            .ok_or_else(|| format!("No upload id returned for {}", key).into())
+    }
+    async fn upload_part(
+        &self,
+        key: &str,
+        upload_id: &str,
+        number: i32,
+        body: Vec<u8>,
+        content_md5: &str,
+    ) -> Result<String, StoreError> {
+        let output = self
+            .client
+            .upload_part()
+            .bucket(&self.bucket)
+            .key(key)
+            .upload_id(upload_id)
+            .part_number(number)
+            .content_md5(content_md5)
+            .body(ByteStream::from(body))
+            .send()
+            .await?;
+        Ok(output.e_tag().unwrap_or_default().to_string())
+    }
+    async fn list_parts(
+        &self,
+        key: &str,
+        upload_id: &str,
+    ) -> Result<Option<Vec<UploadedPart>>, StoreError> {
+        let mut parts = Vec::new();
+        let mut marker = None;
+        loop {
+            let output = match self
+                .client
+                .list_parts()
+                .bucket(&self.bucket)
+                .key(key)
+                .upload_id(upload_id)
+                .set_part_number_marker(marker)
+                .send()
+                .await
+            {
+                Ok(output) => output,
+                Err(e) => {
+                    let e = e.into_service_error();
+                    if e.code() == Some("NoSuchUpload") {
+                        return Ok(None);
+                    }
+                    return Err(e.into());
+                }
+            };
+            parts.extend(
+                output
+                    .parts()
+                    .unwrap_or_default()
+                    .iter()
+                    .map(|part| UploadedPart {
+                        number: part.part_number(),
+                        etag: part.e_tag().unwrap_or_default().to_string(),
+                    }),
+            );
+            if !output.is_truncated() {
+                return Ok(Some(parts));
+            }
+            marker = output.next_part_number_marker().map(str::to_string);
+        }
+    }
+    async fn complete_multipart_upload(
+        &self,
+        key: &str,
+        upload_id: &str,
+        parts: &[UploadedPart],
+    ) -> Result<String, StoreError> {
+        let parts = parts
+            .iter()
+            .map(|part| {
+                CompletedPart::builder()
+                    .part_number(part.number)
+                    .e_tag(&part.etag)
+                    .build()
+            })
+            .collect();
+        let output = self
+            .client
+            .complete_multipart_upload()
+            .bucket(&self.bucket)
+            .key(key)
+            .upload_id(upload_id)
+            .multipart_upload(
+                CompletedMultipartUpload::builder()
+                    .set_parts(Some(parts))
+                    .build(),
+            )
+            .send()
+            .await?;
+        Ok(output.e_tag().unwrap_or_default().to_string())
+    }
+    async fn head_object(&self, key: &str) -> Result<Option<ObjectInfo>, StoreError> {
+        match self
+            .client
+            .head_object()
+            .bucket(&self.bucket)
+            .key(key)
+            .send()
+            .await
+        {
+            Ok(output) => Ok(Some(ObjectInfo {
+                size: output.content_length().max(0) as u64,
+                etag: output.e_tag().unwrap_or_default().to_string(),
+                metadata: output.metadata().cloned().unwrap_or_default(),
+            })),
+            Err(e) => {
+                let e = e.into_service_error();
+                if e.is_not_found() {
+                    return Ok(None);
+                }
+                Err(e.into())
+            }
+        }
+    }
+}
@@ -1,80 +1,111 @@
-use std::{sync::Arc, time::Duration, collections::HashMap};
+use std::{sync::Arc, collections::HashMap, path::Path};
 
-use itertools::Itertools;
-use log::{info, error};
-use tokio::{sync::RwLock, time};
+use log::{info, error, warn};
+use chrono::{DateTime, Utc};
+use tokio::sync::{mpsc, oneshot, RwLock};
 
-use crate::{binance::{models::{trades::Trade, orderbook::OrderbookMessage}, websocket::requests::DataRequest, rest::RestOrderBook}, file_compress::compress_file, bucket_utils::upload_object, settings::OUTGOING_FOLDER_NAME};
+use crate::{binance::{websocket::requests::DataRequest, rest::RestOrderBook}, journal::{Checkpoint, Journal}, upload_queue::UploadQueue, quality::QualityMonitor, settings::OUTGOING_FOLDER_NAME, sinks::{bus::EventBus, manifest::{write_manifest, SIDECAR_EXTENSION}, part_files::{CRASHED_EXTENSION, PART_EXTENSION}, Record}};
 
-/// Creates files from the data received from the websocket and rest api. It also compresses and uploads the files to s3.
-pub async fn create_files(update_messages: Arc<RwLock<Vec<OrderbookMessage>>>,trade_update_messages: Arc<RwLock<Vec<Trade>>>,request:DataRequest,snapshot_rwl: Arc<RwLock<HashMap<String, Vec<RestOrderBook>>>>) {
-    tokio::time::sleep(Duration::from_secs(3600)).await;
-    let mut interval = time::interval(Duration::from_secs(3600)); 
+/// Rotates the files written by the sinks of `bus` at every boundary of its rotation interval, aligned to UTC,
+/// then queues them for upload, already compressed by the sinks, along with the manifest and quality report of the window.
+/// Snapshots taken from the rest api are published to the bus right before rotating.
+/// Journal segments sealed at a boundary are released once the windows before it have been closed,
+/// after the grace period given to late records, unless they also hold records of a later window.
+/// Requests handled by [`create_files`] may also close every open file right away, instead of at the next boundary.
+/// Records of the same windows that arrive later go to new parts. The reply is how many files were queued.
+pub async fn create_files(bus: Arc<EventBus>,journal: Arc<Journal>,uploads: Arc<UploadQueue>,request:DataRequest,snapshot_rwl: Arc<RwLock<HashMap<String, Vec<RestOrderBook>>>>,quality: Arc<QualityMonitor>,mut upload_requests: mpsc::Receiver<UploadRequest>) {
+    let rotation = bus.config().rotation;
     loop {
-        interval.tick().await;
-        match std::fs::create_dir(OUTGOING_FOLDER_NAME) {
-            Ok(_) => info!("Created folder {}", OUTGOING_FOLDER_NAME),
-            Err(e) => error!("Error creating folder {}: {}", OUTGOING_FOLDER_NAME, e),
-        }
-        let mut update_messages = update_messages.write().await;
-        let updates_copy = update_messages.clone();
-        update_messages.clear();
-        drop(update_messages);
-        let update_symbols = updates_copy.iter().map(|x| x.symbol.clone()).unique().collect_vec();
-        for symbol in update_symbols {
-            let mut rows = Vec::new();
-            for update in &updates_copy {
-                if update.symbol == symbol {
-                    rows.extend(update.to_csv_format())
+        let boundary = rotation.next_boundary(Utc::now());
+        tokio::select! {
+            _ = tokio::time::sleep((boundary - Utc::now()).to_std().unwrap_or_default()) => {
+                let checkpoint = journal.seal();
+                let snapshots = std::mem::take(&mut *snapshot_rwl.write().await);
+                for (symbol,books) in snapshots {
+                    for book in books {
+                        bus.publish(Record::Snapshot { symbol: symbol.clone(), book }).await;
+                    }
                 }
+                tokio::time::sleep(bus.config().grace).await;
+                close_windows(&bus,&journal,&uploads,&request,&quality,checkpoint,boundary,boundary).await;
             }
-            let symbol = format!("{}/{}_{}_BOOK_HISTORY",OUTGOING_FOLDER_NAME,request.asset_type,symbol);
-            let filename = format!("{}.csv",symbol);
-            create_csv_file(&rows, &filename);
-        }
-        let snapshots = snapshot_rwl.write().await;
-        let snapshots_copy = snapshots.clone();
-        drop(snapshots);
-        for (key,value) in snapshots_copy.iter() {
-            let mut rows = Vec::new();
-            for snapshot in value {
-                rows.extend(snapshot.to_csv_format())
+            Some(reply) = upload_requests.recv() => {
+                // Every record of the sealed segments is in a window closed below.
+                let checkpoint = journal.seal();
+                let queued = close_windows(&bus,&journal,&uploads,&request,&quality,checkpoint,DateTime::<Utc>::MAX_UTC,Utc::now()).await;
+                _ = reply.send(queued);
             }
-            let filename = format!("{}/{}_{}_BOOK_SNAPSHOT.csv",OUTGOING_FOLDER_NAME,request.asset_type,key);
-            create_csv_file(&rows, &filename);
         }
-        let mut trades = trade_update_messages.write().await;
-        let trades_copy = trades.clone();
-        trades.clear();
-        drop(trades);
-        create_csv_file(&trades_copy, format!("{}/TRADES.csv",OUTGOING_FOLDER_NAME).as_str());
-        //list the contents of the outgoing directory
-        let files = std::fs::read_dir(OUTGOING_FOLDER_NAME).unwrap();
-        for file in files {
-            match file {
-                Ok(file) => {
-                    let file_name = format!("{OUTGOING_FOLDER_NAME}/{}",file.file_name().into_string().unwrap());
-                    match compress_file(&file_name) {
-                        Ok(compressed_filename) => {
-                            match upload_object("buckent_name:m",&compressed_filename,"key").await {
-                                Ok(_) => {
-                                    info!("File {} uploaded to s3",compressed_filename);
-                                    std::fs::remove_file(&compressed_filename).unwrap();
-                                },
-                                Err(e) => error!("Error uploading file to s3: {}",e)
-                            }
-                        },
-                        Err(e) => {
-                            error!("Error compressing file: {}",e);
-                        }
-                    }
-                },
-                Err(e) => {
-                    error!("Error reading file: {}",e);
+    }
+}
+
+/// Asks [`create_files`] to close the open files and queue them for upload, see [`upload_now`].
+pub type UploadRequest = oneshot::Sender<usize>;
+
+/// Has [`create_files`] close every open file right away and queue them for upload.
+/// Returns how many files were queued, or `None` when the rotation task is gone.
+pub async fn upload_now(requests: &mpsc::Sender<UploadRequest>) -> Option<usize> {
+    let (reply, queued) = oneshot::channel();
+    requests.send(reply).await.ok()?;
+    queued.await.ok()
+}
+
+/// Closes the windows ending by `until`, releases the journal segments of `checkpoint` they cover, then queues the
+/// files for upload along with the quality report and manifest named after `time`. Returns how many files were queued.
+#[allow(clippy::too_many_arguments)]
+async fn close_windows(bus: &EventBus,journal: &Journal,uploads: &UploadQueue,request: &DataRequest,quality: &QualityMonitor,checkpoint: Checkpoint,until: DateTime<Utc>,time: DateTime<Utc>) -> usize {
+    match std::fs::create_dir(OUTGOING_FOLDER_NAME) {
+        Ok(_) => info!("Created folder {}", OUTGOING_FOLDER_NAME),
+        Err(e) => error!("Error creating folder {}: {}", OUTGOING_FOLDER_NAME, e),
+    }
+    bus.rotate(until).await;
+    journal.release(checkpoint, until);
+    // Before the manifest, which removes the sidecars telling which files are new.
+    let report_name = format!("{}_QUALITY_{}", request.asset_type, time.format("%Y%m%dT%H%M%SZ"));
+    if let Err(e) = quality.write_report(Path::new(OUTGOING_FOLDER_NAME), &report_name).await {
+        error!("Error writing quality report {}: {}", report_name, e);
+    }
+    let manifest_name = format!("{}_MANIFEST_{}", request.asset_type, time.format("%Y%m%dT%H%M%SZ"));
+    if let Err(e) = write_manifest(Path::new(OUTGOING_FOLDER_NAME), &manifest_name) {
+        error!("Error writing manifest {}: {}", manifest_name, e);
+    }
+    for stats in bus.stats() {
+        if stats.counters.dropped > 0 || stats.counters.spilled > 0 {
+            warn!("{} {} {:?} queued={}", request.asset_type, stats.stream, stats.counters, stats.queued);
+        }
+    }
+    queue_outgoing(uploads)
+}
+
+/// Queues the finished files of the outgoing folder for upload, and returns how many there were.
+fn queue_outgoing(uploads: &UploadQueue) -> usize {
+    let mut queued = 0;
+    //list the contents of the outgoing directory
+    let files = match std::fs::read_dir(OUTGOING_FOLDER_NAME) {
+        Ok(files) => files,
+        Err(e) => {
+            error!("Error listing {}: {}",OUTGOING_FOLDER_NAME,e);
+            return 0;
+        }
+    };
+    for file in files {
+        match file {
+            Ok(file) => {
+                let file_name = format!("{OUTGOING_FOLDER_NAME}/{}",file.file_name().into_string().unwrap());
+                if file_name.ends_with(PART_EXTENSION) || file_name.ends_with(CRASHED_EXTENSION) || file_name.ends_with(SIDECAR_EXTENSION) {
+                    continue;
+                }
+                match uploads.enqueue(Path::new(&file_name)) {
+                    Ok(_) => queued += 1,
+                    Err(e) => error!("Error queueing upload of {}: {}",file_name,e),
                 }
+            },
+            Err(e) => {
+                error!("Error reading file: {}",e);
             }
         }
     }
+    queued
 }
 
 
@@ -0,0 +1,285 @@
+//! Keeps the local folders from filling the disk.
+//!
+//! [`DiskGuard`] periodically measures the folders it is given, deletes the files past the retention of the
+//! folders that have one, and checks the free space of the volume. When free space falls below
+//! [`DiskConfig::min_free_bytes`] it raises an alert and degrades every registered [`LoadShedder`], until
+//! free space is back above [`DiskConfig::resume_free_bytes`].
+use std::path::{Path, PathBuf};
+use std::sync::{
+    atomic::{AtomicBool, Ordering},
+    Arc, Mutex,
+};
+use std::time::{Duration, SystemTime};
+
+use log::{error, info, warn};
+use serde::{Deserialize, Serialize};
+
+/// A folder to track, and optionally prune.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct FolderPolicy {
+    pub folder: PathBuf,
+    /// Files last modified longer ago are deleted.
+    pub max_age: Option<Duration>,
+    /// The oldest files are deleted until the folder is under this size.
+    pub max_bytes: Option<u64>,
+}
+impl FolderPolicy {
+    /// Measured, never pruned.
+    pub fn tracked(folder: impl Into<PathBuf>) -> Self {
+        Self {
+            folder: folder.into(),
+            max_age: None,
+            max_bytes: None,
+        }
+    }
+    pub fn retained(
+        folder: impl Into<PathBuf>,
+        max_age: Option<Duration>,
+        max_bytes: Option<u64>,
+    ) -> Self {
+        Self {
+            folder: folder.into(),
+            max_age,
+            max_bytes,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct DiskConfig {
+    pub folders: Vec<FolderPolicy>,
+    /// Any path on the volume whose free space is checked.
+    pub volume: PathBuf,
+    /// Below this much free space, load is shed.
+    pub min_free_bytes: u64,
+    /// Load shedding stops once free space is back above this.
+    pub resume_free_bytes: u64,
+    pub check_interval: Duration,
+}
+impl Default for DiskConfig {
+    fn default() -> Self {
+        Self {
+            folders: Vec::new(),
+            volume: PathBuf::from("."),
+            min_free_bytes: 2 * 1024 * 1024 * 1024,
+            resume_free_bytes: 4 * 1024 * 1024 * 1024,
+            check_interval: Duration::from_secs(30),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct FolderUsage {
+    pub folder: PathBuf,
+    pub bytes: u64,
+    pub files: u64,
+}
+
+/// The outcome of a check.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct DiskUsage {
+    /// After retention was enforced.
+    pub folders: Vec<FolderUsage>,
+    pub deleted_files: u64,
+    pub deleted_bytes: u64,
+    /// `None` if it could not be read.
+    pub free_bytes: Option<u64>,
+    pub degraded: bool,
+}
+
+/// Something that can do less work, and write less, while the disk is almost full.
+pub trait LoadShedder: Send + Sync {
+    fn set_degraded(&self, degraded: bool);
+}
+
+pub struct DiskGuard {
+    config: DiskConfig,
+    shedders: Vec<Arc<dyn LoadShedder>>,
+    degraded: AtomicBool,
+    last_usage: Mutex<Option<DiskUsage>>,
+}
+impl DiskGuard {
+    pub fn new(config: DiskConfig) -> Self {
+        Self {
+            config,
+            shedders: Vec::new(),
+            degraded: AtomicBool::new(false),
+            last_usage: Mutex::new(None),
+        }
+    }
+    pub fn with_shedder(mut self, shedder: Arc<dyn LoadShedder>) -> Self {
+        self.shedders.push(shedder);
+        self
+    }
+    pub fn is_degraded(&self) -> bool {
+        self.degraded.load(Ordering::SeqCst)
+    }
+    /// The outcome of the last check.
+    pub fn last_usage(&self) -> Option<DiskUsage> {
+        self.last_usage.lock().unwrap().clone()
+    }
+    /// Enforces retention, measures the folders and the free space, and sheds load or stops shedding it.
+    pub fn check(&self) -> DiskUsage {
+        let mut deleted_files = 0;
+        let mut deleted_bytes = 0;
+        let mut folders = Vec::new();
+        for policy in self.config.folders.iter() {
+            let mut files = list_files(&policy.folder);
+            let (files_deleted, bytes_deleted) = enforce_retention(policy, &mut files);
+            deleted_files += files_deleted;
+            deleted_bytes += bytes_deleted;
+            folders.push(FolderUsage {
+                folder: policy.folder.clone(),
+                bytes: files.iter().map(|file| file.size).sum(),
+                files: files.len() as u64,
+            });
+        }
+        if deleted_files > 0 {
+            info!(
+                "Retention deleted {} files, {} bytes",
+                deleted_files, deleted_bytes
+            );
+        }
+        let free_bytes = match free_space(&self.config.volume) {
+            Ok(free) => Some(free),
+            Err(e) => {
+                error!(
+                    "Error reading free space of {}: {}",
+                    self.config.volume.display(),
+                    e
+                );
+                None
+            }
+        };
+        let was_degraded = self.is_degraded();
+        let degraded = match free_bytes {
+            Some(free) if was_degraded => free < self.config.resume_free_bytes,
+            Some(free) => free < self.config.min_free_bytes,
+            None => was_degraded,
+        };
+        if degraded {
+            error!(
+                "Low disk space: {:?} bytes free, shedding load. Usage: {:?}",
+                free_bytes, folders
+            );
+        }
+        if degraded != was_degraded {
+            if !degraded {
+                info!(
+                    "Disk space recovered: {:?} bytes free, resuming",
+                    free_bytes
+                );
+            }
+            self.degraded.store(degraded, Ordering::SeqCst);
+            for shedder in self.shedders.iter() {
+                shedder.set_degraded(degraded);
+            }
+        }
+        let usage = DiskUsage {
+            folders,
+            deleted_files,
+            deleted_bytes,
+            free_bytes,
+            degraded,
+        };
+        *self.last_usage.lock().unwrap() = Some(usage.clone());
+        usage
+    }
+    /// Checks every `check_interval`, forever. The checks walk the folders, so they run on the blocking pool.
+    pub async fn run(self: Arc<Self>) {
+        let mut interval = tokio::time::interval(self.config.check_interval);
+        loop {
+            interval.tick().await;
+            let guard = self.clone();
+            if let Err(e) = tokio::task::spawn_blocking(move || guard.check()).await {
+                error!("Error checking the disk: {}", e);
+            }
+        }
+    }
+}
+
+struct FileInfo {
+    path: PathBuf,
+    size: u64,
+    modified: SystemTime,
+}
+
+/// Every file under `folder`, oldest first.
+fn list_files(folder: &Path) -> Vec<FileInfo> {
+    let mut files = Vec::new();
+    let mut folders = vec![folder.to_path_buf()];
+    while let Some(folder) = folders.pop() {
+        let entries = match std::fs::read_dir(&folder) {
+            Ok(entries) => entries,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => continue,
+            Err(e) => {
+                warn!("Error listing {}: {}", folder.display(), e);
+                continue;
+            }
+        };
+        for entry in entries.flatten() {
+            let metadata = match entry.metadata() {
+                Ok(metadata) => metadata,
+                Err(_) => continue,
+            };
+            if metadata.is_dir() {
+                folders.push(entry.path());
+            } else {
+                files.push(FileInfo {
+                    path: entry.path(),
+                    size: metadata.len(),
+                    modified: metadata.modified().unwrap_or(SystemTime::UNIX_EPOCH),
+                });
+            }
+        }
+    }
+    files.sort_by_key(|file| file.modified);
+    files
+}
+
+/// Deletes the files of `policy` that are too old, then the oldest ones while the folder is too large.
+/// The newest file is always kept, as it may still be written to. Returns the files and bytes deleted.
+fn enforce_retention(policy: &FolderPolicy, files: &mut Vec<FileInfo>) -> (u64, u64) {
+    let now = SystemTime::now();
+    let mut total = files.iter().map(|file| file.size).sum::<u64>();
+    let mut deleted = (0, 0);
+    while files.len() > 1 {
+        let oldest = &files[0];
+        let too_old = policy.max_age.is_some_and(|max_age| {
+            now.duration_since(oldest.modified).unwrap_or_default() > max_age
+        });
+        let too_large = policy.max_bytes.is_some_and(|max_bytes| total > max_bytes);
+        if !too_old && !too_large {
+            break;
+        }
+        if let Err(e) = std::fs::remove_file(&oldest.path) {
+            error!("Error deleting {}: {}", oldest.path.display(), e);
+            break;
+        }
+        total -= oldest.size;
+        deleted.0 += 1;
+        deleted.1 += oldest.size;
+        files.remove(0);
+    }
+    deleted
+}
+
+#[cfg(unix)]
+fn free_space(path: &Path) -> std::io::Result<u64> {
+    use std::os::unix::ffi::OsStrExt;
+    let path = std::ffi::CString::new(path.as_os_str().as_bytes())?;
+    let mut stat: libc::statvfs = unsafe { std::mem::zeroed() };
+    // SAFETY: `path` is a valid C string and `stat` is a properly sized buffer.
+    if unsafe { libc::statvfs(path.as_ptr(), &mut stat) } != 0 {
+        return Err(std::io::Error::last_os_error());
+    }
+    Ok(stat.f_bavail as u64 * stat.f_frsize as u64)
+}
+
+#[cfg(not(unix))]
+fn free_space(_path: &Path) -> std::io::Result<u64> {
+    Err(std::io::Error::new(
+        std::io::ErrorKind::Unsupported,
+        "free space is only read on unix",
+    ))
+}
@@ -1,52 +1,286 @@
-
-use log::error;
-use log::info;
+//! Compression codecs, applied as streaming encoders so nothing uncompressed has to be written first,
+//! and the matching decoders to read files back.
 use std::fs::File;
-use std::io::copy;
-use std::io::BufReader;
-use bzip2::Compression;
-use bzip2::read::BzEncoder;
-
-/// Compresses a file using bzip2, returns the compressed filename
-pub fn compress_file(filepath: &str) -> Result<String, std::io::Error> {
-    let input = BufReader::new(File::open(filepath).unwrap());
-    let mut output = File::create(format!("{}.bz2", filepath)).unwrap();
-    let mut encoder = BzEncoder::new(input, Compression::best());
-    match copy(&mut encoder, &mut output) {
+use std::io::{copy, BufReader, BufWriter, Read, Write};
+use std::path::{Path, PathBuf};
+use std::sync::{
+    atomic::{AtomicU64, Ordering},
+    Arc,
+};
+
+use log::{error, info};
+use serde::{Deserialize, Serialize};
+
+fn invalid_level(codec: &Codec, level: &u32) -> std::io::Error {
+    std::io::Error::new(
+        std::io::ErrorKind::InvalidInput,
+        format!("Invalid {} level {}", codec.name(), level),
+    )
+}
+
+/// A compression format and its settings.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub enum Codec {
+    None,
+    /// Level from 1 to 9.
+    Bzip2 {
+        level: u32,
+    },
+    /// Level from 0 to 9.
+    Gzip {
+        level: u32,
+    },
+    Lz4,
+    /// Level from 1 to 22. A dictionary trained on similar files helps small files a lot,
+    /// the same dictionary is needed to decompress them.
+    Zstd {
+        level: i32,
+        dictionary: Option<PathBuf>,
+    },
+}
+impl Default for Codec {
+    fn default() -> Self {
+        Codec::Zstd {
+            level: 3,
+            dictionary: None,
+        }
+    }
+}
+impl Codec {
+    /// Appended to the names of the files compressed with this codec.
+    pub fn extension(&self) -> &'static str {
+        match self {
+            Codec::None => "",
+            Codec::Bzip2 { .. } => ".bz2",
+            Codec::Gzip { .. } => ".gz",
+            Codec::Lz4 => ".lz4",
+            Codec::Zstd { .. } => ".zst",
+        }
+    }
+    pub fn name(&self) -> &'static str {
+        match self {
+            Codec::None => "none",
+            Codec::Bzip2 { .. } => "bzip2",
+            Codec::Gzip { .. } => "gzip",
+            Codec::Lz4 => "lz4",
+            Codec::Zstd { .. } => "zstd",
+        }
+    }
+    /// Guesses the codec of a file from its extension, without any zstd dictionary.
+    pub fn from_path(path: &Path) -> Self {
+        match path.extension().and_then(|e| e.to_str()) {
+            Some("bz2") => Codec::Bzip2 { level: 9 },
+            Some("gz") => Codec::Gzip { level: 6 },
+            Some("lz4") => Codec::Lz4,
+            Some("zst") => Codec::Zstd {
+                level: 3,
+                dictionary: None,
+            },
+            _ => Codec::None,
+        }
+    }
+    fn dictionary(path: &Option<PathBuf>) -> std::io::Result<Vec<u8>> {
+        match path {
+            Some(path) => std::fs::read(path),
+            None => Ok(Vec::new()),
+        }
+    }
+    /// Wraps `writer` so everything written to it is compressed. Call [`Encoder::finish`] once done.
+    pub fn encoder<W: Write>(&self, writer: W) -> std::io::Result<Encoder<W>> {
+        self.counted_encoder(writer, Arc::default())
+    }
+    /// Like [`Codec::encoder`], adding the bytes that go through the encoder to `counters`.
+    pub fn counted_encoder<W: Write>(
+        &self,
+        writer: W,
+        counters: Arc<CompressionCounters>,
+    ) -> std::io::Result<Encoder<W>> {
+        match self {
+            Codec::Bzip2 { level } if !(1..=9).contains(level) => {
+                return Err(invalid_level(self, level))
+            }
+            Codec::Gzip { level } if *level > 9 => return Err(invalid_level(self, level)),
+            _ => {}
+        }
+        let writer = Counted {
+            inner: writer,
+            counters,
+        };
+        Ok(match self {
+            Codec::None => Encoder::None(writer),
+            Codec::Bzip2 { level } => Encoder::Bzip2(bzip2::write::BzEncoder::new(
+                writer,
+                bzip2::Compression::new(*level),
+            )),
+            Codec::Gzip { level } => Encoder::Gzip(flate2::write::GzEncoder::new(
+                writer,
+                flate2::Compression::new(*level),
+            )),
+            Codec::Lz4 => Encoder::Lz4(lz4_flex::frame::FrameEncoder::new(writer)),
+            Codec::Zstd { level, dictionary } => {
+                Encoder::Zstd(zstd::stream::write::Encoder::with_dictionary(
+                    writer,
+                    *level,
+                    &Self::dictionary(dictionary)?,
+                )?)
+            }
+        })
+    }
+    /// Wraps `reader` so what is read from it is decompressed.
+    pub fn decoder<'a, R: Read + Send + 'a>(
+        &self,
+        reader: R,
+    ) -> std::io::Result<Box<dyn Read + Send + 'a>> {
+        let reader = BufReader::new(reader);
+        Ok(match self {
+            Codec::None => Box::new(reader),
+            Codec::Bzip2 { .. } => Box::new(bzip2::read::MultiBzDecoder::new(reader)),
+            Codec::Gzip { .. } => Box::new(flate2::read::MultiGzDecoder::new(reader)),
+            Codec::Lz4 => Box::new(lz4_flex::frame::FrameDecoder::new(reader)),
+            Codec::Zstd { dictionary, .. } => {
+                Box::new(zstd::stream::read::Decoder::with_dictionary(
+                    reader,
+                    &Self::dictionary(dictionary)?,
+                )?)
+            }
+        })
+    }
+}
+
+/// Bytes given to a set of encoders and bytes they produced, e.g. those of the files of a sink.
+#[derive(Debug, Default)]
+pub struct CompressionCounters {
+    uncompressed: AtomicU64,
+    compressed: AtomicU64,
+}
+impl CompressionCounters {
+    pub fn stats(&self) -> CompressionStats {
+        CompressionStats {
+            uncompressed: self.uncompressed.load(Ordering::Relaxed),
+            compressed: self.compressed.load(Ordering::Relaxed),
+        }
+    }
+}
+
+/// A snapshot of [`CompressionCounters`].
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct CompressionStats {
+    pub uncompressed: u64,
+    pub compressed: u64,
+}
+
+/// Adds the bytes written through it to the compressed total of its counters.
+pub struct Counted<W> {
+    inner: W,
+    counters: Arc<CompressionCounters>,
+}
+impl<W> Counted<W> {
+    pub fn into_inner(self) -> W {
+        self.inner
+    }
+}
+impl<W: Write> Write for Counted<W> {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        let written = self.inner.write(buf)?;
+        self.counters
+            .compressed
+            .fetch_add(written as u64, Ordering::Relaxed);
+        Ok(written)
+    }
+    fn flush(&mut self) -> std::io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+/// A writer compressing with one of the [`Codec`]s, whose output is only complete once finished.
+pub enum Encoder<W: Write> {
+    None(Counted<W>),
+    Bzip2(bzip2::write::BzEncoder<Counted<W>>),
+    Gzip(flate2::write::GzEncoder<Counted<W>>),
+    Lz4(lz4_flex::frame::FrameEncoder<Counted<W>>),
+    Zstd(zstd::stream::write::Encoder<'static, Counted<W>>),
+}
+impl<W: Write> Encoder<W> {
+    /// Writes the end of the compressed stream and returns the inner writer.
+    pub fn finish(self) -> std::io::Result<W> {
+        let writer = match self {
+            Encoder::None(writer) => writer,
+            Encoder::Bzip2(encoder) => encoder.finish()?,
+            Encoder::Gzip(encoder) => encoder.finish()?,
+            Encoder::Lz4(encoder) => encoder.finish().map_err(std::io::Error::other)?,
+            Encoder::Zstd(encoder) => encoder.finish()?,
+        };
+        Ok(writer.into_inner())
+    }
+    fn counters(&self) -> &CompressionCounters {
+        match self {
+            Encoder::None(writer) => &writer.counters,
+            Encoder::Bzip2(encoder) => &encoder.get_ref().counters,
+            Encoder::Gzip(encoder) => &encoder.get_ref().counters,
+            Encoder::Lz4(encoder) => &encoder.get_ref().counters,
+            Encoder::Zstd(encoder) => &encoder.get_ref().counters,
+        }
+    }
+}
+impl<W: Write> Write for Encoder<W> {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        let written = match self {
+            Encoder::None(writer) => writer.write(buf),
+            Encoder::Bzip2(encoder) => encoder.write(buf),
+            Encoder::Gzip(encoder) => encoder.write(buf),
+            Encoder::Lz4(encoder) => encoder.write(buf),
+            Encoder::Zstd(encoder) => encoder.write(buf),
+        }?;
+        self.counters()
+            .uncompressed
+            .fetch_add(written as u64, Ordering::Relaxed);
+        Ok(written)
+    }
+    /// Leaves what was written to the compressor, as flushing it would end a block early and hurt the ratio.
+    /// Only [`Encoder::finish`] writes the compressed stream out.
+    fn flush(&mut self) -> std::io::Result<()> {
+        match self {
+            Encoder::None(writer) => writer.flush(),
+            _ => Ok(()),
+        }
+    }
+}
+
+/// Compresses a file with `codec` next to the original, which is removed. Returns the compressed filename.
+/// With [`Codec::None`] the file is left as is.
+pub fn compress_file(filepath: &str, codec: &Codec) -> Result<String, std::io::Error> {
+    if *codec == Codec::None {
+        return Ok(filepath.to_string());
+    }
+    let compressed = format!("{}{}", filepath, codec.extension());
+    let result = File::open(filepath).and_then(|input| {
+        let mut encoder = codec.encoder(BufWriter::new(File::create(&compressed)?))?;
+        copy(&mut BufReader::new(input), &mut encoder)?;
+        encoder.finish()?.flush()
+    });
+    match result {
         Ok(_) => {
             info!("Succesfully Compressed file {}", filepath);
             match std::fs::remove_file(filepath) {
                 Ok(_) => {
                     info!("Succesfully Deleted uncompressed file {}", filepath);
-                    Ok(format!("{}.bz2", filepath))
-                },
+                    Ok(compressed)
+                }
                 Err(e) => {
                     error!("Error deleting uncompressed file {}: {}", filepath, e);
                     Err(e)
-                },
+                }
             }
-        },
+        }
         Err(e) => {
             error!("Error compressing file: {} {}", e, filepath);
             Err(e)
-        },
-    }
-}
-
-// pub fn decompress_file(filepath:&str) {
-//     let input = BufReader::new(File::open(filepath).unwrap());
-//     let mut output = File::create(format!("{}.csv", filepath)).unwrap();
-//     let mut decoder = bzip2::read::BzDecoder::new(input);
-//     match copy(&mut decoder, &mut output) {
-//         Ok(_) => {
-//             info!("Succesfully Decompressed file {}", filepath);
-//             match std::fs::remove_file(filepath) {
-//                 Ok(_) => info!("Succesfully Deleted compressed file {}", filepath),
-//                 Err(e) => error!("Error deleting compressed file {}: {}", filepath, e),
-//             }
-//         },
-//         Err(e) => {
-//             error!("Error decompressing file: {} {}", e, filepath);
-//         },
-//     }
-// }
\ No newline at end of file
+        }
+    }
+}
+
+/// Opens a compressed file for reading, the codec being guessed from its extension.
+/// Use [`Codec::decoder`] for zstd files compressed with a dictionary.
+pub fn decompress_file(filepath: &Path) -> std::io::Result<Box<dyn Read + Send>> {
+    Codec::from_path(filepath).decoder(File::open(filepath)?)
+}
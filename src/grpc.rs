@@ -0,0 +1,241 @@
+//! gRPC api over what the process holds in memory: the local orderbooks, the recent trades and the live streams.
+//!
+//! The service is described in `proto/market_data.proto`, which other languages can generate their clients from.
+//! The Rust server and client are generated by `build.rs` around the messages of [`crate::proto`].
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::net::SocketAddr;
+use std::pin::Pin;
+use std::sync::{Arc, Mutex};
+
+use async_trait::async_trait;
+use futures_util::{Stream, StreamExt};
+use log::info;
+use serde::{Deserialize, Serialize};
+use tokio::{net::TcpListener, sync::broadcast};
+use tokio_stream::wrappers::{
+    errors::BroadcastStreamRecvError, BroadcastStream, TcpListenerStream,
+};
+use tonic::{Request, Response, Status};
+
+use crate::{
+    binance::{
+        constants::Symbol,
+        models::{
+            orderbook::{OrderBooksRWL, OrderbookMessage},
+            trades::Trade,
+        },
+        websocket::handlers::EventHandler,
+    },
+    proto,
+};
+
+pub mod market_data {
+    include!(concat!(env!("OUT_DIR"), "/binance.MarketData.rs"));
+}
+pub use market_data::{
+    market_data_client::MarketDataClient,
+    market_data_server::{MarketData, MarketDataServer},
+};
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct GrpcConfig {
+    pub address: SocketAddr,
+    /// Trades kept per symbol for `GetRecentTrades`.
+    pub recent_trades: usize,
+    /// Events buffered per streaming call, a client falling further behind gets a `RESOURCE_EXHAUSTED` error.
+    pub stream_buffer: usize,
+}
+impl Default for GrpcConfig {
+    fn default() -> Self {
+        Self {
+            address: SocketAddr::from(([0, 0, 0, 0], 50051)),
+            recent_trades: 1000,
+            stream_buffer: 10_000,
+        }
+    }
+}
+
+/// Serves the gRPC api, and keeps the recent trades and live streams from the events it handles.
+pub struct MarketDataService {
+    config: GrpcConfig,
+    orderbooks: OrderBooksRWL,
+    recent_trades: Mutex<HashMap<Symbol, VecDeque<proto::Trade>>>,
+    trades: broadcast::Sender<Arc<proto::Trade>>,
+    depth_updates: broadcast::Sender<Arc<proto::DepthUpdate>>,
+}
+impl MarketDataService {
+    pub fn new(config: GrpcConfig, orderbooks: OrderBooksRWL) -> Self {
+        let (trades, _) = broadcast::channel(config.stream_buffer.max(1));
+        let (depth_updates, _) = broadcast::channel(config.stream_buffer.max(1));
+        Self {
+            config,
+            orderbooks,
+            recent_trades: Mutex::new(HashMap::new()),
+            trades,
+            depth_updates,
+        }
+    }
+    pub fn config(&self) -> &GrpcConfig {
+        &self.config
+    }
+    /// Serves the api on `config.address` until the server fails.
+    pub async fn run(self: Arc<Self>) -> Result<(), tonic::transport::Error> {
+        let address = self.config.address;
+        info!("Serving gRPC on {}", address);
+        tonic::transport::Server::builder()
+            .add_service(MarketDataServer::from_arc(self))
+            .serve(address)
+            .await
+    }
+    /// Serves the api on `listener` until the server fails.
+    pub async fn serve(
+        self: Arc<Self>,
+        listener: TcpListener,
+    ) -> Result<(), tonic::transport::Error> {
+        tonic::transport::Server::builder()
+            .add_service(MarketDataServer::from_arc(self))
+            .serve_with_incoming(TcpListenerStream::new(listener))
+            .await
+    }
+}
+
+type EventStream<T> = Pin<Box<dyn Stream<Item = Result<T, Status>> + Send>>;
+
+/// The events of `receiver` for `symbols`, or for every symbol when empty.
+fn filtered<T: Clone + Send + Sync + 'static>(
+    receiver: broadcast::Receiver<Arc<T>>,
+    symbols: Vec<String>,
+    symbol: fn(&T) -> &str,
+) -> EventStream<T> {
+    let symbols = symbols
+        .into_iter()
+        .map(|symbol| symbol.to_uppercase())
+        .collect::<HashSet<_>>();
+    let stream = BroadcastStream::new(receiver).filter_map(move |event| {
+        let event = match event {
+            Ok(event) if symbols.is_empty() || symbols.contains(symbol(&event)) => {
+                Some(Ok((*event).clone()))
+            }
+            Ok(_) => None,
+            Err(BroadcastStreamRecvError::Lagged(missed)) => Some(Err(Status::resource_exhausted(
+                format!("Client fell behind, {} events missed", missed),
+            ))),
+        };
+        async move { event }
+    });
+    // The stream ends with the first error, a client that fell behind has to call again.
+    let mut failed = false;
+    Box::pin(stream.take_while(move |event| {
+        let take = !failed;
+        failed |= event.is_err();
+        async move { take }
+    }))
+}
+
+#[async_trait]
+impl MarketData for MarketDataService {
+    async fn get_order_book(
+        &self,
+        request: Request<proto::GetOrderBookRequest>,
+    ) -> Result<Response<proto::OrderBook>, Status> {
+        let request = request.into_inner();
+        let symbol = request.symbol.to_uppercase();
+        let books = self.orderbooks.read().await;
+        match books.get(&symbol).and_then(|books| books.first()) {
+            Some(book) => Ok(Response::new(proto::OrderBook::new(
+                &symbol,
+                book,
+                request.depth,
+            ))),
+            None => Err(Status::not_found(format!("No local book for {}", symbol))),
+        }
+    }
+    async fn get_book_at(
+        &self,
+        request: Request<proto::GetBookAtRequest>,
+    ) -> Result<Response<proto::OrderBook>, Status> {
+        let request = request.into_inner();
+        let symbol = request.symbol.to_uppercase();
+        let books = self.orderbooks.read().await;
+        let books = books.get(&symbol).map(Vec::as_slice).unwrap_or_default();
+        match books.iter().find(|book| {
+            book.first_update_id <= request.update_id && request.update_id <= book.last_update_id
+        }) {
+            Some(book) => Ok(Response::new(proto::OrderBook::new(
+                &symbol,
+                book,
+                request.depth,
+            ))),
+            None => Err(Status::not_found(format!(
+                "Update {} is not among the {} books kept for {}",
+                request.update_id,
+                books.len(),
+                symbol
+            ))),
+        }
+    }
+    async fn get_recent_trades(
+        &self,
+        request: Request<proto::GetRecentTradesRequest>,
+    ) -> Result<Response<proto::RecentTrades>, Status> {
+        let request = request.into_inner();
+        let recent_trades = self.recent_trades.lock().unwrap();
+        let trades = match recent_trades.get(&request.symbol.to_uppercase()) {
+            Some(trades) => {
+                let limit = match request.limit {
+                    0 => trades.len(),
+                    limit => trades.len().min(limit as usize),
+                };
+                trades.iter().skip(trades.len() - limit).cloned().collect()
+            }
+            None => Vec::new(),
+        };
+        Ok(Response::new(proto::RecentTrades { trades }))
+    }
+
+    type StreamTradesStream = EventStream<proto::Trade>;
+    async fn stream_trades(
+        &self,
+        request: Request<proto::StreamRequest>,
+    ) -> Result<Response<Self::StreamTradesStream>, Status> {
+        Ok(Response::new(filtered(
+            self.trades.subscribe(),
+            request.into_inner().symbols,
+            |trade| &trade.symbol,
+        )))
+    }
+
+    type StreamBookUpdatesStream = EventStream<proto::DepthUpdate>;
+    async fn stream_book_updates(
+        &self,
+        request: Request<proto::StreamRequest>,
+    ) -> Result<Response<Self::StreamBookUpdatesStream>, Status> {
+        Ok(Response::new(filtered(
+            self.depth_updates.subscribe(),
+            request.into_inner().symbols,
+            |update| &update.symbol,
+        )))
+    }
+}
+
+#[async_trait]
+impl EventHandler for MarketDataService {
+    async fn on_trade(&self, trade: &Trade) {
+        let trade = proto::Trade::from(trade);
+        {
+            let mut recent_trades = self.recent_trades.lock().unwrap();
+            let trades = recent_trades.entry(trade.symbol.clone()).or_default();
+            if trades.len() >= self.config.recent_trades {
+                trades.pop_front();
+            }
+            trades.push_back(trade.clone());
+        }
+        // Nobody streaming is not an error.
+        _ = self.trades.send(Arc::new(trade));
+    }
+    async fn on_depth(&self, update: &OrderbookMessage) {
+        _ = self
+            .depth_updates
+            .send(Arc::new(proto::DepthUpdate::from(update)));
+    }
+}
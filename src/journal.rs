@@ -0,0 +1,393 @@
+//! Write-ahead journal of the raw frames received on websocket connections.
+//!
+//! Frames are appended to segment files as they arrive and fsynced on a fixed cadence, so a crash only loses
+//! what was received since the last sync rather than everything since the last hourly rotation. The fsyncs run
+//! on the blocking pool, never on the connections appending frames.
+//! Segments are deleted once the outputs holding their records have been closed, see [`Journal::seal`], which
+//! requires the windows of all their records to be over: a segment sealed at a boundary can still hold records of
+//! the window that just started.
+//! On startup, whatever segments are left are replayed into the normal outputs with [`Journal::replay`], except
+//! for the records of the windows closed before the crash, which were written out already.
+use std::fs::{File, OpenOptions};
+use std::io::{BufRead, BufReader, BufWriter, Write};
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+use std::time::Duration;
+
+use async_trait::async_trait;
+use chrono::{DateTime, TimeZone, Utc};
+use log::{error, info, warn};
+use serde::{Deserialize, Serialize};
+use serde_json::value::RawValue;
+use serde_with::{serde_as, TimestampMilliSeconds};
+
+use crate::{
+    binance::websocket::{
+        connection::dispatch,
+        handlers::{EventHandler, EventHandlers, RawFrame},
+        router::{route, Frame},
+    },
+    settings::JOURNAL_FOLDER_NAME,
+};
+
+const SEGMENT_EXTENSION: &str = "wal";
+/// Holds the end of the windows closed so far, in milliseconds, see [`Journal::release`].
+const CLOSED_UNTIL_FILE: &str = "closed_until";
+
+/// Where the journal is kept and how often it is rotated and synced.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct JournalConfig {
+    pub folder: PathBuf,
+    /// A new segment is started once the current one reaches this many bytes.
+    pub segment_size: u64,
+    /// How often the current segment is fsynced, bounding what a crash can lose.
+    pub fsync_interval: Duration,
+}
+impl Default for JournalConfig {
+    fn default() -> Self {
+        Self {
+            folder: PathBuf::from(JOURNAL_FOLDER_NAME),
+            segment_size: 64 * 1024 * 1024,
+            fsync_interval: Duration::from_secs(1),
+        }
+    }
+}
+
+/// One line of a segment.
+#[serde_as]
+#[derive(Debug, Serialize, Deserialize)]
+pub struct JournalEntry {
+    #[serde_as(as = "TimestampMilliSeconds")]
+    pub received: DateTime<Utc>,
+    pub connection_id: u64,
+    pub stream: Option<String>,
+    /// The frame exactly as received.
+    pub payload: Box<RawValue>,
+}
+
+/// Segments sealed by [`Journal::seal`], to be handed back to [`Journal::release`].
+#[derive(Debug)]
+pub struct Checkpoint {
+    segments: Vec<Sealed>,
+}
+
+#[derive(Debug, Clone, Copy)]
+struct Sealed {
+    id: u64,
+    /// The latest event time of its records, which picks the window they are written to.
+    /// `None` for a segment left over by a previous run, until it is replayed.
+    latest: Option<DateTime<Utc>>,
+}
+
+struct Segment {
+    id: u64,
+    writer: BufWriter<File>,
+    size: u64,
+    latest: Option<DateTime<Utc>>,
+}
+
+struct JournalState {
+    active: Option<Segment>,
+    next_id: u64,
+    sealed: Vec<Sealed>,
+    /// Segments sealed since the last sync, written out but not fsynced yet.
+    unsynced: Vec<File>,
+    /// The end of the windows closed so far.
+    closed_until: Option<DateTime<Utc>>,
+}
+
+/// Append-only, segment-rotated log of raw frames. Register it as an [`EventHandler`] to journal a connection.
+pub struct Journal {
+    config: JournalConfig,
+    /// Segments found on disk when the journal was opened, left over by a previous run.
+    recovered: Vec<u64>,
+    state: Mutex<JournalState>,
+}
+impl Journal {
+    /// Opens the journal in `config.folder`, picking up the segments a previous run did not release.
+    pub fn open(config: JournalConfig) -> std::io::Result<Self> {
+        std::fs::create_dir_all(&config.folder)?;
+        let mut recovered = Vec::new();
+        for entry in std::fs::read_dir(&config.folder)? {
+            let path = entry?.path();
+            if path.extension().and_then(|e| e.to_str()) != Some(SEGMENT_EXTENSION) {
+                continue;
+            }
+            match path
+                .file_stem()
+                .and_then(|s| s.to_str())
+                .and_then(|s| s.parse::<u64>().ok())
+            {
+                Some(id) => recovered.push(id),
+                None => warn!("Ignoring unexpected file {} in journal", path.display()),
+            }
+        }
+        recovered.sort_unstable();
+        if !recovered.is_empty() {
+            info!("Found {} unreleased journal segments", recovered.len());
+        }
+        let next_id = recovered.last().map_or(1, |id| id + 1);
+        let closed_until = match std::fs::read_to_string(config.folder.join(CLOSED_UNTIL_FILE)) {
+            Ok(millis) => millis
+                .trim()
+                .parse()
+                .ok()
+                .and_then(|millis| Utc.timestamp_millis_opt(millis).single()),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => None,
+            Err(e) => return Err(e),
+        };
+        Ok(Self {
+            config,
+            state: Mutex::new(JournalState {
+                active: None,
+                next_id,
+                unsynced: Vec::new(),
+                closed_until,
+                sealed: recovered
+                    .iter()
+                    .map(|id| Sealed {
+                        id: *id,
+                        latest: None,
+                    })
+                    .collect(),
+            }),
+            recovered,
+        })
+    }
+    pub fn config(&self) -> &JournalConfig {
+        &self.config
+    }
+    fn segment_path(&self, id: u64) -> PathBuf {
+        self.config
+            .folder
+            .join(format!("{:012}.{}", id, SEGMENT_EXTENSION))
+    }
+    /// Appends a frame to the current segment. Responses to requests carry no data and are skipped.
+    pub fn append(&self, frame: &RawFrame<'_>) -> std::io::Result<()> {
+        let stream = match frame.stream() {
+            Some(stream) => stream,
+            None => return Ok(()),
+        };
+        let mut state = self.state.lock().unwrap();
+        if state.active.is_none() {
+            let id = state.next_id;
+            state.next_id += 1;
+            let file = OpenOptions::new()
+                .create_new(true)
+                .append(true)
+                .open(self.segment_path(id))?;
+            state.active = Some(Segment {
+                id,
+                writer: BufWriter::new(file),
+                size: 0,
+                latest: None,
+            });
+        }
+        let segment = state.active.as_mut().unwrap();
+        let time = record_time(frame);
+        segment.latest = segment.latest.max(Some(time));
+        // Line breaks in a JSON text can only be whitespace, replacing them keeps one entry per line.
+        let payload = frame.text.replace('\n', " ");
+        let line = format!(
+            "{{\"received\":{},\"connection_id\":{},\"stream\":{},\"payload\":{}}}\n",
+            frame.received.timestamp_millis(),
+            frame.connection_id,
+            serde_json::to_string(stream)?,
+            payload
+        );
+        segment.writer.write_all(line.as_bytes())?;
+        segment.size += line.len() as u64;
+        if segment.size >= self.config.segment_size {
+            Self::seal_active(&mut state)?;
+        }
+        Ok(())
+    }
+    /// Writes out the current segment and leaves it to the next sync.
+    fn seal_active(state: &mut JournalState) -> std::io::Result<()> {
+        if let Some(segment) = state.active.take() {
+            state.sealed.push(Sealed {
+                id: segment.id,
+                latest: segment.latest,
+            });
+            let file = segment.writer.into_inner().map_err(|e| e.into_error())?;
+            state.unsynced.push(file);
+        }
+        Ok(())
+    }
+    /// Writes out the current segment and returns the files to fsync, so that the lock is not held meanwhile.
+    fn unsynced(&self) -> std::io::Result<Vec<File>> {
+        let mut state = self.state.lock().unwrap();
+        let mut files = std::mem::take(&mut state.unsynced);
+        if let Some(segment) = state.active.as_mut() {
+            segment.writer.flush()?;
+            files.push(segment.writer.get_ref().try_clone()?);
+        }
+        Ok(files)
+    }
+    /// Writes out and fsyncs what was appended to the journal.
+    pub fn sync(&self) -> std::io::Result<()> {
+        for file in self.unsynced()? {
+            file.sync_data()?;
+        }
+        Ok(())
+    }
+    /// Syncs the journal every `fsync_interval`, forever.
+    pub async fn sync_periodically(&self) {
+        let mut interval = tokio::time::interval(self.config.fsync_interval);
+        loop {
+            interval.tick().await;
+            let synced = match self.unsynced() {
+                Ok(files) => tokio::task::spawn_blocking(move || {
+                    files.iter().try_for_each(|file| file.sync_data())
+                })
+                .await
+                .unwrap_or_else(|e| Err(std::io::Error::other(e))),
+                Err(e) => Err(e),
+            };
+            if let Err(e) = synced {
+                error!("Error syncing journal: {}", e);
+            }
+        }
+    }
+    /// Closes the current segment and returns every segment not released yet.
+    /// Frames are journaled after being dispatched, so once the outputs fed by those handlers have been
+    /// rotated, the checkpoint can be passed to [`Journal::release`] along with the end of the closed windows.
+    pub fn seal(&self) -> Checkpoint {
+        let mut state = self.state.lock().unwrap();
+        if let Err(e) = Self::seal_active(&mut state) {
+            error!("Error sealing journal segment: {}", e);
+        }
+        Checkpoint {
+            segments: state.sealed.clone(),
+        }
+    }
+    /// Deletes the segments of a checkpoint whose records all fall in windows ending at or before `closed_until`,
+    /// those windows having been closed. The other segments are kept, and returned again by the next checkpoint.
+    /// `closed_until` is kept on disk, for [`Journal::replay`] to skip the records of the closed windows.
+    pub fn release(&self, checkpoint: Checkpoint, closed_until: DateTime<Utc>) {
+        let mut state = self.state.lock().unwrap();
+        if state.closed_until < Some(closed_until) {
+            match self.write_closed_until(closed_until) {
+                Ok(_) => state.closed_until = Some(closed_until),
+                Err(e) => error!(
+                    "Error writing the end of the closed windows to the journal: {}",
+                    e
+                ),
+            }
+        }
+        for segment in checkpoint.segments {
+            // The latest record falls in a window ending after its time.
+            if segment.latest.map_or(true, |latest| latest >= closed_until) {
+                continue;
+            }
+            let path = self.segment_path(segment.id);
+            match std::fs::remove_file(&path) {
+                Ok(_) => state.sealed.retain(|sealed| sealed.id != segment.id),
+                Err(e) => error!("Error removing journal segment {}: {}", path.display(), e),
+            }
+        }
+    }
+    fn write_closed_until(&self, closed_until: DateTime<Utc>) -> std::io::Result<()> {
+        let path = self.config.folder.join(CLOSED_UNTIL_FILE);
+        let temporary = path.with_extension("tmp");
+        let mut file = File::create(&temporary)?;
+        file.write_all(closed_until.timestamp_millis().to_string().as_bytes())?;
+        file.sync_data()?;
+        std::fs::rename(temporary, path)
+    }
+    /// Dispatches the frames of the segments left over by a previous run to `handlers`, then hands them to `on_frame`.
+    /// Frames whose record falls in a window closed before the crash are skipped, their files are written already.
+    /// Those segments are released by the checkpoints that follow, like any other.
+    /// Returns the number of frames replayed.
+    pub async fn replay(&self, handlers: &EventHandlers) -> std::io::Result<u64> {
+        let closed_until = self.state.lock().unwrap().closed_until;
+        let mut replayed = 0;
+        for id in self.recovered.iter() {
+            let path = self.segment_path(*id);
+            info!("Replaying journal segment {}", path.display());
+            let (frames, latest) = replay_segment(&path, handlers, closed_until).await?;
+            replayed += frames;
+            let mut state = self.state.lock().unwrap();
+            if let Some(sealed) = state.sealed.iter_mut().find(|sealed| sealed.id == *id) {
+                sealed.latest = latest;
+            }
+        }
+        Ok(replayed)
+    }
+}
+
+/// The time the bus assigns the window of a frame's record by: its event time, or when it was received.
+fn record_time(frame: &RawFrame<'_>) -> DateTime<Utc> {
+    match frame.frame {
+        Some(Frame::Event { event, .. }) => event.event_time(),
+        _ => None,
+    }
+    .unwrap_or(frame.received)
+}
+
+/// Returns the frames replayed and the latest time of their records.
+async fn replay_segment(
+    path: &Path,
+    handlers: &EventHandlers,
+    closed_until: Option<DateTime<Utc>>,
+) -> std::io::Result<(u64, Option<DateTime<Utc>>)> {
+    let mut replayed = 0;
+    let mut skipped = 0;
+    let mut latest = None;
+    for line in BufReader::new(File::open(path)?).lines() {
+        let line = line?;
+        // The last line of a segment may have been cut short by a crash.
+        let entry = match serde_json::from_str::<JournalEntry>(&line) {
+            Ok(entry) => entry,
+            Err(e) => {
+                warn!(
+                    "Skipping unreadable journal entry in {}: {}",
+                    path.display(),
+                    e
+                );
+                continue;
+            }
+        };
+        let routed = route(entry.payload.get());
+        let raw = RawFrame {
+            received: entry.received,
+            connection_id: entry.connection_id,
+            frame: routed.as_ref().ok(),
+            text: entry.payload.get(),
+        };
+        let time = record_time(&raw);
+        latest = latest.max(Some(time));
+        // The window of the record ends at or before the windows closed, it was written out already.
+        if closed_until.is_some_and(|closed_until| time < closed_until) {
+            skipped += 1;
+            continue;
+        }
+        match &routed {
+            Ok(frame) => {
+                dispatch(frame, handlers).await;
+                replayed += 1;
+            }
+            Err(e) => error!("Error parsing journaled frame: {:?} {}", e, entry.payload),
+        }
+        for handler in handlers.iter() {
+            handler.on_frame(&raw).await;
+        }
+    }
+    if skipped > 0 {
+        info!(
+            "Skipped {} frames of {} in windows closed already",
+            skipped,
+            path.display()
+        );
+    }
+    Ok((replayed, latest))
+}
+
+#[async_trait]
+impl EventHandler for Journal {
+    async fn on_frame(&self, frame: &RawFrame<'_>) {
+        if let Err(e) = self.append(frame) {
+            error!("Error appending to journal: {}", e);
+        }
+    }
+}
@@ -0,0 +1,69 @@
+//! Streams market data from Binance websockets, maintains local orderbooks and persists what it receives.
+//!
+//! The crate is split into:
+//! - [`binance`]: the exchange facing side. [`binance::models`] holds the deserialized payloads
+//!   (trades, depth updates, book tickers) and the local [`binance::models::orderbook::OrderBook`] engine,
+//!   [`binance::websocket::requests::DataRequest`] builds stream urls and subscriptions, and
+//!   [`binance::websocket::connection::establish_and_persist`] keeps a connection alive and
+//!   dispatches every event to a set of [`binance::websocket::handlers::EventHandler`]s.
+//! - The sinks: [`sinks::bus::EventBus`] is a handler that queues every event per stream and sink,
+//!   with bounded buffers and a configurable overflow policy, and writer tasks stream them into
+//!   [`sinks::Sink`]s such as CSV files, compressed on the fly by [`file_compress`]. [`data_manager`]
+//!   rotates those files and hands them to [`upload_queue`], which uploads them to a [`bucket_utils`] store.
+//! - [`journal`]: a write-ahead log of the raw frames, replayed into the sinks after a crash.
+//! - [`local_store`]: an embedded SQLite database of the last few days, with a query api by symbol and time range.
+//! - [`broadcast`]: a websocket server re-broadcasting the normalized streams to internal consumers.
+//! - [`grpc`]: a gRPC api over the local books, the recent trades and the live streams.
+//! - [`admin`]: an HTTP api reporting on the connections, books and buffers, and controlling them at runtime.
+//! - [`metrics`]: Prometheus metrics of ingestion, books and persistence.
+//! - [`quality`]: gap, duplicate and ordering checks of the trades and depth diffs, live and over the files,
+//!   with a report uploaded along with each rotation.
+//! - [`disk_guard`]: enforces the retention of the local folders and sheds load when the disk is almost full.
+//!
+//! A minimal consumer only needs a request and a handler:
+//! ```no_run
+//! use std::sync::Arc;
+//! use binance_data_gatherer::binance::{
+//!     models::trades::Trade,
+//!     websocket::{
+//!         connection::establish_and_persist,
+//!         handlers::EventHandler,
+//!         requests::{BinanceAssetType, DataRequest, FuturesType, Stream},
+//!         watchdog::LivenessConfig,
+//!     },
+//! };
+//!
+//! struct Printer;
+//! #[async_trait::async_trait]
+//! impl EventHandler for Printer {
+//!     async fn on_trade(&self, trade: &Trade) {
+//!         println!("{} {} {}", trade.symbol, trade.side(), trade.price);
+//!     }
+//! }
+//!
+//! # async fn run() {
+//! let request = DataRequest::new(
+//!     BinanceAssetType::Futures(FuturesType::USDMargined),
+//!     vec![Stream::Trade("BTCUSDT".to_string())],
+//! );
+//! establish_and_persist(request, vec![Arc::new(Printer)], LivenessConfig::default()).await;
+//! # }
+//! ```
+pub mod admin;
+pub mod binance;
+pub mod broadcast;
+pub mod bucket_utils;
+pub mod data_manager;
+pub mod disk_guard;
+pub mod file_compress;
+pub mod grpc;
+pub mod journal;
+pub mod local_store;
+pub mod metrics;
+pub mod proto;
+pub mod quality;
+pub mod settings;
+pub mod sinks;
+pub mod upload_queue;
+#[cfg(test)]
+mod tests;
@@ -0,0 +1,342 @@
+//! Embedded SQLite store of the last few days of data, for querying recent data without fetching the archives.
+//!
+//! [`LocalStore`] keeps trades, book tickers and book snapshots in indexed tables, fed through the bus by
+//! [`SqliteSinkFactory`](crate::sinks::sqlite::SqliteSinkFactory), and answers queries by symbol and time range.
+//! Inserts are idempotent, so replaying a journal does not duplicate rows. Rows older than the retention are
+//! deleted by [`LocalStore::run`].
+use std::ops::Range;
+use std::path::Path;
+use std::str::FromStr;
+use std::sync::Mutex;
+use std::time::Duration;
+
+use chrono::{DateTime, TimeZone, Utc};
+use log::{error, info};
+use rusqlite::{params, Connection, Row};
+use rust_decimal::Decimal;
+use serde::{Deserialize, Serialize};
+
+use crate::binance::{
+    models::{book_ticker::BookTicker, orderbook::PriceSize, trades::Trade},
+    rest::RestOrderBook,
+    websocket::requests::BinanceAssetType,
+};
+use crate::sinks::Record;
+
+pub type StoreResult<T> = Result<T, rusqlite::Error>;
+
+const SCHEMA: &str = "
+CREATE TABLE IF NOT EXISTS trades (
+    asset_type TEXT NOT NULL,
+    symbol TEXT NOT NULL,
+    trade_id INTEGER NOT NULL,
+    event_time INTEGER NOT NULL,
+    trade_time INTEGER NOT NULL,
+    price TEXT NOT NULL,
+    quantity TEXT NOT NULL,
+    buyer_is_maker INTEGER NOT NULL,
+    order_type TEXT,
+    received INTEGER NOT NULL,
+    PRIMARY KEY (asset_type, symbol, trade_id)
+) WITHOUT ROWID;
+CREATE INDEX IF NOT EXISTS trades_by_time ON trades (asset_type, symbol, trade_time);
+CREATE INDEX IF NOT EXISTS trades_by_received ON trades (received);
+CREATE TABLE IF NOT EXISTS book_tickers (
+    asset_type TEXT NOT NULL,
+    symbol TEXT NOT NULL,
+    update_id INTEGER NOT NULL,
+    received INTEGER NOT NULL,
+    bid TEXT NOT NULL,
+    bid_size TEXT NOT NULL,
+    ask TEXT NOT NULL,
+    ask_size TEXT NOT NULL,
+    PRIMARY KEY (asset_type, symbol, update_id)
+) WITHOUT ROWID;
+CREATE INDEX IF NOT EXISTS book_tickers_by_time ON book_tickers (asset_type, symbol, received);
+CREATE INDEX IF NOT EXISTS book_tickers_by_received ON book_tickers (received);
+CREATE TABLE IF NOT EXISTS book_snapshots (
+    asset_type TEXT NOT NULL,
+    symbol TEXT NOT NULL,
+    last_update_id INTEGER NOT NULL,
+    received INTEGER NOT NULL,
+    bids TEXT NOT NULL,
+    asks TEXT NOT NULL,
+    PRIMARY KEY (asset_type, symbol, last_update_id)
+) WITHOUT ROWID;
+CREATE INDEX IF NOT EXISTS book_snapshots_by_time ON book_snapshots (asset_type, symbol, received);
+CREATE INDEX IF NOT EXISTS book_snapshots_by_received ON book_snapshots (received);
+";
+
+/// Tables pruned by [`LocalStore::prune`], by their `received` column.
+const TABLES: [&str; 3] = ["trades", "book_tickers", "book_snapshots"];
+
+/// A book ticker along with when it was received, as it carries no time of its own.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct ReceivedBookTicker {
+    pub received: DateTime<Utc>,
+    pub ticker: BookTicker,
+}
+
+/// Rows written and ignored as duplicates by [`LocalStore::insert`].
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct InsertCounts {
+    pub inserted: usize,
+    pub duplicates: usize,
+}
+
+/// A SQLite database shared by every writer of the process and by the callers of the query api.
+pub struct LocalStore {
+    connection: Mutex<Connection>,
+}
+impl LocalStore {
+    /// Opens or creates the database at `path`, creating the tables if needed.
+    pub fn open(path: &Path) -> StoreResult<Self> {
+        if let Some(folder) = path.parent() {
+            if let Err(e) = std::fs::create_dir_all(folder) {
+                error!("Error creating {}: {}", folder.display(), e);
+            }
+        }
+        Self::init(Connection::open(path)?)
+    }
+    /// A store that only lives as long as it is kept.
+    pub fn in_memory() -> StoreResult<Self> {
+        Self::init(Connection::open_in_memory()?)
+    }
+    fn init(connection: Connection) -> StoreResult<Self> {
+        // WAL lets the query api read while the writers insert.
+        connection.pragma_update(None, "journal_mode", "WAL")?;
+        connection.pragma_update(None, "synchronous", "NORMAL")?;
+        connection.busy_timeout(Duration::from_secs(5))?;
+        connection.execute_batch(SCHEMA)?;
+        Ok(Self {
+            connection: Mutex::new(connection),
+        })
+    }
+    /// Inserts the records of `asset_type` in one transaction. `received` is used for the records
+    /// that are not [`Record::Frame`]s. Depth updates are not stored.
+    pub fn insert(
+        &self,
+        asset_type: &str,
+        records: &[Record],
+        received: DateTime<Utc>,
+    ) -> StoreResult<InsertCounts> {
+        let mut connection = self.connection.lock().unwrap();
+        let transaction = connection.transaction()?;
+        let mut counts = InsertCounts::default();
+        {
+            let mut trades = transaction.prepare_cached(
+                "INSERT OR IGNORE INTO trades VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9, ?10)",
+            )?;
+            let mut tickers = transaction.prepare_cached(
+                "INSERT OR IGNORE INTO book_tickers VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8)",
+            )?;
+            let mut snapshots = transaction.prepare_cached(
+                "INSERT OR IGNORE INTO book_snapshots VALUES (?1, ?2, ?3, ?4, ?5, ?6)",
+            )?;
+            for record in records {
+                let (record, received) = match record {
+                    Record::Frame(frame) => (&*frame.record, frame.received),
+                    record => (record, received),
+                };
+                let inserted = match record {
+                    Record::Trade(trade) => trades.execute(params![
+                        asset_type,
+                        trade.symbol,
+                        trade.trade_id,
+                        trade.event_time.timestamp_millis(),
+                        trade.trade_time.timestamp_millis(),
+                        trade.price.to_string(),
+                        trade.quantity.to_string(),
+                        trade.buyer_is_the_market_maker,
+                        trade.x,
+                        received.timestamp_millis(),
+                    ])?,
+                    Record::BookTicker(ticker) => tickers.execute(params![
+                        asset_type,
+                        ticker.symbol,
+                        ticker.orderbook_update_id,
+                        received.timestamp_millis(),
+                        ticker.bid.to_string(),
+                        ticker.bid_size.to_string(),
+                        ticker.ask.to_string(),
+                        ticker.ask_size.to_string(),
+                    ])?,
+                    Record::Snapshot { symbol, book } => snapshots.execute(params![
+                        asset_type,
+                        symbol,
+                        book.last_update_id,
+                        book.received_ts.timestamp_millis(),
+                        levels_to_json(&book.bids),
+                        levels_to_json(&book.asks),
+                    ])?,
+                    Record::DepthUpdate(_) | Record::Frame(_) => continue,
+                };
+                if inserted > 0 {
+                    counts.inserted += 1;
+                } else {
+                    counts.duplicates += 1;
+                }
+            }
+        }
+        transaction.commit()?;
+        Ok(counts)
+    }
+    /// Trades of `symbol` whose trade time is in `range`, oldest first.
+    pub fn trades(
+        &self,
+        asset_type: &BinanceAssetType,
+        symbol: &str,
+        range: Range<DateTime<Utc>>,
+    ) -> StoreResult<Vec<Trade>> {
+        let connection = self.connection.lock().unwrap();
+        let mut statement = connection.prepare_cached(
+            "SELECT event_time, trade_time, symbol, trade_id, price, quantity, order_type, buyer_is_maker
+             FROM trades WHERE asset_type = ?1 AND symbol = ?2 AND trade_time >= ?3 AND trade_time < ?4
+             ORDER BY trade_time, trade_id",
+        )?;
+        let rows = statement.query_map(query_params(asset_type, symbol, &range), |row| {
+            Ok(Trade {
+                event_type: "trade".to_string(),
+                event_time: time(row, 0)?,
+                trade_time: time(row, 1)?,
+                symbol: row.get(2)?,
+                trade_id: row.get(3)?,
+                price: decimal(row, 4)?,
+                quantity: decimal(row, 5)?,
+                x: row.get(6)?,
+                buyer_is_the_market_maker: row.get(7)?,
+            })
+        })?;
+        rows.collect()
+    }
+    /// Book tickers of `symbol` received in `range`, oldest first.
+    pub fn book_tickers(
+        &self,
+        asset_type: &BinanceAssetType,
+        symbol: &str,
+        range: Range<DateTime<Utc>>,
+    ) -> StoreResult<Vec<ReceivedBookTicker>> {
+        let connection = self.connection.lock().unwrap();
+        let mut statement = connection.prepare_cached(
+            "SELECT received, update_id, symbol, bid, bid_size, ask, ask_size
+             FROM book_tickers WHERE asset_type = ?1 AND symbol = ?2 AND received >= ?3 AND received < ?4
+             ORDER BY received, update_id",
+        )?;
+        let rows = statement.query_map(query_params(asset_type, symbol, &range), |row| {
+            Ok(ReceivedBookTicker {
+                received: time(row, 0)?,
+                ticker: BookTicker {
+                    orderbook_update_id: row.get(1)?,
+                    symbol: row.get(2)?,
+                    bid: decimal(row, 3)?,
+                    bid_size: decimal(row, 4)?,
+                    ask: decimal(row, 5)?,
+                    ask_size: decimal(row, 6)?,
+                },
+            })
+        })?;
+        rows.collect()
+    }
+    /// Book snapshots of `symbol` received in `range`, oldest first.
+    pub fn snapshots(
+        &self,
+        asset_type: &BinanceAssetType,
+        symbol: &str,
+        range: Range<DateTime<Utc>>,
+    ) -> StoreResult<Vec<RestOrderBook>> {
+        let connection = self.connection.lock().unwrap();
+        let mut statement = connection.prepare_cached(
+            "SELECT last_update_id, received, bids, asks
+             FROM book_snapshots WHERE asset_type = ?1 AND symbol = ?2 AND received >= ?3 AND received < ?4
+             ORDER BY received, last_update_id",
+        )?;
+        let rows = statement.query_map(query_params(asset_type, symbol, &range), |row| {
+            Ok(RestOrderBook {
+                last_update_id: row.get(0)?,
+                received_ts: time(row, 1)?,
+                bids: levels_from_json(row, 2)?,
+                asks: levels_from_json(row, 3)?,
+            })
+        })?;
+        rows.collect()
+    }
+    /// Deletes the rows received before `before`. Returns the number of rows deleted.
+    pub fn prune(&self, before: DateTime<Utc>) -> StoreResult<usize> {
+        let connection = self.connection.lock().unwrap();
+        let mut deleted = 0;
+        for table in TABLES {
+            deleted += connection.execute(
+                &format!("DELETE FROM {} WHERE received < ?1", table),
+                params![before.timestamp_millis()],
+            )?;
+        }
+        Ok(deleted)
+    }
+    /// Deletes the rows older than `retention` every hour, forever.
+    pub async fn run(&self, retention: Duration) {
+        let retention = chrono::Duration::from_std(retention).unwrap();
+        let mut interval = tokio::time::interval(Duration::from_secs(60 * 60));
+        loop {
+            interval.tick().await;
+            match self.prune(Utc::now() - retention) {
+                Ok(0) => {}
+                Ok(deleted) => info!("Deleted {} rows from the local store", deleted),
+                Err(e) => error!("Error pruning the local store: {}", e),
+            }
+        }
+    }
+}
+
+fn query_params(
+    asset_type: &BinanceAssetType,
+    symbol: &str,
+    range: &Range<DateTime<Utc>>,
+) -> [Box<dyn rusqlite::ToSql>; 4] {
+    [
+        Box::new(asset_type.to_string()),
+        Box::new(symbol.to_string()),
+        Box::new(range.start.timestamp_millis()),
+        Box::new(range.end.timestamp_millis()),
+    ]
+}
+
+fn conversion_error(
+    index: usize,
+    e: impl std::error::Error + Send + Sync + 'static,
+) -> rusqlite::Error {
+    rusqlite::Error::FromSqlConversionFailure(index, rusqlite::types::Type::Text, Box::new(e))
+}
+
+fn time(row: &Row, index: usize) -> StoreResult<DateTime<Utc>> {
+    let millis: i64 = row.get(index)?;
+    Ok(Utc.timestamp_millis_opt(millis).unwrap())
+}
+
+fn decimal(row: &Row, index: usize) -> StoreResult<Decimal> {
+    let text: String = row.get(index)?;
+    Decimal::from_str(&text).map_err(|e| conversion_error(index, e))
+}
+
+/// Levels are kept as JSON arrays of `[price, size]` strings, like the exchange sends them.
+fn levels_to_json(levels: &[PriceSize]) -> String {
+    let levels = levels
+        .iter()
+        .map(|level| [level.price.to_string(), level.size.to_string()])
+        .collect::<Vec<_>>();
+    serde_json::to_string(&levels).unwrap()
+}
+
+fn levels_from_json(row: &Row, index: usize) -> StoreResult<Vec<PriceSize>> {
+    let text: String = row.get(index)?;
+    let levels: Vec<[String; 2]> =
+        serde_json::from_str(&text).map_err(|e| conversion_error(index, e))?;
+    levels
+        .into_iter()
+        .map(|[price, size]| {
+            Ok(PriceSize {
+                price: Decimal::from_str(&price).map_err(|e| conversion_error(index, e))?,
+                size: Decimal::from_str(&size).map_err(|e| conversion_error(index, e))?,
+            })
+        })
+        .collect()
+}
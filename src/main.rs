@@ -1,9 +1,58 @@
-use crate::binance::{
-    models::orderbook::new_orderbooks_rwl,
-    websocket::requests::{BinanceAssetType, DataRequest, FuturesType, Stream},
+use std::{
+    collections::HashMap,
+    path::{Path, PathBuf},
+    sync::Arc,
+    time::Duration,
 };
 
-mod binance;
+use binance_data_gatherer::{
+    admin::{AdminApi, AdminConfig},
+    binance::{
+        models::orderbook::new_orderbooks_rwl,
+        time_sync::{ServerClock, TimeSyncConfig},
+        websocket::{
+            connection::establish_and_persist_with_control,
+            control::ConnectionControl,
+            handlers::{
+                book_ticker::BookTickerPrinter, depth_update::OrderBookMaintainer, EventHandler,
+            },
+            recorder::{replay_file, FrameRecorder, ReplaySpeed},
+            requests::{BinanceAssetType, DataRequest, FuturesType, Stream},
+            watchdog::LivenessConfig,
+        },
+    },
+    broadcast::{BroadcastConfig, Broadcaster},
+    bucket_utils::S3Bucket,
+    data_manager::create_files,
+    disk_guard::{DiskConfig, DiskGuard, FolderPolicy},
+    file_compress::Codec,
+    grpc::{GrpcConfig, MarketDataService},
+    journal::{Journal, JournalConfig},
+    local_store::LocalStore,
+    metrics::Metrics,
+    quality::{check_file, describe, LogAlert, QualityConfig, QualityMonitor, WebhookAlert},
+    settings::{
+        ARCHIVE_FOLDER_NAME, JOURNAL_FOLDER_NAME, LOCAL_STORE_FOLDER_NAME, LOG_FOLDER_NAME,
+        OUTGOING_FOLDER_NAME, RECORDING_FOLDER_NAME, SPILL_FOLDER_NAME, UPLOAD_QUEUE_FOLDER_NAME,
+    },
+    sinks::{
+        bus::{EventBus, PipelineConfig},
+        clickhouse::{ClickHouseConfig, ClickHouseSinkFactory, ClickHouseWriter},
+        csv_file::CsvFileSinkFactory,
+        encoding::Encoding,
+        json_lines::{JsonLinesMode, JsonLinesSinkFactory},
+        kafka::{Delivery, KafkaConfig, KafkaPublisher, KafkaSinkFactory},
+        nats::{NatsConfig, NatsPublisher, NatsSinkFactory},
+        part_files::{remove_set_aside, set_aside_parts},
+        postgres::{PostgresSinkFactory, PostgresStore},
+        queue::OverflowPolicy,
+        redis_streams::{RedisStreamsConfig, RedisStreamsPublisher, RedisStreamsSinkFactory},
+        sqlite::SqliteSinkFactory,
+    },
+    upload_queue::{UploadConfig, UploadQueue},
+};
+use log::{error, info};
+use tokio::sync::RwLock;
 
 pub const ALL_SYMBOLS: [&str; 206] = [
     "RUNEUSDT",
@@ -214,9 +263,78 @@ pub const ALL_SYMBOLS: [&str; 206] = [
     "BCHUSDT",
 ];
 
+/// Feeds recorded frames through the orderbook engine, without persisting anything.
+/// Usage: `binance_data_gatherer replay <original|max|factor> <file>...`
+async fn replay(args: &[String]) {
+    let speed = match args.first().map(|speed| speed.parse::<ReplaySpeed>()) {
+        Some(Ok(speed)) => speed,
+        Some(Err(e)) => {
+            error!("{}", e);
+            return;
+        }
+        None => {
+            error!("Usage: replay <original|max|factor> <file>...");
+            return;
+        }
+    };
+    let handlers: Vec<Arc<dyn EventHandler>> = vec![
+        Arc::new(OrderBookMaintainer::new(new_orderbooks_rwl())),
+        Arc::new(BookTickerPrinter),
+    ];
+    for file in args[1..].iter() {
+        match replay_file(Path::new(file), &handlers, speed).await {
+            Ok(replayed) => info!("Replayed {} frames from {}", replayed, file),
+            Err(e) => error!("Error replaying {}: {}", file, e),
+        }
+    }
+}
+
+/// Checks written trade and depth files for gaps, duplicates and time regressions, and prints the reports.
+/// Usage: `binance_data_gatherer check <file>...`
+fn check(files: &[String]) {
+    for file in files {
+        let path = Path::new(file);
+        let Some((dataset, symbol)) = describe(path) else {
+            error!("{} is not a file of trades or depth updates", file);
+            continue;
+        };
+        match check_file(path, dataset, &symbol, None) {
+            Ok(report) => println!("{}", serde_json::to_string(&report).unwrap()),
+            Err(e) => error!("Error checking {}: {}", file, e),
+        }
+    }
+}
+
+/// The encoding named by `variable`, json|avro|protobuf, JSON when unset.
+fn encoding_from_env(variable: &str) -> Encoding {
+    match std::env::var(variable).as_deref() {
+        Ok("json") | Err(_) => Encoding::Json,
+        Ok("avro") => Encoding::Avro,
+        Ok("protobuf") => Encoding::Protobuf,
+        Ok(other) => {
+            error!("Unknown {} {}, publishing JSON", variable, other);
+            Encoding::Json
+        }
+    }
+}
+
 #[tokio::main]
 async fn main() {
     log4rs::init_file("log_config.yaml", Default::default()).unwrap();
+    let args = std::env::args().skip(1).collect::<Vec<String>>();
+    if args.first().map(String::as_str) == Some("replay") {
+        replay(&args[1..]).await;
+        return;
+    }
+    if args.first().map(String::as_str) == Some("check") {
+        check(&args[1..]);
+        return;
+    }
+    // S3_BUCKET names the bucket the outputs are uploaded to.
+    let Ok(bucket_name) = std::env::var("S3_BUCKET") else {
+        error!("S3_BUCKET is not set, there is no bucket to upload the outputs to");
+        std::process::exit(1);
+    };
     let request = DataRequest::new(
         BinanceAssetType::Futures(FuturesType::USDMargined),
         ALL_SYMBOLS
@@ -225,12 +343,318 @@ async fn main() {
             .collect(),
     );
     let orderbooks_rwl = new_orderbooks_rwl();
-    let trade_update_messages = std::sync::Arc::new(tokio::sync::RwLock::new(Vec::new()));
-    _ = tokio::join!(tokio::spawn(
-        binance::websocket::connection::establish_and_persist(
-            request.clone(),
+    let snapshot_rwl = Arc::new(RwLock::new(HashMap::new()));
+    let codec = Codec::Bzip2 { level: 9 };
+    let clock = Arc::new(ServerClock::new(TimeSyncConfig::new(&request.asset_type)));
+    let mut bus = EventBus::new(
+        request.asset_type.clone(),
+        PipelineConfig {
+            overflow: OverflowPolicy::SpillToDisk(PathBuf::from(SPILL_FOLDER_NAME)),
+            ..Default::default()
+        },
+    )
+    .with_clock(clock.clone())
+    .with_sink(Arc::new(
+        CsvFileSinkFactory::new(OUTGOING_FOLDER_NAME)
+            .with_codec(codec.clone())
+            .with_clock(clock.clone()),
+    ));
+    // JSONL_OUTPUT=raw|normalized also writes JSON Lines files.
+    let jsonl_mode = match std::env::var("JSONL_OUTPUT").as_deref() {
+        Ok("raw") => Some(JsonLinesMode::Raw),
+        Ok("normalized") => Some(JsonLinesMode::Normalized),
+        Ok(other) => {
+            error!("Unknown JSONL_OUTPUT {}, expected raw or normalized", other);
+            None
+        }
+        Err(_) => None,
+    };
+    if let Some(mode) = jsonl_mode {
+        bus = bus.with_sink(Arc::new(
+            JsonLinesSinkFactory::new(OUTGOING_FOLDER_NAME, mode)
+                .with_codec(codec)
+                .with_clock(clock.clone()),
+        ));
+    }
+    let local_store =
+        match LocalStore::open(&Path::new(LOCAL_STORE_FOLDER_NAME).join("market_data.sqlite")) {
+            Ok(store) => Some(Arc::new(store)),
+            Err(e) => {
+                error!(
+                    "Error opening the local store, recent data will not be queryable: {}",
+                    e
+                );
+                None
+            }
+        };
+    if let Some(store) = local_store.clone() {
+        bus = bus.with_sink(Arc::new(SqliteSinkFactory::new(store)));
+    }
+    // POSTGRES_URL, e.g. `host=db user=collector dbname=market_data`, also writes to postgres.
+    // The sinks connect when they first flush, and keep the records while the database is down.
+    if let Ok(url) = std::env::var("POSTGRES_URL") {
+        let store = Arc::new(PostgresStore::new(&url));
+        bus = bus.with_sink(Arc::new(PostgresSinkFactory::new(store)));
+    }
+    // KAFKA_BROKERS, e.g. `kafka:9092`, also publishes every record to Kafka.
+    // KAFKA_ENCODING=json|avro|protobuf and KAFKA_DELIVERY=at_most_once|at_least_once pick how.
+    if let Ok(brokers) = std::env::var("KAFKA_BROKERS") {
+        let mut config = KafkaConfig {
+            brokers,
+            encoding: encoding_from_env("KAFKA_ENCODING"),
+            ..Default::default()
+        };
+        match std::env::var("KAFKA_DELIVERY").as_deref() {
+            Ok("at_least_once") | Err(_) => {}
+            Ok("at_most_once") => config.delivery = Delivery::AtMostOnce,
+            Ok(other) => error!("Unknown KAFKA_DELIVERY {}, delivering at least once", other),
+        }
+        if let Ok(template) = std::env::var("KAFKA_TOPIC_TEMPLATE") {
+            config.topic_template = template;
+        }
+        match KafkaPublisher::new(config) {
+            Ok(publisher) => {
+                bus = bus.with_sink(Arc::new(KafkaSinkFactory::new(Arc::new(publisher))))
+            }
+            Err(e) => error!("Error creating Kafka producer, not publishing to it: {}", e),
+        }
+    }
+    // NATS_URL, e.g. `nats://nats:4222`, also publishes every record to JetStream, encoded as NATS_ENCODING.
+    if let Ok(url) = std::env::var("NATS_URL") {
+        match NatsPublisher::connect(NatsConfig {
+            url,
+            encoding: encoding_from_env("NATS_ENCODING"),
+            ..Default::default()
+        })
+        .await
+        {
+            Ok(publisher) => {
+                bus = bus.with_sink(Arc::new(NatsSinkFactory::new(Arc::new(publisher))))
+            }
+            Err(e) => error!("Error connecting to NATS, not publishing to it: {}", e),
+        }
+    }
+    // REDIS_URL, e.g. `redis://redis:6379`, also appends every record to Redis streams, encoded as REDIS_ENCODING.
+    if let Ok(url) = std::env::var("REDIS_URL") {
+        match RedisStreamsPublisher::connect(RedisStreamsConfig {
+            url,
+            encoding: encoding_from_env("REDIS_ENCODING"),
+            ..Default::default()
+        })
+        .await
+        {
+            Ok(publisher) => {
+                bus = bus.with_sink(Arc::new(RedisStreamsSinkFactory::new(Arc::new(publisher))))
+            }
+            Err(e) => error!("Error connecting to Redis, not publishing to it: {}", e),
+        }
+    }
+    // CLICKHOUSE_URL, e.g. `http://clickhouse:8123`, also writes depth diffs to ClickHouse.
+    let clickhouse = std::env::var("CLICKHOUSE_URL").ok().map(|url| {
+        Arc::new(ClickHouseWriter::new(ClickHouseConfig {
+            url,
+            user: std::env::var("CLICKHOUSE_USER").ok(),
+            password: std::env::var("CLICKHOUSE_PASSWORD").ok(),
+            ..Default::default()
+        }))
+    });
+    if let Some(writer) = clickhouse.clone() {
+        bus = bus.with_sink(Arc::new(ClickHouseSinkFactory::new(writer)));
+    }
+    let bus = Arc::new(bus);
+    // Files a crash left unfinished are written again from the journal.
+    let set_aside = set_aside_parts(Path::new(OUTGOING_FOLDER_NAME)).unwrap_or_else(|e| {
+        error!("Error setting aside unfinished files: {}", e);
+        Vec::new()
+    });
+    let journal = Arc::new(Journal::open(JournalConfig::default()).unwrap());
+    match journal.replay(&vec![bus.clone()]).await {
+        Ok(replayed) => {
+            info!("Replayed {} journaled frames", replayed);
+            remove_set_aside(&set_aside);
+        }
+        Err(e) => error!("Error replaying journal: {}", e),
+    }
+    // S3_ENDPOINT points to an S3-compatible store instead of AWS.
+    let bucket =
+        S3Bucket::from_env(&bucket_name, std::env::var("S3_ENDPOINT").ok().as_deref()).await;
+    let uploads = Arc::new(
+        UploadQueue::open(
+            UploadConfig {
+                prefix: std::env::var("S3_PREFIX").unwrap_or_default(),
+                archive: Some(PathBuf::from(ARCHIVE_FOLDER_NAME)),
+                ..Default::default()
+            },
+            Arc::new(bucket),
+        )
+        .unwrap(),
+    );
+    // RECORD_FRAMES, e.g. `1`, also records the raw frames, so a session can be replayed.
+    let recorder = std::env::var("RECORD_FRAMES")
+        .is_ok()
+        .then(|| Arc::new(FrameRecorder::new(RECORDING_FOLDER_NAME)));
+    const DAY: Duration = Duration::from_secs(24 * 60 * 60);
+    const GIB: u64 = 1024 * 1024 * 1024;
+    let mut disk_guard = DiskGuard::new(DiskConfig {
+        folders: vec![
+            FolderPolicy::tracked(OUTGOING_FOLDER_NAME),
+            FolderPolicy::tracked(SPILL_FOLDER_NAME),
+            FolderPolicy::tracked(JOURNAL_FOLDER_NAME),
+            FolderPolicy::tracked(UPLOAD_QUEUE_FOLDER_NAME),
+            FolderPolicy::tracked(LOCAL_STORE_FOLDER_NAME),
+            FolderPolicy::retained(ARCHIVE_FOLDER_NAME, Some(DAY), Some(20 * GIB)),
+            FolderPolicy::retained(RECORDING_FOLDER_NAME, Some(7 * DAY), Some(20 * GIB)),
+            FolderPolicy::retained(LOG_FOLDER_NAME, Some(30 * DAY), None),
+        ],
+        ..Default::default()
+    })
+    .with_shedder(bus.clone());
+    if let Some(recorder) = recorder.clone() {
+        disk_guard = disk_guard.with_shedder(recorder);
+    }
+    let disk_guard = Arc::new(disk_guard);
+    // BROADCAST_ADDRESS, e.g. `0.0.0.0:9400`, re-broadcasts the streams to internal websocket clients.
+    let broadcaster = match std::env::var("BROADCAST_ADDRESS").map(|address| address.parse()) {
+        Ok(Ok(address)) => Some(Arc::new(Broadcaster::new(
+            BroadcastConfig {
+                address,
+                ..Default::default()
+            },
+            orderbooks_rwl.clone(),
+        ))),
+        Ok(Err(e)) => {
+            error!("Invalid BROADCAST_ADDRESS, not broadcasting: {}", e);
+            None
+        }
+        Err(_) => None,
+    };
+    // GRPC_ADDRESS, e.g. `0.0.0.0:50051`, serves the books, recent trades and live streams over gRPC.
+    let grpc = match std::env::var("GRPC_ADDRESS").map(|address| address.parse()) {
+        Ok(Ok(address)) => Some(Arc::new(MarketDataService::new(
+            GrpcConfig {
+                address,
+                ..Default::default()
+            },
+            orderbooks_rwl.clone(),
+        ))),
+        Ok(Err(e)) => {
+            error!("Invalid GRPC_ADDRESS, not serving gRPC: {}", e);
+            None
+        }
+        Err(_) => None,
+    };
+    let control = Arc::new(ConnectionControl::new(request.clone()));
+    // The admin api listens on localhost, ADMIN_ADDRESS e.g. `0.0.0.0:9300` moves it.
+    let admin_config = match std::env::var("ADMIN_ADDRESS").map(|address| address.parse()) {
+        Ok(Ok(address)) => AdminConfig {
+            address,
+            ..Default::default()
+        },
+        Ok(Err(e)) => {
+            error!(
+                "Invalid ADMIN_ADDRESS, serving the admin api on localhost: {}",
+                e
+            );
+            AdminConfig::default()
+        }
+        Err(_) => AdminConfig::default(),
+    };
+    let metrics = Arc::new(
+        Metrics::new()
+            .with_bus(bus.clone())
+            .with_uploads(uploads.clone())
+            .with_orderbooks(orderbooks_rwl.clone())
+            .with_clock(clock.clone()),
+    );
+    let (upload_requests, upload_requests_rx) = tokio::sync::mpsc::channel(1);
+    let admin = Arc::new(
+        AdminApi::new(
+            admin_config,
+            control.clone(),
             orderbooks_rwl.clone(),
-            trade_update_messages.clone()
+            bus.clone(),
+            uploads.clone(),
         )
-    ),);
+        .with_metrics(metrics.clone())
+        .with_upload_requests(upload_requests),
+    );
+    // QUALITY_WEBHOOK_URL also posts the data-quality issues found there.
+    let mut quality = QualityMonitor::new(QualityConfig::default()).with_hook(Arc::new(LogAlert));
+    if let Ok(url) = std::env::var("QUALITY_WEBHOOK_URL") {
+        quality = quality.with_hook(Arc::new(WebhookAlert::new(&url)));
+    }
+    let quality = Arc::new(quality);
+    let mut handlers: Vec<Arc<dyn EventHandler>> = vec![
+        Arc::new(OrderBookMaintainer::new(orderbooks_rwl.clone())),
+        bus.clone(),
+        journal.clone(),
+        metrics,
+        quality.clone(),
+    ];
+    if let Some(recorder) = recorder {
+        handlers.push(recorder);
+    }
+    // After the books are updated, so depth subscribers get snapshots holding the diffs already broadcast.
+    if let Some(broadcaster) = broadcaster.clone() {
+        handlers.insert(1, broadcaster);
+    }
+    if let Some(grpc) = grpc.clone() {
+        handlers.push(grpc);
+    }
+    _ = tokio::join!(
+        tokio::spawn(establish_and_persist_with_control(
+            control,
+            handlers,
+            LivenessConfig::default()
+        )),
+        tokio::spawn(create_files(
+            bus.clone(),
+            journal.clone(),
+            uploads.clone(),
+            request.clone(),
+            snapshot_rwl.clone(),
+            quality.clone(),
+            upload_requests_rx
+        )),
+        tokio::spawn(async move { quality.run().await }),
+        tokio::spawn(async move { clock.run().await }),
+        tokio::spawn({
+            let journal = journal.clone();
+            async move { journal.sync_periodically().await }
+        }),
+        tokio::spawn({
+            let uploads = uploads.clone();
+            async move { uploads.run().await }
+        }),
+        tokio::spawn(async move { disk_guard.run().await }),
+        tokio::spawn(async move {
+            if let Some(store) = local_store {
+                store.run(3 * DAY).await;
+            }
+        }),
+        tokio::spawn(async move {
+            if let Some(writer) = clickhouse {
+                writer.run().await;
+            }
+        }),
+        tokio::spawn(async move {
+            if let Some(broadcaster) = broadcaster {
+                if let Err(e) = broadcaster.run().await {
+                    error!("Error starting the broadcast server: {}", e);
+                }
+            }
+        }),
+        tokio::spawn(async move {
+            if let Err(e) = admin.run().await {
+                error!("Error serving the admin api: {}", e);
+            }
+        }),
+        tokio::spawn(async move {
+            if let Some(grpc) = grpc {
+                if let Err(e) = grpc.run().await {
+                    error!("Error serving gRPC: {}", e);
+                }
+            }
+        }),
+    );
 }
@@ -0,0 +1,408 @@
+//! Prometheus metrics of ingestion, local books and persistence, served on `/metrics` by the [`crate::admin`] api.
+//!
+//! Event counters and latencies are kept by [`Metrics`] as an [`EventHandler`] of the connection. Buffers, books,
+//! files, uploads and the clock offset are read from their owners whenever the metrics are rendered.
+//! Latencies are measured in the exchange clock once its offset is estimated by a [`ServerClock`].
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+
+use async_trait::async_trait;
+use log::error;
+use prometheus::{
+    core::Collector, Encoder, Gauge, GaugeVec, HistogramOpts, HistogramVec, IntCounter,
+    IntCounterVec, IntGauge, IntGaugeVec, Opts, Registry, TextEncoder,
+};
+use serde_json::value::RawValue;
+use tokio::time::Instant;
+
+use crate::{
+    binance::{
+        models::{
+            book_ticker::BookTicker,
+            orderbook::{OrderBooksRWL, OrderbookMessage},
+            trades::Trade,
+        },
+        rest::RestOrderBook,
+        time_sync::ServerClock,
+        websocket::{
+            handlers::{depth_update::DepthConnections, EventHandler, RawFrame},
+            router::{Frame, StreamEvent},
+        },
+    },
+    file_compress::CompressionStats,
+    sinks::bus::EventBus,
+    upload_queue::UploadQueue,
+};
+
+/// Bounds of the exchange to local latency buckets, in seconds.
+const LATENCY_BUCKETS: [f64; 12] = [
+    0.001, 0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 5.0, 10.0,
+];
+
+fn registered<C: Collector + Clone + 'static>(registry: &Registry, collector: C) -> C {
+    registry.register(Box::new(collector.clone())).unwrap();
+    collector
+}
+
+/// Adds to `counter` what `total`, counted elsewhere, grew by since the last time.
+fn advance(counter: &IntCounter, total: u64) {
+    counter.inc_by(total.saturating_sub(counter.get()));
+}
+
+/// The host and port of a websocket url, the full url holds every stream name.
+fn host(endpoint: &str) -> String {
+    match url::Url::parse(endpoint) {
+        Ok(url) => format!(
+            "{}:{}",
+            url.host_str().unwrap_or_default(),
+            url.port_or_known_default().unwrap_or_default()
+        ),
+        Err(_) => endpoint.to_string(),
+    }
+}
+
+pub struct Metrics {
+    registry: Registry,
+    messages: IntCounterVec,
+    parse_errors: IntCounter,
+    connections: IntCounter,
+    reconnects: IntCounter,
+    uptime: GaugeVec,
+    latency: HistogramVec,
+    book_resyncs: IntCounterVec,
+    books: IntGauge,
+    invalid_books: IntGauge,
+    buffered: IntGaugeVec,
+    records: IntCounterVec,
+    file_bytes: IntCounterVec,
+    compression_ratio: GaugeVec,
+    uploads: IntCounterVec,
+    uploaded_bytes: IntCounter,
+    uploads_pending: IntGauge,
+    clock_offset: Gauge,
+    clock_rtt: Gauge,
+    connected_since: Mutex<HashMap<String, Instant>>,
+    /// Depth streams of each connection, whose local books are dropped when it ends.
+    depth_connections: DepthConnections,
+    bus: Option<Arc<EventBus>>,
+    upload_queue: Option<Arc<UploadQueue>>,
+    orderbooks: Option<OrderBooksRWL>,
+    clock: Option<Arc<ServerClock>>,
+}
+impl Default for Metrics {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+impl Metrics {
+    pub fn new() -> Self {
+        let registry = Registry::new();
+        let counter_vec = |name: &str, help: &str, labels: &[&str]| {
+            registered(
+                &registry,
+                IntCounterVec::new(Opts::new(name, help), labels).unwrap(),
+            )
+        };
+        let counter =
+            |name: &str, help: &str| registered(&registry, IntCounter::new(name, help).unwrap());
+        let gauge =
+            |name: &str, help: &str| registered(&registry, IntGauge::new(name, help).unwrap());
+        Self {
+            messages: counter_vec(
+                "binance_messages_total",
+                "Events received, by stream type and symbol.",
+                &["stream_type", "symbol"],
+            ),
+            parse_errors: counter(
+                "binance_parse_errors_total",
+                "Frames that could not be parsed.",
+            ),
+            connections: counter(
+                "binance_connections_total",
+                "Websocket connections established.",
+            ),
+            reconnects: counter(
+                "binance_reconnects_total",
+                "Websocket connections established after a disconnection.",
+            ),
+            uptime: registered(
+                &registry,
+                GaugeVec::new(
+                    Opts::new(
+                        "binance_connection_uptime_seconds",
+                        "Time since the live connection to a host was established.",
+                    ),
+                    &["host"],
+                )
+                .unwrap(),
+            ),
+            latency: registered(
+                &registry,
+                HistogramVec::new(
+                    HistogramOpts::new(
+                        "binance_receipt_delay_seconds",
+                        "Time between the exchange event and its receipt, in the exchange clock once estimated.",
+                    )
+                    .buckets(LATENCY_BUCKETS.to_vec()),
+                    &["stream_type"],
+                )
+                .unwrap(),
+            ),
+            book_resyncs: counter_vec(
+                "binance_book_resyncs_total",
+                "Local books dropped to be rebuilt, by symbol.",
+                &["symbol"],
+            ),
+            books: gauge("binance_books", "Symbols with a local book."),
+            invalid_books: gauge(
+                "binance_invalid_books",
+                "Local books that missed an update and are not valid.",
+            ),
+            buffered: registered(
+                &registry,
+                IntGaugeVec::new(
+                    Opts::new(
+                        "binance_buffered_records",
+                        "Records queued for a sink, waiting to be written.",
+                    ),
+                    &["sink", "stream"],
+                )
+                .unwrap(),
+            ),
+            records: counter_vec(
+                "binance_records_total",
+                "Records handed to the sinks, by outcome: written, dropped or spilled to disk.",
+                &["sink", "outcome"],
+            ),
+            file_bytes: counter_vec(
+                "binance_file_bytes_total",
+                "Bytes written to compressed files, before and after compression.",
+                &["codec", "stage"],
+            ),
+            compression_ratio: registered(
+                &registry,
+                GaugeVec::new(
+                    Opts::new(
+                        "binance_compression_ratio",
+                        "Uncompressed bytes per compressed byte.",
+                    ),
+                    &["codec"],
+                )
+                .unwrap(),
+            ),
+            uploads: counter_vec(
+                "binance_uploads_total",
+                "Upload attempts, by result.",
+                &["result"],
+            ),
+            uploaded_bytes: counter(
+                "binance_uploaded_bytes_total",
+                "Bytes of the files uploaded.",
+            ),
+            uploads_pending: gauge("binance_uploads_pending", "Files waiting to be uploaded."),
+            clock_offset: registered(
+                &registry,
+                Gauge::new(
+                    "binance_clock_offset_seconds",
+                    "Estimated offset of the exchange clock to the local one.",
+                )
+                .unwrap(),
+            ),
+            clock_rtt: registered(
+                &registry,
+                Gauge::new(
+                    "binance_clock_rtt_seconds",
+                    "Round trip of the server time request the clock offset was estimated with.",
+                )
+                .unwrap(),
+            ),
+            registry,
+            connected_since: Mutex::new(HashMap::new()),
+            depth_connections: DepthConnections::default(),
+            bus: None,
+            upload_queue: None,
+            orderbooks: None,
+            clock: None,
+        }
+    }
+    pub fn with_bus(mut self, bus: Arc<EventBus>) -> Self {
+        self.bus = Some(bus);
+        self
+    }
+    pub fn with_uploads(mut self, uploads: Arc<UploadQueue>) -> Self {
+        self.upload_queue = Some(uploads);
+        self
+    }
+    pub fn with_orderbooks(mut self, orderbooks: OrderBooksRWL) -> Self {
+        self.orderbooks = Some(orderbooks);
+        self
+    }
+    pub fn with_clock(mut self, clock: Arc<ServerClock>) -> Self {
+        self.clock = Some(clock);
+        self
+    }
+    pub fn registry(&self) -> &Registry {
+        &self.registry
+    }
+    fn received(&self, stream_type: &str, symbol: &str) {
+        self.messages
+            .with_label_values(&[stream_type, symbol])
+            .inc();
+    }
+    /// Observes the delay between the exchange time of the event in `frame` and the time it was received at,
+    /// corrected to the exchange clock, so replayed frames are measured as they were received.
+    fn observe_latency(&self, frame: &RawFrame<'_>) {
+        let event = match frame.frame {
+            Some(Frame::Event { event, .. }) => event,
+            _ => return,
+        };
+        let clock = self.clock.as_deref();
+        let (stream_type, delay) = match event {
+            StreamEvent::Trade(trade) => (
+                "trade",
+                trade.calculate_receipt_delay(frame.received, clock) as f64 / 1e3,
+            ),
+            StreamEvent::DepthUpdate(update) => {
+                let received = clock.map_or(frame.received, |clock| clock.correct(frame.received));
+                let delay = (received - update.time).num_microseconds().unwrap_or(0);
+                ("depth", delay as f64 / 1e6)
+            }
+            StreamEvent::PartialDepth { .. } | StreamEvent::BookTicker(_) => return,
+        };
+        self.latency
+            .with_label_values(&[stream_type])
+            .observe(delay);
+    }
+    /// Reads the gauges and totals kept elsewhere.
+    async fn collect(&self) {
+        for (host, since) in self.connected_since.lock().unwrap().iter() {
+            self.uptime
+                .with_label_values(&[host])
+                .set(since.elapsed().as_secs_f64());
+        }
+        if let Some(orderbooks) = &self.orderbooks {
+            let books = orderbooks.read().await;
+            self.books.set(books.len() as i64);
+            let invalid = books
+                .values()
+                .filter(|books| books.first().is_some_and(|book| !book.is_valid))
+                .count();
+            self.invalid_books.set(invalid as i64);
+        }
+        if let Some(bus) = &self.bus {
+            let mut outcomes: HashMap<(String, &str), u64> = HashMap::new();
+            for stats in bus.stats() {
+                self.buffered
+                    .with_label_values(&[&stats.sink, &stats.stream])
+                    .set(stats.queued as i64);
+                for (outcome, count) in [
+                    ("written", stats.counters.written),
+                    ("dropped", stats.counters.dropped),
+                    ("spilled", stats.counters.spilled),
+                ] {
+                    *outcomes.entry((stats.sink.clone(), outcome)).or_default() += count;
+                }
+            }
+            for ((sink, outcome), total) in outcomes {
+                advance(&self.records.with_label_values(&[&sink, outcome]), total);
+            }
+        }
+        let mut compression: HashMap<&str, CompressionStats> = HashMap::new();
+        for (_, codec, stats) in self
+            .bus
+            .as_ref()
+            .map(|bus| bus.compression_stats())
+            .unwrap_or_default()
+        {
+            let total = compression.entry(codec).or_default();
+            total.uncompressed += stats.uncompressed;
+            total.compressed += stats.compressed;
+        }
+        for (codec, stats) in compression {
+            if stats.compressed == 0 {
+                continue;
+            }
+            advance(
+                &self.file_bytes.with_label_values(&[codec, "uncompressed"]),
+                stats.uncompressed,
+            );
+            advance(
+                &self.file_bytes.with_label_values(&[codec, "compressed"]),
+                stats.compressed,
+            );
+            self.compression_ratio
+                .with_label_values(&[codec])
+                .set(stats.uncompressed as f64 / stats.compressed as f64);
+        }
+        if let Some(uploads) = &self.upload_queue {
+            let stats = uploads.stats();
+            advance(
+                &self.uploads.with_label_values(&["success"]),
+                stats.succeeded,
+            );
+            advance(&self.uploads.with_label_values(&["failure"]), stats.failed);
+            advance(&self.uploads.with_label_values(&["skipped"]), stats.skipped);
+            advance(&self.uploaded_bytes, stats.bytes);
+            self.uploads_pending.set(uploads.pending() as i64);
+        }
+        if let Some(estimate) = self.clock.as_ref().and_then(|clock| clock.estimate()) {
+            self.clock_offset.set(estimate.offset_ms / 1e3);
+            self.clock_rtt.set(estimate.rtt_ms / 1e3);
+        }
+    }
+    /// Every metric, in the Prometheus text format.
+    pub async fn render(&self) -> String {
+        self.collect().await;
+        let mut buffer = Vec::new();
+        if let Err(e) = TextEncoder::new().encode(&self.registry.gather(), &mut buffer) {
+            error!("Error encoding metrics: {}", e);
+        }
+        String::from_utf8(buffer).unwrap_or_default()
+    }
+}
+
+#[async_trait]
+impl EventHandler for Metrics {
+    async fn on_connect(&self, _connection_id: u64, endpoint: &str) {
+        if self.connections.get() > 0 {
+            self.reconnects.inc();
+        }
+        self.connections.inc();
+        self.connected_since
+            .lock()
+            .unwrap()
+            .insert(host(endpoint), Instant::now());
+    }
+    async fn on_disconnect(&self, connection_id: u64, endpoint: &str) {
+        let host = host(endpoint);
+        self.connected_since.lock().unwrap().remove(&host);
+        _ = self.uptime.remove_label_values(&[&host]);
+        for symbol in self.depth_connections.disconnected(connection_id) {
+            self.book_resyncs.with_label_values(&[&symbol]).inc();
+        }
+    }
+    async fn on_trade(&self, trade: &Trade) {
+        self.received("trade", &trade.symbol);
+    }
+    async fn on_depth(&self, update: &OrderbookMessage) {
+        self.received("depth", &update.symbol);
+    }
+    async fn on_partial_depth(&self, symbol: &str, _book: &RestOrderBook) {
+        self.received("partial_depth", symbol);
+    }
+    async fn on_book_ticker(&self, ticker: &BookTicker) {
+        self.received("book_ticker", &ticker.symbol);
+    }
+    async fn on_unrouted(&self, stream: &str, _data: &RawValue) {
+        let symbol = stream.split('@').next().unwrap_or_default().to_uppercase();
+        self.received("unrouted", &symbol);
+    }
+    async fn on_frame(&self, frame: &RawFrame<'_>) {
+        self.depth_connections.observe(frame);
+        self.observe_latency(frame);
+        // Frames are routed once before dispatch, those that could not be are parse errors.
+        if frame.frame.is_none() {
+            self.parse_errors.inc();
+        }
+    }
+}
@@ -0,0 +1,251 @@
+//! Protobuf messages of the normalized records and of the gRPC api, as described in `proto/market_data.proto`.
+//! Prices and sizes are decimal strings, so no precision is lost, and times are milliseconds since the epoch.
+//! Records published from frames carry the times the frames were received, in the local then the exchange clock.
+use prost::Message;
+
+use crate::binance::{
+    models::{
+        book_ticker::BookTicker as BookTickerModel, orderbook::OrderBook as OrderBookModel,
+        orderbook::OrderbookMessage, orderbook::PriceSize, trades::Trade as TradeModel,
+    },
+    rest::RestOrderBook,
+};
+
+#[derive(Clone, PartialEq, Message)]
+pub struct Level {
+    #[prost(string, tag = "1")]
+    pub price: String,
+    #[prost(string, tag = "2")]
+    pub size: String,
+}
+
+#[derive(Clone, PartialEq, Message)]
+pub struct Trade {
+    #[prost(string, tag = "1")]
+    pub symbol: String,
+    #[prost(int64, tag = "2")]
+    pub trade_id: i64,
+    #[prost(int64, tag = "3")]
+    pub event_time: i64,
+    #[prost(int64, tag = "4")]
+    pub trade_time: i64,
+    #[prost(string, tag = "5")]
+    pub price: String,
+    #[prost(string, tag = "6")]
+    pub quantity: String,
+    #[prost(bool, tag = "7")]
+    pub buyer_is_maker: bool,
+    #[prost(string, optional, tag = "8")]
+    pub order_type: Option<String>,
+    #[prost(int64, optional, tag = "9")]
+    pub recv_ts: Option<i64>,
+    #[prost(int64, optional, tag = "10")]
+    pub recv_ts_corrected: Option<i64>,
+}
+
+#[derive(Clone, PartialEq, Message)]
+pub struct DepthUpdate {
+    #[prost(string, tag = "1")]
+    pub symbol: String,
+    #[prost(int64, tag = "2")]
+    pub event_time: i64,
+    #[prost(int64, tag = "3")]
+    pub first_update_id: i64,
+    #[prost(int64, tag = "4")]
+    pub last_update_id: i64,
+    #[prost(int64, optional, tag = "5")]
+    pub prev_last_update_id: Option<i64>,
+    #[prost(message, repeated, tag = "6")]
+    pub bids: Vec<Level>,
+    #[prost(message, repeated, tag = "7")]
+    pub asks: Vec<Level>,
+    #[prost(int64, optional, tag = "8")]
+    pub recv_ts: Option<i64>,
+    #[prost(int64, optional, tag = "9")]
+    pub recv_ts_corrected: Option<i64>,
+}
+
+#[derive(Clone, PartialEq, Message)]
+pub struct BookTicker {
+    #[prost(string, tag = "1")]
+    pub symbol: String,
+    #[prost(int64, tag = "2")]
+    pub update_id: i64,
+    #[prost(string, tag = "3")]
+    pub bid: String,
+    #[prost(string, tag = "4")]
+    pub bid_size: String,
+    #[prost(string, tag = "5")]
+    pub ask: String,
+    #[prost(string, tag = "6")]
+    pub ask_size: String,
+    #[prost(int64, optional, tag = "7")]
+    pub recv_ts: Option<i64>,
+    #[prost(int64, optional, tag = "8")]
+    pub recv_ts_corrected: Option<i64>,
+}
+
+#[derive(Clone, PartialEq, Message)]
+pub struct BookSnapshot {
+    #[prost(string, tag = "1")]
+    pub symbol: String,
+    #[prost(int64, tag = "2")]
+    pub last_update_id: i64,
+    #[prost(int64, tag = "3")]
+    pub received: i64,
+    #[prost(message, repeated, tag = "4")]
+    pub bids: Vec<Level>,
+    #[prost(message, repeated, tag = "5")]
+    pub asks: Vec<Level>,
+    #[prost(int64, optional, tag = "6")]
+    pub recv_ts: Option<i64>,
+    #[prost(int64, optional, tag = "7")]
+    pub recv_ts_corrected: Option<i64>,
+}
+
+#[derive(Clone, PartialEq, Message)]
+pub struct OrderBook {
+    #[prost(string, tag = "1")]
+    pub symbol: String,
+    #[prost(int64, tag = "2")]
+    pub first_update_id: i64,
+    #[prost(int64, tag = "3")]
+    pub last_update_id: i64,
+    #[prost(int64, tag = "4")]
+    pub event_time: i64,
+    #[prost(bool, tag = "5")]
+    pub valid: bool,
+    #[prost(message, repeated, tag = "6")]
+    pub bids: Vec<Level>,
+    #[prost(message, repeated, tag = "7")]
+    pub asks: Vec<Level>,
+}
+
+#[derive(Clone, PartialEq, Message)]
+pub struct GetOrderBookRequest {
+    #[prost(string, tag = "1")]
+    pub symbol: String,
+    #[prost(uint32, tag = "2")]
+    pub depth: u32,
+}
+
+#[derive(Clone, PartialEq, Message)]
+pub struct GetBookAtRequest {
+    #[prost(string, tag = "1")]
+    pub symbol: String,
+    #[prost(int64, tag = "2")]
+    pub update_id: i64,
+    #[prost(uint32, tag = "3")]
+    pub depth: u32,
+}
+
+#[derive(Clone, PartialEq, Message)]
+pub struct GetRecentTradesRequest {
+    #[prost(string, tag = "1")]
+    pub symbol: String,
+    #[prost(uint32, tag = "2")]
+    pub limit: u32,
+}
+
+#[derive(Clone, PartialEq, Message)]
+pub struct RecentTrades {
+    #[prost(message, repeated, tag = "1")]
+    pub trades: Vec<Trade>,
+}
+
+#[derive(Clone, PartialEq, Message)]
+pub struct StreamRequest {
+    #[prost(string, repeated, tag = "1")]
+    pub symbols: Vec<String>,
+}
+
+fn levels(levels: &[PriceSize]) -> Vec<Level> {
+    levels
+        .iter()
+        .map(|level| Level {
+            price: level.price.to_string(),
+            size: level.size.to_string(),
+        })
+        .collect()
+}
+
+impl From<&TradeModel> for Trade {
+    fn from(trade: &TradeModel) -> Self {
+        Self {
+            symbol: trade.symbol.clone(),
+            trade_id: trade.trade_id,
+            event_time: trade.event_time.timestamp_millis(),
+            trade_time: trade.trade_time.timestamp_millis(),
+            price: trade.price.to_string(),
+            quantity: trade.quantity.to_string(),
+            buyer_is_maker: trade.buyer_is_the_market_maker,
+            order_type: trade.x.clone(),
+            recv_ts: None,
+            recv_ts_corrected: None,
+        }
+    }
+}
+
+impl From<&OrderbookMessage> for DepthUpdate {
+    fn from(update: &OrderbookMessage) -> Self {
+        Self {
+            symbol: update.symbol.clone(),
+            event_time: update.time.timestamp_millis(),
+            first_update_id: update.first_update_id,
+            last_update_id: update.last_update_id,
+            prev_last_update_id: update.prev_last_update_id,
+            bids: levels(&update.bids),
+            asks: levels(&update.asks),
+            recv_ts: None,
+            recv_ts_corrected: None,
+        }
+    }
+}
+
+impl From<&BookTickerModel> for BookTicker {
+    fn from(ticker: &BookTickerModel) -> Self {
+        Self {
+            symbol: ticker.symbol.clone(),
+            update_id: ticker.orderbook_update_id,
+            bid: ticker.bid.to_string(),
+            bid_size: ticker.bid_size.to_string(),
+            ask: ticker.ask.to_string(),
+            ask_size: ticker.ask_size.to_string(),
+            recv_ts: None,
+            recv_ts_corrected: None,
+        }
+    }
+}
+
+impl BookSnapshot {
+    pub fn new(symbol: &str, book: &RestOrderBook) -> Self {
+        Self {
+            symbol: symbol.to_string(),
+            last_update_id: book.last_update_id,
+            received: book.received_ts.timestamp_millis(),
+            bids: levels(&book.bids),
+            asks: levels(&book.asks),
+            recv_ts: None,
+            recv_ts_corrected: None,
+        }
+    }
+}
+
+impl OrderBook {
+    /// The top `depth` levels of each side of `book`, all of them when `depth` is 0.
+    pub fn new(symbol: &str, book: &OrderBookModel, depth: u32) -> Self {
+        let depth = match depth {
+            0 => usize::MAX,
+            depth => depth as usize,
+        };
+        Self {
+            symbol: symbol.to_string(),
+            first_update_id: book.first_update_id,
+            last_update_id: book.last_update_id,
+            event_time: book.time.timestamp_millis(),
+            valid: book.is_valid,
+            bids: levels(&book.bids[..book.bids.len().min(depth)]),
+            asks: levels(&book.asks[..book.asks.len().min(depth)]),
+        }
+    }
+}
@@ -0,0 +1,575 @@
+//! Data-quality checks of trades and depth diffs: trade id continuity, the `pu` / `U = u+1` chain of the depth
+//! updates, timestamp monotonicity and duplicate ids, per symbol.
+//!
+//! [`QualityChecker`] holds the checks. [`QualityMonitor`] runs them on the live streams as an [`EventHandler`],
+//! and over the files closed at each rotation, into a report uploaded along with them. Issues are also handed to
+//! [`AlertHook`]s in batches. [`check_file`] checks a file written earlier, such as an archived one.
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::{BufRead, BufReader, BufWriter};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use async_trait::async_trait;
+use chrono::{DateTime, Utc};
+use log::{error, info, warn};
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+
+use crate::{
+    binance::{
+        constants::Symbol,
+        models::{
+            orderbook::{OrderbookMessage, UpdateCSVFormat},
+            trades::Trade,
+        },
+        websocket::handlers::EventHandler,
+    },
+    file_compress::decompress_file,
+    sinks::{
+        manifest::{ManifestEntry, SIDECAR_EXTENSION},
+        Dataset, Record,
+    },
+};
+
+/// What is wrong with a record, compared to the previous one of its symbol.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(tag = "kind", rename_all = "snake_case")]
+pub enum IssueKind {
+    /// The record does not follow the `last` id: `next` is the trade id, or the `pu` of a futures update and
+    /// `U - 1` of a spot one.
+    Gap { last: i64, next: i64 },
+    /// The id was already seen last.
+    Duplicate { id: i64 },
+    /// The id is lower than the `last` one.
+    OutOfOrder { last: i64, id: i64 },
+    /// The event time is earlier than the `last` one.
+    TimeRegression {
+        last: DateTime<Utc>,
+        time: DateTime<Utc>,
+    },
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Issue {
+    pub dataset: Dataset,
+    pub symbol: Symbol,
+    pub event_time: DateTime<Utc>,
+    /// The file the record was read from, for the issues found in files.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub file: Option<String>,
+    #[serde(flatten)]
+    pub kind: IssueKind,
+}
+
+/// The last id and event time of a stream.
+#[derive(Debug, Clone, Copy)]
+struct Last {
+    id: i64,
+    time: DateTime<Utc>,
+}
+
+/// Checks every record against the previous one of the same dataset and symbol.
+#[derive(Debug, Default)]
+pub struct QualityChecker {
+    last: HashMap<(Dataset, Symbol), Last>,
+}
+impl QualityChecker {
+    pub fn new() -> Self {
+        Self::default()
+    }
+    /// Takes `id` and `time` as the last ones of the stream, for the next record to be checked against.
+    pub fn seed(&mut self, dataset: Dataset, symbol: &str, id: i64, time: DateTime<Utc>) {
+        self.last
+            .insert((dataset, symbol.to_string()), Last { id, time });
+    }
+    /// Checks trades and depth updates, other records are not checked.
+    pub fn check(&mut self, record: &Record) -> Vec<Issue> {
+        match record {
+            Record::Trade(trade) => self.check_trade(trade),
+            Record::DepthUpdate(update) => self.check_depth(update),
+            Record::Frame(frame) => self.check(&frame.record),
+            _ => Vec::new(),
+        }
+    }
+    pub fn check_trade(&mut self, trade: &Trade) -> Vec<Issue> {
+        self.advance(
+            Dataset::Trades,
+            &trade.symbol,
+            trade.event_time,
+            trade.trade_id,
+            |last| trade.trade_id - 1 == last,
+            trade.trade_id,
+        )
+    }
+    pub fn check_depth(&mut self, update: &OrderbookMessage) -> Vec<Issue> {
+        let previous = update
+            .prev_last_update_id
+            .unwrap_or(update.first_update_id - 1);
+        self.advance(
+            Dataset::BookHistory,
+            &update.symbol,
+            update.time,
+            update.last_update_id,
+            |last| previous == last,
+            previous,
+        )
+    }
+    /// Checks a record of id `id` that chains from the previous one when `follows` its last id, and keeps it as
+    /// the last one unless it is a duplicate or out of order.
+    fn advance(
+        &mut self,
+        dataset: Dataset,
+        symbol: &str,
+        time: DateTime<Utc>,
+        id: i64,
+        follows: impl Fn(i64) -> bool,
+        next: i64,
+    ) -> Vec<Issue> {
+        let issue = |kind| Issue {
+            dataset,
+            symbol: symbol.to_string(),
+            event_time: time,
+            file: None,
+            kind,
+        };
+        let last = match self.last.get_mut(&(dataset, symbol.to_string())) {
+            Some(last) => last,
+            None => {
+                self.last
+                    .insert((dataset, symbol.to_string()), Last { id, time });
+                return Vec::new();
+            }
+        };
+        let mut issues = Vec::new();
+        if time < last.time {
+            issues.push(issue(IssueKind::TimeRegression {
+                last: last.time,
+                time,
+            }));
+        }
+        if id == last.id {
+            issues.push(issue(IssueKind::Duplicate { id }));
+        } else if id < last.id {
+            issues.push(issue(IssueKind::OutOfOrder { last: last.id, id }));
+        } else {
+            if !follows(last.id) {
+                issues.push(issue(IssueKind::Gap {
+                    last: last.id,
+                    next,
+                }));
+            }
+            last.id = id;
+        }
+        last.time = last.time.max(time);
+        issues
+    }
+}
+
+/// The checks of one file.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct FileReport {
+    pub file: String,
+    pub dataset: Dataset,
+    pub symbol: Symbol,
+    /// Records read, or rows for CSV files of depth updates.
+    pub records: u64,
+    /// False for CSV files of depth updates written before they held the update ids: only their timestamps are
+    /// checked.
+    pub ids_checked: bool,
+    pub issues: Vec<Issue>,
+}
+
+/// The dataset and symbol of a file written by the sinks, from its name.
+pub fn describe(path: &Path) -> Option<(Dataset, Symbol)> {
+    let name = path.file_name()?.to_str()?;
+    [Dataset::Trades, Dataset::BookHistory]
+        .into_iter()
+        .find_map(|dataset| {
+            let (before, _) = name.split_once(&format!("_{}_", dataset))?;
+            Some((dataset, before.rsplit('_').next()?.to_string()))
+        })
+}
+
+fn invalid(e: impl std::fmt::Display) -> std::io::Error {
+    std::io::Error::new(std::io::ErrorKind::InvalidData, e.to_string())
+}
+
+/// Checks a CSV or JSON Lines file of trades or depth updates, compressed or not.
+/// Both raw and normalized JSON Lines files are read. The first record is checked against the last one of the
+/// `previous` file of the stream when given, so that gaps between files are found too.
+pub fn check_file(
+    path: &Path,
+    dataset: Dataset,
+    symbol: &str,
+    previous: Option<&ManifestEntry>,
+) -> std::io::Result<FileReport> {
+    let name = path
+        .file_name()
+        .map(|name| name.to_string_lossy().to_string())
+        .unwrap_or_default();
+    let reader = decompress_file(path)?;
+    let mut checker = QualityChecker::new();
+    if let Some((id, time)) = previous.and_then(|entry| entry.last_id.zip(entry.last_event_time)) {
+        checker.seed(dataset, symbol, id, time);
+    }
+    let mut report = FileReport {
+        file: name.clone(),
+        dataset,
+        symbol: symbol.to_string(),
+        records: 0,
+        ids_checked: true,
+        issues: Vec::new(),
+    };
+    let mut check = |report: &mut FileReport, record: Record| {
+        report.records += 1;
+        report.issues.extend(checker.check(&record));
+    };
+    if name.contains(".jsonl") {
+        for line in BufReader::new(reader).lines() {
+            let line = line?;
+            if line.is_empty() {
+                continue;
+            }
+            let mut value: Value = serde_json::from_str(&line)?;
+            // Raw lines wrap the payload of the frame.
+            if let Some(data) = value.get_mut("data") {
+                value = data.take();
+            }
+            let record = match dataset {
+                Dataset::Trades => Record::Trade(serde_json::from_value(value)?),
+                _ => Record::DepthUpdate(serde_json::from_value(value)?),
+            };
+            check(&mut report, record);
+        }
+    } else if dataset == Dataset::Trades {
+        for trade in csv::Reader::from_reader(reader).deserialize::<Trade>() {
+            check(&mut report, Record::Trade(trade.map_err(invalid)?));
+        }
+    } else {
+        // The rows of an update follow each other, it is checked at its first one.
+        let mut update = None;
+        let mut last: Option<DateTime<Utc>> = None;
+        for row in csv::Reader::from_reader(reader).deserialize::<UpdateCSVFormat>() {
+            let row = row.map_err(invalid)?;
+            let time = row.timestamp;
+            report.records += 1;
+            if let (Some(first_update_id), Some(last_update_id)) =
+                (row.first_update_id, row.last_update_id)
+            {
+                let ids = (first_update_id, last_update_id, row.prev_last_update_id);
+                if update != Some(ids) {
+                    update = Some(ids);
+                    report.issues.extend(checker.check_depth(&OrderbookMessage {
+                        time,
+                        symbol: symbol.to_string(),
+                        first_update_id,
+                        last_update_id,
+                        prev_last_update_id: row.prev_last_update_id,
+                        ..Default::default()
+                    }));
+                }
+                continue;
+            }
+            report.ids_checked = false;
+            match last {
+                Some(last) if time < last => report.issues.push(Issue {
+                    dataset,
+                    symbol: symbol.to_string(),
+                    event_time: time,
+                    file: None,
+                    kind: IssueKind::TimeRegression { last, time },
+                }),
+                _ => last = Some(time),
+            }
+        }
+    }
+    for issue in report.issues.iter_mut() {
+        issue.file = Some(name.clone());
+    }
+    Ok(report)
+}
+
+/// The quality report of a rotation, uploaded along with its files.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct QualityReport {
+    pub name: String,
+    pub generated_at: DateTime<Utc>,
+    /// The files closed by the rotation.
+    pub files: Vec<FileReport>,
+    /// Files that could not be read, with the error.
+    pub unreadable: Vec<(String, String)>,
+    /// Found in the live streams since the previous report.
+    pub live: Vec<Issue>,
+    /// Live issues left out of `live`, past [`QualityConfig::max_issues`].
+    pub live_dropped: u64,
+}
+impl QualityReport {
+    /// Every issue found, in the files and live.
+    pub fn issues(&self) -> u64 {
+        let in_files: usize = self.files.iter().map(|file| file.issues.len()).sum();
+        (in_files + self.live.len()) as u64 + self.live_dropped
+    }
+}
+
+/// Told about the issues found, in batches.
+#[async_trait]
+pub trait AlertHook: Send + Sync {
+    async fn alert(&self, issues: &[Issue]);
+}
+
+/// Logs a summary of the issues.
+pub struct LogAlert;
+#[async_trait]
+impl AlertHook for LogAlert {
+    async fn alert(&self, issues: &[Issue]) {
+        let mut symbols = issues
+            .iter()
+            .map(|issue| issue.symbol.as_str())
+            .collect::<Vec<_>>();
+        symbols.sort();
+        symbols.dedup();
+        warn!(
+            "{} data-quality issues in {}, first: {:?}",
+            issues.len(),
+            symbols.join(","),
+            issues.first()
+        );
+    }
+}
+
+/// Posts the issues as `{"issues": [...]}` to a url.
+pub struct WebhookAlert {
+    url: String,
+    client: reqwest::Client,
+}
+impl WebhookAlert {
+    pub fn new(url: &str) -> Self {
+        Self {
+            url: url.to_string(),
+            client: reqwest::Client::new(),
+        }
+    }
+}
+#[async_trait]
+impl AlertHook for WebhookAlert {
+    async fn alert(&self, issues: &[Issue]) {
+        let response = self
+            .client
+            .post(&self.url)
+            .json(&json!({ "issues": issues }))
+            .send()
+            .await;
+        match response.and_then(|response| response.error_for_status()) {
+            Ok(_) => {}
+            Err(e) => error!("Error posting data-quality alert to {}: {}", self.url, e),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct QualityConfig {
+    /// How often the issues found since the last time are handed to the hooks.
+    pub alert_interval: Duration,
+    /// Live issues kept for the next report, and waiting for the hooks, the others are only counted.
+    pub max_issues: usize,
+}
+impl Default for QualityConfig {
+    fn default() -> Self {
+        Self {
+            alert_interval: Duration::from_secs(10),
+            max_issues: 10_000,
+        }
+    }
+}
+
+/// Issues held until they are taken, up to a limit.
+#[derive(Debug, Default)]
+struct Held {
+    issues: Vec<Issue>,
+    dropped: u64,
+}
+impl Held {
+    fn extend(&mut self, issues: Vec<Issue>, max: usize) {
+        for issue in issues {
+            match self.issues.len() < max {
+                true => self.issues.push(issue),
+                false => self.dropped += 1,
+            }
+        }
+    }
+}
+
+/// Checks the live streams and the files of each rotation, and alerts the hooks of the issues found.
+pub struct QualityMonitor {
+    config: QualityConfig,
+    checker: Mutex<QualityChecker>,
+    live: Mutex<Held>,
+    alerts: Mutex<Held>,
+    hooks: Vec<Arc<dyn AlertHook>>,
+    /// The last file checked of each stream, the next one is checked from.
+    last_files: Mutex<LastFiles>,
+}
+impl QualityMonitor {
+    pub fn new(config: QualityConfig) -> Self {
+        Self {
+            config,
+            checker: Mutex::new(QualityChecker::new()),
+            live: Mutex::new(Held::default()),
+            alerts: Mutex::new(Held::default()),
+            hooks: Vec::new(),
+            last_files: Mutex::new(LastFiles::new()),
+        }
+    }
+    pub fn with_hook(mut self, hook: Arc<dyn AlertHook>) -> Self {
+        self.hooks.push(hook);
+        self
+    }
+    fn found(&self, issues: Vec<Issue>) {
+        if issues.is_empty() {
+            return;
+        }
+        self.live
+            .lock()
+            .unwrap()
+            .extend(issues.clone(), self.config.max_issues);
+        self.alerts
+            .lock()
+            .unwrap()
+            .extend(issues, self.config.max_issues);
+    }
+    /// Hands the issues found since the last time to the hooks. Returns how many there were.
+    pub async fn alert(&self) -> u64 {
+        let held = std::mem::take(&mut *self.alerts.lock().unwrap());
+        if held.dropped > 0 {
+            warn!("{} more data-quality issues were not alerted", held.dropped);
+        }
+        if !held.issues.is_empty() {
+            for hook in self.hooks.iter() {
+                hook.alert(&held.issues).await;
+            }
+        }
+        held.issues.len() as u64 + held.dropped
+    }
+    /// Alerts the hooks every `config.alert_interval`.
+    pub async fn run(&self) {
+        let mut interval = tokio::time::interval(self.config.alert_interval);
+        loop {
+            interval.tick().await;
+            self.alert().await;
+        }
+    }
+    /// Checks the files of `folder` that still have a sidecar manifest, those closed since the manifest was
+    /// last merged, and writes them with the live issues found since the previous report to
+    /// `{folder}/{name}.json`. Returns the path of the report, or `None` if there were no files.
+    pub async fn write_report(
+        &self,
+        folder: &Path,
+        name: &str,
+    ) -> std::io::Result<Option<PathBuf>> {
+        let folder_path = folder.to_path_buf();
+        let mut last_files = self.last_files.lock().unwrap().clone();
+        let (checked, last_files) = tokio::task::spawn_blocking(move || {
+            (
+                check_closed_files(&folder_path, &mut last_files),
+                last_files,
+            )
+        })
+        .await
+        .map_err(std::io::Error::other)?;
+        let (files, unreadable) = checked?;
+        *self.last_files.lock().unwrap() = last_files;
+        if files.is_empty() && unreadable.is_empty() {
+            return Ok(None);
+        }
+        let live = std::mem::take(&mut *self.live.lock().unwrap());
+        let report = QualityReport {
+            name: name.to_string(),
+            generated_at: Utc::now(),
+            files,
+            unreadable,
+            live: live.issues,
+            live_dropped: live.dropped,
+        };
+        let path = folder.join(format!("{}.json", name));
+        serde_json::to_writer_pretty(BufWriter::new(File::create(&path)?), &report)?;
+        self.alerts.lock().unwrap().extend(
+            report
+                .files
+                .iter()
+                .flat_map(|file| file.issues.iter().cloned())
+                .collect(),
+            self.config.max_issues,
+        );
+        info!(
+            "Succesfully Created quality report {} of {} files, {} issues",
+            path.display(),
+            report.files.len(),
+            report.issues()
+        );
+        Ok(Some(path))
+    }
+}
+
+type CheckedFiles = (Vec<FileReport>, Vec<(String, String)>);
+type LastFiles = HashMap<(Dataset, Symbol), ManifestEntry>;
+
+/// Checks the files in the order they were written, each from the last one of its stream in `last_files`.
+fn check_closed_files(folder: &Path, last_files: &mut LastFiles) -> std::io::Result<CheckedFiles> {
+    let mut entries = Vec::new();
+    let mut files = Vec::new();
+    let mut unreadable = Vec::new();
+    for file in std::fs::read_dir(folder)? {
+        let path = file?.path();
+        if !path.to_string_lossy().ends_with(SIDECAR_EXTENSION) {
+            continue;
+        }
+        let entry = match serde_json::from_reader::<_, ManifestEntry>(File::open(&path)?) {
+            Ok(entry) => entry,
+            Err(e) => {
+                error!("Error reading manifest {}: {}", path.display(), e);
+                continue;
+            }
+        };
+        if matches!(entry.dataset, Dataset::Trades | Dataset::BookHistory) {
+            entries.push(entry);
+        }
+    }
+    // The parts of a window after it, `_2` before `_10`.
+    entries.sort_by(|a, b| {
+        (a.window_start, a.file.len(), &a.file).cmp(&(b.window_start, b.file.len(), &b.file))
+    });
+    for entry in entries {
+        let key = (entry.dataset, entry.symbol.clone());
+        // A file checked again is not checked from itself.
+        let previous = last_files.get(&key).filter(|last| last.file != entry.file);
+        match check_file(
+            &folder.join(&entry.file),
+            entry.dataset,
+            &entry.symbol,
+            previous,
+        ) {
+            Ok(report) => files.push(report),
+            Err(e) => {
+                error!("Error checking {}: {}", entry.file, e);
+                unreadable.push((entry.file.clone(), e.to_string()));
+            }
+        }
+        last_files.insert(key, entry);
+    }
+    files.sort_by(|a, b| a.file.cmp(&b.file));
+    Ok((files, unreadable))
+}
+
+#[async_trait]
+impl EventHandler for QualityMonitor {
+    async fn on_trade(&self, trade: &Trade) {
+        let issues = self.checker.lock().unwrap().check_trade(trade);
+        self.found(issues);
+    }
+    async fn on_depth(&self, update: &OrderbookMessage) {
+        let issues = self.checker.lock().unwrap().check_depth(update);
+        self.found(issues);
+    }
+}
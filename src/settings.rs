@@ -1 +1,22 @@
-pub const OUTGOING_FOLDER_NAME:&str = "outgoing";
\ No newline at end of file
+/// Folder where files are written before they are compressed and uploaded.
+pub const OUTGOING_FOLDER_NAME: &str = "outgoing";
+/// Folder where queues spill records that do not fit in memory.
+pub const SPILL_FOLDER_NAME: &str = "spill";
+
+/// Folder holding the journal of raw frames not yet flushed to the outputs.
+pub const JOURNAL_FOLDER_NAME: &str = "journal";
+
+/// Folder where raw frames are recorded for replay.
+pub const RECORDING_FOLDER_NAME: &str = "recordings";
+
+/// Folder holding the uploads not done yet.
+pub const UPLOAD_QUEUE_FOLDER_NAME: &str = "uploads";
+
+/// Folder holding the uploaded files kept locally until their retention expires.
+pub const ARCHIVE_FOLDER_NAME: &str = "uploaded";
+
+/// Folder of the log files, see `log_config.yaml`.
+pub const LOG_FOLDER_NAME: &str = "logs";
+
+/// Folder of the embedded database of recent data.
+pub const LOCAL_STORE_FOLDER_NAME: &str = "local_store";
@@ -0,0 +1,472 @@
+use std::collections::{BTreeMap, HashMap};
+use std::sync::{
+    atomic::{AtomicBool, Ordering},
+    Arc, Mutex,
+};
+use std::time::Duration;
+
+use async_trait::async_trait;
+use chrono::{DateTime, Utc};
+use log::{error, info, warn};
+use serde::{Deserialize, Serialize};
+use tokio::sync::{mpsc, oneshot};
+use tokio::task::JoinHandle;
+
+use crate::{
+    binance::{
+        models::{book_ticker::BookTicker, orderbook::OrderbookMessage, trades::Trade},
+        rest::RestOrderBook,
+        time_sync::ServerClock,
+        websocket::{
+            handlers::{EventHandler, RawFrame},
+            requests::BinanceAssetType,
+            router::Frame,
+        },
+    },
+    disk_guard::LoadShedder,
+    file_compress::CompressionStats,
+};
+
+use super::{
+    queue::{BoundedQueue, OverflowPolicy, QueueCounters, QueueStats},
+    rotation::{RotationInterval, Window},
+    Dataset, FrameRecord, Record, Sink, SinkFactory, StreamKey,
+};
+
+/// Sizing and overflow behaviour of the per-stream queues.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct PipelineConfig {
+    /// Records held in memory per stream and sink before the overflow policy applies.
+    pub capacity: usize,
+    pub overflow: OverflowPolicy,
+    /// How often writer tasks flush their sinks and close the windows that are over.
+    pub flush_interval: Duration,
+    pub rotation: RotationInterval,
+    /// How long a window stays open after its end, for records that arrive late.
+    pub grace: Duration,
+    /// Datasets dropped while the bus is degraded, e.g. when the disk is almost full.
+    #[serde(default)]
+    pub low_priority: Vec<Dataset>,
+}
+impl Default for PipelineConfig {
+    fn default() -> Self {
+        Self {
+            capacity: 10_000,
+            overflow: OverflowPolicy::Block,
+            flush_interval: Duration::from_secs(1),
+            rotation: RotationInterval::Hour,
+            grace: Duration::from_secs(10),
+            low_priority: vec![Dataset::BookTicker],
+        }
+    }
+}
+
+enum Control {
+    /// Write out what is queued, then close the windows ending at or before the given time.
+    Rotate(DateTime<Utc>, oneshot::Sender<()>),
+    /// Write out what is queued and flush the open sinks.
+    Flush(oneshot::Sender<()>),
+}
+
+struct Writer {
+    sink_name: String,
+    queue: Arc<BoundedQueue<Record>>,
+    control: mpsc::UnboundedSender<Control>,
+    task: Mutex<Option<JoinHandle<()>>>,
+}
+
+/// Counters of one stream of one sink.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct StreamStats {
+    pub sink: String,
+    pub stream: String,
+    pub queued: u64,
+    pub counters: QueueStats,
+}
+
+/// Fans the events of a connection out to every sink, through one bounded queue and writer task per stream and sink.
+/// Register it as an [`EventHandler`] of the connection.
+pub struct EventBus {
+    asset_type: BinanceAssetType,
+    config: PipelineConfig,
+    factories: Vec<Arc<dyn SinkFactory>>,
+    writers: Mutex<HashMap<(usize, StreamKey), Arc<Writer>>>,
+    degraded: AtomicBool,
+    clock: Option<Arc<ServerClock>>,
+}
+impl EventBus {
+    pub fn new(asset_type: BinanceAssetType, config: PipelineConfig) -> Self {
+        Self {
+            asset_type,
+            config,
+            factories: Vec::new(),
+            writers: Mutex::new(HashMap::new()),
+            degraded: AtomicBool::new(false),
+            clock: None,
+        }
+    }
+    pub fn with_sink(mut self, factory: Arc<dyn SinkFactory>) -> Self {
+        self.factories.push(factory);
+        self
+    }
+    /// Frames are stamped with their receive time in the exchange clock, as estimated by `clock`.
+    pub fn with_clock(mut self, clock: Arc<ServerClock>) -> Self {
+        self.clock = Some(clock);
+        self
+    }
+    pub fn asset_type(&self) -> &BinanceAssetType {
+        &self.asset_type
+    }
+    pub fn config(&self) -> &PipelineConfig {
+        &self.config
+    }
+    /// Queues a record for every sink, applying the overflow policy of each stream.
+    /// [`Record::Frame`]s go to the sinks that consume frames, every other record to the rest, and to the sinks
+    /// consuming frames that [consume records](SinkFactory::consumes_records) too, as it comes in no frame.
+    /// While degraded, records of low priority datasets are counted as dropped instead.
+    pub async fn publish(&self, record: Record) {
+        self.queue(record, false).await;
+    }
+    /// [`Self::publish`], for a record also published in a [`Record::Frame`] when `in_frame`, as the events of
+    /// the handler are.
+    async fn queue(&self, record: Record, in_frame: bool) {
+        let key = StreamKey::new(&self.asset_type, &record);
+        let shed = self.degraded.load(Ordering::Relaxed)
+            && self.config.low_priority.contains(&key.dataset);
+        let is_frame = matches!(record, Record::Frame(_));
+        let indices = (0..self.factories.len())
+            .filter(|index| {
+                let factory = &self.factories[*index];
+                let consumed = match (is_frame, factory.consumes_frames()) {
+                    (true, consumes_frames) => consumes_frames,
+                    (false, false) => true,
+                    (false, true) => !in_frame && factory.consumes_records(),
+                };
+                consumed && factory.accepts(key.dataset)
+            })
+            .collect::<Vec<_>>();
+        for (position, index) in indices.iter().enumerate() {
+            let writer = self.writer(*index, &key);
+            if shed {
+                let counters = writer.queue.counters();
+                counters.received.fetch_add(1, Ordering::Relaxed);
+                counters.dropped.fetch_add(1, Ordering::Relaxed);
+                continue;
+            }
+            if position + 1 == indices.len() {
+                writer.queue.push(record).await;
+                return;
+            }
+            writer.queue.push(record.clone()).await;
+        }
+    }
+    fn consumes_frames(&self) -> bool {
+        self.factories
+            .iter()
+            .any(|factory| factory.consumes_frames())
+    }
+    fn writer(&self, index: usize, key: &StreamKey) -> Arc<Writer> {
+        let mut writers = self.writers.lock().unwrap();
+        if let Some(writer) = writers.get(&(index, key.clone())) {
+            return writer.clone();
+        }
+        let factory = self.factories[index].clone();
+        let sink_name = factory.name();
+        let queue = Arc::new(BoundedQueue::new(
+            &format!("{}_{}", sink_name, key),
+            self.config.capacity,
+            self.config.overflow.clone(),
+        ));
+        let (control, control_rx) = mpsc::unbounded_channel();
+        let task = tokio::spawn(write_stream(
+            factory,
+            key.clone(),
+            queue.clone(),
+            control_rx,
+            self.config.clone(),
+        ));
+        info!("Started {} writer for {}", sink_name, key);
+        let writer = Arc::new(Writer {
+            sink_name,
+            queue,
+            control,
+            task: Mutex::new(Some(task)),
+        });
+        writers.insert((index, key.clone()), writer.clone());
+        writer
+    }
+    fn all_writers(&self) -> Vec<(StreamKey, Arc<Writer>)> {
+        self.writers
+            .lock()
+            .unwrap()
+            .iter()
+            .map(|((_, key), writer)| (key.clone(), writer.clone()))
+            .collect()
+    }
+    /// Closes the windows of every stream ending at or before `until`, once the records queued so far
+    /// have been written. Everything published before the call that falls in those windows is in a closed
+    /// sink when it returns. Writers also close windows on their own once they are over by `grace`.
+    pub async fn rotate(&self, until: DateTime<Utc>) {
+        let mut pending = Vec::new();
+        for (key, writer) in self.all_writers() {
+            let (done, rotated) = oneshot::channel();
+            match writer.control.send(Control::Rotate(until, done)) {
+                Ok(_) => pending.push(rotated),
+                Err(_) => error!(
+                    "{} writer for {} is gone, cannot rotate",
+                    writer.sink_name, key
+                ),
+            }
+        }
+        futures::future::join_all(pending).await;
+    }
+    /// Writes out the records queued so far and flushes every open sink, without waiting for the flush interval.
+    pub async fn flush(&self) {
+        let mut pending = Vec::new();
+        for (key, writer) in self.all_writers() {
+            let (done, flushed) = oneshot::channel();
+            match writer.control.send(Control::Flush(done)) {
+                Ok(_) => pending.push(flushed),
+                Err(_) => error!(
+                    "{} writer for {} is gone, cannot flush",
+                    writer.sink_name, key
+                ),
+            }
+        }
+        futures::future::join_all(pending).await;
+    }
+    pub fn stats(&self) -> Vec<StreamStats> {
+        self.all_writers()
+            .into_iter()
+            .map(|(key, writer)| StreamStats {
+                sink: writer.sink_name.clone(),
+                stream: key.to_string(),
+                queued: writer.queue.len(),
+                counters: writer.queue.counters().snapshot(),
+            })
+            .collect()
+    }
+    /// The codec and compression totals of the sinks writing compressed files, by sink name.
+    pub fn compression_stats(&self) -> Vec<(String, &'static str, CompressionStats)> {
+        self.factories
+            .iter()
+            .filter_map(|factory| {
+                let (codec, stats) = factory.compression()?;
+                Some((factory.name(), codec, stats))
+            })
+            .collect()
+    }
+    /// Stops accepting records, waits for every writer to drain its queue and closes the sinks.
+    pub async fn shutdown(&self) {
+        let writers = self
+            .writers
+            .lock()
+            .unwrap()
+            .drain()
+            .map(|(_, writer)| writer)
+            .collect::<Vec<_>>();
+        for writer in writers.iter() {
+            writer.queue.close();
+        }
+        for writer in writers {
+            let task = writer.task.lock().unwrap().take();
+            if let Some(task) = task {
+                if let Err(e) = task.await {
+                    error!("{} writer task failed: {}", writer.sink_name, e);
+                }
+            }
+        }
+    }
+}
+
+impl LoadShedder for EventBus {
+    fn set_degraded(&self, degraded: bool) {
+        if degraded {
+            warn!(
+                "Dropping {:?} records until resumed",
+                self.config.low_priority
+            );
+        }
+        self.degraded.store(degraded, Ordering::Relaxed);
+    }
+}
+
+/// Sinks of the windows of a stream that are still open, and how many sinks were closed per window.
+struct OpenWindows {
+    sinks: BTreeMap<DateTime<Utc>, (Window, Box<dyn Sink>)>,
+    closed: HashMap<DateTime<Utc>, u32>,
+}
+impl OpenWindows {
+    async fn flush(&mut self, factory: &dyn SinkFactory, key: &StreamKey) {
+        for (window, sink) in self.sinks.values_mut() {
+            if let Err(e) = sink.flush().await {
+                error!(
+                    "Error flushing {} sink for {} {}: {}",
+                    factory.name(),
+                    key,
+                    window,
+                    e
+                );
+            }
+        }
+    }
+    /// Closes the windows ending at or before `until`.
+    async fn close_until(&mut self, until: DateTime<Utc>, key: &StreamKey) {
+        while let Some(entry) = self.sinks.first_entry() {
+            if entry.get().0.end > until {
+                break;
+            }
+            let (window, mut sink) = entry.remove();
+            if let Err(e) = sink.close().await {
+                error!("Error closing sink for {} {}: {}", key, window, e);
+            }
+            *self.closed.entry(window.start).or_default() += 1;
+        }
+        // Only recent windows can still receive late records, including when closed ahead of time.
+        let recent = until.min(Utc::now()) - chrono::Duration::days(1);
+        self.closed.retain(|start, _| *start > recent);
+    }
+}
+
+/// Drains the queue of one stream into sinks created on demand by `factory`, one per window.
+async fn write_stream(
+    factory: Arc<dyn SinkFactory>,
+    key: StreamKey,
+    queue: Arc<BoundedQueue<Record>>,
+    mut control: mpsc::UnboundedReceiver<Control>,
+    config: PipelineConfig,
+) {
+    let counters: Arc<QueueCounters> = queue.counters();
+    let grace = chrono::Duration::from_std(config.grace).unwrap();
+    let mut windows = OpenWindows {
+        sinks: BTreeMap::new(),
+        closed: HashMap::new(),
+    };
+    let mut flush = tokio::time::interval(config.flush_interval);
+    loop {
+        tokio::select! {
+            biased;
+            Some(control) = control.recv() => {
+                for _ in 0..queue.len() {
+                    match queue.try_pop() {
+                        Some(record) => write_record(&mut windows, &*factory, &key, &counters, config.rotation, &record).await,
+                        None => break,
+                    }
+                }
+                match control {
+                    Control::Rotate(until, done) => {
+                        windows.close_until(until, &key).await;
+                        _ = done.send(());
+                    }
+                    Control::Flush(done) => {
+                        windows.flush(&*factory, &key).await;
+                        _ = done.send(());
+                    }
+                }
+            }
+            _ = flush.tick() => {
+                windows.flush(&*factory, &key).await;
+                windows.close_until(Utc::now() - grace, &key).await;
+            }
+            record = queue.pop() => {
+                match record {
+                    Some(record) => write_record(&mut windows, &*factory, &key, &counters, config.rotation, &record).await,
+                    None => break,
+                }
+            }
+        }
+    }
+    windows.close_until(DateTime::<Utc>::MAX_UTC, &key).await;
+}
+
+/// Writes a record to the sink of its window, creating it first if needed.
+/// Records without an event time go to the window of the current time.
+async fn write_record(
+    windows: &mut OpenWindows,
+    factory: &dyn SinkFactory,
+    key: &StreamKey,
+    counters: &QueueCounters,
+    rotation: RotationInterval,
+    record: &Record,
+) {
+    let mut window = rotation.window(record.event_time().unwrap_or_else(Utc::now));
+    if !windows.sinks.contains_key(&window.start) {
+        window.part = windows.closed.get(&window.start).copied().unwrap_or(0);
+        match factory.create(key, &window) {
+            Ok(created) => {
+                windows.sinks.insert(window.start, (window, created));
+            }
+            Err(e) => {
+                error!(
+                    "Error creating {} sink for {} {}, dropping record: {}",
+                    factory.name(),
+                    key,
+                    window,
+                    e
+                );
+                counters.dropped.fetch_add(1, Ordering::Relaxed);
+                return;
+            }
+        }
+    }
+    let (_, sink) = windows.sinks.get_mut(&window.start).unwrap();
+    match sink.write(record).await {
+        Ok(_) => {
+            counters.written.fetch_add(1, Ordering::Relaxed);
+        }
+        Err(e) => {
+            error!(
+                "Error writing record of {} to {}: {}",
+                key,
+                factory.name(),
+                e
+            );
+            counters.dropped.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+}
+
+#[async_trait]
+impl EventHandler for EventBus {
+    async fn on_trade(&self, trade: &Trade) {
+        self.queue(Record::Trade(trade.clone()), true).await;
+    }
+    async fn on_depth(&self, update: &OrderbookMessage) {
+        self.queue(Record::DepthUpdate(update.clone()), true).await;
+    }
+    async fn on_partial_depth(&self, symbol: &str, book: &RestOrderBook) {
+        let snapshot = Record::Snapshot {
+            symbol: symbol.to_string(),
+            book: book.clone(),
+        };
+        self.queue(snapshot, true).await;
+    }
+    async fn on_book_ticker(&self, ticker: &BookTicker) {
+        self.queue(Record::BookTicker(ticker.clone()), true).await;
+    }
+    async fn on_frame(&self, frame: &RawFrame<'_>) {
+        if !self.consumes_frames() {
+            return;
+        }
+        // Parse errors were already reported when the frame was dispatched.
+        if let Some(Frame::Event {
+            stream,
+            event,
+            data,
+        }) = frame.frame
+        {
+            self.publish(Record::Frame(FrameRecord {
+                stream: stream.to_string(),
+                received: frame.received,
+                corrected: self
+                    .clock
+                    .as_ref()
+                    .and_then(|clock| clock.estimate())
+                    .map(|estimate| frame.received + estimate.offset()),
+                payload: (*data).to_owned(),
+                record: Box::new(Record::from_event(event.clone())),
+            }))
+            .await;
+        }
+    }
+}
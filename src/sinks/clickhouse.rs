@@ -0,0 +1,444 @@
+//! ClickHouse sink for depth diffs, one row per price level change.
+//!
+//! Depth streams of every symbol push their rows to a single [`ClickHouseWriter`], which inserts them over the
+//! HTTP interface in large batches, as ClickHouse prefers few big inserts to many small ones. Failed inserts are
+//! retried with a backoff, and kept for the next flush after that. While the buffer is full the sinks wait, so the
+//! overflow policy of the bus applies to the records behind them. The table is set up on the first flush, and again
+//! with a backoff while ClickHouse is unreachable, so the writer can be created while it is down. The table is a `ReplacingMergeTree` with insert deduplication, so retried batches and
+//! replayed frames do not end up as duplicate rows.
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use async_trait::async_trait;
+use chrono::{DateTime, Utc};
+use log::{error, info, warn};
+use rust_decimal::Decimal;
+use serde::{Deserialize, Serialize};
+use tokio::{sync::Notify, time::Instant};
+
+use crate::binance::models::orderbook::{OrderbookMessage, PriceSize};
+
+use super::{rotation::Window, Dataset, Record, Sink, SinkError, SinkFactory, StreamKey};
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ClickHouseConfig {
+    /// Of the HTTP interface, e.g. `http://localhost:8123`.
+    pub url: String,
+    pub database: String,
+    pub table: String,
+    pub user: Option<String>,
+    pub password: Option<String>,
+    /// Rows that trigger an insert without waiting for `flush_interval`.
+    pub batch_rows: usize,
+    pub flush_interval: Duration,
+    /// Rows held while ClickHouse is unreachable, before the sinks wait for room.
+    pub max_buffered_rows: usize,
+    /// Attempts of an insert in a flush, its rows are kept for the next flush after the last one.
+    pub max_attempts: u32,
+    pub initial_backoff: Duration,
+    pub max_backoff: Duration,
+}
+impl Default for ClickHouseConfig {
+    fn default() -> Self {
+        Self {
+            url: "http://localhost:8123".to_string(),
+            database: "default".to_string(),
+            table: "depth_levels".to_string(),
+            user: None,
+            password: None,
+            batch_rows: 200_000,
+            flush_interval: Duration::from_secs(5),
+            max_buffered_rows: 5_000_000,
+            max_attempts: 6,
+            initial_backoff: Duration::from_millis(500),
+            max_backoff: Duration::from_secs(30),
+        }
+    }
+}
+
+/// A changed level of a depth update.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct LevelRow {
+    pub asset_type: String,
+    pub symbol: String,
+    #[serde(with = "clickhouse_time")]
+    pub event_time: DateTime<Utc>,
+    pub first_update_id: i64,
+    pub last_update_id: i64,
+    pub prev_last_update_id: Option<i64>,
+    /// `BID` or `ASK`.
+    pub side: String,
+    #[serde(with = "rust_decimal::serde::str")]
+    pub price: Decimal,
+    /// Zero when the level was removed.
+    #[serde(with = "rust_decimal::serde::str")]
+    pub quantity: Decimal,
+    #[serde(with = "clickhouse_time")]
+    pub received: DateTime<Utc>,
+    /// `received` in the exchange clock, when its offset is estimated.
+    #[serde(default, with = "clickhouse_time::optional")]
+    pub received_corrected: Option<DateTime<Utc>>,
+}
+impl LevelRow {
+    /// One row per changed level, bids first.
+    pub fn from_update(
+        asset_type: &str,
+        update: &OrderbookMessage,
+        received: DateTime<Utc>,
+        received_corrected: Option<DateTime<Utc>>,
+    ) -> Vec<Self> {
+        let rows = |side: &str, levels: &[PriceSize]| {
+            levels
+                .iter()
+                .map(|level| Self {
+                    asset_type: asset_type.to_string(),
+                    symbol: update.symbol.clone(),
+                    event_time: update.time,
+                    first_update_id: update.first_update_id,
+                    last_update_id: update.last_update_id,
+                    prev_last_update_id: update.prev_last_update_id,
+                    side: side.to_string(),
+                    price: level.price,
+                    quantity: level.size,
+                    received,
+                    received_corrected,
+                })
+                .collect::<Vec<_>>()
+        };
+        let mut all_rows = rows("BID", &update.bids);
+        all_rows.extend(rows("ASK", &update.asks));
+        all_rows
+    }
+}
+
+/// `DateTime64(3)` as read by ClickHouse from JSON.
+mod clickhouse_time {
+    use chrono::{DateTime, NaiveDateTime, TimeZone, Utc};
+    use serde::{Deserialize, Deserializer, Serializer};
+
+    const FORMAT: &str = "%Y-%m-%d %H:%M:%S%.3f";
+
+    pub fn serialize<S: Serializer>(
+        time: &DateTime<Utc>,
+        serializer: S,
+    ) -> Result<S::Ok, S::Error> {
+        serializer.collect_str(&time.format(FORMAT))
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(
+        deserializer: D,
+    ) -> Result<DateTime<Utc>, D::Error> {
+        let text = String::deserialize(deserializer)?;
+        NaiveDateTime::parse_from_str(&text, FORMAT)
+            .map(|time| Utc.from_utc_datetime(&time))
+            .map_err(serde::de::Error::custom)
+    }
+
+    /// `Nullable(DateTime64(3))`.
+    pub mod optional {
+        use chrono::{DateTime, Utc};
+        use serde::{Deserialize, Deserializer, Serializer};
+
+        pub fn serialize<S: Serializer>(
+            time: &Option<DateTime<Utc>>,
+            serializer: S,
+        ) -> Result<S::Ok, S::Error> {
+            match time {
+                Some(time) => super::serialize(time, serializer),
+                None => serializer.serialize_none(),
+            }
+        }
+
+        pub fn deserialize<'de, D: Deserializer<'de>>(
+            deserializer: D,
+        ) -> Result<Option<DateTime<Utc>>, D::Error> {
+            #[derive(Deserialize)]
+            struct Time(#[serde(with = "super")] DateTime<Utc>);
+            Ok(Option::<Time>::deserialize(deserializer)?.map(|Time(time)| time))
+        }
+    }
+}
+
+/// Batches the rows of every depth stream and inserts them into ClickHouse.
+pub struct ClickHouseWriter {
+    config: ClickHouseConfig,
+    client: reqwest::Client,
+    buffer: Mutex<Vec<LevelRow>>,
+    /// Woken when the buffer holds a full batch.
+    batch_ready: Notify,
+    /// Woken when rows were inserted, making room in the buffer.
+    room: Notify,
+    /// Only one insert runs at a time, so batches are inserted in order.
+    inserting: tokio::sync::Mutex<()>,
+    setup: Mutex<Setup>,
+}
+/// Whether the table of a [`ClickHouseWriter`] is set up, and when to try again after a failure.
+struct Setup {
+    done: bool,
+    retry_at: Option<Instant>,
+    backoff: Duration,
+}
+impl ClickHouseWriter {
+    /// A writer that sets up the table on the first flush, so that it can be created while ClickHouse is down.
+    pub fn new(config: ClickHouseConfig) -> Self {
+        let backoff = config.initial_backoff;
+        Self {
+            config,
+            client: reqwest::Client::new(),
+            buffer: Mutex::new(Vec::new()),
+            batch_ready: Notify::new(),
+            room: Notify::new(),
+            inserting: tokio::sync::Mutex::new(()),
+            setup: Mutex::new(Setup {
+                done: false,
+                retry_at: None,
+                backoff,
+            }),
+        }
+    }
+    /// Sets up the table now.
+    pub async fn connect(config: ClickHouseConfig) -> Result<Self, SinkError> {
+        let writer = Self::new(config);
+        writer.set_up().await?;
+        writer.setup.lock().unwrap().done = true;
+        Ok(writer)
+    }
+    /// Creates the table if it does not exist yet, and adds the columns a table created earlier lacks.
+    async fn set_up(&self) -> Result<(), SinkError> {
+        self.execute(&self.create_table_query(), Vec::new()).await?;
+        let add_column = format!(
+            "ALTER TABLE {}.{} ADD COLUMN IF NOT EXISTS received_corrected Nullable(DateTime64(3, 'UTC'))",
+            self.config.database, self.config.table
+        );
+        self.execute(&add_column, Vec::new()).await
+    }
+    /// Whether the table is set up, setting it up first unless the last attempt failed too recently.
+    async fn is_set_up(&self) -> bool {
+        {
+            let setup = self.setup.lock().unwrap();
+            if setup.done {
+                return true;
+            }
+            if setup
+                .retry_at
+                .is_some_and(|retry_at| retry_at > Instant::now())
+            {
+                return false;
+            }
+        }
+        let result = self.set_up().await;
+        let mut setup = self.setup.lock().unwrap();
+        match result {
+            Ok(()) => {
+                info!("Connected to ClickHouse");
+                setup.done = true;
+            }
+            Err(e) => {
+                warn!(
+                    "Error setting up the ClickHouse table, retrying in {:?}: {}",
+                    setup.backoff, e
+                );
+                setup.retry_at = Some(Instant::now() + setup.backoff);
+                setup.backoff = (setup.backoff * 2).min(self.config.max_backoff);
+            }
+        }
+        setup.done
+    }
+    pub fn config(&self) -> &ClickHouseConfig {
+        &self.config
+    }
+    fn create_table_query(&self) -> String {
+        format!(
+            "CREATE TABLE IF NOT EXISTS {}.{} (
+                asset_type LowCardinality(String),
+                symbol LowCardinality(String),
+                event_time DateTime64(3, 'UTC'),
+                first_update_id Int64,
+                last_update_id Int64,
+                prev_last_update_id Nullable(Int64),
+                side Enum8('BID' = 1, 'ASK' = 2),
+                price Decimal(38, 18),
+                quantity Decimal(38, 18),
+                received DateTime64(3, 'UTC'),
+                received_corrected Nullable(DateTime64(3, 'UTC'))
+            )
+            ENGINE = ReplacingMergeTree
+            PARTITION BY toYYYYMMDD(event_time)
+            ORDER BY (asset_type, symbol, event_time, last_update_id, side, price)
+            SETTINGS non_replicated_deduplication_window = 1000",
+            self.config.database, self.config.table
+        )
+    }
+    /// Runs `query` with `body` appended, as the HTTP interface expects for inserts.
+    async fn execute(&self, query: &str, body: Vec<u8>) -> Result<(), SinkError> {
+        let mut request = self
+            .client
+            .post(&self.config.url)
+            .query(&[("query", query)])
+            .body(body);
+        if let Some(user) = &self.config.user {
+            request = request.basic_auth(user, self.config.password.as_ref());
+        }
+        let response = request.send().await?;
+        let status = response.status();
+        if !status.is_success() {
+            let message = response.text().await.unwrap_or_default();
+            return Err(format!("ClickHouse returned {}: {}", status, message.trim()).into());
+        }
+        Ok(())
+    }
+    /// Buffers `rows` for the next insert, waiting for room when too many rows are waiting already.
+    pub async fn push(&self, rows: Vec<LevelRow>) {
+        loop {
+            let room = self.room.notified();
+            tokio::pin!(room);
+            room.as_mut().enable();
+            {
+                let mut buffer = self.buffer.lock().unwrap();
+                if buffer.is_empty() || buffer.len() + rows.len() <= self.config.max_buffered_rows {
+                    buffer.extend(rows);
+                    if buffer.len() >= self.config.batch_rows {
+                        self.batch_ready.notify_one();
+                    }
+                    return;
+                }
+            }
+            self.batch_ready.notify_one();
+            room.await;
+        }
+    }
+    pub fn buffered(&self) -> usize {
+        self.buffer.lock().unwrap().len()
+    }
+    /// Inserts the buffered rows, in batches of `batch_rows`, retrying each one with a backoff.
+    /// When a batch still fails after `max_attempts`, it and the following ones go back to the buffer, in order.
+    /// Nothing is inserted until the table is set up. Returns the number of rows inserted.
+    pub async fn flush(&self) -> usize {
+        let _inserting = self.inserting.lock().await;
+        if !self.is_set_up().await {
+            return 0;
+        }
+        let mut rows = std::mem::take(&mut *self.buffer.lock().unwrap());
+        let mut inserted = 0;
+        while inserted < rows.len() {
+            let end = rows.len().min(inserted + self.config.batch_rows.max(1));
+            if !self.insert(&rows[inserted..end]).await {
+                let mut buffer = self.buffer.lock().unwrap();
+                let newer = std::mem::replace(&mut *buffer, rows.split_off(inserted));
+                buffer.extend(newer);
+                break;
+            }
+            inserted = end;
+        }
+        if inserted > 0 {
+            self.room.notify_waiters();
+        }
+        inserted
+    }
+    async fn insert(&self, rows: &[LevelRow]) -> bool {
+        let mut body = Vec::new();
+        for row in rows {
+            serde_json::to_writer(&mut body, row).unwrap();
+            body.push(b'\n');
+        }
+        let query = format!(
+            "INSERT INTO {}.{} FORMAT JSONEachRow",
+            self.config.database, self.config.table
+        );
+        let mut backoff = self.config.initial_backoff;
+        for attempt in 1..=self.config.max_attempts {
+            match self.execute(&query, body.clone()).await {
+                Ok(_) => return true,
+                Err(e) if attempt < self.config.max_attempts => {
+                    warn!(
+                        "Error inserting {} rows into ClickHouse, attempt {}, retrying in {:?}: {}",
+                        rows.len(),
+                        attempt,
+                        backoff,
+                        e
+                    );
+                    tokio::time::sleep(backoff).await;
+                    backoff = (backoff * 2).min(self.config.max_backoff);
+                }
+                Err(e) => error!(
+                    "Error inserting {} rows into ClickHouse, keeping them for the next flush: {}",
+                    rows.len(),
+                    e
+                ),
+            }
+        }
+        false
+    }
+    /// Inserts every `flush_interval`, or as soon as a batch is full, forever.
+    pub async fn run(&self) {
+        let mut interval = tokio::time::interval(self.config.flush_interval);
+        loop {
+            tokio::select! {
+                _ = interval.tick() => {}
+                _ = self.batch_ready.notified() => {}
+            }
+            let inserted = self.flush().await;
+            if inserted > 0 {
+                info!("Inserted {} depth rows into ClickHouse", inserted);
+            }
+        }
+    }
+}
+
+/// Sends depth updates to a [`ClickHouseWriter`], other datasets are not sent to it.
+/// It consumes frames, so every row carries the time it was received, in the exchange clock too when known.
+pub struct ClickHouseSinkFactory {
+    pub writer: Arc<ClickHouseWriter>,
+}
+impl ClickHouseSinkFactory {
+    pub fn new(writer: Arc<ClickHouseWriter>) -> Self {
+        Self { writer }
+    }
+}
+impl SinkFactory for ClickHouseSinkFactory {
+    fn name(&self) -> String {
+        "clickhouse".to_string()
+    }
+    fn consumes_frames(&self) -> bool {
+        true
+    }
+    fn accepts(&self, dataset: Dataset) -> bool {
+        dataset == Dataset::BookHistory
+    }
+    fn create(&self, key: &StreamKey, _window: &Window) -> Result<Box<dyn Sink>, SinkError> {
+        Ok(Box::new(ClickHouseSink {
+            writer: self.writer.clone(),
+            asset_type: key.asset_type.clone(),
+        }))
+    }
+}
+
+pub struct ClickHouseSink {
+    writer: Arc<ClickHouseWriter>,
+    asset_type: String,
+}
+
+#[async_trait]
+impl Sink for ClickHouseSink {
+    async fn write(&mut self, record: &Record) -> Result<(), SinkError> {
+        let (record, received, corrected) = record.received();
+        match record {
+            Record::DepthUpdate(update) => {
+                self.writer
+                    .push(LevelRow::from_update(
+                        &self.asset_type,
+                        update,
+                        received.unwrap_or_else(Utc::now),
+                        corrected,
+                    ))
+                    .await;
+                Ok(())
+            }
+            _ => Err(format!(
+                "ClickHouse only takes depth updates, not {:?}",
+                record.dataset()
+            )
+            .into()),
+        }
+    }
+}
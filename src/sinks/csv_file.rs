@@ -0,0 +1,177 @@
+use std::fs::{File, OpenOptions};
+use std::io::{BufWriter, Write};
+use std::path::PathBuf;
+use std::sync::Arc;
+
+use async_trait::async_trait;
+use log::info;
+use serde::Serialize;
+
+use crate::{
+    binance::time_sync::ServerClock,
+    file_compress::{Codec, CompressionCounters, CompressionStats, Encoder},
+};
+
+use super::{
+    manifest::ManifestEntry,
+    part_files::{free_part, PART_EXTENSION},
+    rotation::Window,
+    Record, Sink, SinkError, SinkFactory, StreamKey,
+};
+
+/// Writes each window of each stream to its own CSV file in `folder`, e.g. `USDM_FUT_BTCUSDT_TRADES_20230212T150000Z.csv`.
+/// Files are compressed while being written with `codec`, whose extension is appended, e.g. `.csv.zst`.
+/// They are suffixed with `.part` while being written and renamed when closed,
+/// along with a `.manifest.json` sidecar describing their contents.
+/// A window with files already in `folder` gets a new part, e.g. `_1`, so none is overwritten.
+/// It consumes frames, so every row ends with the receive time columns of [`ReceiveTimes`].
+pub struct CsvFileSinkFactory {
+    pub folder: PathBuf,
+    pub codec: Codec,
+    /// Bytes that went through the encoders of the files written.
+    pub compression: Arc<CompressionCounters>,
+    /// Its estimate is recorded in the manifests of the files when they are closed.
+    pub clock: Option<Arc<ServerClock>>,
+}
+impl CsvFileSinkFactory {
+    pub fn new(folder: impl Into<PathBuf>) -> Self {
+        Self {
+            folder: folder.into(),
+            codec: Codec::None,
+            compression: Arc::default(),
+            clock: None,
+        }
+    }
+    pub fn with_codec(mut self, codec: Codec) -> Self {
+        self.codec = codec;
+        self
+    }
+    pub fn with_clock(mut self, clock: Arc<ServerClock>) -> Self {
+        self.clock = Some(clock);
+        self
+    }
+}
+impl SinkFactory for CsvFileSinkFactory {
+    fn name(&self) -> String {
+        "csv".to_string()
+    }
+    fn consumes_frames(&self) -> bool {
+        true
+    }
+    fn consumes_records(&self) -> bool {
+        true
+    }
+    fn create(&self, key: &StreamKey, window: &Window) -> Result<Box<dyn Sink>, SinkError> {
+        std::fs::create_dir_all(&self.folder)?;
+        let name = |window: &Window| format!("{}_{}.csv{}", key, window, self.codec.extension());
+        let window = &free_part(&self.folder, window, name);
+        let file_name = name(window);
+        let path = self.folder.join(&file_name);
+        let part_path = self
+            .folder
+            .join(format!("{}.{}", file_name, PART_EXTENSION));
+        let file = OpenOptions::new()
+            .write(true)
+            .create_new(true)
+            .open(&part_path)?;
+        let encoder = self
+            .codec
+            .counted_encoder(BufWriter::new(file), self.compression.clone())?;
+        Ok(Box::new(CsvFileSink {
+            path,
+            part_path,
+            writer: Some(csv::Writer::from_writer(encoder)),
+            manifest: ManifestEntry::new(&file_name, key, window),
+            clock: self.clock.clone(),
+        }))
+    }
+    fn compression(&self) -> Option<(&'static str, CompressionStats)> {
+        Some((self.codec.name(), self.compression.stats()))
+    }
+}
+
+/// Columns appended to every row: when the frame was received in milliseconds, in the local clock then in the
+/// exchange clock, empty when unknown.
+#[derive(Debug, Clone, Copy, Serialize)]
+pub struct ReceiveTimes {
+    pub recv_ts: Option<i64>,
+    pub recv_ts_corrected: Option<i64>,
+}
+
+pub struct CsvFileSink {
+    path: PathBuf,
+    part_path: PathBuf,
+    /// Taken when the sink is closed, to finish the compressed stream.
+    writer: Option<csv::Writer<Encoder<BufWriter<File>>>>,
+    manifest: ManifestEntry,
+    clock: Option<Arc<ServerClock>>,
+}
+impl CsvFileSink {
+    fn writer(&mut self) -> Result<&mut csv::Writer<Encoder<BufWriter<File>>>, SinkError> {
+        let path = &self.path;
+        self.writer
+            .as_mut()
+            .ok_or_else(|| format!("{} is already closed", path.display()).into())
+    }
+}
+
+#[async_trait]
+impl Sink for CsvFileSink {
+    async fn write(&mut self, record: &Record) -> Result<(), SinkError> {
+        let (record, received, corrected) = record.received();
+        let times = ReceiveTimes {
+            recv_ts: received.map(|time| time.timestamp_millis()),
+            recv_ts_corrected: corrected.map(|time| time.timestamp_millis()),
+        };
+        let writer = self.writer()?;
+        let rows = match record {
+            Record::Trade(trade) => {
+                writer.serialize((trade, times))?;
+                1
+            }
+            Record::BookTicker(ticker) => {
+                writer.serialize((ticker, times))?;
+                1
+            }
+            Record::DepthUpdate(update) => {
+                let rows = update.to_csv_format();
+                for row in rows.iter() {
+                    writer.serialize((row, times))?;
+                }
+                rows.len() as u64
+            }
+            Record::Snapshot { book, .. } => {
+                let rows = book.to_csv_format();
+                for row in rows.iter() {
+                    writer.serialize((row, times))?;
+                }
+                rows.len() as u64
+            }
+            // Only the record parsed from a frame is written, a frame never holds another one.
+            Record::Frame(_) => return Ok(()),
+        };
+        self.manifest.add(record, rows);
+        Ok(())
+    }
+    /// Hands the buffered rows to the encoder, the compressed stream is only finished on close.
+    async fn flush(&mut self) -> Result<(), SinkError> {
+        self.writer()?.flush()?;
+        Ok(())
+    }
+    async fn close(&mut self) -> Result<(), SinkError> {
+        let writer = self.writer()?;
+        writer.flush()?;
+        let encoder = self
+            .writer
+            .take()
+            .unwrap()
+            .into_inner()
+            .map_err(|e| e.into_error())?;
+        encoder.finish()?.flush()?;
+        self.manifest.clock = self.clock.as_ref().and_then(|clock| clock.estimate());
+        self.manifest.write_sidecar(&self.path)?;
+        std::fs::rename(&self.part_path, &self.path)?;
+        info!("Succesfully Created file {}", self.path.display());
+        Ok(())
+    }
+}
@@ -0,0 +1,299 @@
+//! Serialization of normalized records for the message brokers, and the names they are published under.
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+
+use crate::proto;
+
+use super::{Dataset, Record, SinkError, StreamKey};
+
+/// How records are serialized in messages.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum Encoding {
+    /// The normalized model, as in the JSON Lines files.
+    Json,
+    /// Avro single object encoding: `C3 01`, the fingerprint of the schema, then the binary encoded record.
+    /// See [`avro::schema`] for the schemas.
+    Avro,
+    /// The messages of `proto/market_data.proto`.
+    Protobuf,
+}
+impl Encoding {
+    pub fn content_type(&self) -> &'static str {
+        match self {
+            Encoding::Json => "application/json",
+            Encoding::Avro => "avro/binary",
+            Encoding::Protobuf => "application/x-protobuf",
+        }
+    }
+    /// The payload of a message for `record`. Frames are encoded as the record parsed from them, along with the
+    /// times they were received.
+    pub fn encode(&self, record: &Record) -> Result<Vec<u8>, SinkError> {
+        Ok(match self {
+            Encoding::Json => serde_json::to_vec(&normalized_json(record, None)?)?,
+            Encoding::Avro => avro::encode(record)?,
+            Encoding::Protobuf => {
+                use prost::Message;
+                let (record, received, corrected) = record.received();
+                let (recv_ts, recv_ts_corrected) = (millis(received), millis(corrected));
+                match record {
+                    Record::Trade(trade) => proto::Trade {
+                        recv_ts,
+                        recv_ts_corrected,
+                        ..trade.into()
+                    }
+                    .encode_to_vec(),
+                    Record::DepthUpdate(update) => proto::DepthUpdate {
+                        recv_ts,
+                        recv_ts_corrected,
+                        ..update.into()
+                    }
+                    .encode_to_vec(),
+                    Record::BookTicker(ticker) => proto::BookTicker {
+                        recv_ts,
+                        recv_ts_corrected,
+                        ..ticker.into()
+                    }
+                    .encode_to_vec(),
+                    Record::Snapshot { symbol, book } => proto::BookSnapshot {
+                        recv_ts,
+                        recv_ts_corrected,
+                        ..proto::BookSnapshot::new(symbol, book)
+                    }
+                    .encode_to_vec(),
+                    Record::Frame(_) => return Err(NESTED_FRAME.into()),
+                }
+            }
+        })
+    }
+}
+
+/// The error of a frame holding another frame, which the bus never builds.
+const NESTED_FRAME: &str = "A frame holds another frame";
+
+fn millis(time: Option<DateTime<Utc>>) -> Option<i64> {
+    time.map(|time| time.timestamp_millis())
+}
+
+/// The normalized model of a record, with `recv_ts` added when known, and `recv_ts_corrected` for frames
+/// stamped in the exchange clock.
+pub fn normalized_json(record: &Record, recv_ts: Option<i64>) -> Result<Value, SinkError> {
+    let mut value = match record {
+        Record::Trade(trade) => serde_json::to_value(trade)?,
+        Record::DepthUpdate(update) => serde_json::to_value(update)?,
+        Record::BookTicker(ticker) => serde_json::to_value(ticker)?,
+        Record::Snapshot { symbol, book } => {
+            let mut value = serde_json::to_value(book)?;
+            value["symbol"] = json!(symbol);
+            value
+        }
+        Record::Frame(frame) => {
+            let mut value =
+                normalized_json(&frame.record, Some(frame.received.timestamp_millis()))?;
+            if let (Some(corrected), Value::Object(fields)) = (frame.corrected, &mut value) {
+                fields.insert(
+                    "recv_ts_corrected".to_string(),
+                    json!(corrected.timestamp_millis()),
+                );
+            }
+            return Ok(value);
+        }
+    };
+    if let (Some(recv_ts), Value::Object(fields)) = (recv_ts, &mut value) {
+        fields.insert("recv_ts".to_string(), json!(recv_ts));
+    }
+    Ok(value)
+}
+
+/// The name of the stream of a dataset in topics and subjects.
+pub fn stream_name(dataset: Dataset) -> &'static str {
+    match dataset {
+        Dataset::Trades => "trade",
+        Dataset::BookHistory => "depth",
+        Dataset::BookSnapshot => "book_snapshot",
+        Dataset::BookTicker => "book_ticker",
+    }
+}
+
+/// Fills `{asset_type}`, `{stream}` and `{symbol}` in `template` for the stream `key`,
+/// e.g. `binance.{asset_type}.{stream}.{symbol}` gives `binance.usdm_fut.trade.BTCUSDT`.
+pub fn render_template(template: &str, key: &StreamKey) -> String {
+    template
+        .replace("{asset_type}", &key.asset_type.to_lowercase())
+        .replace("{stream}", stream_name(key.dataset))
+        .replace("{symbol}", &key.symbol)
+}
+
+/// Just enough of Avro to write the records, without a schema registry.
+pub mod avro {
+    use crate::proto;
+    use crate::sinks::{Dataset, Record, SinkError};
+
+    use super::{millis, NESTED_FRAME};
+
+    macro_rules! level_schema {
+        () => {
+            r#"{"name":"binance.Level","type":"record","fields":[{"name":"price","type":"string"},{"name":"size","type":"string"}]}"#
+        };
+    }
+
+    /// The last fields of every schema: when the frame was received, in the local then the exchange clock.
+    macro_rules! receive_times_schema {
+        () => {
+            r#"{"name":"recv_ts","type":["null","long"]},{"name":"recv_ts_corrected","type":["null","long"]}"#
+        };
+    }
+
+    /// Schemas in parsing canonical form, which the fingerprints are computed from.
+    pub fn schema(dataset: Dataset) -> &'static str {
+        match dataset {
+            Dataset::Trades => concat!(
+                r#"{"name":"binance.Trade","type":"record","fields":["#,
+                r#"{"name":"symbol","type":"string"},{"name":"trade_id","type":"long"},"#,
+                r#"{"name":"event_time","type":"long"},{"name":"trade_time","type":"long"},"#,
+                r#"{"name":"price","type":"string"},{"name":"quantity","type":"string"},"#,
+                r#"{"name":"buyer_is_maker","type":"boolean"},{"name":"order_type","type":["null","string"]},"#,
+                receive_times_schema!(),
+                "]}"
+            ),
+            Dataset::BookHistory => concat!(
+                r#"{"name":"binance.DepthUpdate","type":"record","fields":["#,
+                r#"{"name":"symbol","type":"string"},{"name":"event_time","type":"long"},"#,
+                r#"{"name":"first_update_id","type":"long"},{"name":"last_update_id","type":"long"},"#,
+                r#"{"name":"prev_last_update_id","type":["null","long"]},"#,
+                r#"{"name":"bids","type":{"type":"array","items":"#,
+                level_schema!(),
+                r#"}},{"name":"asks","type":{"type":"array","items":"binance.Level"}},"#,
+                receive_times_schema!(),
+                "]}"
+            ),
+            Dataset::BookTicker => concat!(
+                r#"{"name":"binance.BookTicker","type":"record","fields":["#,
+                r#"{"name":"symbol","type":"string"},{"name":"update_id","type":"long"},"#,
+                r#"{"name":"bid","type":"string"},{"name":"bid_size","type":"string"},"#,
+                r#"{"name":"ask","type":"string"},{"name":"ask_size","type":"string"},"#,
+                receive_times_schema!(),
+                "]}"
+            ),
+            Dataset::BookSnapshot => concat!(
+                r#"{"name":"binance.BookSnapshot","type":"record","fields":["#,
+                r#"{"name":"symbol","type":"string"},{"name":"last_update_id","type":"long"},"#,
+                r#"{"name":"received","type":"long"},{"name":"bids","type":{"type":"array","items":"#,
+                level_schema!(),
+                r#"}},{"name":"asks","type":{"type":"array","items":"binance.Level"}},"#,
+                receive_times_schema!(),
+                "]}"
+            ),
+        }
+    }
+
+    /// The CRC-64-AVRO (Rabin) fingerprint of a schema.
+    pub fn fingerprint(schema: &str) -> u64 {
+        const EMPTY: u64 = 0xc15d_213a_a4d7_a795;
+        let mut table = [0u64; 256];
+        for (i, entry) in table.iter_mut().enumerate() {
+            let mut fp = i as u64;
+            for _ in 0..8 {
+                fp = (fp >> 1) ^ (EMPTY & (fp & 1).wrapping_neg());
+            }
+            *entry = fp;
+        }
+        schema.bytes().fold(EMPTY, |fp, byte| {
+            (fp >> 8) ^ table[((fp ^ byte as u64) & 0xff) as usize]
+        })
+    }
+
+    #[derive(Default)]
+    struct Writer(Vec<u8>);
+    impl Writer {
+        fn long(&mut self, value: i64) {
+            let mut zigzag = ((value << 1) ^ (value >> 63)) as u64;
+            while zigzag >= 0x80 {
+                self.0.push((zigzag as u8) | 0x80);
+                zigzag >>= 7;
+            }
+            self.0.push(zigzag as u8);
+        }
+        fn string(&mut self, value: &str) {
+            self.long(value.len() as i64);
+            self.0.extend_from_slice(value.as_bytes());
+        }
+        fn boolean(&mut self, value: bool) {
+            self.0.push(value as u8);
+        }
+        /// A `["null", T]` union.
+        fn optional<T>(&mut self, value: Option<T>, write: impl FnOnce(&mut Self, T)) {
+            match value {
+                None => self.long(0),
+                Some(value) => {
+                    self.long(1);
+                    write(self, value);
+                }
+            }
+        }
+        fn levels(&mut self, levels: &[proto::Level]) {
+            if !levels.is_empty() {
+                self.long(levels.len() as i64);
+                for level in levels {
+                    self.string(&level.price);
+                    self.string(&level.size);
+                }
+            }
+            self.long(0);
+        }
+    }
+
+    /// `record` in single object encoding, with the receive times of the frame it was parsed from, if any.
+    pub fn encode(record: &Record) -> Result<Vec<u8>, SinkError> {
+        let (record, received, corrected) = record.received();
+        let mut writer = Writer::default();
+        writer.0.extend_from_slice(&[0xc3, 0x01]);
+        writer
+            .0
+            .extend_from_slice(&fingerprint(schema(record.dataset())).to_le_bytes());
+        match record {
+            Record::Trade(trade) => {
+                let trade = proto::Trade::from(trade);
+                writer.string(&trade.symbol);
+                writer.long(trade.trade_id);
+                writer.long(trade.event_time);
+                writer.long(trade.trade_time);
+                writer.string(&trade.price);
+                writer.string(&trade.quantity);
+                writer.boolean(trade.buyer_is_maker);
+                writer.optional(trade.order_type.as_deref(), Writer::string);
+            }
+            Record::DepthUpdate(update) => {
+                let update = proto::DepthUpdate::from(update);
+                writer.string(&update.symbol);
+                writer.long(update.event_time);
+                writer.long(update.first_update_id);
+                writer.long(update.last_update_id);
+                writer.optional(update.prev_last_update_id, Writer::long);
+                writer.levels(&update.bids);
+                writer.levels(&update.asks);
+            }
+            Record::BookTicker(ticker) => {
+                let ticker = proto::BookTicker::from(ticker);
+                writer.string(&ticker.symbol);
+                writer.long(ticker.update_id);
+                writer.string(&ticker.bid);
+                writer.string(&ticker.bid_size);
+                writer.string(&ticker.ask);
+                writer.string(&ticker.ask_size);
+            }
+            Record::Snapshot { symbol, book } => {
+                let snapshot = proto::BookSnapshot::new(symbol, book);
+                writer.string(&snapshot.symbol);
+                writer.long(snapshot.last_update_id);
+                writer.long(snapshot.received);
+                writer.levels(&snapshot.bids);
+                writer.levels(&snapshot.asks);
+            }
+            Record::Frame(_) => return Err(NESTED_FRAME.into()),
+        }
+        writer.optional(millis(received), Writer::long);
+        writer.optional(millis(corrected), Writer::long);
+        Ok(writer.0)
+    }
+}
@@ -0,0 +1,164 @@
+use std::fs::{File, OpenOptions};
+use std::io::{BufWriter, Write};
+use std::path::PathBuf;
+use std::sync::Arc;
+
+use async_trait::async_trait;
+use log::info;
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    binance::time_sync::ServerClock,
+    file_compress::{Codec, CompressionCounters, CompressionStats, Encoder},
+};
+
+use super::{
+    encoding::normalized_json, manifest::ManifestEntry, part_files::{free_part, PART_EXTENSION}, rotation::Window,
+    FrameRecord, Record, Sink, SinkError, SinkFactory, StreamKey,
+};
+
+/// What each line of a JSON Lines file holds.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum JsonLinesMode {
+    /// `{"recv_ts":..,"recv_ts_corrected":..,"stream":..,"data":..}`, with `data` exactly as sent by the exchange,
+    /// including fields the models do not know about.
+    Raw,
+    /// The normalized model, as serialized in the CSV files, with `recv_ts` and `recv_ts_corrected` fields added.
+    Normalized,
+}
+
+/// Writes each window of each stream to its own JSON Lines file in `folder`, e.g. `USDM_FUT_BTCUSDT_TRADES_20230212T150000Z.jsonl`,
+/// one line per received payload. `recv_ts` is the local receive time in milliseconds, and `recv_ts_corrected`
+/// the same time in the exchange clock, left out until the offset of the local clock is estimated.
+/// Compression, `.part` suffixes and manifests work as for [`CsvFileSinkFactory`](super::csv_file::CsvFileSinkFactory).
+pub struct JsonLinesSinkFactory {
+    pub folder: PathBuf,
+    pub codec: Codec,
+    /// Bytes that went through the encoders of the files written.
+    pub compression: Arc<CompressionCounters>,
+    /// Its estimate is recorded in the manifests of the files when they are closed.
+    pub clock: Option<Arc<ServerClock>>,
+    pub mode: JsonLinesMode,
+}
+impl JsonLinesSinkFactory {
+    pub fn new(folder: impl Into<PathBuf>, mode: JsonLinesMode) -> Self {
+        Self {
+            folder: folder.into(),
+            codec: Codec::None,
+            compression: Arc::default(),
+            clock: None,
+            mode,
+        }
+    }
+    pub fn with_codec(mut self, codec: Codec) -> Self {
+        self.codec = codec;
+        self
+    }
+    pub fn with_clock(mut self, clock: Arc<ServerClock>) -> Self {
+        self.clock = Some(clock);
+        self
+    }
+}
+impl SinkFactory for JsonLinesSinkFactory {
+    fn name(&self) -> String {
+        match self.mode {
+            JsonLinesMode::Raw => "jsonl_raw".to_string(),
+            JsonLinesMode::Normalized => "jsonl".to_string(),
+        }
+    }
+    fn consumes_frames(&self) -> bool {
+        true
+    }
+    fn create(&self, key: &StreamKey, window: &Window) -> Result<Box<dyn Sink>, SinkError> {
+        std::fs::create_dir_all(&self.folder)?;
+        let name = |window: &Window| format!("{}_{}.jsonl{}", key, window, self.codec.extension());
+        let window = &free_part(&self.folder, window, name);
+        let file_name = name(window);
+        let path = self.folder.join(&file_name);
+        let part_path = self
+            .folder
+            .join(format!("{}.{}", file_name, PART_EXTENSION));
+        let file = OpenOptions::new()
+            .write(true)
+            .create_new(true)
+            .open(&part_path)?;
+        let encoder = self
+            .codec
+            .counted_encoder(BufWriter::new(file), self.compression.clone())?;
+        Ok(Box::new(JsonLinesSink {
+            path,
+            part_path,
+            mode: self.mode,
+            writer: Some(encoder),
+            manifest: ManifestEntry::new(&file_name, key, window),
+            clock: self.clock.clone(),
+        }))
+    }
+    fn compression(&self) -> Option<(&'static str, CompressionStats)> {
+        Some((self.codec.name(), self.compression.stats()))
+    }
+}
+
+pub struct JsonLinesSink {
+    path: PathBuf,
+    part_path: PathBuf,
+    mode: JsonLinesMode,
+    /// Taken when the sink is closed, to finish the compressed stream.
+    writer: Option<Encoder<BufWriter<File>>>,
+    manifest: ManifestEntry,
+    clock: Option<Arc<ServerClock>>,
+}
+impl JsonLinesSink {
+    fn writer(&mut self) -> Result<&mut Encoder<BufWriter<File>>, SinkError> {
+        let path = &self.path;
+        self.writer
+            .as_mut()
+            .ok_or_else(|| format!("{} is already closed", path.display()).into())
+    }
+}
+
+/// The frame with its payload left untouched.
+fn raw(frame: &FrameRecord) -> Result<String, SinkError> {
+    let corrected = match frame.corrected {
+        Some(corrected) => format!(",\"recv_ts_corrected\":{}", corrected.timestamp_millis()),
+        None => String::new(),
+    };
+    Ok(format!(
+        "{{\"recv_ts\":{}{},\"stream\":{},\"data\":{}}}",
+        frame.received.timestamp_millis(),
+        corrected,
+        serde_json::to_string(&frame.stream)?,
+        frame.payload.get()
+    ))
+}
+
+#[async_trait]
+impl Sink for JsonLinesSink {
+    async fn write(&mut self, record: &Record) -> Result<(), SinkError> {
+        let line = match (self.mode, record) {
+            (JsonLinesMode::Raw, Record::Frame(frame)) => raw(frame)?,
+            (JsonLinesMode::Raw, _) => {
+                return Err(format!("No raw payload for {:?}", record.dataset()).into())
+            }
+            (JsonLinesMode::Normalized, _) => serde_json::to_string(&normalized_json(record, None)?)?,
+        };
+        let writer = self.writer()?;
+        writer.write_all(line.as_bytes())?;
+        writer.write_all(b"\n")?;
+        self.manifest.add(record, 1);
+        Ok(())
+    }
+    async fn flush(&mut self) -> Result<(), SinkError> {
+        self.writer()?.flush()?;
+        Ok(())
+    }
+    async fn close(&mut self) -> Result<(), SinkError> {
+        self.writer()?.flush()?;
+        self.writer.take().unwrap().finish()?.flush()?;
+        self.manifest.clock = self.clock.as_ref().and_then(|clock| clock.estimate());
+        self.manifest.write_sidecar(&self.path)?;
+        std::fs::rename(&self.part_path, &self.path)?;
+        info!("Succesfully Created file {}", self.path.display());
+        Ok(())
+    }
+}
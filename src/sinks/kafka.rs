@@ -0,0 +1,191 @@
+//! Kafka sink publishing the normalized records, one message per record.
+//!
+//! Topics are rendered from a template per stream, see [`render_template`], and messages are keyed by symbol,
+//! so the records of a symbol land on one partition in the order they were received.
+use std::sync::Arc;
+use std::time::Duration;
+
+use async_trait::async_trait;
+use log::info;
+use rdkafka::{
+    error::{KafkaError, RDKafkaErrorCode},
+    message::OwnedHeaders,
+    producer::{DeliveryFuture, FutureProducer, FutureRecord},
+    ClientConfig,
+};
+use serde::{Deserialize, Serialize};
+
+use super::{
+    encoding::{render_template, Encoding},
+    rotation::Window,
+    Record, Sink, SinkError, SinkFactory, StreamKey,
+};
+
+/// What is guaranteed about the delivery of each message.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum Delivery {
+    /// Sent without waiting for the broker, nor retrying. Messages can be lost, never duplicated.
+    AtMostOnce,
+    /// Acknowledged by every in-sync replica with an idempotent producer. Flushing a sink waits for
+    /// its messages to be acknowledged and fails if any was not, so the bus reports it.
+    AtLeastOnce,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct KafkaConfig {
+    /// `bootstrap.servers`, e.g. `localhost:9092`.
+    pub brokers: String,
+    /// See [`render_template`].
+    pub topic_template: String,
+    pub encoding: Encoding,
+    pub delivery: Delivery,
+    /// How long a message waits for room in the producer queue before it is dropped.
+    pub queue_timeout: Duration,
+    /// Passed to librdkafka as is, after the settings implied by `delivery`, e.g. `("compression.type", "lz4")`.
+    pub settings: Vec<(String, String)>,
+}
+impl Default for KafkaConfig {
+    fn default() -> Self {
+        Self {
+            brokers: "localhost:9092".to_string(),
+            topic_template: "binance.{asset_type}.{stream}".to_string(),
+            encoding: Encoding::Json,
+            delivery: Delivery::AtLeastOnce,
+            queue_timeout: Duration::from_secs(5),
+            settings: Vec::new(),
+        }
+    }
+}
+
+/// A producer shared by the sinks of every stream.
+pub struct KafkaPublisher {
+    config: KafkaConfig,
+    producer: FutureProducer,
+}
+impl KafkaPublisher {
+    pub fn new(config: KafkaConfig) -> Result<Self, SinkError> {
+        let mut client = ClientConfig::new();
+        client.set("bootstrap.servers", &config.brokers);
+        match config.delivery {
+            Delivery::AtMostOnce => client.set("acks", "1").set("message.send.max.retries", "0"),
+            Delivery::AtLeastOnce => client.set("acks", "all").set("enable.idempotence", "true"),
+        };
+        for (key, value) in config.settings.iter() {
+            client.set(key, value);
+        }
+        let producer = client.create()?;
+        info!(
+            "Publishing to Kafka at {} as {:?}, {:?}",
+            config.brokers, config.encoding, config.delivery
+        );
+        Ok(Self { config, producer })
+    }
+    pub fn config(&self) -> &KafkaConfig {
+        &self.config
+    }
+    /// Queues a message, waiting up to `queue_timeout` for room in the producer queue.
+    async fn send(
+        &self,
+        topic: &str,
+        key: &str,
+        payload: &[u8],
+    ) -> Result<DeliveryFuture, SinkError> {
+        let headers = OwnedHeaders::new().add("content-type", self.config.encoding.content_type());
+        let mut record = FutureRecord::to(topic)
+            .key(key)
+            .payload(payload)
+            .headers(headers);
+        let deadline = tokio::time::Instant::now() + self.config.queue_timeout;
+        loop {
+            match self.producer.send_result(record) {
+                Ok(delivery) => return Ok(delivery),
+                Err((KafkaError::MessageProduction(RDKafkaErrorCode::QueueFull), returned))
+                    if tokio::time::Instant::now() < deadline =>
+                {
+                    record = returned;
+                    tokio::time::sleep(Duration::from_millis(10)).await;
+                }
+                Err((e, _)) => return Err(e.into()),
+            }
+        }
+    }
+}
+
+/// Publishes every dataset to Kafka.
+/// It consumes frames, so the messages carry the times they were received.
+pub struct KafkaSinkFactory {
+    pub publisher: Arc<KafkaPublisher>,
+}
+impl KafkaSinkFactory {
+    pub fn new(publisher: Arc<KafkaPublisher>) -> Self {
+        Self { publisher }
+    }
+}
+impl SinkFactory for KafkaSinkFactory {
+    fn name(&self) -> String {
+        "kafka".to_string()
+    }
+    fn consumes_frames(&self) -> bool {
+        true
+    }
+    fn consumes_records(&self) -> bool {
+        true
+    }
+    fn create(&self, key: &StreamKey, _window: &Window) -> Result<Box<dyn Sink>, SinkError> {
+        Ok(Box::new(KafkaSink {
+            publisher: self.publisher.clone(),
+            topic: render_template(&self.publisher.config.topic_template, key),
+            symbol: key.symbol.clone(),
+            pending: Vec::new(),
+        }))
+    }
+}
+
+pub struct KafkaSink {
+    publisher: Arc<KafkaPublisher>,
+    topic: String,
+    symbol: String,
+    /// Deliveries not acknowledged yet, only kept for [`Delivery::AtLeastOnce`].
+    pending: Vec<DeliveryFuture>,
+}
+
+#[async_trait]
+impl Sink for KafkaSink {
+    async fn write(&mut self, record: &Record) -> Result<(), SinkError> {
+        let payload = self.publisher.config.encoding.encode(record)?;
+        let delivery = self
+            .publisher
+            .send(&self.topic, &self.symbol, &payload)
+            .await?;
+        if self.publisher.config.delivery == Delivery::AtLeastOnce {
+            self.pending.push(delivery);
+        }
+        Ok(())
+    }
+    /// Waits for every message sent so far to be acknowledged.
+    async fn flush(&mut self) -> Result<(), SinkError> {
+        let mut failed = 0;
+        let mut last_error = None;
+        for delivery in self.pending.drain(..) {
+            match delivery.await {
+                Ok(Ok(_)) => {}
+                Ok(Err((e, _))) => {
+                    failed += 1;
+                    last_error = Some(e.to_string());
+                }
+                Err(_) => {
+                    failed += 1;
+                    last_error = Some("producer dropped".to_string());
+                }
+            }
+        }
+        match last_error {
+            None => Ok(()),
+            Some(e) => Err(format!(
+                "{} messages to {} were not delivered: {}",
+                failed, self.topic, e
+            )
+            .into()),
+        }
+    }
+}
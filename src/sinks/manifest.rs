@@ -0,0 +1,111 @@
+use std::fs::File;
+use std::io::{BufWriter, Write};
+use std::path::{Path, PathBuf};
+
+use chrono::{DateTime, Utc};
+use log::{error, info};
+use serde::{Deserialize, Serialize};
+
+use super::{rotation::Window, Dataset, Record, StreamKey};
+use crate::binance::time_sync::ClockEstimate;
+
+/// Extension of the manifest written next to each file by the sinks, until merged by [`write_manifest`].
+pub const SIDECAR_EXTENSION: &str = "manifest.json";
+
+/// Describes one produced file.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct ManifestEntry {
+    pub file: String,
+    pub asset_type: String,
+    pub symbol: String,
+    pub dataset: Dataset,
+    pub window_start: DateTime<Utc>,
+    pub window_end: DateTime<Utc>,
+    pub rows: u64,
+    pub first_event_time: Option<DateTime<Utc>>,
+    pub last_event_time: Option<DateTime<Utc>>,
+    /// Trade id for trades, update id for book datasets.
+    pub first_id: Option<i64>,
+    pub last_id: Option<i64>,
+    /// The offset of the local clock to the exchange's when the file was closed, when estimated.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub clock: Option<ClockEstimate>,
+}
+impl ManifestEntry {
+    pub fn new(file: &str, key: &StreamKey, window: &Window) -> Self {
+        Self {
+            file: file.to_string(),
+            asset_type: key.asset_type.clone(),
+            symbol: key.symbol.clone(),
+            dataset: key.dataset,
+            window_start: window.start,
+            window_end: window.end,
+            rows: 0,
+            first_event_time: None,
+            last_event_time: None,
+            first_id: None,
+            last_id: None,
+            clock: None,
+        }
+    }
+    /// Accounts for a record written as `rows` rows.
+    pub fn add(&mut self, record: &Record, rows: u64) {
+        self.rows += rows;
+        // Records of a window are not always in order, of event time nor of id.
+        if let Some(time) = record.event_time() {
+            self.first_event_time =
+                Some(self.first_event_time.map_or(time, |first| first.min(time)));
+            self.last_event_time = Some(self.last_event_time.map_or(time, |last| last.max(time)));
+        }
+        if let Some((first, last)) = record.ids() {
+            self.first_id = Some(self.first_id.map_or(first, |id| id.min(first)));
+            self.last_id = Some(self.last_id.map_or(last, |id| id.max(last)));
+        }
+    }
+    /// Writes the entry next to `path`, as `{path}.manifest.json`.
+    pub fn write_sidecar(&self, path: &Path) -> std::io::Result<()> {
+        let sidecar = PathBuf::from(format!("{}.{}", path.display(), SIDECAR_EXTENSION));
+        let mut writer = BufWriter::new(File::create(sidecar)?);
+        serde_json::to_writer(&mut writer, self)?;
+        writer.flush()?;
+        Ok(())
+    }
+}
+
+/// Merges the sidecar manifests found in `folder` into `{folder}/{name}.json`, a list of entries sorted by file,
+/// and removes the sidecars. Returns the path of the manifest, or `None` if there was nothing to merge.
+pub fn write_manifest(folder: &Path, name: &str) -> std::io::Result<Option<PathBuf>> {
+    let mut entries = Vec::new();
+    let mut sidecars = Vec::new();
+    for file in std::fs::read_dir(folder)? {
+        let path = file?.path();
+        if !path.to_string_lossy().ends_with(SIDECAR_EXTENSION) {
+            continue;
+        }
+        match serde_json::from_reader::<_, ManifestEntry>(File::open(&path)?) {
+            Ok(entry) => {
+                entries.push(entry);
+                sidecars.push(path);
+            }
+            Err(e) => error!("Error reading manifest {}: {}", path.display(), e),
+        }
+    }
+    if entries.is_empty() {
+        return Ok(None);
+    }
+    entries.sort_by(|a, b| a.file.cmp(&b.file));
+    let path = folder.join(format!("{}.json", name));
+    let mut writer = BufWriter::new(File::create(&path)?);
+    serde_json::to_writer_pretty(&mut writer, &entries)?;
+    // The sidecars are only removed once the manifest is written out.
+    writer.flush()?;
+    for sidecar in sidecars {
+        std::fs::remove_file(sidecar)?;
+    }
+    info!(
+        "Succesfully Created manifest {} of {} files",
+        path.display(),
+        entries.len()
+    );
+    Ok(Some(path))
+}
@@ -0,0 +1,209 @@
+//! Persistence pipeline. The [`bus::EventBus`] turns handler events into [`Record`]s and pushes them onto
+//! bounded per-stream queues, each drained by its own writer task into a [`Sink`].
+//! Every stream is split into [`rotation::Window`]s aligned to UTC boundaries, by exchange event time,
+//! and each window gets its own sink.
+use std::fmt::{Display, Formatter};
+
+use async_trait::async_trait;
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use serde_json::value::RawValue;
+
+use self::rotation::Window;
+use crate::file_compress::CompressionStats;
+use crate::binance::{
+    constants::Symbol,
+    models::{book_ticker::BookTicker, orderbook::OrderbookMessage, trades::Trade},
+    rest::RestOrderBook,
+    websocket::{requests::BinanceAssetType, router::StreamEvent},
+};
+
+pub mod bus;
+pub mod clickhouse;
+pub mod csv_file;
+pub mod encoding;
+pub mod json_lines;
+pub mod kafka;
+pub mod manifest;
+pub mod nats;
+pub mod part_files;
+pub mod postgres;
+pub mod queue;
+pub mod redis_streams;
+pub mod rotation;
+pub mod sqlite;
+
+/// Error returned by sinks, boxed so each backend can surface its own error type.
+pub type SinkError = Box<dyn std::error::Error + Send + Sync>;
+
+/// A normalized event on its way to persistence.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub enum Record {
+    Trade(Trade),
+    DepthUpdate(OrderbookMessage),
+    BookTicker(BookTicker),
+    Snapshot {
+        symbol: Symbol,
+        book: RestOrderBook,
+    },
+    /// Only sent to the sinks that [consume frames](SinkFactory::consumes_frames).
+    Frame(FrameRecord),
+}
+impl Record {
+    pub fn from_event(event: StreamEvent) -> Self {
+        match event {
+            StreamEvent::Trade(trade) => Record::Trade(trade),
+            StreamEvent::DepthUpdate(update) => Record::DepthUpdate(update),
+            StreamEvent::BookTicker(ticker) => Record::BookTicker(ticker),
+            StreamEvent::PartialDepth { symbol, book } => Record::Snapshot { symbol, book },
+        }
+    }
+    pub fn dataset(&self) -> Dataset {
+        match self {
+            Record::Trade(_) => Dataset::Trades,
+            Record::DepthUpdate(_) => Dataset::BookHistory,
+            Record::BookTicker(_) => Dataset::BookTicker,
+            Record::Snapshot { .. } => Dataset::BookSnapshot,
+            Record::Frame(frame) => frame.record.dataset(),
+        }
+    }
+    pub fn symbol(&self) -> &str {
+        match self {
+            Record::Trade(trade) => &trade.symbol,
+            Record::DepthUpdate(update) => &update.symbol,
+            Record::BookTicker(ticker) => &ticker.symbol,
+            Record::Snapshot { symbol, .. } => symbol,
+            Record::Frame(frame) => frame.record.symbol(),
+        }
+    }
+    /// First and last id covered by the record: the trade id, or the range of book update ids.
+    pub fn ids(&self) -> Option<(i64, i64)> {
+        match self {
+            Record::Trade(trade) => Some((trade.trade_id, trade.trade_id)),
+            Record::DepthUpdate(update) => Some((update.first_update_id, update.last_update_id)),
+            Record::BookTicker(ticker) => {
+                Some((ticker.orderbook_update_id, ticker.orderbook_update_id))
+            }
+            Record::Snapshot { book, .. } => Some((book.last_update_id, book.last_update_id)),
+            Record::Frame(frame) => frame.record.ids(),
+        }
+    }
+    /// The record parsed from a frame, or the record itself, with the time the frame was received, in the local
+    /// clock then in the exchange clock when its offset is estimated.
+    pub fn received(&self) -> (&Record, Option<DateTime<Utc>>, Option<DateTime<Utc>>) {
+        match self {
+            Record::Frame(frame) => (&frame.record, Some(frame.received), frame.corrected),
+            record => (record, None, None),
+        }
+    }
+    /// Exchange time of the event, book tickers do not carry one.
+    pub fn event_time(&self) -> Option<DateTime<Utc>> {
+        match self {
+            Record::Trade(trade) => Some(trade.event_time),
+            Record::DepthUpdate(update) => Some(update.time),
+            Record::BookTicker(_) => None,
+            Record::Snapshot { book, .. } => Some(book.received_ts),
+            Record::Frame(frame) => frame.record.event_time(),
+        }
+    }
+}
+
+/// A stream payload exactly as received, along with its receive time and the record parsed from it.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct FrameRecord {
+    pub stream: String,
+    pub received: DateTime<Utc>,
+    /// `received` in the exchange clock, when the offset of the local clock is estimated.
+    #[serde(default)]
+    pub corrected: Option<DateTime<Utc>>,
+    /// The `data` object of the frame, untouched.
+    pub payload: Box<RawValue>,
+    pub record: Box<Record>,
+}
+impl PartialEq for FrameRecord {
+    fn eq(&self, other: &Self) -> bool {
+        self.stream == other.stream
+            && self.received == other.received
+            && self.corrected == other.corrected
+            && self.payload.get() == other.payload.get()
+            && self.record == other.record
+    }
+}
+
+/// The kind of file or table a record ends up in.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub enum Dataset {
+    Trades,
+    BookHistory,
+    BookSnapshot,
+    BookTicker,
+}
+impl Display for Dataset {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Dataset::Trades => write!(f, "TRADES"),
+            Dataset::BookHistory => write!(f, "BOOK_HISTORY"),
+            Dataset::BookSnapshot => write!(f, "BOOK_SNAPSHOT"),
+            Dataset::BookTicker => write!(f, "BOOK_TICKER"),
+        }
+    }
+}
+
+/// Identifies a stream of records, each one gets its own queue and writer task.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct StreamKey {
+    pub asset_type: String,
+    pub dataset: Dataset,
+    pub symbol: Symbol,
+}
+impl StreamKey {
+    pub fn new(asset_type: &BinanceAssetType, record: &Record) -> Self {
+        Self {
+            asset_type: asset_type.to_string(),
+            dataset: record.dataset(),
+            symbol: record.symbol().to_string(),
+        }
+    }
+}
+impl Display for StreamKey {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}_{}_{}", self.asset_type, self.symbol, self.dataset)
+    }
+}
+
+/// Destination of the records of a single window of a stream.
+#[async_trait]
+pub trait Sink: Send {
+    async fn write(&mut self, record: &Record) -> Result<(), SinkError>;
+    async fn flush(&mut self) -> Result<(), SinkError> {
+        Ok(())
+    }
+    /// Flushes and finalizes what was written so far, once the window is over. The sink is dropped afterwards.
+    async fn close(&mut self) -> Result<(), SinkError> {
+        self.flush().await
+    }
+}
+
+/// Creates a sink for each window of each stream the bus sees.
+pub trait SinkFactory: Send + Sync {
+    fn name(&self) -> String;
+    /// Whether the sink gets a [`Record::Frame`] for every routed frame instead of the bare records.
+    fn consumes_frames(&self) -> bool {
+        false
+    }
+    /// Whether a sink consuming frames also gets the records [published](bus::EventBus::publish) outside of any
+    /// frame, such as the snapshots of the rest api, which carry no receive time.
+    fn consumes_records(&self) -> bool {
+        false
+    }
+    /// Whether records of `dataset` are sent to the sink at all.
+    #[allow(unused_variables)]
+    fn accepts(&self, dataset: Dataset) -> bool {
+        true
+    }
+    fn create(&self, key: &StreamKey, window: &Window) -> Result<Box<dyn Sink>, SinkError>;
+    /// For sinks writing compressed files, the name of the codec and the bytes that went through it so far.
+    fn compression(&self) -> Option<(&'static str, CompressionStats)> {
+        None
+    }
+}
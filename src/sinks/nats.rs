@@ -0,0 +1,159 @@
+//! NATS JetStream sink publishing the normalized records, one message per record.
+//!
+//! Subjects are rendered from a template per stream, see [`render_template`]. Every message carries a
+//! `Nats-Msg-Id` built from its subject and update id, so JetStream drops the copies sent again after a
+//! reconnect or a journal replay, within the duplicate window of the stream.
+use std::sync::Arc;
+use std::time::Duration;
+
+use async_nats::{
+    header::NATS_MESSAGE_ID,
+    jetstream::{self, context::PublishAckFuture, stream},
+    HeaderMap,
+};
+use async_trait::async_trait;
+use log::info;
+use serde::{Deserialize, Serialize};
+
+use super::{
+    encoding::{render_template, Encoding},
+    rotation::Window,
+    Record, Sink, SinkError, SinkFactory, StreamKey,
+};
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct NatsConfig {
+    /// e.g. `nats://localhost:4222`.
+    pub url: String,
+    /// See [`render_template`].
+    pub subject_template: String,
+    pub encoding: Encoding,
+    /// The JetStream stream capturing the subjects, created if it does not exist yet.
+    pub stream: String,
+    /// Subjects captured by `stream`, they must cover those rendered from `subject_template`.
+    pub stream_subjects: Vec<String>,
+    /// How long the stream keeps messages.
+    pub max_age: Duration,
+}
+impl Default for NatsConfig {
+    fn default() -> Self {
+        Self {
+            url: "nats://localhost:4222".to_string(),
+            subject_template: "binance.{asset_type}.{stream}.{symbol}".to_string(),
+            encoding: Encoding::Json,
+            stream: "BINANCE".to_string(),
+            stream_subjects: vec!["binance.>".to_string()],
+            max_age: Duration::from_secs(24 * 60 * 60),
+        }
+    }
+}
+
+/// A JetStream context shared by the sinks of every stream.
+pub struct NatsPublisher {
+    config: NatsConfig,
+    jetstream: jetstream::Context,
+}
+impl NatsPublisher {
+    /// Connects and creates the stream if it does not exist yet.
+    pub async fn connect(config: NatsConfig) -> Result<Self, SinkError> {
+        let client = async_nats::connect(&config.url).await?;
+        let jetstream = jetstream::new(client);
+        jetstream
+            .get_or_create_stream(stream::Config {
+                name: config.stream.clone(),
+                subjects: config.stream_subjects.clone(),
+                max_age: config.max_age,
+                ..Default::default()
+            })
+            .await?;
+        info!(
+            "Publishing to NATS stream {} at {} as {:?}",
+            config.stream, config.url, config.encoding
+        );
+        Ok(Self { config, jetstream })
+    }
+    pub fn config(&self) -> &NatsConfig {
+        &self.config
+    }
+}
+
+/// Publishes every dataset to NATS JetStream.
+/// It consumes frames, so the messages carry the times they were received.
+pub struct NatsSinkFactory {
+    pub publisher: Arc<NatsPublisher>,
+}
+impl NatsSinkFactory {
+    pub fn new(publisher: Arc<NatsPublisher>) -> Self {
+        Self { publisher }
+    }
+}
+impl SinkFactory for NatsSinkFactory {
+    fn name(&self) -> String {
+        "nats".to_string()
+    }
+    fn consumes_frames(&self) -> bool {
+        true
+    }
+    fn consumes_records(&self) -> bool {
+        true
+    }
+    fn create(&self, key: &StreamKey, _window: &Window) -> Result<Box<dyn Sink>, SinkError> {
+        Ok(Box::new(NatsSink {
+            publisher: self.publisher.clone(),
+            subject: render_template(&self.publisher.config.subject_template, key),
+            pending: Vec::new(),
+        }))
+    }
+}
+
+pub struct NatsSink {
+    publisher: Arc<NatsPublisher>,
+    subject: String,
+    /// Messages not acknowledged by the server yet.
+    pending: Vec<PublishAckFuture>,
+}
+
+#[async_trait]
+impl Sink for NatsSink {
+    async fn write(&mut self, record: &Record) -> Result<(), SinkError> {
+        let encoding = self.publisher.config.encoding;
+        let mut headers = HeaderMap::new();
+        headers.insert("Content-Type", encoding.content_type());
+        if let Some((_, last_id)) = record.ids() {
+            headers.insert(
+                NATS_MESSAGE_ID,
+                format!("{}.{}", self.subject, last_id).as_str(),
+            );
+        }
+        let ack = self
+            .publisher
+            .jetstream
+            .publish_with_headers(
+                self.subject.clone(),
+                headers,
+                encoding.encode(record)?.into(),
+            )
+            .await?;
+        self.pending.push(ack);
+        Ok(())
+    }
+    /// Waits for every message published so far to be acknowledged.
+    async fn flush(&mut self) -> Result<(), SinkError> {
+        let mut failed = 0;
+        let mut last_error = None;
+        for ack in self.pending.drain(..) {
+            if let Err(e) = ack.await {
+                failed += 1;
+                last_error = Some(e);
+            }
+        }
+        match last_error {
+            None => Ok(()),
+            Some(e) => Err(format!(
+                "{} messages to {} were not acknowledged: {}",
+                failed, self.subject, e
+            )
+            .into()),
+        }
+    }
+}
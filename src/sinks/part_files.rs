@@ -0,0 +1,62 @@
+//! Naming of the files written by the file sinks, which are suffixed with `.part` until closed, with one file
+//! per part of a window.
+use std::path::{Path, PathBuf};
+
+use log::warn;
+
+use super::rotation::Window;
+
+/// Suffix of the files being written.
+pub const PART_EXTENSION: &str = "part";
+/// Suffix given to the `.part` files a previous run left behind, see [`set_aside_parts`].
+pub const CRASHED_EXTENSION: &str = "crashed";
+
+/// Renames the `.part` files left in `folder` by a run that did not close them to `{file}.crashed`, so they are
+/// neither overwritten nor uploaded. Their records are still in the journal, which writes them again when replayed,
+/// after which they are deleted by [`remove_set_aside`].
+/// Returns the files set aside.
+pub fn set_aside_parts(folder: &Path) -> std::io::Result<Vec<PathBuf>> {
+    let mut set_aside = Vec::new();
+    let files = match std::fs::read_dir(folder) {
+        Ok(files) => files,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(set_aside),
+        Err(e) => return Err(e),
+    };
+    for file in files {
+        let path = file?.path();
+        if path.extension().and_then(|extension| extension.to_str()) != Some(PART_EXTENSION) {
+            continue;
+        }
+        let crashed = PathBuf::from(format!("{}.{}", path.display(), CRASHED_EXTENSION));
+        std::fs::rename(&path, &crashed)?;
+        warn!(
+            "Set aside {} left over by a previous run",
+            crashed.display()
+        );
+        set_aside.push(crashed);
+    }
+    Ok(set_aside)
+}
+
+/// Deletes the files [`set_aside_parts`] returned, once the journal has been replayed.
+pub fn remove_set_aside(files: &[PathBuf]) {
+    for file in files {
+        if let Err(e) = std::fs::remove_file(file) {
+            warn!("Error removing {}: {}", file.display(), e);
+        }
+    }
+}
+
+/// `window`, with its part moved past those already in `folder`, finished or not, as named by `file_name`.
+/// The bus only counts the parts it closed itself, so this keeps a file from being overwritten after a restart.
+pub fn free_part(folder: &Path, window: &Window, file_name: impl Fn(&Window) -> String) -> Window {
+    let mut window = *window;
+    loop {
+        let name = file_name(&window);
+        let path = folder.join(&name);
+        if !path.exists() && !folder.join(format!("{}.{}", name, PART_EXTENSION)).exists() {
+            return window;
+        }
+        window.part += 1;
+    }
+}
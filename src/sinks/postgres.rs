@@ -0,0 +1,616 @@
+//! PostgreSQL sink, for the shared research database.
+//!
+//! Records are buffered per stream and ingested on every flush with a binary `COPY` into a temporary table,
+//! then inserted into the target table with `ON CONFLICT DO NOTHING`, so replaying a journal or a recording
+//! never duplicates rows. The schema is created and upgraded from [`MIGRATIONS`] whenever the store connects,
+//! which it does on the first ingest and again with a backoff while the database is unreachable.
+//! The store keeps a few connections, so that the sinks of different streams copy concurrently, and bounds the
+//! records all its sinks buffer while the database is unreachable.
+//! When the TimescaleDB extension is available the tables are turned into hypertables.
+use std::sync::{
+    atomic::{AtomicUsize, Ordering},
+    Arc,
+};
+use std::time::Duration;
+
+use async_trait::async_trait;
+use chrono::{DateTime, Utc};
+use futures::pin_mut;
+use log::{error, info, warn};
+use rust_decimal::Decimal;
+use tokio::{
+    sync::{Mutex, MutexGuard},
+    time::Instant,
+};
+use tokio_postgres::{
+    binary_copy::BinaryCopyInWriter,
+    types::{ToSql, Type},
+    Client, NoTls, Transaction,
+};
+
+use crate::binance::models::orderbook::PriceSize;
+
+use super::{rotation::Window, Dataset, Record, Sink, SinkError, SinkFactory, StreamKey};
+
+/// Schema versions, applied in order and recorded in `schema_migrations`. Never edit one that was released.
+pub const MIGRATIONS: [(i32, &str); 3] = [
+    (
+        1,
+        "CREATE TABLE trades (
+            asset_type TEXT NOT NULL,
+            symbol TEXT NOT NULL,
+            trade_id BIGINT NOT NULL,
+            event_time TIMESTAMPTZ NOT NULL,
+            trade_time TIMESTAMPTZ NOT NULL,
+            price NUMERIC NOT NULL,
+            quantity NUMERIC NOT NULL,
+            buyer_is_maker BOOLEAN NOT NULL,
+            order_type TEXT,
+            received TIMESTAMPTZ NOT NULL,
+            PRIMARY KEY (asset_type, symbol, trade_id, trade_time)
+        );
+        CREATE INDEX trades_by_time ON trades (asset_type, symbol, trade_time);
+        CREATE TABLE depth_updates (
+            asset_type TEXT NOT NULL,
+            symbol TEXT NOT NULL,
+            first_update_id BIGINT NOT NULL,
+            last_update_id BIGINT NOT NULL,
+            prev_last_update_id BIGINT,
+            event_time TIMESTAMPTZ NOT NULL,
+            bid_prices NUMERIC[] NOT NULL,
+            bid_sizes NUMERIC[] NOT NULL,
+            ask_prices NUMERIC[] NOT NULL,
+            ask_sizes NUMERIC[] NOT NULL,
+            received TIMESTAMPTZ NOT NULL,
+            PRIMARY KEY (asset_type, symbol, last_update_id, event_time)
+        );
+        CREATE INDEX depth_updates_by_time ON depth_updates (asset_type, symbol, event_time);
+        CREATE TABLE book_tickers (
+            asset_type TEXT NOT NULL,
+            symbol TEXT NOT NULL,
+            update_id BIGINT NOT NULL,
+            bid NUMERIC NOT NULL,
+            bid_size NUMERIC NOT NULL,
+            ask NUMERIC NOT NULL,
+            ask_size NUMERIC NOT NULL,
+            received TIMESTAMPTZ NOT NULL,
+            PRIMARY KEY (asset_type, symbol, update_id, received)
+        );
+        CREATE INDEX book_tickers_by_time ON book_tickers (asset_type, symbol, received);",
+    ),
+    (
+        2,
+        "CREATE VIEW trade_bars_1m AS
+        SELECT asset_type, symbol, date_trunc('minute', trade_time) AS bar_start,
+            (array_agg(price ORDER BY trade_time, trade_id))[1] AS open,
+            max(price) AS high,
+            min(price) AS low,
+            (array_agg(price ORDER BY trade_time DESC, trade_id DESC))[1] AS close,
+            sum(quantity) AS volume,
+            sum(price * quantity) AS quote_volume,
+            count(*) AS trades
+        FROM trades
+        GROUP BY asset_type, symbol, bar_start;",
+    ),
+    (
+        3,
+        "ALTER TABLE trades ADD COLUMN received_corrected TIMESTAMPTZ;
+        ALTER TABLE depth_updates ADD COLUMN received_corrected TIMESTAMPTZ;
+        ALTER TABLE book_tickers ADD COLUMN received_corrected TIMESTAMPTZ;",
+    ),
+];
+
+/// Tables turned into hypertables when TimescaleDB is available, with their time column.
+const HYPERTABLES: [(&str, &str); 3] = [
+    ("trades", "trade_time"),
+    ("depth_updates", "event_time"),
+    ("book_tickers", "received"),
+];
+
+/// Records buffered by a sink before they are ingested without waiting for the next flush.
+const BATCH_SIZE: usize = 5_000;
+/// Records kept by all the sinks of a store while the database is unreachable, before new ones are refused.
+const MAX_BUFFERED: usize = 500_000;
+/// Connections of a store.
+const POOL_SIZE: usize = 4;
+/// Wait after a failed connection, doubled on each failure up to `MAX_BACKOFF`.
+const INITIAL_BACKOFF: Duration = Duration::from_secs(1);
+const MAX_BACKOFF: Duration = Duration::from_secs(60);
+
+/// Connections to the database, shared by every sink and re-established when they are lost.
+pub struct PostgresStore {
+    /// libpq style connection string, e.g. `host=localhost user=postgres dbname=market_data`.
+    url: String,
+    pool: Vec<Mutex<Connection>>,
+    /// The connection waited for when they are all busy.
+    next: AtomicUsize,
+    /// Records held by the sinks and in `kept`, up to `MAX_BUFFERED`.
+    buffered: AtomicUsize,
+    /// Batches of the closed sinks that could not be copied, with their asset type, copied on the next flush.
+    kept: std::sync::Mutex<Vec<(String, Vec<Record>)>>,
+}
+impl PostgresStore {
+    /// A store that connects on the first ingest, so that it can be created while the database is down.
+    pub fn new(url: &str) -> Self {
+        Self {
+            url: url.to_string(),
+            pool: (0..POOL_SIZE)
+                .map(|_| {
+                    Mutex::new(Connection {
+                        client: None,
+                        retry_at: None,
+                        backoff: INITIAL_BACKOFF,
+                    })
+                })
+                .collect(),
+            next: AtomicUsize::new(0),
+            buffered: AtomicUsize::new(0),
+            kept: std::sync::Mutex::new(Vec::new()),
+        }
+    }
+    /// Connects now, applies the pending migrations and sets up the hypertables.
+    pub async fn connect(url: &str) -> Result<Self, SinkError> {
+        let store = Self::new(url);
+        store.pool[0].lock().await.client(url).await?;
+        Ok(store)
+    }
+    /// Records buffered by the sinks, or kept from closed ones, waiting to be copied.
+    pub fn buffered(&self) -> usize {
+        self.buffered.load(Ordering::SeqCst)
+    }
+    /// A free connection, or the next one in turn when they are all busy.
+    async fn connection(&self) -> MutexGuard<'_, Connection> {
+        for connection in self.pool.iter() {
+            if let Ok(connection) = connection.try_lock() {
+                return connection;
+            }
+        }
+        let next = self.next.fetch_add(1, Ordering::Relaxed) % self.pool.len();
+        self.pool[next].lock().await
+    }
+    /// Counts a record a sink buffers, unless the sinks buffer `MAX_BUFFERED` already.
+    fn reserve(&self, asset_type: &str) -> Result<(), SinkError> {
+        let buffered = self.buffered.fetch_add(1, Ordering::SeqCst);
+        if buffered >= MAX_BUFFERED {
+            self.buffered.fetch_sub(1, Ordering::SeqCst);
+            return Err(format!(
+                "{} records are waiting for postgres already, refusing those of {}",
+                buffered, asset_type
+            )
+            .into());
+        }
+        Ok(())
+    }
+    fn release(&self, records: usize) {
+        self.buffered.fetch_sub(records, Ordering::SeqCst);
+    }
+    /// Keeps the batch of a closed sink, to copy it on the next flush of any sink.
+    fn keep(&self, asset_type: &str, batch: Vec<Record>) {
+        self.kept
+            .lock()
+            .unwrap()
+            .push((asset_type.to_string(), batch));
+    }
+    /// Copies the batches kept from closed sinks, keeping those not copied for the next flush.
+    async fn ingest_kept(&self) -> Result<(), SinkError> {
+        let mut kept = std::mem::take(&mut *self.kept.lock().unwrap());
+        while let Some((asset_type, batch)) = kept.pop() {
+            if let Err(e) = self.ingest(&asset_type, &batch, Utc::now()).await {
+                kept.push((asset_type, batch));
+                self.kept.lock().unwrap().append(&mut kept);
+                return Err(e);
+            }
+            info!(
+                "Copied {} records of {} kept from a closed window",
+                batch.len(),
+                asset_type
+            );
+            self.release(batch.len());
+        }
+        Ok(())
+    }
+    /// Copies `records` of `asset_type` to the database in one transaction. `received` is used for the records
+    /// that are not [`Record::Frame`]s, which have no `received_corrected`. Snapshots are not stored.
+    pub async fn ingest(
+        &self,
+        asset_type: &str,
+        records: &[Record],
+        received: DateTime<Utc>,
+    ) -> Result<(), SinkError> {
+        let mut connection = self.connection().await;
+        let transaction = connection.client(&self.url).await?.transaction().await?;
+        let mut trades = Vec::new();
+        let mut updates = Vec::new();
+        let mut tickers = Vec::new();
+        for record in records {
+            let (record, frame_received, corrected) = record.received();
+            let received = (frame_received.unwrap_or(received), corrected);
+            match record {
+                Record::Trade(trade) => trades.push((trade, received)),
+                Record::DepthUpdate(update) => updates.push((update, received)),
+                Record::BookTicker(ticker) => tickers.push((ticker, received)),
+                Record::Snapshot { .. } | Record::Frame(_) => {}
+            }
+        }
+        if !trades.is_empty() {
+            let rows = trades
+                .iter()
+                .map(|(trade, (received, corrected))| {
+                    let row: Vec<Box<dyn ToSql + Sync + Send>> = vec![
+                        Box::new(asset_type.to_string()),
+                        Box::new(trade.symbol.clone()),
+                        Box::new(trade.trade_id),
+                        Box::new(trade.event_time),
+                        Box::new(trade.trade_time),
+                        Box::new(trade.price),
+                        Box::new(trade.quantity),
+                        Box::new(trade.buyer_is_the_market_maker),
+                        Box::new(trade.x.clone()),
+                        Box::new(*received),
+                        Box::new(*corrected),
+                    ];
+                    row
+                })
+                .collect::<Vec<_>>();
+            copy(
+                &transaction,
+                "trades",
+                "asset_type, symbol, trade_id, event_time, trade_time, price, quantity, buyer_is_maker, order_type, received, received_corrected",
+                &[
+                    Type::TEXT,
+                    Type::TEXT,
+                    Type::INT8,
+                    Type::TIMESTAMPTZ,
+                    Type::TIMESTAMPTZ,
+                    Type::NUMERIC,
+                    Type::NUMERIC,
+                    Type::BOOL,
+                    Type::TEXT,
+                    Type::TIMESTAMPTZ,
+                    Type::TIMESTAMPTZ,
+                ],
+                rows,
+            )
+            .await?;
+        }
+        if !updates.is_empty() {
+            let rows = updates
+                .iter()
+                .map(|(update, (received, corrected))| {
+                    let row: Vec<Box<dyn ToSql + Sync + Send>> = vec![
+                        Box::new(asset_type.to_string()),
+                        Box::new(update.symbol.clone()),
+                        Box::new(update.first_update_id),
+                        Box::new(update.last_update_id),
+                        Box::new(update.prev_last_update_id),
+                        Box::new(update.time),
+                        Box::new(prices(&update.bids)),
+                        Box::new(sizes(&update.bids)),
+                        Box::new(prices(&update.asks)),
+                        Box::new(sizes(&update.asks)),
+                        Box::new(*received),
+                        Box::new(*corrected),
+                    ];
+                    row
+                })
+                .collect::<Vec<_>>();
+            copy(
+                &transaction,
+                "depth_updates",
+                "asset_type, symbol, first_update_id, last_update_id, prev_last_update_id, event_time, bid_prices, bid_sizes, ask_prices, ask_sizes, received, received_corrected",
+                &[
+                    Type::TEXT,
+                    Type::TEXT,
+                    Type::INT8,
+                    Type::INT8,
+                    Type::INT8,
+                    Type::TIMESTAMPTZ,
+                    Type::NUMERIC_ARRAY,
+                    Type::NUMERIC_ARRAY,
+                    Type::NUMERIC_ARRAY,
+                    Type::NUMERIC_ARRAY,
+                    Type::TIMESTAMPTZ,
+                    Type::TIMESTAMPTZ,
+                ],
+                rows,
+            )
+            .await?;
+        }
+        if !tickers.is_empty() {
+            let rows = tickers
+                .iter()
+                .map(|(ticker, (received, corrected))| {
+                    let row: Vec<Box<dyn ToSql + Sync + Send>> = vec![
+                        Box::new(asset_type.to_string()),
+                        Box::new(ticker.symbol.clone()),
+                        Box::new(ticker.orderbook_update_id),
+                        Box::new(ticker.bid),
+                        Box::new(ticker.bid_size),
+                        Box::new(ticker.ask),
+                        Box::new(ticker.ask_size),
+                        Box::new(*received),
+                        Box::new(*corrected),
+                    ];
+                    row
+                })
+                .collect::<Vec<_>>();
+            copy(
+                &transaction,
+                "book_tickers",
+                "asset_type, symbol, update_id, bid, bid_size, ask, ask_size, received, received_corrected",
+                &[
+                    Type::TEXT,
+                    Type::TEXT,
+                    Type::INT8,
+                    Type::NUMERIC,
+                    Type::NUMERIC,
+                    Type::NUMERIC,
+                    Type::NUMERIC,
+                    Type::TIMESTAMPTZ,
+                    Type::TIMESTAMPTZ,
+                ],
+                rows,
+            )
+            .await?;
+        }
+        transaction.commit().await?;
+        Ok(())
+    }
+}
+
+/// The client of a [`PostgresStore`], and when to try connecting again after a failure.
+struct Connection {
+    client: Option<Client>,
+    retry_at: Option<Instant>,
+    backoff: Duration,
+}
+impl Connection {
+    /// The open client, connecting and migrating first when there is none, unless the last attempt failed too
+    /// recently.
+    async fn client(&mut self, url: &str) -> Result<&mut Client, SinkError> {
+        if self.client.as_ref().map_or(true, Client::is_closed) {
+            if let Some(retry_at) = self.retry_at.filter(|retry_at| *retry_at > Instant::now()) {
+                return Err(format!(
+                    "Not connecting to postgres again for {:?}",
+                    retry_at - Instant::now()
+                )
+                .into());
+            }
+            self.client = None;
+            let mut client = match connect(url).await {
+                Ok(client) => client,
+                Err(e) => return Err(self.failed(e)),
+            };
+            if let Err(e) = migrate(&mut client).await {
+                return Err(self.failed(e));
+            }
+            info!("Connected to postgres");
+            self.client = Some(client);
+            self.retry_at = None;
+            self.backoff = INITIAL_BACKOFF;
+        }
+        Ok(self.client.as_mut().unwrap())
+    }
+    fn failed(&mut self, e: tokio_postgres::Error) -> SinkError {
+        warn!(
+            "Error connecting to postgres, retrying in {:?}: {}",
+            self.backoff, e
+        );
+        self.retry_at = Some(Instant::now() + self.backoff);
+        self.backoff = (self.backoff * 2).min(MAX_BACKOFF);
+        e.into()
+    }
+}
+
+async fn connect(url: &str) -> Result<Client, tokio_postgres::Error> {
+    let (client, connection) = tokio_postgres::connect(url, NoTls).await?;
+    tokio::spawn(async move {
+        if let Err(e) = connection.await {
+            error!("Postgres connection failed: {}", e);
+        }
+    });
+    Ok(client)
+}
+
+/// Applies the migrations not applied yet, each in its own transaction, then sets up the hypertables.
+/// An advisory lock keeps several collectors starting at once from migrating concurrently.
+async fn migrate(client: &mut Client) -> Result<(), tokio_postgres::Error> {
+    // "binance" in ASCII.
+    const LOCK_ID: i64 = 0x0062_696e_616e_6365;
+    client
+        .execute("SELECT pg_advisory_lock($1)", &[&LOCK_ID])
+        .await?;
+    let migrated = apply_migrations(client).await;
+    client
+        .execute("SELECT pg_advisory_unlock($1)", &[&LOCK_ID])
+        .await?;
+    migrated?;
+    let timescale = client
+        .query_opt(
+            "SELECT 1 FROM pg_available_extensions WHERE name = 'timescaledb'",
+            &[],
+        )
+        .await?
+        .is_some();
+    if !timescale {
+        info!("TimescaleDB is not available, using plain tables");
+        return Ok(());
+    }
+    client
+        .batch_execute("CREATE EXTENSION IF NOT EXISTS timescaledb")
+        .await?;
+    for (table, column) in HYPERTABLES {
+        if let Err(e) = client
+            .execute(
+                "SELECT create_hypertable($1::text::regclass, $2::text::name, if_not_exists => TRUE, migrate_data => TRUE)",
+                &[&table, &column],
+            )
+            .await
+        {
+            warn!("Error turning {} into a hypertable: {}", table, e);
+        }
+    }
+    Ok(())
+}
+
+async fn apply_migrations(client: &mut Client) -> Result<(), tokio_postgres::Error> {
+    client
+        .batch_execute(
+            "CREATE TABLE IF NOT EXISTS schema_migrations (
+                version INTEGER PRIMARY KEY,
+                applied_at TIMESTAMPTZ NOT NULL DEFAULT now()
+            )",
+        )
+        .await?;
+    let applied = client
+        .query("SELECT version FROM schema_migrations", &[])
+        .await?
+        .iter()
+        .map(|row| row.get::<_, i32>(0))
+        .collect::<Vec<_>>();
+    for (version, sql) in MIGRATIONS {
+        if applied.contains(&version) {
+            continue;
+        }
+        let transaction = client.transaction().await?;
+        transaction.batch_execute(sql).await?;
+        transaction
+            .execute(
+                "INSERT INTO schema_migrations (version) VALUES ($1)",
+                &[&version],
+            )
+            .await?;
+        transaction.commit().await?;
+        info!("Applied postgres migration {}", version);
+    }
+    Ok(())
+}
+
+/// Copies `rows` into a temporary copy of `table`, then inserts those not already there.
+async fn copy(
+    transaction: &Transaction<'_>,
+    table: &str,
+    columns: &str,
+    types: &[Type],
+    rows: Vec<Vec<Box<dyn ToSql + Sync + Send>>>,
+) -> Result<(), tokio_postgres::Error> {
+    let staging = format!("staging_{}", table);
+    transaction
+        .batch_execute(&format!(
+            "CREATE TEMP TABLE {} (LIKE {} INCLUDING DEFAULTS) ON COMMIT DROP",
+            staging, table
+        ))
+        .await?;
+    let sink = transaction
+        .copy_in(&format!("COPY {} ({}) FROM STDIN BINARY", staging, columns))
+        .await?;
+    let writer = BinaryCopyInWriter::new(sink, types);
+    pin_mut!(writer);
+    for row in rows.iter() {
+        let row = row
+            .iter()
+            .map(|value| &**value as &(dyn ToSql + Sync))
+            .collect::<Vec<_>>();
+        writer.as_mut().write(&row).await?;
+    }
+    writer.finish().await?;
+    transaction
+        .batch_execute(&format!(
+            "INSERT INTO {table} ({columns}) SELECT {columns} FROM {staging} ON CONFLICT DO NOTHING"
+        ))
+        .await
+}
+
+fn prices(levels: &[PriceSize]) -> Vec<Decimal> {
+    levels.iter().map(|level| level.price).collect()
+}
+
+fn sizes(levels: &[PriceSize]) -> Vec<Decimal> {
+    levels.iter().map(|level| level.size).collect()
+}
+
+/// Writes trades, depth updates and book tickers to a [`PostgresStore`].
+/// It consumes frames, so every row carries the time it was received, in the exchange clock too when known.
+pub struct PostgresSinkFactory {
+    pub store: Arc<PostgresStore>,
+}
+impl PostgresSinkFactory {
+    pub fn new(store: Arc<PostgresStore>) -> Self {
+        Self { store }
+    }
+}
+impl SinkFactory for PostgresSinkFactory {
+    fn name(&self) -> String {
+        "postgres".to_string()
+    }
+    fn consumes_frames(&self) -> bool {
+        true
+    }
+    fn accepts(&self, dataset: Dataset) -> bool {
+        dataset != Dataset::BookSnapshot
+    }
+    fn create(&self, key: &StreamKey, _window: &Window) -> Result<Box<dyn Sink>, SinkError> {
+        Ok(Box::new(PostgresSink {
+            store: self.store.clone(),
+            asset_type: key.asset_type.clone(),
+            batch: Vec::new(),
+        }))
+    }
+}
+
+/// Buffers records and copies them on every flush. They are kept for the next flush if the copy fails, and
+/// handed to the store when the window closes before they could be copied.
+pub struct PostgresSink {
+    store: Arc<PostgresStore>,
+    asset_type: String,
+    batch: Vec<Record>,
+}
+
+#[async_trait]
+impl Sink for PostgresSink {
+    async fn write(&mut self, record: &Record) -> Result<(), SinkError> {
+        self.store.reserve(&self.asset_type)?;
+        self.batch.push(record.clone());
+        if self.batch.len() % BATCH_SIZE == 0 {
+            if let Err(e) = self.flush().await {
+                error!(
+                    "Error copying to postgres, retrying on the next flush: {}",
+                    e
+                );
+            }
+        }
+        Ok(())
+    }
+    async fn flush(&mut self) -> Result<(), SinkError> {
+        self.store.ingest_kept().await?;
+        if self.batch.is_empty() {
+            return Ok(());
+        }
+        self.store
+            .ingest(&self.asset_type, &self.batch, Utc::now())
+            .await?;
+        self.store.release(self.batch.len());
+        self.batch.clear();
+        Ok(())
+    }
+    async fn close(&mut self) -> Result<(), SinkError> {
+        if let Err(e) = self.flush().await {
+            if !self.batch.is_empty() {
+                warn!(
+                    "Error copying to postgres, keeping {} records of {} for the next flush: {}",
+                    self.batch.len(),
+                    self.asset_type,
+                    e
+                );
+                self.store
+                    .keep(&self.asset_type, std::mem::take(&mut self.batch));
+            }
+        }
+        Ok(())
+    }
+}
+impl Drop for PostgresSink {
+    fn drop(&mut self) {
+        self.store.release(self.batch.len());
+    }
+}
@@ -0,0 +1,272 @@
+use std::collections::VecDeque;
+use std::fs::{File, OpenOptions};
+use std::io::{BufRead, BufReader, BufWriter, Seek, SeekFrom, Write};
+use std::marker::PhantomData;
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+
+use log::{error, warn};
+use serde::de::DeserializeOwned;
+use serde::{Deserialize, Serialize};
+use tokio::sync::Notify;
+
+/// What to do with a record pushed onto a full queue.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub enum OverflowPolicy {
+    /// Wait for room, slowing down ingestion.
+    Block,
+    /// Discard the oldest record in the queue.
+    DropOldest,
+    /// Append the record to a file in the given folder, it is read back once the queue has drained.
+    SpillToDisk(PathBuf),
+}
+
+/// Running totals of a queue, shared with its writer task.
+#[derive(Debug, Default)]
+pub struct QueueCounters {
+    pub received: AtomicU64,
+    pub dropped: AtomicU64,
+    pub spilled: AtomicU64,
+    pub written: AtomicU64,
+}
+impl QueueCounters {
+    pub fn snapshot(&self) -> QueueStats {
+        QueueStats {
+            received: self.received.load(Ordering::Relaxed),
+            dropped: self.dropped.load(Ordering::Relaxed),
+            spilled: self.spilled.load(Ordering::Relaxed),
+            written: self.written.load(Ordering::Relaxed),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct QueueStats {
+    pub received: u64,
+    pub dropped: u64,
+    pub spilled: u64,
+    pub written: u64,
+}
+
+/// Records spilled to disk as JSON lines, in the order they were pushed.
+struct Spill<T> {
+    path: PathBuf,
+    writer: BufWriter<File>,
+    reader: BufReader<File>,
+    pending: u64,
+    kind: PhantomData<T>,
+}
+impl<T: Serialize + DeserializeOwned> Spill<T> {
+    fn open(path: PathBuf) -> std::io::Result<Self> {
+        if let Some(folder) = path.parent() {
+            std::fs::create_dir_all(folder)?;
+        }
+        let file = OpenOptions::new()
+            .create(true)
+            .truncate(true)
+            .read(true)
+            .write(true)
+            .open(&path)?;
+        let reader = BufReader::new(file.try_clone()?);
+        let writer = BufWriter::new(OpenOptions::new().append(true).open(&path)?);
+        Ok(Self {
+            path,
+            writer,
+            reader,
+            pending: 0,
+            kind: PhantomData,
+        })
+    }
+    fn write(&mut self, item: &T) -> std::io::Result<()> {
+        serde_json::to_writer(&mut self.writer, item)?;
+        self.writer.write_all(b"\n")?;
+        self.pending += 1;
+        Ok(())
+    }
+    fn read(&mut self) -> std::io::Result<T> {
+        self.writer.flush()?;
+        let mut line = String::new();
+        self.reader.read_line(&mut line)?;
+        self.pending -= 1;
+        if self.pending == 0 {
+            self.reset()?;
+        }
+        serde_json::from_str(&line)
+            .map_err(|e| std::io::Error::new(std::io::ErrorKind::InvalidData, e))
+    }
+    /// Empties the file once everything spilled has been read back.
+    fn reset(&mut self) -> std::io::Result<()> {
+        self.pending = 0;
+        self.writer.flush()?;
+        self.writer.get_ref().set_len(0)?;
+        self.reader.seek(SeekFrom::Start(0))?;
+        Ok(())
+    }
+}
+impl<T> Drop for Spill<T> {
+    fn drop(&mut self) {
+        if let Err(e) = std::fs::remove_file(&self.path) {
+            warn!("Error removing spill file {}: {}", self.path.display(), e);
+        }
+    }
+}
+
+struct QueueState<T> {
+    items: VecDeque<T>,
+    spill: Option<Spill<T>>,
+    closed: bool,
+}
+
+/// A bounded multi-producer queue whose behaviour when full is set by an [`OverflowPolicy`].
+/// Order is preserved across spills: once something has been spilled, later records are spilled too
+/// until the file has been read back.
+pub struct BoundedQueue<T> {
+    name: String,
+    capacity: usize,
+    policy: OverflowPolicy,
+    state: Mutex<QueueState<T>>,
+    pushed: Notify,
+    popped: Notify,
+    counters: Arc<QueueCounters>,
+}
+impl<T: Serialize + DeserializeOwned> BoundedQueue<T> {
+    pub fn new(name: &str, capacity: usize, policy: OverflowPolicy) -> Self {
+        Self {
+            name: name.to_string(),
+            capacity: capacity.max(1),
+            policy,
+            state: Mutex::new(QueueState {
+                items: VecDeque::with_capacity(capacity.min(1024)),
+                spill: None,
+                closed: false,
+            }),
+            pushed: Notify::new(),
+            popped: Notify::new(),
+            counters: Arc::new(QueueCounters::default()),
+        }
+    }
+    pub fn counters(&self) -> Arc<QueueCounters> {
+        self.counters.clone()
+    }
+    /// Records waiting, in memory and on disk.
+    pub fn len(&self) -> u64 {
+        let state = self.state.lock().unwrap();
+        state.items.len() as u64 + state.spill.as_ref().map_or(0, |spill| spill.pending)
+    }
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+    pub async fn push(&self, item: T) {
+        self.counters.received.fetch_add(1, Ordering::Relaxed);
+        let mut item = Some(item);
+        loop {
+            let popped = self.popped.notified();
+            tokio::pin!(popped);
+            popped.as_mut().enable();
+            {
+                let mut state = self.state.lock().unwrap();
+                if state.closed {
+                    self.counters.dropped.fetch_add(1, Ordering::Relaxed);
+                    return;
+                }
+                let spilling = state.spill.as_ref().is_some_and(|spill| spill.pending > 0);
+                if state.items.len() < self.capacity && !spilling {
+                    state.items.push_back(item.take().unwrap());
+                    drop(state);
+                    self.pushed.notify_one();
+                    return;
+                }
+                match &self.policy {
+                    OverflowPolicy::Block => {}
+                    OverflowPolicy::DropOldest => {
+                        state.items.pop_front();
+                        state.items.push_back(item.take().unwrap());
+                        drop(state);
+                        self.counters.dropped.fetch_add(1, Ordering::Relaxed);
+                        self.pushed.notify_one();
+                        return;
+                    }
+                    OverflowPolicy::SpillToDisk(folder) => {
+                        if state.spill.is_none() {
+                            match Spill::open(folder.join(format!("{}.spill", self.name))) {
+                                Ok(spill) => state.spill = Some(spill),
+                                Err(e) => {
+                                    error!("Error opening spill file for {}: {}", self.name, e)
+                                }
+                            }
+                        }
+                        let spilled = match state.spill.as_mut() {
+                            Some(spill) => spill.write(item.as_ref().unwrap()),
+                            None => Err(std::io::Error::other("no spill file")),
+                        };
+                        drop(state);
+                        match spilled {
+                            Ok(_) => {
+                                self.counters.spilled.fetch_add(1, Ordering::Relaxed);
+                            }
+                            Err(e) => {
+                                error!(
+                                    "Error spilling record of {} to disk, dropping it: {}",
+                                    self.name, e
+                                );
+                                self.counters.dropped.fetch_add(1, Ordering::Relaxed);
+                            }
+                        }
+                        self.pushed.notify_one();
+                        return;
+                    }
+                }
+            }
+            popped.await;
+        }
+    }
+    /// Takes the next record if there is one, without waiting.
+    pub fn try_pop(&self) -> Option<T> {
+        let mut state = self.state.lock().unwrap();
+        if let Some(item) = state.items.pop_front() {
+            drop(state);
+            self.popped.notify_one();
+            return Some(item);
+        }
+        while let Some(spill) = state.spill.as_mut().filter(|spill| spill.pending > 0) {
+            match spill.read() {
+                Ok(item) => return Some(item),
+                Err(e) if e.kind() == std::io::ErrorKind::InvalidData => {
+                    error!("Dropping unreadable spilled record of {}: {}", self.name, e);
+                    self.counters.dropped.fetch_add(1, Ordering::Relaxed);
+                }
+                Err(e) => {
+                    error!("Error reading back spilled records of {}: {}", self.name, e);
+                    let lost = spill.pending;
+                    if let Err(e) = spill.reset() {
+                        error!("Error resetting spill file of {}: {}", self.name, e);
+                    }
+                    self.counters.dropped.fetch_add(lost, Ordering::Relaxed);
+                }
+            }
+        }
+        None
+    }
+    /// Waits for the next record, returns `None` once the queue is closed and drained.
+    pub async fn pop(&self) -> Option<T> {
+        loop {
+            let pushed = self.pushed.notified();
+            tokio::pin!(pushed);
+            pushed.as_mut().enable();
+            if let Some(item) = self.try_pop() {
+                return Some(item);
+            }
+            if self.state.lock().unwrap().closed {
+                return self.try_pop();
+            }
+            pushed.await;
+        }
+    }
+    /// Rejects further pushes and lets the consumer drain what is left.
+    pub fn close(&self) {
+        self.state.lock().unwrap().closed = true;
+        self.pushed.notify_waiters();
+        self.popped.notify_waiters();
+    }
+}
@@ -0,0 +1,110 @@
+//! Redis Streams sink, appending the normalized records to a stream per symbol.
+//!
+//! Stream keys are rendered from a template, see [`render_template`]. Each entry has a `data` field holding the
+//! encoded record and a `content_type` field, and streams are trimmed to about `max_len` entries as they grow.
+use std::sync::Arc;
+
+use async_trait::async_trait;
+use log::info;
+use redis::{aio::ConnectionManager, streams::StreamMaxlen, AsyncCommands};
+use serde::{Deserialize, Serialize};
+
+use super::{
+    encoding::{render_template, Encoding},
+    rotation::Window,
+    Record, Sink, SinkError, SinkFactory, StreamKey,
+};
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct RedisStreamsConfig {
+    /// e.g. `redis://localhost:6379`.
+    pub url: String,
+    /// See [`render_template`].
+    pub key_template: String,
+    pub encoding: Encoding,
+    /// Entries kept per stream, approximately.
+    pub max_len: usize,
+}
+impl Default for RedisStreamsConfig {
+    fn default() -> Self {
+        Self {
+            url: "redis://localhost:6379".to_string(),
+            key_template: "binance.{asset_type}.{stream}.{symbol}".to_string(),
+            encoding: Encoding::Json,
+            max_len: 100_000,
+        }
+    }
+}
+
+/// A connection shared by the sinks of every stream, re-established when it drops.
+pub struct RedisStreamsPublisher {
+    config: RedisStreamsConfig,
+    connection: ConnectionManager,
+}
+impl RedisStreamsPublisher {
+    pub async fn connect(config: RedisStreamsConfig) -> Result<Self, SinkError> {
+        let client = redis::Client::open(config.url.as_str())?;
+        let connection = ConnectionManager::new(client).await?;
+        info!(
+            "Publishing to Redis streams at {} as {:?}",
+            config.url, config.encoding
+        );
+        Ok(Self { config, connection })
+    }
+    pub fn config(&self) -> &RedisStreamsConfig {
+        &self.config
+    }
+}
+
+/// Appends every dataset to Redis streams.
+/// It consumes frames, so the messages carry the times they were received.
+pub struct RedisStreamsSinkFactory {
+    pub publisher: Arc<RedisStreamsPublisher>,
+}
+impl RedisStreamsSinkFactory {
+    pub fn new(publisher: Arc<RedisStreamsPublisher>) -> Self {
+        Self { publisher }
+    }
+}
+impl SinkFactory for RedisStreamsSinkFactory {
+    fn name(&self) -> String {
+        "redis_streams".to_string()
+    }
+    fn consumes_frames(&self) -> bool {
+        true
+    }
+    fn consumes_records(&self) -> bool {
+        true
+    }
+    fn create(&self, key: &StreamKey, _window: &Window) -> Result<Box<dyn Sink>, SinkError> {
+        Ok(Box::new(RedisStreamsSink {
+            connection: self.publisher.connection.clone(),
+            config: self.publisher.config.clone(),
+            key: render_template(&self.publisher.config.key_template, key),
+        }))
+    }
+}
+
+pub struct RedisStreamsSink {
+    connection: ConnectionManager,
+    config: RedisStreamsConfig,
+    key: String,
+}
+
+#[async_trait]
+impl Sink for RedisStreamsSink {
+    async fn write(&mut self, record: &Record) -> Result<(), SinkError> {
+        let data = self.config.encoding.encode(record)?;
+        let content_type = self.config.encoding.content_type().as_bytes();
+        let _id: String = self
+            .connection
+            .xadd_maxlen(
+                &self.key,
+                StreamMaxlen::Approx(self.config.max_len),
+                "*",
+                &[("data", data.as_slice()), ("content_type", content_type)],
+            )
+            .await?;
+        Ok(())
+    }
+}
@@ -0,0 +1,87 @@
+use std::fmt::{Display, Formatter};
+use std::str::FromStr;
+
+use chrono::{DateTime, Duration, TimeZone, Utc};
+use serde::{Deserialize, Serialize};
+
+/// Length of the time windows outputs are split into. Windows are aligned to UTC boundaries,
+/// e.g. hourly windows start on the hour.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub enum RotationInterval {
+    Minute,
+    FiveMinutes,
+    Hour,
+    Day,
+}
+impl RotationInterval {
+    pub fn duration(&self) -> Duration {
+        match self {
+            RotationInterval::Minute => Duration::minutes(1),
+            RotationInterval::FiveMinutes => Duration::minutes(5),
+            RotationInterval::Hour => Duration::hours(1),
+            RotationInterval::Day => Duration::days(1),
+        }
+    }
+    /// Start of the window `time` falls in.
+    pub fn window_start(&self, time: DateTime<Utc>) -> DateTime<Utc> {
+        let length = self.duration().num_seconds();
+        Utc.timestamp_opt(time.timestamp().div_euclid(length) * length, 0)
+            .unwrap()
+    }
+    /// The window `time` falls in.
+    pub fn window(&self, time: DateTime<Utc>) -> Window {
+        let start = self.window_start(time);
+        Window {
+            start,
+            end: start + self.duration(),
+            part: 0,
+        }
+    }
+    /// The first boundary strictly after `time`.
+    pub fn next_boundary(&self, time: DateTime<Utc>) -> DateTime<Utc> {
+        self.window_start(time) + self.duration()
+    }
+}
+impl Display for RotationInterval {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            RotationInterval::Minute => write!(f, "1m"),
+            RotationInterval::FiveMinutes => write!(f, "5m"),
+            RotationInterval::Hour => write!(f, "1h"),
+            RotationInterval::Day => write!(f, "1d"),
+        }
+    }
+}
+impl FromStr for RotationInterval {
+    type Err = String;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "1m" => Ok(RotationInterval::Minute),
+            "5m" => Ok(RotationInterval::FiveMinutes),
+            "1h" => Ok(RotationInterval::Hour),
+            "1d" => Ok(RotationInterval::Day),
+            _ => Err(format!(
+                "Invalid rotation interval {s}, expected 1m, 5m, 1h or 1d"
+            )),
+        }
+    }
+}
+
+/// The time window a sink holds the records of, by exchange event time.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct Window {
+    pub start: DateTime<Utc>,
+    pub end: DateTime<Utc>,
+    /// Counts the sinks already closed for this window, when records arrive after it was rotated.
+    pub part: u32,
+}
+impl Display for Window {
+    /// The window start, e.g. `20230212T150000Z`, followed by the part when there is more than one.
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.start.format("%Y%m%dT%H%M%SZ"))?;
+        if self.part > 0 {
+            write!(f, "_{}", self.part)?;
+        }
+        Ok(())
+    }
+}
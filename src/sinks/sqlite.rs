@@ -0,0 +1,182 @@
+use std::sync::{
+    atomic::{AtomicUsize, Ordering},
+    Arc, Mutex,
+};
+
+use async_trait::async_trait;
+use chrono::Utc;
+use log::{info, warn};
+
+use crate::local_store::LocalStore;
+
+use super::{rotation::Window, Dataset, Record, Sink, SinkError, SinkFactory, StreamKey};
+
+/// Records buffered by a sink before they are inserted without waiting for the next flush.
+const BATCH_SIZE: usize = 1000;
+/// Records kept by all the sinks while the inserts fail, before new ones are refused.
+const MAX_BUFFERED: usize = 500_000;
+
+/// Writes trades, book tickers and book snapshots to a [`LocalStore`].
+/// It consumes frames, so book tickers are stored with the time they were received, and the snapshots of the rest api.
+/// The inserts run on the blocking thread pool.
+pub struct SqliteSinkFactory {
+    pub store: Arc<LocalStore>,
+    backlog: Arc<Backlog>,
+}
+impl SqliteSinkFactory {
+    pub fn new(store: Arc<LocalStore>) -> Self {
+        Self {
+            store,
+            backlog: Arc::default(),
+        }
+    }
+    /// Records buffered by the sinks, or kept from closed ones, waiting to be inserted.
+    pub fn buffered(&self) -> usize {
+        self.backlog.buffered.load(Ordering::SeqCst)
+    }
+}
+
+/// What the sinks of a factory have not inserted yet.
+#[derive(Default)]
+struct Backlog {
+    /// Records held by the sinks and in `kept`, up to `MAX_BUFFERED`.
+    buffered: AtomicUsize,
+    /// Batches of the closed sinks that could not be inserted, with their asset type, inserted on the next flush.
+    kept: Mutex<Vec<(String, Vec<Record>)>>,
+}
+impl SinkFactory for SqliteSinkFactory {
+    fn name(&self) -> String {
+        "sqlite".to_string()
+    }
+    fn consumes_frames(&self) -> bool {
+        true
+    }
+    fn consumes_records(&self) -> bool {
+        true
+    }
+    fn accepts(&self, dataset: Dataset) -> bool {
+        dataset != Dataset::BookHistory
+    }
+    fn create(&self, key: &StreamKey, _window: &Window) -> Result<Box<dyn Sink>, SinkError> {
+        Ok(Box::new(SqliteSink {
+            store: self.store.clone(),
+            asset_type: key.asset_type.clone(),
+            batch: Vec::new(),
+            backlog: self.backlog.clone(),
+        }))
+    }
+}
+
+/// Buffers records and inserts them in one transaction on every flush. They are kept for the next flush if the
+/// insert fails, and handed to the factory when the window closes before they could be inserted.
+pub struct SqliteSink {
+    store: Arc<LocalStore>,
+    asset_type: String,
+    batch: Vec<Record>,
+    backlog: Arc<Backlog>,
+}
+impl SqliteSink {
+    /// Inserts `batch` on the blocking thread pool, and gives it back.
+    async fn insert(
+        &self,
+        asset_type: String,
+        batch: Vec<Record>,
+    ) -> (Vec<Record>, Result<(), SinkError>) {
+        let store = self.store.clone();
+        let inserted = tokio::task::spawn_blocking(move || {
+            let counts = store.insert(&asset_type, &batch, Utc::now());
+            if let Ok(counts) = &counts {
+                if counts.duplicates > 0 {
+                    info!(
+                        "Ignored {} {} records already in the local store",
+                        counts.duplicates, asset_type
+                    );
+                }
+            }
+            (batch, counts)
+        })
+        .await;
+        match inserted {
+            Ok((batch, counts)) => (batch, counts.map(|_| ()).map_err(SinkError::from)),
+            Err(e) => (Vec::new(), Err(e.into())),
+        }
+    }
+    /// Inserts the batches kept from closed sinks, keeping those not inserted for the next flush.
+    async fn insert_kept(&self) -> Result<(), SinkError> {
+        let mut kept = std::mem::take(&mut *self.backlog.kept.lock().unwrap());
+        while let Some((asset_type, batch)) = kept.pop() {
+            let (batch, inserted) = self.insert(asset_type.clone(), batch).await;
+            if let Err(e) = inserted {
+                kept.push((asset_type, batch));
+                self.backlog.kept.lock().unwrap().append(&mut kept);
+                return Err(e);
+            }
+            self.backlog
+                .buffered
+                .fetch_sub(batch.len(), Ordering::SeqCst);
+        }
+        Ok(())
+    }
+}
+
+#[async_trait]
+impl Sink for SqliteSink {
+    async fn write(&mut self, record: &Record) -> Result<(), SinkError> {
+        let buffered = self.backlog.buffered.fetch_add(1, Ordering::SeqCst);
+        if buffered >= MAX_BUFFERED {
+            self.backlog.buffered.fetch_sub(1, Ordering::SeqCst);
+            return Err(format!(
+                "{} records are waiting for the local store already, refusing those of {}",
+                buffered, self.asset_type
+            )
+            .into());
+        }
+        self.batch.push(record.clone());
+        if self.batch.len() % BATCH_SIZE == 0 {
+            self.flush().await?;
+        }
+        Ok(())
+    }
+    async fn flush(&mut self) -> Result<(), SinkError> {
+        self.insert_kept().await?;
+        if self.batch.is_empty() {
+            return Ok(());
+        }
+        let batch = std::mem::take(&mut self.batch);
+        let (batch, inserted) = self.insert(self.asset_type.clone(), batch).await;
+        // Kept for the next flush when the insert failed.
+        self.batch = batch;
+        inserted?;
+        self.backlog
+            .buffered
+            .fetch_sub(self.batch.len(), Ordering::SeqCst);
+        self.batch.clear();
+        Ok(())
+    }
+    async fn close(&mut self) -> Result<(), SinkError> {
+        if let Err(e) = self.flush().await {
+            if !self.batch.is_empty() {
+                warn!(
+                    "Error inserting into the local store, keeping {} records of {} for the next flush: {}",
+                    self.batch.len(),
+                    self.asset_type,
+                    e
+                );
+                let batch = std::mem::take(&mut self.batch);
+                self.backlog
+                    .kept
+                    .lock()
+                    .unwrap()
+                    .push((self.asset_type.clone(), batch));
+            }
+        }
+        Ok(())
+    }
+}
+impl Drop for SqliteSink {
+    fn drop(&mut self) {
+        self.backlog
+            .buffered
+            .fetch_sub(self.batch.len(), Ordering::SeqCst);
+    }
+}
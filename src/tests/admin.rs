@@ -0,0 +1,201 @@
+use std::{
+    collections::HashSet,
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+
+use async_trait::async_trait;
+use serde_json::{json, Value};
+use tokio::{net::TcpListener, sync::mpsc};
+
+use crate::{
+    admin::{AdminApi, AdminConfig},
+    binance::{
+        mock::{MockConfig, MockExchange},
+        models::{orderbook::new_orderbooks_rwl, trades::Trade},
+        websocket::{
+            connection::establish_and_persist_with_control,
+            control::ConnectionControl,
+            handlers::{depth_update::OrderBookMaintainer, EventHandler, EventHandlers},
+            requests::{BinanceAssetType, DataRequest, FuturesType, Stream},
+            watchdog::LivenessConfig,
+        },
+    },
+    bucket_utils::mock::MockS3,
+    data_manager::UploadRequest,
+    sinks::bus::{EventBus, PipelineConfig},
+    upload_queue::{UploadConfig, UploadQueue},
+};
+
+#[derive(Default)]
+struct TradedSymbols {
+    symbols: Mutex<HashSet<String>>,
+}
+
+#[async_trait]
+impl EventHandler for TradedSymbols {
+    async fn on_trade(&self, trade: &Trade) {
+        self.symbols.lock().unwrap().insert(trade.symbol.clone());
+    }
+}
+
+async fn wait_for(condition: impl Fn() -> bool) {
+    let deadline = tokio::time::Instant::now() + Duration::from_secs(10);
+    while !condition() {
+        assert!(tokio::time::Instant::now() < deadline, "timed out");
+        tokio::time::sleep(Duration::from_millis(20)).await;
+    }
+}
+
+#[tokio::test]
+async fn test_inspect_and_change_subscriptions_at_runtime() {
+    let mock = MockExchange::start(MockConfig {
+        symbols: vec!["BTCUSDT".to_string(), "ETHUSDT".to_string()],
+        event_interval: Duration::from_millis(5),
+        ..Default::default()
+    })
+    .await
+    .unwrap();
+    let request = DataRequest::new(
+        BinanceAssetType::Futures(FuturesType::USDMargined),
+        vec![
+            Stream::Trade("BTCUSDT".to_string()),
+            Stream::Depth("BTCUSDT".to_string(), 100),
+        ],
+    )
+    .with_ws_base_urls(vec![mock.ws_base_url()]);
+    let control = Arc::new(ConnectionControl::new(request.clone()));
+    let orderbooks_rwl = new_orderbooks_rwl();
+    let bus = Arc::new(EventBus::new(
+        request.asset_type.clone(),
+        PipelineConfig::default(),
+    ));
+    let folder = std::env::temp_dir().join("binance_data_gatherer_admin_test");
+    _ = std::fs::remove_dir_all(&folder);
+    let s3 = MockS3::start().await.unwrap();
+    let uploads = Arc::new(
+        UploadQueue::open(
+            UploadConfig {
+                folder: folder.join("uploads"),
+                ..Default::default()
+            },
+            Arc::new(s3.bucket("data")),
+        )
+        .unwrap(),
+    );
+    let traded = Arc::new(TradedSymbols::default());
+    let handlers: EventHandlers = vec![
+        Arc::new(OrderBookMaintainer::new(orderbooks_rwl.clone())),
+        bus.clone(),
+        traded.clone(),
+    ];
+    let collector = tokio::spawn(establish_and_persist_with_control(
+        control.clone(),
+        handlers,
+        LivenessConfig::default(),
+    ));
+    // Stands for the task rotating the files, which has nothing to queue.
+    let (upload_requests, mut received) = mpsc::channel::<UploadRequest>(1);
+    tokio::spawn(async move {
+        while let Some(reply) = received.recv().await {
+            _ = reply.send(0);
+        }
+    });
+    let admin = Arc::new(
+        AdminApi::new(
+            AdminConfig::default(),
+            control.clone(),
+            orderbooks_rwl.clone(),
+            bus,
+            uploads,
+        )
+        .with_upload_requests(upload_requests),
+    );
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let url = format!("http://{}", listener.local_addr().unwrap());
+    tokio::spawn(admin.serve(listener));
+    let client = reqwest::Client::new();
+    let get = |path: &str| client.get(format!("{url}{path}")).send();
+
+    wait_for(|| traded.symbols.lock().unwrap().contains("BTCUSDT")).await;
+    // Trades and depth updates are independent streams, so the book may come after the first trade.
+    wait_for(|| {
+        orderbooks_rwl
+            .try_read()
+            .is_ok_and(|books| books.contains_key("BTCUSDT"))
+    })
+    .await;
+    let connections = get("/connections")
+        .await
+        .unwrap()
+        .json::<Value>()
+        .await
+        .unwrap();
+    assert_eq!(connections.as_array().unwrap().len(), 1);
+    let book = get("/books/btcusdt?depth=2").await.unwrap();
+    assert_eq!(book.status(), 200);
+    let book = book.json::<Value>().await.unwrap();
+    assert_eq!(book["symbol"], "BTCUSDT");
+    assert!(book["last_update_id"].as_i64().unwrap() > 0);
+    assert!(book["bids"].as_array().unwrap().len() <= 2);
+    assert_eq!(get("/books/ETHUSDT").await.unwrap().status(), 404);
+
+    let added = client
+        .post(format!("{url}/symbols/ethusdt"))
+        .send()
+        .await
+        .unwrap()
+        .json::<Value>()
+        .await
+        .unwrap();
+    assert_eq!(
+        added,
+        json!({"subscribed": ["ethusdt@trade", "ethusdt@depth@100ms"]})
+    );
+    wait_for(|| traded.symbols.lock().unwrap().contains("ETHUSDT")).await;
+    let removed = client
+        .delete(format!("{url}/symbols/BTCUSDT"))
+        .send()
+        .await
+        .unwrap()
+        .json::<Value>()
+        .await
+        .unwrap();
+    assert_eq!(
+        removed,
+        json!({"unsubscribed": ["btcusdt@trade", "btcusdt@depth@100ms"]})
+    );
+    let subscriptions = get("/subscriptions")
+        .await
+        .unwrap()
+        .json::<Value>()
+        .await
+        .unwrap();
+    assert_eq!(
+        subscriptions,
+        json!(["ethusdt@trade", "ethusdt@depth@100ms"])
+    );
+    // Still the same connection, the changes were sent on it.
+    assert_eq!(mock.connections(), 1);
+
+    let flushed = client
+        .post(format!("{url}/flush"))
+        .send()
+        .await
+        .unwrap()
+        .json::<Value>()
+        .await
+        .unwrap();
+    assert_eq!(flushed["buffered"], 0);
+    let uploaded = client
+        .post(format!("{url}/upload"))
+        .send()
+        .await
+        .unwrap()
+        .json::<Value>()
+        .await
+        .unwrap();
+    assert_eq!(uploaded, json!({"queued": 0}));
+    collector.abort();
+    std::fs::remove_dir_all(&folder).unwrap();
+}
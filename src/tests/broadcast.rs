@@ -0,0 +1,113 @@
+use std::sync::Arc;
+use std::time::Duration;
+
+use futures_util::{SinkExt, StreamExt};
+use serde_json::{json, Value};
+use tokio::net::TcpListener;
+use tokio_tungstenite::tungstenite::{self, protocol::frame::coding::CloseCode, Message};
+
+use crate::{
+    binance::{
+        models::orderbook::new_orderbooks_rwl,
+        websocket::{
+            connection::dispatch,
+            handlers::{depth_update::OrderBookMaintainer, EventHandler, EventHandlers},
+            router::route,
+        },
+    },
+    broadcast::{BroadcastConfig, Broadcaster},
+    tests::fixtures::{depth, TRADE},
+};
+
+async fn start(client_buffer: usize) -> (Arc<Broadcaster>, EventHandlers, String) {
+    let orderbooks_rwl = new_orderbooks_rwl();
+    let broadcaster = Arc::new(Broadcaster::new(
+        BroadcastConfig {
+            client_buffer,
+            ..Default::default()
+        },
+        orderbooks_rwl.clone(),
+    ));
+    let handlers: EventHandlers = vec![
+        Arc::new(OrderBookMaintainer::new(orderbooks_rwl)),
+        broadcaster.clone(),
+    ];
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let url = format!("ws://{}", listener.local_addr().unwrap());
+    tokio::spawn(broadcaster.clone().serve(listener));
+    (broadcaster, handlers, url)
+}
+
+async fn next_json<S>(socket: &mut S) -> Value
+where
+    S: StreamExt<Item = Result<Message, tungstenite::Error>> + Unpin,
+{
+    let message = tokio::time::timeout(Duration::from_secs(5), socket.next())
+        .await
+        .unwrap();
+    match message.unwrap().unwrap() {
+        Message::Text(text) => serde_json::from_str(&text).unwrap(),
+        other => panic!("unexpected {:?}", other),
+    }
+}
+
+#[tokio::test]
+async fn test_depth_subscribers_get_a_snapshot_then_diffs() {
+    let (broadcaster, handlers, url) = start(100).await;
+    for handler in handlers.iter() {
+        handler.on_depth(&depth(10, 12, 9, 2_180_000)).await;
+    }
+    let (mut socket, _) = tokio_tungstenite::connect_async(url).await.unwrap();
+    let request =
+        json!({"method": "SUBSCRIBE", "params": ["btcusdt@depth", "btcusdt@trade"], "id": 1});
+    socket
+        .send(Message::Text(request.to_string()))
+        .await
+        .unwrap();
+    assert_eq!(
+        next_json(&mut socket).await,
+        json!({"result": null, "id": 1})
+    );
+    let snapshot = next_json(&mut socket).await;
+    assert_eq!(snapshot["stream"], "btcusdt@depth");
+    assert_eq!(snapshot["data"]["e"], "depthSnapshot");
+    assert_eq!(snapshot["data"]["u"], 12);
+    assert_eq!(snapshot["data"]["b"][0][0], "21800.00");
+
+    // Diffs already in the snapshot are skipped.
+    broadcaster.on_depth(&depth(11, 12, 10, 2_180_000)).await;
+    for handler in handlers.iter() {
+        handler.on_depth(&depth(13, 14, 12, 2_180_000)).await;
+    }
+    dispatch(&route(TRADE).unwrap(), &handlers).await;
+    let diff = next_json(&mut socket).await;
+    assert_eq!(diff["data"]["U"], 13);
+    assert_eq!(diff["data"]["u"], 14);
+    let trade = next_json(&mut socket).await;
+    assert_eq!(trade["stream"], "btcusdt@trade");
+    assert_eq!(trade["data"]["tradeId"], 1);
+}
+
+#[tokio::test]
+async fn test_slow_consumers_are_disconnected() {
+    let (broadcaster, handlers, url) = start(1).await;
+    let (mut socket, _) = tokio_tungstenite::connect_async(url).await.unwrap();
+    let request = json!({"method": "SUBSCRIBE", "params": ["btcusdt@trade"], "id": 1});
+    socket
+        .send(Message::Text(request.to_string()))
+        .await
+        .unwrap();
+    socket.next().await.unwrap().unwrap();
+    // Broadcast back to back, the second trade finds the queue still full.
+    dispatch(&route(TRADE).unwrap(), &handlers).await;
+    dispatch(&route(TRADE).unwrap(), &handlers).await;
+    assert_eq!(broadcaster.clients().await, 0);
+    let close = loop {
+        match socket.next().await.unwrap().unwrap() {
+            Message::Close(close) => break close.unwrap(),
+            Message::Text(_) => {}
+            other => panic!("unexpected {:?}", other),
+        }
+    };
+    assert_eq!(close.code, CloseCode::Policy);
+}
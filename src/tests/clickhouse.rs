@@ -0,0 +1,250 @@
+use std::sync::{
+    atomic::{AtomicUsize, Ordering},
+    Arc, Mutex,
+};
+use std::time::Duration;
+
+use chrono::{TimeZone, Utc};
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt},
+    net::{TcpListener, TcpStream},
+};
+
+use crate::{
+    binance::websocket::{
+        handlers::{EventHandler, RawFrame},
+        requests::{BinanceAssetType, FuturesType},
+        router::route,
+    },
+    sinks::{
+        bus::{EventBus, PipelineConfig},
+        clickhouse::{ClickHouseConfig, ClickHouseSinkFactory, ClickHouseWriter, LevelRow},
+    },
+};
+
+const FRAMES: [&str; 2] = [
+    r#"{"stream":"btcusdt@depth@100ms","data":{"e":"depthUpdate","E":1676214000123,"s":"BTCUSDT","U":10,"u":12,"pu":9,"b":[["21800.00","1.204"]],"a":[["21803.50","3.019"],["21803.60","0"]]}}"#,
+    r#"{"stream":"btcusdt@trade","data":{"e":"trade","E":1676214000123,"T":1676214000120,"s":"BTCUSDT","t":1,"p":"21803.40","q":"0.015","X":"MARKET","m":false}}"#,
+];
+
+/// Queries received by [`serve`], with their bodies.
+type Queries = Arc<Mutex<Vec<(String, String)>>>;
+
+/// Answers like the HTTP interface of ClickHouse, failing the next `failures` inserts.
+async fn serve(listener: TcpListener, queries: Queries, failures: Arc<AtomicUsize>) {
+    while let Ok((stream, _)) = listener.accept().await {
+        tokio::spawn(answer(stream, queries.clone(), failures.clone()));
+    }
+}
+
+async fn answer(mut stream: TcpStream, queries: Queries, failures: Arc<AtomicUsize>) {
+    let mut received = Vec::new();
+    let mut buffer = [0u8; 8192];
+    let head_end = loop {
+        if let Some(end) = received.windows(4).position(|w| w == b"\r\n\r\n") {
+            break end;
+        }
+        match stream.read(&mut buffer).await {
+            Ok(0) | Err(_) => return,
+            Ok(read) => received.extend_from_slice(&buffer[..read]),
+        }
+    };
+    let head = String::from_utf8_lossy(&received[..head_end]).to_string();
+    let path = head.split_whitespace().nth(1).unwrap();
+    let url = url::Url::parse(&format!("http://localhost{}", path)).unwrap();
+    let query = url
+        .query_pairs()
+        .find(|(name, _)| name == "query")
+        .map(|(_, query)| query.into_owned())
+        .unwrap();
+    let length = head
+        .lines()
+        .filter_map(|line| line.split_once(':'))
+        .find(|(name, _)| name.eq_ignore_ascii_case("content-length"))
+        .map(|(_, length)| length.trim().parse().unwrap())
+        .unwrap_or(0);
+    let mut body = received.split_off(head_end + 4);
+    while body.len() < length {
+        match stream.read(&mut buffer).await {
+            Ok(0) | Err(_) => return,
+            Ok(read) => body.extend_from_slice(&buffer[..read]),
+        }
+    }
+    let fail = query.starts_with("INSERT")
+        && failures
+            .fetch_update(Ordering::SeqCst, Ordering::SeqCst, |left| {
+                left.checked_sub(1)
+            })
+            .is_ok();
+    let status = if fail {
+        "500 Internal Server Error"
+    } else {
+        queries
+            .lock()
+            .unwrap()
+            .push((query, String::from_utf8(body).unwrap()));
+        "200 OK"
+    };
+    let response = format!("HTTP/1.1 {status}\r\nContent-Length: 0\r\nConnection: close\r\n\r\n");
+    _ = stream.write_all(response.as_bytes()).await;
+}
+
+#[tokio::test]
+async fn test_clickhouse_batches_and_retries() {
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let url = format!("http://{}", listener.local_addr().unwrap());
+    let queries = Queries::default();
+    let failures = Arc::new(AtomicUsize::new(0));
+    tokio::spawn(serve(listener, queries.clone(), failures.clone()));
+    let writer = Arc::new(
+        ClickHouseWriter::connect(ClickHouseConfig {
+            url,
+            batch_rows: 2,
+            initial_backoff: Duration::ZERO,
+            ..Default::default()
+        })
+        .await
+        .unwrap(),
+    );
+    assert!(queries.lock().unwrap()[0]
+        .0
+        .starts_with("CREATE TABLE IF NOT EXISTS default.depth_levels"));
+    let bus = EventBus::new(
+        BinanceAssetType::Futures(FuturesType::USDMargined),
+        PipelineConfig::default(),
+    )
+    .with_sink(Arc::new(ClickHouseSinkFactory::new(writer.clone())));
+    let received = Utc.timestamp_millis_opt(1_676_214_000_200).unwrap();
+    for text in FRAMES {
+        bus.on_frame(&RawFrame {
+            received,
+            connection_id: 1,
+            frame: Some(&route(text).unwrap()),
+            text,
+        })
+        .await;
+    }
+    // Trades are not sent to ClickHouse.
+    assert_eq!(bus.stats().len(), 1);
+    bus.shutdown().await;
+    assert_eq!(writer.buffered(), 3);
+
+    // The rows are kept when every attempt fails.
+    failures.store(6, Ordering::SeqCst);
+    assert_eq!(writer.flush().await, 0);
+    assert_eq!(writer.buffered(), 3);
+    failures.store(1, Ordering::SeqCst);
+    assert_eq!(writer.flush().await, 3);
+    assert_eq!(writer.buffered(), 0);
+    let queries = queries.lock().unwrap();
+    let inserts = queries
+        .iter()
+        .filter(|(query, _)| query == "INSERT INTO default.depth_levels FORMAT JSONEachRow")
+        .collect::<Vec<_>>();
+    assert_eq!(inserts.len(), 2);
+    let rows = inserts
+        .iter()
+        .flat_map(|(_, body)| body.lines())
+        .map(|line| serde_json::from_str::<LevelRow>(line).unwrap())
+        .collect::<Vec<_>>();
+    assert_eq!(
+        rows.iter()
+            .map(|row| (row.side.as_str(), row.price.to_string()))
+            .collect::<Vec<_>>(),
+        [
+            ("BID", "21800.00".to_string()),
+            ("ASK", "21803.50".to_string()),
+            ("ASK", "21803.60".to_string())
+        ]
+    );
+    assert_eq!(
+        (
+            rows[2].last_update_id,
+            rows[2].received,
+            rows[2].received_corrected
+        ),
+        (12, received, None)
+    );
+    assert!(inserts[0]
+        .1
+        .contains(r#""event_time":"2023-02-12 15:00:00.123""#));
+}
+
+#[tokio::test]
+async fn test_clickhouse_sets_up_lazily() {
+    // Nothing listens on the port until the server starts.
+    let address = TcpListener::bind("127.0.0.1:0")
+        .await
+        .unwrap()
+        .local_addr()
+        .unwrap();
+    let writer = Arc::new(ClickHouseWriter::new(ClickHouseConfig {
+        url: format!("http://{}", address),
+        initial_backoff: Duration::ZERO,
+        ..Default::default()
+    }));
+    let bus = EventBus::new(
+        BinanceAssetType::Futures(FuturesType::USDMargined),
+        PipelineConfig::default(),
+    )
+    .with_sink(Arc::new(ClickHouseSinkFactory::new(writer.clone())));
+    let text = FRAMES[0];
+    bus.on_frame(&RawFrame {
+        received: Utc.timestamp_millis_opt(1_676_214_000_200).unwrap(),
+        connection_id: 1,
+        frame: Some(&route(text).unwrap()),
+        text,
+    })
+    .await;
+    bus.shutdown().await;
+    assert_eq!(writer.flush().await, 0);
+    assert_eq!(writer.buffered(), 3);
+
+    let queries = Queries::default();
+    let listener = TcpListener::bind(address).await.unwrap();
+    tokio::spawn(serve(listener, queries.clone(), Arc::default()));
+    assert_eq!(writer.flush().await, 3);
+    assert!(queries.lock().unwrap()[0]
+        .0
+        .starts_with("CREATE TABLE IF NOT EXISTS default.depth_levels"));
+}
+
+/// Run with `CLICKHOUSE_TEST_URL=http://localhost:8123 cargo test clickhouse -- --ignored`,
+/// e.g. against `docker run -p 8123:8123 clickhouse/clickhouse-server`.
+#[tokio::test]
+#[ignore]
+async fn test_clickhouse_server() {
+    let url = std::env::var("CLICKHOUSE_TEST_URL").unwrap();
+    let writer = ClickHouseWriter::connect(ClickHouseConfig {
+        url: url.clone(),
+        table: "depth_levels_test".to_string(),
+        ..Default::default()
+    })
+    .await
+    .unwrap();
+    let update =
+        serde_json::from_str(&FRAMES[0][FRAMES[0].find(r#"{"e""#).unwrap()..FRAMES[0].len() - 1])
+            .unwrap();
+    let rows = LevelRow::from_update("USDM_FUT", &update, Utc::now(), None);
+    // The same batch twice is deduplicated.
+    for _ in 0..2 {
+        writer.push(rows.clone()).await;
+        assert_eq!(writer.flush().await, 3);
+    }
+    let count = reqwest::Client::new()
+        .post(&url)
+        .query(&[("query", "SELECT count() FROM default.depth_levels_test")])
+        .send()
+        .await
+        .unwrap()
+        .text()
+        .await
+        .unwrap();
+    assert_eq!(count.trim(), "3");
+    reqwest::Client::new()
+        .post(&url)
+        .query(&[("query", "DROP TABLE default.depth_levels_test")])
+        .send()
+        .await
+        .unwrap();
+}
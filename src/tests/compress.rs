@@ -1,13 +1,132 @@
-#[cfg(test)]
+use std::io::{Read, Write};
+use std::sync::Arc;
 
-#[tokio::test]
-async fn test_compress() {
-    use crate::file_compress::compress_file;
-    compress_file("outgoing/trades.csv");
+use crate::{
+    binance::websocket::requests::{BinanceAssetType, FuturesType},
+    file_compress::{compress_file, decompress_file, Codec},
+    sinks::{
+        bus::{EventBus, PipelineConfig},
+        csv_file::CsvFileSinkFactory,
+        Record,
+    },
+};
+
+const CSV: &str = "symbol,price\nBTCUSDT,23000.1\nBTCUSDT,23000.2\n";
+
+fn codecs() -> Vec<Codec> {
+    vec![
+        Codec::None,
+        Codec::Bzip2 { level: 9 },
+        Codec::Gzip { level: 6 },
+        Codec::Lz4,
+        Codec::Zstd {
+            level: 19,
+            dictionary: None,
+        },
+    ]
+}
+
+#[test]
+fn test_codecs_round_trip() {
+    let dictionary = std::env::temp_dir().join("binance_data_gatherer_zstd.dict");
+    std::fs::write(&dictionary, CSV.repeat(4)).unwrap();
+    let mut codecs = codecs();
+    codecs.push(Codec::Zstd {
+        level: 3,
+        dictionary: Some(dictionary),
+    });
+    for codec in codecs {
+        let mut encoder = codec.encoder(Vec::new()).unwrap();
+        encoder.write_all(CSV.as_bytes()).unwrap();
+        let compressed = encoder.finish().unwrap();
+        let mut decompressed = String::new();
+        codec
+            .decoder(compressed.as_slice())
+            .unwrap()
+            .read_to_string(&mut decompressed)
+            .unwrap();
+        assert_eq!(decompressed, CSV, "{:?}", codec);
+    }
+}
+
+#[test]
+fn test_invalid_levels_are_rejected() {
+    for codec in [
+        Codec::Bzip2 { level: 0 },
+        Codec::Bzip2 { level: 10 },
+        Codec::Gzip { level: 10 },
+    ] {
+        let error = codec.encoder(Vec::new()).err().unwrap();
+        assert_eq!(
+            error.kind(),
+            std::io::ErrorKind::InvalidInput,
+            "{:?}",
+            codec
+        );
+    }
 }
 
-// #[tokio::test]
-// async fn test_decompress() {
-//     use crate::file_compress::decompress_file;
-//     decompress_file("outgoing/trades.csv.bz2");
-// }
\ No newline at end of file
+#[test]
+fn test_flushing_leaves_the_stream_whole() {
+    for codec in codecs() {
+        let encode = |flush: bool| {
+            let mut encoder = codec.encoder(Vec::new()).unwrap();
+            for line in CSV.lines().cycle().take(100) {
+                writeln!(encoder, "{}", line).unwrap();
+                if flush {
+                    encoder.flush().unwrap();
+                }
+            }
+            encoder.finish().unwrap()
+        };
+        assert_eq!(encode(true), encode(false), "{:?}", codec);
+    }
+}
+
+#[test]
+fn test_compress() {
+    for codec in codecs() {
+        let filepath = std::env::temp_dir().join("binance_data_gatherer_trades.csv");
+        std::fs::write(&filepath, CSV).unwrap();
+        let compressed = compress_file(filepath.to_str().unwrap(), &codec).unwrap();
+        assert!(compressed.ends_with(codec.extension()));
+        assert_eq!(filepath.exists(), codec == Codec::None);
+        let mut decompressed = String::new();
+        decompress_file(compressed.as_ref())
+            .unwrap()
+            .read_to_string(&mut decompressed)
+            .unwrap();
+        assert_eq!(decompressed, CSV, "{:?}", codec);
+        std::fs::remove_file(compressed).unwrap();
+    }
+}
+
+#[tokio::test]
+async fn test_sink_writes_compressed_files() {
+    let folder = std::env::temp_dir().join("binance_data_gatherer_compressed_sink_test");
+    _ = std::fs::remove_dir_all(&folder);
+    let bus = EventBus::new(
+        BinanceAssetType::Futures(FuturesType::USDMargined),
+        PipelineConfig::default(),
+    )
+    .with_sink(Arc::new(CsvFileSinkFactory::new(&folder).with_codec(
+        Codec::Zstd {
+            level: 3,
+            dictionary: None,
+        },
+    )));
+    let trade = serde_json::from_str(
+        r#"{"e":"trade","E":1676214000100,"T":1676214000099,"s":"BTCUSDT","t":1,"p":"21800.10","q":"0.010","X":"MARKET","m":true}"#,
+    )
+    .unwrap();
+    bus.publish(Record::Trade(trade)).await;
+    bus.shutdown().await;
+    let path = folder.join("USDM_FUT_BTCUSDT_TRADES_20230212T150000Z.csv.zst");
+    let mut trades = String::new();
+    decompress_file(&path)
+        .unwrap()
+        .read_to_string(&mut trades)
+        .unwrap();
+    assert_eq!(trades.lines().count(), 2);
+    assert!(trades.contains("21800.10"));
+}
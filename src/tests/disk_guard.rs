@@ -0,0 +1,102 @@
+use std::fs::File;
+use std::sync::Arc;
+use std::time::{Duration, SystemTime};
+
+use chrono::Utc;
+
+use crate::{
+    binance::{
+        models::book_ticker::BookTicker,
+        websocket::{
+            handlers::EventHandler,
+            requests::{BinanceAssetType, FuturesType},
+        },
+    },
+    disk_guard::{DiskConfig, DiskGuard, FolderPolicy},
+    sinks::{
+        bus::{EventBus, PipelineConfig},
+        csv_file::CsvFileSinkFactory,
+    },
+};
+
+#[test]
+fn test_retention_by_age_and_size() {
+    let folder = std::env::temp_dir().join("binance_data_gatherer_retention_test");
+    _ = std::fs::remove_dir_all(&folder);
+    std::fs::create_dir_all(folder.join("nested")).unwrap();
+    let now = SystemTime::now();
+    let files = [
+        ("a", Duration::from_secs(3 * 3600)),
+        ("nested/b", Duration::from_secs(1800)),
+        ("c", Duration::from_secs(1200)),
+        ("d", Duration::from_secs(600)),
+        ("e", Duration::ZERO),
+    ];
+    for (name, age) in files {
+        std::fs::write(folder.join(name), [0u8; 100]).unwrap();
+        File::options()
+            .write(true)
+            .open(folder.join(name))
+            .unwrap()
+            .set_modified(now - age)
+            .unwrap();
+    }
+    let guard = DiskGuard::new(DiskConfig {
+        folders: vec![FolderPolicy::retained(
+            &folder,
+            Some(Duration::from_secs(3600)),
+            Some(300),
+        )],
+        min_free_bytes: 0,
+        ..Default::default()
+    });
+    let usage = guard.check();
+    // `a` is too old, then `b` goes to get down to 300 bytes.
+    assert_eq!(usage.deleted_files, 2);
+    assert_eq!(usage.folders[0].bytes, 300);
+    assert!(!folder.join("a").exists() && !folder.join("nested/b").exists());
+    assert!(folder.join("c").exists());
+    assert!(usage.free_bytes.is_some());
+    assert!(!usage.degraded);
+}
+
+#[tokio::test]
+async fn test_low_disk_space_sheds_low_priority_streams() {
+    let folder = std::env::temp_dir().join("binance_data_gatherer_shedding_test");
+    _ = std::fs::remove_dir_all(&folder);
+    let bus = Arc::new(
+        EventBus::new(
+            BinanceAssetType::Futures(FuturesType::USDMargined),
+            PipelineConfig::default(),
+        )
+        .with_sink(Arc::new(CsvFileSinkFactory::new(&folder))),
+    );
+    let guard = DiskGuard::new(DiskConfig {
+        folders: vec![FolderPolicy::tracked(&folder)],
+        min_free_bytes: u64::MAX,
+        resume_free_bytes: u64::MAX,
+        ..Default::default()
+    })
+    .with_shedder(bus.clone());
+    assert!(guard.check().degraded);
+    let ticker: BookTicker = serde_json::from_str(
+        r#"{"u":1,"s":"BTCUSDT","b":"21800.10","B":"1.5","a":"21800.20","A":"2.0"}"#,
+    )
+    .unwrap();
+    bus.on_book_ticker(&ticker).await;
+    let trade = serde_json::from_str(
+        r#"{"e":"trade","E":1676214000100,"T":1676214000099,"s":"BTCUSDT","t":1,"p":"21800.10","q":"0.010","X":"MARKET","m":true}"#,
+    )
+    .unwrap();
+    bus.on_trade(&trade).await;
+    bus.rotate(Utc::now()).await;
+    for stats in bus.stats() {
+        if stats.stream.ends_with("BOOK_TICKER") {
+            assert_eq!((stats.counters.dropped, stats.counters.written), (1, 0));
+        } else {
+            assert_eq!((stats.counters.dropped, stats.counters.written), (0, 1));
+        }
+    }
+    assert!(guard.last_usage().unwrap().degraded);
+    bus.shutdown().await;
+}
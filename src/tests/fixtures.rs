@@ -0,0 +1,36 @@
+//! Frames and events shared by the tests.
+use chrono::{TimeZone, Utc};
+use rust_decimal::Decimal;
+
+use crate::binance::models::orderbook::{OrderbookMessage, PriceSize};
+
+/// A trade on a futures combined stream.
+pub const TRADE: &str = r#"{"stream":"btcusdt@trade","data":{"e":"trade","E":1676214000123,"T":1676214000120,"s":"BTCUSDT","t":1,"p":"21803.40","q":"0.015","X":"MARKET","m":false}}"#;
+/// The payload of [`TRADE`].
+pub const TRADE_DATA: &str = r#"{"e":"trade","E":1676214000123,"T":1676214000120,"s":"BTCUSDT","t":1,"p":"21803.40","q":"0.015","X":"MARKET","m":false}"#;
+/// A depth update on a futures combined stream, removing an ask.
+pub const DEPTH: &str = r#"{"stream":"btcusdt@depth@100ms","data":{"e":"depthUpdate","E":1676214000123,"s":"BTCUSDT","U":10,"u":12,"pu":9,"b":[["21800.00","1.204"]],"a":[["21803.50","3.019"],["21803.60","0"]]}}"#;
+
+/// [`TRADE`] with the trade id `id`.
+pub fn trade(id: i64) -> String {
+    format!(
+        r#"{{"stream":"btcusdt@trade","data":{{"e":"trade","E":1676214000123,"T":1676214000120,"s":"BTCUSDT","t":{id},"p":"21803.40","q":"0.015","X":"MARKET","m":false}}}}"#
+    )
+}
+
+/// A depth update of BTCUSDT with a single bid at `price` hundredths.
+pub fn depth(first: i64, last: i64, previous: i64, price: i64) -> OrderbookMessage {
+    OrderbookMessage {
+        event_type: "depthUpdate".to_string(),
+        time: Utc.timestamp_millis_opt(1_676_214_000_123).unwrap(),
+        symbol: "BTCUSDT".to_string(),
+        first_update_id: first,
+        last_update_id: last,
+        bids: vec![PriceSize {
+            price: Decimal::new(price, 2),
+            size: Decimal::new(1_204, 3),
+        }],
+        asks: vec![],
+        prev_last_update_id: Some(previous),
+    }
+}
@@ -0,0 +1,109 @@
+use std::sync::Arc;
+use std::time::Duration;
+
+use futures_util::StreamExt;
+use tokio::net::TcpListener;
+use tonic::{transport::Channel, Code};
+
+use crate::{
+    binance::{
+        models::orderbook::new_orderbooks_rwl,
+        websocket::{
+            connection::dispatch,
+            handlers::{depth_update::OrderBookMaintainer, EventHandlers},
+            router::route,
+        },
+    },
+    grpc::{GrpcConfig, MarketDataClient, MarketDataService},
+    proto::{GetBookAtRequest, GetOrderBookRequest, GetRecentTradesRequest, StreamRequest},
+    tests::fixtures::{depth, TRADE},
+};
+
+async fn start() -> (EventHandlers, MarketDataClient<Channel>) {
+    let orderbooks_rwl = new_orderbooks_rwl();
+    let service = Arc::new(MarketDataService::new(
+        GrpcConfig::default(),
+        orderbooks_rwl.clone(),
+    ));
+    let handlers: EventHandlers = vec![
+        Arc::new(OrderBookMaintainer::new(orderbooks_rwl)),
+        service.clone(),
+    ];
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let url = format!("http://{}", listener.local_addr().unwrap());
+    tokio::spawn(service.serve(listener));
+    (handlers, MarketDataClient::connect(url).await.unwrap())
+}
+
+#[tokio::test]
+async fn test_books_are_served_by_update_id() {
+    let (handlers, mut client) = start().await;
+    for update in [depth(10, 12, 9, 2_180_000), depth(13, 14, 12, 2_180_100)] {
+        for handler in handlers.iter() {
+            handler.on_depth(&update).await;
+        }
+    }
+    let book = client
+        .get_order_book(GetOrderBookRequest {
+            symbol: "btcusdt".to_string(),
+            depth: 1,
+        })
+        .await
+        .unwrap()
+        .into_inner();
+    assert_eq!(book.last_update_id, 14);
+    assert_eq!(book.bids.len(), 1);
+    assert_eq!(book.bids[0].price, "21801.00");
+
+    let book = client
+        .get_book_at(GetBookAtRequest {
+            symbol: "BTCUSDT".to_string(),
+            update_id: 11,
+            depth: 0,
+        })
+        .await
+        .unwrap()
+        .into_inner();
+    assert_eq!(book.last_update_id, 12);
+    assert_eq!(book.bids.len(), 1);
+
+    let missing = client
+        .get_book_at(GetBookAtRequest {
+            symbol: "BTCUSDT".to_string(),
+            update_id: 100,
+            depth: 0,
+        })
+        .await
+        .unwrap_err();
+    assert_eq!(missing.code(), Code::NotFound);
+}
+
+#[tokio::test]
+async fn test_trades_are_streamed_and_kept() {
+    let (handlers, mut client) = start().await;
+    let mut trades = client
+        .stream_trades(StreamRequest {
+            symbols: vec!["btcusdt".to_string()],
+        })
+        .await
+        .unwrap()
+        .into_inner();
+    dispatch(&route(TRADE).unwrap(), &handlers).await;
+    let trade = tokio::time::timeout(Duration::from_secs(5), trades.next())
+        .await
+        .unwrap()
+        .unwrap()
+        .unwrap();
+    assert_eq!(trade.trade_id, 1);
+    assert_eq!(trade.price, "21803.40");
+
+    let recent = client
+        .get_recent_trades(GetRecentTradesRequest {
+            symbol: "BTCUSDT".to_string(),
+            limit: 10,
+        })
+        .await
+        .unwrap()
+        .into_inner();
+    assert_eq!(recent.trades, vec![trade]);
+}
@@ -0,0 +1,68 @@
+use std::sync::{
+    atomic::{AtomicUsize, Ordering},
+    Arc,
+};
+
+use async_trait::async_trait;
+use chrono::Utc;
+
+use crate::binance::{
+    models::{orderbook::new_orderbooks_rwl, trades::Trade},
+    websocket::{
+        connection::dispatch,
+        handlers::{depth_update::OrderBookMaintainer, EventHandler, EventHandlers, RawFrame},
+        router::route,
+    },
+};
+
+#[derive(Default)]
+struct TradeCounter {
+    trades: AtomicUsize,
+}
+
+#[async_trait]
+impl EventHandler for TradeCounter {
+    async fn on_trade(&self, _trade: &Trade) {
+        self.trades.fetch_add(1, Ordering::SeqCst);
+    }
+}
+
+#[tokio::test]
+async fn test_dispatch_to_custom_handlers() {
+    let counter = Arc::new(TradeCounter::default());
+    let orderbooks_rwl = new_orderbooks_rwl();
+    let maintainer = Arc::new(OrderBookMaintainer::new(orderbooks_rwl.clone()));
+    let handlers: EventHandlers = vec![counter.clone(), maintainer.clone()];
+    let trade = r#"{"stream":"btcusdt@trade","data":{"e":"trade","E":1676214000123,"T":1676214000120,"s":"BTCUSDT","t":1,"p":"21803.40","q":"0.015","m":false}}"#;
+    let depth = r#"{"stream":"btcusdt@depth@100ms","data":{"e":"depthUpdate","E":1676214000123,"s":"BTCUSDT","U":10,"u":12,"pu":9,"b":[["21800.00","1.204"]],"a":[["21803.50","3.019"]]}}"#;
+    let eth_depth = depth
+        .replace("btcusdt", "ethusdt")
+        .replace("BTCUSDT", "ETHUSDT");
+    // The btcusdt depth is delivered by connection 1, the ethusdt one by connection 2.
+    for (connection_id, text) in [(1, trade), (1, trade), (1, depth), (2, eth_depth.as_str())] {
+        let frame = route(text).unwrap();
+        dispatch(&frame, &handlers).await;
+        for handler in handlers.iter() {
+            handler
+                .on_frame(&RawFrame {
+                    received: Utc::now(),
+                    connection_id,
+                    frame: Some(&frame),
+                    text,
+                })
+                .await;
+        }
+    }
+    assert_eq!(counter.trades.load(Ordering::SeqCst), 2);
+    assert_eq!(orderbooks_rwl.read().await["BTCUSDT"][0].last_update_id, 12);
+    // Only the books fed by the connection that ended are dropped.
+    maintainer
+        .on_disconnect(1, "wss://fstream.binance.com")
+        .await;
+    assert!(!orderbooks_rwl.read().await.contains_key("BTCUSDT"));
+    assert!(orderbooks_rwl.read().await.contains_key("ETHUSDT"));
+    maintainer
+        .on_disconnect(2, "wss://fstream.binance.com")
+        .await;
+    assert!(orderbooks_rwl.read().await.is_empty());
+}
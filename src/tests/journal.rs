@@ -0,0 +1,227 @@
+use std::{
+    io::Write,
+    path::PathBuf,
+    sync::{Arc, Mutex},
+};
+
+use async_trait::async_trait;
+use chrono::{DateTime, Duration, TimeZone, Utc};
+
+use crate::{
+    binance::{
+        models::trades::Trade,
+        websocket::{
+            connection::dispatch,
+            handlers::{EventHandler, EventHandlers, RawFrame},
+            requests::{BinanceAssetType, FuturesType},
+            router::route,
+        },
+    },
+    journal::{Journal, JournalConfig},
+    sinks::{
+        bus::{EventBus, PipelineConfig},
+        csv_file::CsvFileSinkFactory,
+        part_files::{remove_set_aside, set_aside_parts},
+    },
+    tests::fixtures::trade,
+};
+
+#[derive(Default)]
+struct TradeIds {
+    ids: Mutex<Vec<i64>>,
+}
+
+#[async_trait]
+impl EventHandler for TradeIds {
+    async fn on_trade(&self, trade: &Trade) {
+        self.ids.lock().unwrap().push(trade.trade_id);
+    }
+}
+
+fn config(name: &str, segment_size: u64) -> JournalConfig {
+    let folder = std::env::temp_dir().join(name);
+    _ = std::fs::remove_dir_all(&folder);
+    JournalConfig {
+        folder,
+        segment_size,
+        ..Default::default()
+    }
+}
+
+async fn journal_trades(journal: &Journal, ids: std::ops::Range<i64>) {
+    journal_trades_at(journal, ids, 1_676_214_000_123).await;
+}
+
+async fn journal_trades_at(journal: &Journal, ids: std::ops::Range<i64>, time: i64) {
+    for id in ids {
+        let text = trade(id).replace("1676214000123", &time.to_string());
+        let routed = route(&text).unwrap();
+        let frame = RawFrame {
+            received: Utc::now(),
+            connection_id: 1,
+            frame: Some(&routed),
+            text: &text,
+        };
+        journal.on_frame(&frame).await;
+    }
+}
+
+fn segments(folder: &PathBuf) -> usize {
+    std::fs::read_dir(folder)
+        .unwrap()
+        .filter(|entry| {
+            entry
+                .as_ref()
+                .unwrap()
+                .path()
+                .extension()
+                .unwrap_or_default()
+                == "wal"
+        })
+        .count()
+}
+
+#[tokio::test]
+async fn test_replay_after_crash() {
+    let config = config("binance_data_gatherer_journal_replay", 1 << 20);
+    let journal = Journal::open(config.clone()).unwrap();
+    journal_trades(&journal, 0..3).await;
+    let text = r#"{"result":null,"id":1}"#;
+    let routed = route(text).unwrap();
+    let response = RawFrame {
+        received: Utc::now(),
+        connection_id: 1,
+        frame: Some(&routed),
+        text,
+    };
+    journal.on_frame(&response).await;
+    journal.sync().unwrap();
+    drop(journal);
+    // A crash in the middle of a write leaves a partial line behind.
+    let segment = std::fs::read_dir(&config.folder)
+        .unwrap()
+        .next()
+        .unwrap()
+        .unwrap()
+        .path();
+    let mut file = std::fs::OpenOptions::new()
+        .append(true)
+        .open(&segment)
+        .unwrap();
+    file.write_all(br#"{"received":1676214000123,"connection_id":1,"str"#)
+        .unwrap();
+
+    let journal = Journal::open(config.clone()).unwrap();
+    let recorder = Arc::new(TradeIds::default());
+    let handlers: EventHandlers = vec![recorder.clone()];
+    assert_eq!(journal.replay(&handlers).await.unwrap(), 3);
+    assert_eq!(*recorder.ids.lock().unwrap(), vec![0, 1, 2]);
+    // Replayed segments stay until the outputs they were replayed into are flushed.
+    journal_trades(&journal, 3..4).await;
+    assert_eq!(segments(&config.folder), 2);
+    let checkpoint = journal.seal();
+    journal.release(checkpoint, Utc::now());
+    assert_eq!(segments(&config.folder), 0);
+    std::fs::remove_dir_all(&config.folder).unwrap();
+}
+
+#[tokio::test]
+async fn test_release_keeps_later_segments() {
+    let config = config("binance_data_gatherer_journal_segments", 512);
+    let journal = Journal::open(config.clone()).unwrap();
+    journal_trades(&journal, 0..10).await;
+    let sealed = segments(&config.folder);
+    assert!(sealed > 1);
+    let checkpoint = journal.seal();
+    // In the window that follows the closed one.
+    journal_trades_at(&journal, 10..12, 1_676_217_600_123).await;
+    journal.release(
+        checkpoint,
+        Utc.with_ymd_and_hms(2023, 2, 12, 16, 0, 0).unwrap(),
+    );
+    assert_eq!(segments(&config.folder), 1);
+    drop(journal);
+
+    let journal = Journal::open(config.clone()).unwrap();
+    let recorder = Arc::new(TradeIds::default());
+    journal.replay(&vec![recorder.clone()]).await.unwrap();
+    assert_eq!(*recorder.ids.lock().unwrap(), vec![10, 11]);
+    std::fs::remove_dir_all(&config.folder).unwrap();
+}
+
+/// Handles a trade at `time` like a connection: the frame is dispatched, then journaled.
+async fn receive(handlers: &EventHandlers, id: i64, time: DateTime<Utc>) {
+    let text = trade(id).replace("1676214000123", &time.timestamp_millis().to_string());
+    let routed = route(&text).unwrap();
+    dispatch(&routed, handlers).await;
+    let frame = RawFrame {
+        received: time,
+        connection_id: 1,
+        frame: Some(&routed),
+        text: &text,
+    };
+    for handler in handlers.iter() {
+        handler.on_frame(&frame).await;
+    }
+}
+
+#[tokio::test]
+async fn test_open_window_survives_a_crash() {
+    let config = config("binance_data_gatherer_journal_open_window", 1 << 20);
+    let files = std::env::temp_dir().join("binance_data_gatherer_journal_open_window_files");
+    _ = std::fs::remove_dir_all(&files);
+    let bus = || {
+        Arc::new(
+            EventBus::new(
+                BinanceAssetType::Futures(FuturesType::USDMargined),
+                PipelineConfig::default(),
+            )
+            .with_sink(Arc::new(CsvFileSinkFactory::new(&files))),
+        )
+    };
+    let now = Utc::now();
+    let boundary = PipelineConfig::default().rotation.window_start(now);
+    let previous = boundary - Duration::hours(1);
+    let journal = Arc::new(Journal::open(config.clone()).unwrap());
+    let first = bus();
+    let handlers: EventHandlers = vec![first.clone(), journal.clone()];
+    // The last trade of the previous window and one of the window that just started, both before the rotation.
+    receive(&handlers, 1, boundary - Duration::seconds(1)).await;
+    receive(&handlers, 2, now).await;
+    let checkpoint = journal.seal();
+    first.rotate(boundary).await;
+    journal.release(checkpoint, boundary);
+    assert_eq!(segments(&config.folder), 1);
+
+    // A crash leaves the file of the open window unfinished.
+    let set_aside = set_aside_parts(&files).unwrap();
+    assert_eq!(set_aside.len(), 1);
+    let journal = Journal::open(config.clone()).unwrap();
+    let second = bus();
+    assert_eq!(journal.replay(&vec![second.clone()]).await.unwrap(), 1);
+    remove_set_aside(&set_aside);
+    assert!(!set_aside[0].exists());
+    second.shutdown().await;
+    let file = |start: DateTime<Utc>, part: &str| {
+        let name = format!(
+            "USDM_FUT_BTCUSDT_TRADES_{}{}.csv",
+            start.format("%Y%m%dT%H%M%SZ"),
+            part
+        );
+        std::fs::read_to_string(files.join(name)).unwrap()
+    };
+    assert!(file(boundary, "").contains(",2,"));
+    // The closed window is not written again.
+    assert!(file(previous, "").contains(",1,"));
+    assert!(!files
+        .join(format!(
+            "USDM_FUT_BTCUSDT_TRADES_{}_1.csv",
+            previous.format("%Y%m%dT%H%M%SZ")
+        ))
+        .exists());
+    let checkpoint = journal.seal();
+    journal.release(checkpoint, boundary + Duration::hours(1));
+    assert_eq!(segments(&config.folder), 0);
+    std::fs::remove_dir_all(&config.folder).unwrap();
+    std::fs::remove_dir_all(&files).unwrap();
+}
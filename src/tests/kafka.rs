@@ -0,0 +1,113 @@
+use std::sync::Arc;
+use std::time::Duration;
+
+use chrono::Utc;
+use prost::Message as _;
+use rdkafka::{
+    consumer::{Consumer, StreamConsumer},
+    ClientConfig, Message,
+};
+
+use crate::{
+    binance::websocket::{
+        requests::{BinanceAssetType, FuturesType},
+        router::{route, Frame},
+    },
+    proto,
+    sinks::{
+        bus::{EventBus, PipelineConfig},
+        encoding::{avro, render_template, Encoding},
+        kafka::{KafkaConfig, KafkaPublisher, KafkaSinkFactory},
+        Dataset, Record, StreamKey,
+    },
+    tests::fixtures::{DEPTH, TRADE},
+};
+
+fn record(text: &str) -> Record {
+    match route(text).unwrap() {
+        Frame::Event { event, .. } => Record::from_event(event),
+        _ => panic!("not an event"),
+    }
+}
+
+#[test]
+fn test_topics_are_rendered_per_stream() {
+    let key = StreamKey::new(
+        &BinanceAssetType::Futures(FuturesType::USDMargined),
+        &record(TRADE),
+    );
+    assert_eq!(
+        render_template("binance.{asset_type}.{stream}.{symbol}", &key),
+        "binance.usdm_fut.trade.BTCUSDT"
+    );
+}
+
+#[test]
+fn test_records_are_encoded() {
+    let trade = record(TRADE);
+    let json: serde_json::Value =
+        serde_json::from_slice(&Encoding::Json.encode(&trade).unwrap()).unwrap();
+    assert_eq!(json["symbol"], "BTCUSDT");
+    assert_eq!(json["price"], "21803.40");
+
+    let depth = record(DEPTH);
+    let decoded = proto::DepthUpdate::decode(&*Encoding::Protobuf.encode(&depth).unwrap()).unwrap();
+    assert_eq!(decoded.symbol, "BTCUSDT");
+    assert_eq!(decoded.prev_last_update_id, Some(9));
+    assert_eq!(decoded.asks.len(), 2);
+    assert_eq!(decoded.asks[1].size, "0");
+
+    assert_eq!(avro::fingerprint(r#""null""#), 0x63dd_24e7_cc25_8f8a);
+    let encoded = Encoding::Avro.encode(&trade).unwrap();
+    assert_eq!(encoded[..2], [0xc3, 0x01]);
+    assert_eq!(
+        encoded[2..10],
+        avro::fingerprint(avro::schema(Dataset::Trades)).to_le_bytes()
+    );
+    // The symbol, as a zigzag encoded length followed by its bytes, then the trade id.
+    assert_eq!(encoded[10], 14);
+    assert_eq!(&encoded[11..18], b"BTCUSDT");
+    assert_eq!(encoded[18], 2);
+}
+
+/// Needs a broker that creates topics on first use, given by `KAFKA_TEST_BROKERS`.
+/// Run with `KAFKA_TEST_BROKERS=localhost:9092 cargo test kafka -- --ignored`.
+#[tokio::test]
+#[ignore]
+async fn test_kafka_sink_publishes_by_symbol() {
+    let brokers = std::env::var("KAFKA_TEST_BROKERS").unwrap();
+    let prefix = format!("test{}", Utc::now().timestamp_millis());
+    let publisher = KafkaPublisher::new(KafkaConfig {
+        brokers: brokers.clone(),
+        topic_template: format!("{}.{{asset_type}}.{{stream}}", prefix),
+        encoding: Encoding::Protobuf,
+        ..Default::default()
+    })
+    .unwrap();
+    let bus = EventBus::new(
+        BinanceAssetType::Futures(FuturesType::USDMargined),
+        PipelineConfig::default(),
+    )
+    .with_sink(Arc::new(KafkaSinkFactory::new(Arc::new(publisher))));
+    // Kafka takes bare records, not frames.
+    bus.publish(record(TRADE)).await;
+    bus.shutdown().await;
+
+    let consumer: StreamConsumer = ClientConfig::new()
+        .set("bootstrap.servers", &brokers)
+        .set("group.id", &prefix)
+        .set("auto.offset.reset", "earliest")
+        .create()
+        .unwrap();
+    consumer
+        .subscribe(&[&format!("{}.usdm_fut.trade", prefix)])
+        .unwrap();
+    let message = tokio::time::timeout(Duration::from_secs(30), consumer.recv())
+        .await
+        .unwrap()
+        .unwrap();
+    assert_eq!(message.key(), Some(&b"BTCUSDT"[..]));
+    let trade = proto::Trade::decode(message.payload().unwrap()).unwrap();
+    assert_eq!(trade.trade_id, 1);
+    assert_eq!(trade.price, "21803.40");
+}
@@ -0,0 +1,146 @@
+use std::sync::Arc;
+
+use chrono::{Duration, TimeZone, Utc};
+
+use crate::{
+    binance::{
+        rest::RestOrderBook,
+        websocket::{
+            handlers::{EventHandler, RawFrame},
+            requests::{BinanceAssetType, FuturesType},
+            router::route,
+        },
+    },
+    local_store::LocalStore,
+    sinks::{
+        bus::{EventBus, PipelineConfig},
+        sqlite::SqliteSinkFactory,
+        Record,
+    },
+};
+
+const TRADES: [&str; 2] = [
+    r#"{"stream":"btcusdt@trade","data":{"e":"trade","E":1676214000123,"T":1676214000120,"s":"BTCUSDT","t":1,"p":"21803.40","q":"0.015","X":"MARKET","m":false}}"#,
+    r#"{"stream":"btcusdt@trade","data":{"e":"trade","E":1676214001123,"T":1676214001120,"s":"BTCUSDT","t":2,"p":"21803.50","q":"0.100","X":"MARKET","m":true}}"#,
+];
+const BOOK_TICKER: &str = r#"{"stream":"btcusdt@bookTicker","data":{"e":"bookTicker","u":400900217,"E":1676214000125,"T":1676214000124,"s":"BTCUSDT","b":"21803.40","B":"31.21","a":"21803.50","A":"40.66"}}"#;
+
+#[tokio::test]
+async fn test_local_store_queries_recent_data() {
+    let asset_type = BinanceAssetType::Futures(FuturesType::USDMargined);
+    let store = Arc::new(LocalStore::in_memory().unwrap());
+    let received = Utc.timestamp_millis_opt(1_676_214_002_000).unwrap();
+    // Frames are replayed twice, like after a crash, without duplicating rows.
+    for _ in 0..2 {
+        let bus = EventBus::new(asset_type.clone(), PipelineConfig::default())
+            .with_sink(Arc::new(SqliteSinkFactory::new(store.clone())));
+        for text in TRADES.iter().chain([&BOOK_TICKER]) {
+            bus.on_frame(&RawFrame {
+                received,
+                connection_id: 1,
+                frame: Some(&route(text).unwrap()),
+                text,
+            })
+            .await;
+        }
+        bus.shutdown().await;
+    }
+    let start = Utc.timestamp_millis_opt(1_676_214_000_000).unwrap();
+    let trades = store
+        .trades(&asset_type, "BTCUSDT", start..start + Duration::seconds(1))
+        .unwrap();
+    assert_eq!(trades.len(), 1);
+    assert_eq!(
+        (trades[0].trade_id, trades[0].price.to_string()),
+        (1, "21803.40".to_string())
+    );
+    let trades = store
+        .trades(&asset_type, "BTCUSDT", start..start + Duration::hours(1))
+        .unwrap();
+    assert_eq!(
+        trades
+            .iter()
+            .map(|trade| trade.trade_id)
+            .collect::<Vec<_>>(),
+        [1, 2]
+    );
+    let tickers = store
+        .book_tickers(&asset_type, "BTCUSDT", start..start + Duration::hours(1))
+        .unwrap();
+    assert_eq!(tickers.len(), 1);
+    assert_eq!(tickers[0].received, received);
+    assert_eq!(tickers[0].ticker.ask_size.to_string(), "40.66");
+
+    let book: RestOrderBook = serde_json::from_str(
+        r#"{"lastUpdateId":160,"bids":[["0.0024","10"]],"asks":[["0.0026","100"]]}"#,
+    )
+    .unwrap();
+    // Snapshots of the rest api come in no frame.
+    let bus = EventBus::new(asset_type.clone(), PipelineConfig::default())
+        .with_sink(Arc::new(SqliteSinkFactory::new(store.clone())));
+    bus.publish(Record::Snapshot {
+        symbol: "BTCUSDT".to_string(),
+        book: book.clone(),
+    })
+    .await;
+    bus.shutdown().await;
+    let around = book.received_ts - Duration::seconds(1)..book.received_ts + Duration::seconds(1);
+    let snapshots = store
+        .snapshots(&asset_type, "BTCUSDT", around.clone())
+        .unwrap();
+    assert_eq!(snapshots.len(), 1);
+    assert_eq!(snapshots[0].asks, book.asks);
+    // Everything but the snapshot was received before it.
+    assert_eq!(store.prune(received + Duration::seconds(1)).unwrap(), 3);
+    assert!(store
+        .trades(&asset_type, "BTCUSDT", start..start + Duration::hours(1))
+        .unwrap()
+        .is_empty());
+    assert_eq!(
+        store
+            .snapshots(&asset_type, "BTCUSDT", around)
+            .unwrap()
+            .len(),
+        1
+    );
+}
+
+#[tokio::test]
+async fn test_sqlite_sink_keeps_records_of_closed_windows() {
+    let asset_type = BinanceAssetType::Futures(FuturesType::USDMargined);
+    let folder = std::env::temp_dir().join("binance_data_gatherer_sqlite_keep_test");
+    _ = std::fs::remove_dir_all(&folder);
+    let path = folder.join("local.sqlite");
+    let store = Arc::new(LocalStore::open(&path).unwrap());
+    let factory = Arc::new(SqliteSinkFactory::new(store.clone()));
+    // The inserts fail until the table is created again.
+    rusqlite::Connection::open(&path)
+        .unwrap()
+        .execute_batch("DROP TABLE trades")
+        .unwrap();
+    let bus =
+        EventBus::new(asset_type.clone(), PipelineConfig::default()).with_sink(factory.clone());
+    let received = Utc.timestamp_millis_opt(1_676_214_002_000).unwrap();
+    for text in TRADES {
+        bus.on_frame(&RawFrame {
+            received,
+            connection_id: 1,
+            frame: Some(&route(text).unwrap()),
+            text,
+        })
+        .await;
+        if text == TRADES[0] {
+            bus.rotate(Utc::now()).await;
+            assert_eq!(factory.buffered(), 1);
+            drop(LocalStore::open(&path).unwrap());
+        }
+    }
+    bus.shutdown().await;
+    assert_eq!(factory.buffered(), 0);
+    let start = Utc.timestamp_millis_opt(1_676_214_000_000).unwrap();
+    let trades = store
+        .trades(&asset_type, "BTCUSDT", start..start + Duration::hours(1))
+        .unwrap();
+    assert_eq!(trades.len(), 2);
+    std::fs::remove_dir_all(&folder).unwrap();
+}
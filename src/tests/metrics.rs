@@ -0,0 +1,150 @@
+use std::sync::Arc;
+
+use chrono::{TimeZone, Utc};
+
+use crate::{
+    binance::{
+        models::orderbook::new_orderbooks_rwl,
+        websocket::{
+            connection::dispatch,
+            handlers::{depth_update::OrderBookMaintainer, EventHandler, EventHandlers, RawFrame},
+            requests::{BinanceAssetType, FuturesType},
+            router::{route, Frame, StreamEvent},
+        },
+    },
+    file_compress::Codec,
+    metrics::Metrics,
+    sinks::{
+        bus::{EventBus, PipelineConfig},
+        csv_file::CsvFileSinkFactory,
+        Record,
+    },
+    tests::fixtures::TRADE,
+};
+
+const DEPTH: &str = r#"{"stream":"btcusdt@depth@100ms","data":{"e":"depthUpdate","E":1676214000123,"T":1676214000120,"s":"BTCUSDT","U":10,"u":12,"pu":9,"b":[["21800.00","1.204"]],"a":[]}}"#;
+const GAP: &str = r#"{"stream":"btcusdt@depth@100ms","data":{"e":"depthUpdate","E":1676214000223,"T":1676214000220,"s":"BTCUSDT","U":20,"u":22,"pu":19,"b":[],"a":[]}}"#;
+const ENDPOINT: &str = "wss://fstream.binance.com/stream?streams=btcusdt@trade/btcusdt@depth@100ms";
+
+/// The value of the sample starting with `name`, labels included.
+fn sample(rendered: &str, name: &str) -> f64 {
+    rendered
+        .lines()
+        .find_map(|line| line.strip_prefix(name)?.strip_prefix(' '))
+        .unwrap_or_else(|| panic!("no {} in\n{}", name, rendered))
+        .parse()
+        .unwrap()
+}
+
+#[tokio::test]
+async fn test_events_books_and_files_are_measured() {
+    let orderbooks_rwl = new_orderbooks_rwl();
+    let folder = std::env::temp_dir().join("binance_data_gatherer_metrics_test");
+    _ = std::fs::remove_dir_all(&folder);
+    let bus = Arc::new(
+        EventBus::new(
+            BinanceAssetType::Futures(FuturesType::USDMargined),
+            PipelineConfig::default(),
+        )
+        .with_sink(Arc::new(
+            CsvFileSinkFactory::new(&folder).with_codec(Codec::default()),
+        )),
+    );
+    let metrics = Arc::new(
+        Metrics::new()
+            .with_orderbooks(orderbooks_rwl.clone())
+            .with_bus(bus.clone()),
+    );
+    let handlers: EventHandlers = vec![
+        Arc::new(OrderBookMaintainer::new(orderbooks_rwl)),
+        metrics.clone(),
+    ];
+    metrics.on_connect(1, ENDPOINT).await;
+    for text in [TRADE, DEPTH, GAP] {
+        let frame = route(text).unwrap();
+        dispatch(&frame, &handlers).await;
+        metrics
+            .on_frame(&RawFrame {
+                // 100ms after the trade.
+                received: Utc.timestamp_millis_opt(1_676_214_000_220).unwrap(),
+                connection_id: 1,
+                frame: Some(&frame),
+                text,
+            })
+            .await;
+    }
+    metrics
+        .on_frame(&RawFrame {
+            received: Utc::now(),
+            connection_id: 1,
+            frame: None,
+            text: "{not json",
+        })
+        .await;
+    // Compression is measured on the files of the bus, a thousand identical rows compress well.
+    if let Ok(Frame::Event {
+        event: StreamEvent::Trade(trade),
+        ..
+    }) = route(TRADE)
+    {
+        for _ in 0..1_000 {
+            bus.publish(Record::Trade(trade.clone())).await;
+        }
+    }
+    bus.shutdown().await;
+
+    let rendered = metrics.render().await;
+    assert_eq!(
+        sample(
+            &rendered,
+            r#"binance_messages_total{stream_type="trade",symbol="BTCUSDT"}"#
+        ),
+        1.0
+    );
+    assert_eq!(
+        sample(
+            &rendered,
+            r#"binance_messages_total{stream_type="depth",symbol="BTCUSDT"}"#
+        ),
+        2.0
+    );
+    assert_eq!(sample(&rendered, "binance_parse_errors_total"), 1.0);
+    assert_eq!(
+        sample(
+            &rendered,
+            r#"binance_receipt_delay_seconds_count{stream_type="trade"}"#
+        ),
+        1.0
+    );
+    assert_eq!(
+        sample(
+            &rendered,
+            r#"binance_receipt_delay_seconds_sum{stream_type="trade"}"#
+        ),
+        0.1
+    );
+    assert!(
+        sample(
+            &rendered,
+            r#"binance_connection_uptime_seconds{host="fstream.binance.com:443"}"#
+        ) >= 0.0
+    );
+    assert_eq!(sample(&rendered, "binance_invalid_books"), 1.0);
+    assert!(sample(&rendered, r#"binance_compression_ratio{codec="zstd"}"#) > 1.0);
+
+    // Another connection ending leaves the book alone.
+    metrics.on_disconnect(2, ENDPOINT).await;
+    assert!(!metrics
+        .render()
+        .await
+        .contains("binance_book_resyncs_total{"));
+    metrics.on_disconnect(1, ENDPOINT).await;
+    metrics.on_connect(3, ENDPOINT).await;
+    let rendered = metrics.render().await;
+    assert_eq!(sample(&rendered, "binance_reconnects_total"), 1.0);
+    assert_eq!(
+        sample(&rendered, r#"binance_book_resyncs_total{symbol="BTCUSDT"}"#),
+        1.0
+    );
+    std::fs::remove_dir_all(&folder).unwrap();
+}
@@ -0,0 +1,169 @@
+use std::{
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+
+use async_trait::async_trait;
+use futures_util::{SinkExt, StreamExt};
+use tokio_tungstenite::tungstenite::Message;
+
+use crate::{
+    binance::{
+        mock::{MockConfig, MockExchange},
+        models::{orderbook::new_orderbooks_rwl, trades::Trade},
+        rest::RestOrderBook,
+        websocket::{
+            connection::establish_and_persist,
+            handlers::{depth_update::OrderBookMaintainer, EventHandler, EventHandlers},
+            requests::{BinanceAssetType, DataRequest, FuturesType, Stream},
+            watchdog::LivenessConfig,
+        },
+    },
+    sinks::{
+        bus::{EventBus, PipelineConfig},
+        csv_file::CsvFileSinkFactory,
+    },
+};
+
+#[derive(Default)]
+struct TradeIds {
+    ids: Mutex<Vec<i64>>,
+}
+
+#[async_trait]
+impl EventHandler for TradeIds {
+    async fn on_trade(&self, trade: &Trade) {
+        self.ids.lock().unwrap().push(trade.trade_id);
+    }
+}
+
+#[tokio::test]
+async fn test_collect_through_daily_disconnects() {
+    let mock = MockExchange::start(MockConfig {
+        event_interval: Duration::from_millis(5),
+        ping_interval: Duration::from_millis(50),
+        connection_lifetime: Duration::from_millis(300),
+        ..Default::default()
+    })
+    .await
+    .unwrap();
+    let request = DataRequest::new(
+        BinanceAssetType::Futures(FuturesType::USDMargined),
+        vec![
+            Stream::Trade("BTCUSDT".to_string()),
+            Stream::Depth("BTCUSDT".to_string(), 100),
+        ],
+    )
+    .with_ws_base_urls(vec![mock.ws_base_url()]);
+    let folder = std::env::temp_dir().join("binance_data_gatherer_mock_exchange");
+    _ = std::fs::remove_dir_all(&folder);
+    let bus = Arc::new(
+        EventBus::new(request.asset_type.clone(), PipelineConfig::default())
+            .with_sink(Arc::new(CsvFileSinkFactory::new(&folder))),
+    );
+    let trades = Arc::new(TradeIds::default());
+    let orderbooks_rwl = new_orderbooks_rwl();
+    let handlers: EventHandlers = vec![
+        trades.clone(),
+        Arc::new(OrderBookMaintainer::new(orderbooks_rwl.clone())),
+        bus.clone(),
+    ];
+    let collector = tokio::spawn(establish_and_persist(
+        request,
+        handlers,
+        LivenessConfig::default(),
+    ));
+    let deadline = tokio::time::Instant::now() + Duration::from_secs(10);
+    while mock.connections() < 2 && tokio::time::Instant::now() < deadline {
+        tokio::time::sleep(Duration::from_millis(20)).await;
+    }
+    tokio::time::sleep(Duration::from_millis(100)).await;
+    collector.abort();
+    // A session that delivered data is not a failed attempt, so the collector reconnected.
+    assert!(mock.connections() >= 2);
+    let ids = trades.ids.lock().unwrap().clone();
+    assert!(!ids.is_empty());
+    assert!(ids.windows(2).all(|pair| pair[0] < pair[1]));
+    let books = orderbooks_rwl.read().await;
+    assert!(books["BTCUSDT"][0].is_valid);
+    drop(books);
+    bus.shutdown().await;
+    let mut trade_rows = 0;
+    let mut datasets = Vec::new();
+    for file in std::fs::read_dir(&folder).unwrap() {
+        let name = file.unwrap().file_name().into_string().unwrap();
+        if !name.ends_with("Z.csv") {
+            continue;
+        }
+        if name.starts_with("USDM_FUT_BTCUSDT_TRADES_") {
+            let csv = std::fs::read_to_string(folder.join(&name)).unwrap();
+            trade_rows += csv.lines().count() - 1;
+        }
+        datasets.push(name.rsplit_once('_').unwrap().0.to_string());
+    }
+    assert_eq!(trade_rows, ids.len());
+    assert!(datasets.contains(&"USDM_FUT_BTCUSDT_BOOK_HISTORY".to_string()));
+    std::fs::remove_dir_all(&folder).unwrap();
+}
+
+#[tokio::test]
+async fn test_rest_endpoints() {
+    let mock = MockExchange::start(MockConfig {
+        event_interval: Duration::from_secs(3600),
+        ..Default::default()
+    })
+    .await
+    .unwrap();
+    tokio::time::sleep(Duration::from_millis(20)).await;
+    let url = format!("{}/fapi/v1/depth", mock.http_base_url());
+    let response = reqwest::get(format!("{url}?symbol=BTCUSDT&limit=5"))
+        .await
+        .unwrap();
+    let book = response.json::<RestOrderBook>().await.unwrap();
+    assert_eq!(book.bids.len(), 5);
+    assert!(book.bids[0].price < book.asks[0].price);
+    assert_eq!(Some(book.last_update_id), mock.last_update_id("BTCUSDT"));
+    let response = reqwest::get(format!("{url}?symbol=ETHUSDT")).await.unwrap();
+    assert_eq!(response.status(), 400);
+    let info = reqwest::get(format!("{}/fapi/v1/exchangeInfo", mock.http_base_url()))
+        .await
+        .unwrap()
+        .json::<serde_json::Value>()
+        .await
+        .unwrap();
+    assert_eq!(info["symbols"][0]["symbol"], "BTCUSDT");
+}
+
+#[tokio::test]
+async fn test_fixtures_and_subscriptions() {
+    let fixtures = vec![
+        r#"{"stream":"btcusdt@trade","data":{"e":"trade","E":1676214000123,"T":1676214000120,"s":"BTCUSDT","t":1,"p":"21803.40","q":"0.015","m":false}}"#.to_string(),
+        r#"{"stream":"btcusdt@trade","data":{"e":"trade","E":1676214000124,"T":1676214000121,"s":"BTCUSDT","t":2,"p":"21803.50","q":"0.020","m":true}}"#.to_string(),
+    ];
+    let mock = MockExchange::start(MockConfig {
+        fixtures: Some(fixtures.clone()),
+        event_interval: Duration::from_millis(5),
+        ..Default::default()
+    })
+    .await
+    .unwrap();
+    let url = format!("{}/stream?streams=btcusdt@trade", mock.ws_base_url());
+    let (mut socket, _) = tokio_tungstenite::connect_async(url).await.unwrap();
+    let subscribe = r#"{"method":"SUBSCRIBE","params":["ethusdt@trade"],"id":7}"#;
+    socket
+        .send(Message::Text(subscribe.to_string()))
+        .await
+        .unwrap();
+    let mut received = Vec::new();
+    while received.len() < 3 {
+        match tokio::time::timeout(Duration::from_secs(5), socket.next()).await {
+            Ok(Some(Ok(Message::Text(text)))) => received.push(text),
+            Ok(Some(Ok(_))) => continue,
+            other => panic!("Unexpected {other:?}"),
+        }
+    }
+    assert!(received.contains(&r#"{"id":7,"result":null}"#.to_string()));
+    received.retain(|text| text.starts_with(r#"{"stream""#));
+    assert_eq!(received, fixtures);
+    assert_eq!(mock.connections(), 1);
+}
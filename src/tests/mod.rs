@@ -1,2 +1,23 @@
+pub mod fixtures;
 pub mod websocket;
-pub mod compress;
\ No newline at end of file
+pub mod compress;
+pub mod watchdog;
+pub mod router;
+pub mod handlers;
+pub mod pipeline;
+pub mod journal;
+pub mod recorder;
+pub mod mock_exchange;
+pub mod upload;
+pub mod disk_guard;
+pub mod local_store;
+pub mod postgres;
+pub mod clickhouse;
+pub mod kafka;
+pub mod publishers;
+pub mod broadcast;
+pub mod grpc;
+pub mod admin;
+pub mod metrics;
+pub mod quality;
+pub mod time_sync;
@@ -0,0 +1,303 @@
+use std::{sync::Arc, time::Duration};
+
+use chrono::{TimeZone, Utc};
+
+use crate::{
+    binance::websocket::{
+        handlers::{EventHandler, RawFrame},
+        requests::{BinanceAssetType, FuturesType},
+        router::{route, Frame, StreamEvent},
+    },
+    sinks::{
+        bus::{EventBus, PipelineConfig},
+        csv_file::CsvFileSinkFactory,
+        json_lines::{JsonLinesMode, JsonLinesSinkFactory},
+        manifest::{write_manifest, ManifestEntry},
+        part_files::set_aside_parts,
+        queue::{BoundedQueue, OverflowPolicy},
+        rotation::RotationInterval,
+        Dataset, Record,
+    },
+    tests::fixtures::{DEPTH, TRADE},
+};
+
+fn event(frame: &str) -> StreamEvent {
+    match route(frame).unwrap() {
+        Frame::Event { event, .. } => event,
+        frame => panic!("Unexpected frame {frame:?}"),
+    }
+}
+
+#[tokio::test]
+async fn test_drop_oldest() {
+    let queue = BoundedQueue::new("drop_oldest", 2, OverflowPolicy::DropOldest);
+    for i in 0..3u32 {
+        queue.push(i).await;
+    }
+    assert_eq!(queue.pop().await, Some(1));
+    assert_eq!(queue.pop().await, Some(2));
+    let stats = queue.counters().snapshot();
+    assert_eq!((stats.received, stats.dropped), (3, 1));
+}
+
+#[tokio::test]
+async fn test_spill_to_disk_keeps_order() {
+    let folder = std::env::temp_dir().join("binance_data_gatherer_spill_test");
+    let queue = BoundedQueue::new("spill", 2, OverflowPolicy::SpillToDisk(folder.clone()));
+    for i in 0..5u32 {
+        queue.push(i).await;
+    }
+    assert_eq!(queue.len(), 5);
+    assert_eq!(queue.counters().snapshot().spilled, 3);
+    assert!(folder.join("spill.spill").exists());
+    // Spilling goes on until the file is read back, even once memory has room again.
+    assert_eq!(queue.pop().await, Some(0));
+    queue.push(5).await;
+    queue.close();
+    let mut popped = Vec::new();
+    while let Some(i) = queue.pop().await {
+        popped.push(i);
+    }
+    assert_eq!(popped, vec![1, 2, 3, 4, 5]);
+    drop(queue);
+    assert!(!folder.join("spill.spill").exists());
+}
+
+#[tokio::test]
+async fn test_block_waits_for_room() {
+    let queue = Arc::new(BoundedQueue::new("block", 1, OverflowPolicy::Block));
+    queue.push(0u32).await;
+    let producer = tokio::spawn({
+        let queue = queue.clone();
+        async move { queue.push(1).await }
+    });
+    tokio::time::sleep(Duration::from_millis(50)).await;
+    assert!(!producer.is_finished());
+    assert_eq!(queue.pop().await, Some(0));
+    producer.await.unwrap();
+    assert_eq!(queue.pop().await, Some(1));
+    assert_eq!(queue.counters().snapshot().dropped, 0);
+}
+
+#[test]
+fn test_record_round_trip() {
+    for record in [
+        match event(TRADE) {
+            StreamEvent::Trade(trade) => Record::Trade(trade),
+            _ => unreachable!(),
+        },
+        match event(DEPTH) {
+            StreamEvent::DepthUpdate(update) => Record::DepthUpdate(update),
+            _ => unreachable!(),
+        },
+    ] {
+        let json = serde_json::to_string(&record).unwrap();
+        assert_eq!(serde_json::from_str::<Record>(&json).unwrap(), record);
+    }
+}
+
+#[tokio::test]
+async fn test_bus_writes_per_stream_files() {
+    let folder = std::env::temp_dir().join("binance_data_gatherer_bus_test");
+    _ = std::fs::remove_dir_all(&folder);
+    let bus = EventBus::new(
+        BinanceAssetType::Futures(FuturesType::USDMargined),
+        PipelineConfig::default(),
+    )
+    .with_sink(Arc::new(CsvFileSinkFactory::new(&folder)));
+    // The second trade is the earliest one, and has the lowest id.
+    for (earlier, id) in [(0, 3), (23, 1), (0, 2)] {
+        if let StreamEvent::Trade(mut trade) = event(TRADE) {
+            trade.event_time -= chrono::Duration::milliseconds(earlier);
+            trade.trade_id = id;
+            bus.publish(Record::Trade(trade)).await;
+        }
+    }
+    if let StreamEvent::DepthUpdate(update) = event(DEPTH) {
+        bus.publish(Record::DepthUpdate(update)).await;
+    }
+    // Rotating waits for what was already published.
+    bus.rotate(Utc::now()).await;
+    let trades =
+        std::fs::read_to_string(folder.join("USDM_FUT_BTCUSDT_TRADES_20230212T150000Z.csv"))
+            .unwrap();
+    assert_eq!(trades.lines().count(), 4);
+    let history =
+        std::fs::read_to_string(folder.join("USDM_FUT_BTCUSDT_BOOK_HISTORY_20230212T150000Z.csv"))
+            .unwrap();
+    assert_eq!(history.lines().count(), 4);
+    assert!(history.contains(",21803.60,0"));
+    let written = bus
+        .stats()
+        .iter()
+        .map(|stats| stats.counters.written)
+        .sum::<u64>();
+    assert_eq!(written, 4);
+    bus.shutdown().await;
+    let manifest = write_manifest(&folder, "USDM_FUT_MANIFEST")
+        .unwrap()
+        .unwrap();
+    let entries: Vec<ManifestEntry> =
+        serde_json::from_reader(std::fs::File::open(manifest).unwrap()).unwrap();
+    assert_eq!(entries.len(), 2);
+    assert_eq!(
+        entries[0].file,
+        "USDM_FUT_BTCUSDT_BOOK_HISTORY_20230212T150000Z.csv"
+    );
+    assert_eq!(
+        (entries[0].rows, entries[0].first_id, entries[0].last_id),
+        (3, Some(10), Some(12))
+    );
+    assert_eq!(entries[1].dataset, Dataset::Trades);
+    assert_eq!(
+        (entries[1].rows, entries[1].first_id, entries[1].last_id),
+        (3, Some(1), Some(3))
+    );
+    assert_eq!(
+        (entries[1].first_event_time, entries[1].last_event_time),
+        (
+            Some(Utc.timestamp_millis_opt(1_676_214_000_100).unwrap()),
+            Some(Utc.timestamp_millis_opt(1_676_214_000_123).unwrap())
+        )
+    );
+    // Sidecars are merged into the manifest.
+    assert_eq!(std::fs::read_dir(&folder).unwrap().count(), 3);
+    std::fs::remove_dir_all(&folder).unwrap();
+}
+
+#[tokio::test]
+async fn test_json_lines_keep_the_exchange_payload() {
+    let folder = std::env::temp_dir().join("binance_data_gatherer_jsonl_test");
+    _ = std::fs::remove_dir_all(&folder);
+    let bus = EventBus::new(
+        BinanceAssetType::Futures(FuturesType::USDMargined),
+        PipelineConfig::default(),
+    )
+    .with_sink(Arc::new(CsvFileSinkFactory::new(folder.join("csv"))))
+    .with_sink(Arc::new(JsonLinesSinkFactory::new(
+        folder.join("raw"),
+        JsonLinesMode::Raw,
+    )))
+    .with_sink(Arc::new(JsonLinesSinkFactory::new(
+        folder.join("normalized"),
+        JsonLinesMode::Normalized,
+    )));
+    // A field the models do not know about.
+    let text = TRADE.replace(r#""m":false"#, r#""m":false,"z":"new""#);
+    if let StreamEvent::Trade(trade) = event(&text) {
+        bus.on_trade(&trade).await;
+    }
+    bus.on_frame(&RawFrame {
+        received: Utc.timestamp_millis_opt(1_676_214_000_200).unwrap(),
+        connection_id: 1,
+        frame: Some(&route(&text).unwrap()),
+        text: &text,
+    })
+    .await;
+    bus.shutdown().await;
+    let file = "USDM_FUT_BTCUSDT_TRADES_20230212T150000Z";
+    let raw = std::fs::read_to_string(folder.join(format!("raw/{file}.jsonl"))).unwrap();
+    let data = &text[text.find(r#"{"e""#).unwrap()..text.len() - 1];
+    assert_eq!(
+        raw,
+        format!("{{\"recv_ts\":1676214000200,\"stream\":\"btcusdt@trade\",\"data\":{data}}}\n")
+    );
+    let normalized =
+        std::fs::read_to_string(folder.join(format!("normalized/{file}.jsonl"))).unwrap();
+    let normalized: serde_json::Value = serde_json::from_str(&normalized).unwrap();
+    assert_eq!(normalized["recv_ts"], 1_676_214_000_200i64);
+    assert_eq!(normalized["price"], "21803.40");
+    // The CSV sink only got the trade once.
+    let csv = std::fs::read_to_string(folder.join(format!("csv/{file}.csv"))).unwrap();
+    assert_eq!(csv.lines().count(), 2);
+    std::fs::remove_dir_all(&folder).unwrap();
+}
+
+#[test]
+fn test_rotation_windows() {
+    let time = Utc.timestamp_millis_opt(1_676_214_345_678).unwrap();
+    let starts = [
+        (RotationInterval::Minute, "2023-02-12T15:05:00Z"),
+        (RotationInterval::FiveMinutes, "2023-02-12T15:05:00Z"),
+        (RotationInterval::Hour, "2023-02-12T15:00:00Z"),
+        (RotationInterval::Day, "2023-02-12T00:00:00Z"),
+    ];
+    for (rotation, start) in starts {
+        assert_eq!(
+            rotation
+                .window_start(time)
+                .to_rfc3339()
+                .replace("+00:00", "Z"),
+            start
+        );
+        assert_eq!(rotation.to_string().parse(), Ok(rotation));
+    }
+    let boundary = RotationInterval::Hour.next_boundary(time);
+    assert_eq!(
+        boundary,
+        Utc.with_ymd_and_hms(2023, 2, 12, 16, 0, 0).unwrap()
+    );
+    assert_eq!(
+        RotationInterval::Hour.next_boundary(boundary),
+        boundary + chrono::Duration::hours(1)
+    );
+    assert!("2h".parse::<RotationInterval>().is_err());
+}
+
+#[tokio::test]
+async fn test_windows_by_event_time() {
+    let folder = std::env::temp_dir().join("binance_data_gatherer_windows_test");
+    _ = std::fs::remove_dir_all(&folder);
+    let bus = EventBus::new(
+        BinanceAssetType::Futures(FuturesType::USDMargined),
+        PipelineConfig {
+            rotation: RotationInterval::Minute,
+            ..Default::default()
+        },
+    )
+    .with_sink(Arc::new(CsvFileSinkFactory::new(&folder)));
+    let trade = match event(TRADE) {
+        StreamEvent::Trade(trade) => trade,
+        _ => unreachable!(),
+    };
+    let mut next_minute = trade.clone();
+    next_minute.event_time = trade.event_time + chrono::Duration::minutes(1);
+    for trade in [&trade, &next_minute, &trade] {
+        bus.publish(Record::Trade(trade.clone())).await;
+    }
+    bus.rotate(Utc.with_ymd_and_hms(2023, 2, 12, 15, 1, 0).unwrap())
+        .await;
+    // A record arriving after its window was closed goes to a new part.
+    bus.publish(Record::Trade(trade.clone())).await;
+    bus.shutdown().await;
+    let path = |name: &str| folder.join(format!("USDM_FUT_BTCUSDT_TRADES_{name}"));
+    let first = std::fs::read_to_string(path("20230212T150000Z.csv")).unwrap();
+    assert_eq!(first.lines().count(), 3);
+    let late = std::fs::read_to_string(path("20230212T150000Z_1.csv")).unwrap();
+    assert_eq!(late.lines().count(), 2);
+    assert!(path("20230212T150100Z.csv").exists());
+
+    // After a restart, the unfinished files are set aside and the parts on disk are not overwritten.
+    std::fs::write(path("20230212T150100Z.csv.part"), "unfinished").unwrap();
+    assert_eq!(
+        set_aside_parts(&folder).unwrap(),
+        vec![path("20230212T150100Z.csv.part.crashed")]
+    );
+    let bus = EventBus::new(
+        BinanceAssetType::Futures(FuturesType::USDMargined),
+        PipelineConfig {
+            rotation: RotationInterval::Minute,
+            ..Default::default()
+        },
+    )
+    .with_sink(Arc::new(CsvFileSinkFactory::new(&folder)));
+    bus.publish(Record::Trade(trade.clone())).await;
+    bus.shutdown().await;
+    let restarted = std::fs::read_to_string(path("20230212T150000Z_2.csv")).unwrap();
+    assert_eq!(restarted.lines().count(), 2);
+    assert_eq!(
+        first,
+        std::fs::read_to_string(path("20230212T150000Z.csv")).unwrap()
+    );
+    std::fs::remove_dir_all(&folder).unwrap();
+}
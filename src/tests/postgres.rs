@@ -0,0 +1,141 @@
+use std::sync::Arc;
+
+use chrono::{TimeZone, Utc};
+
+use crate::{
+    binance::websocket::{
+        handlers::{EventHandler, RawFrame},
+        requests::{BinanceAssetType, FuturesType},
+        router::route,
+    },
+    sinks::{
+        bus::{EventBus, PipelineConfig},
+        postgres::{PostgresSinkFactory, PostgresStore, MIGRATIONS},
+    },
+};
+
+const FRAMES: [&str; 4] = [
+    r#"{"stream":"btcusdt@trade","data":{"e":"trade","E":1676214000123,"T":1676214000120,"s":"BTCUSDT","t":1,"p":"21803.40","q":"0.015","X":"MARKET","m":false}}"#,
+    r#"{"stream":"btcusdt@trade","data":{"e":"trade","E":1676214001123,"T":1676214001120,"s":"BTCUSDT","t":2,"p":"21803.50","q":"0.100","X":"MARKET","m":true}}"#,
+    r#"{"stream":"btcusdt@depth@100ms","data":{"e":"depthUpdate","E":1676214000123,"s":"BTCUSDT","U":10,"u":12,"pu":9,"b":[["21800.00","1.204"]],"a":[["21803.50","3.019"],["21803.60","0"]]}}"#,
+    r#"{"stream":"btcusdt@bookTicker","data":{"e":"bookTicker","u":400900217,"E":1676214000125,"T":1676214000124,"s":"BTCUSDT","b":"21803.40","B":"31.21","a":"21803.50","A":"40.66"}}"#,
+];
+
+#[tokio::test]
+async fn test_postgres_store_connects_lazily() {
+    // Nothing listens on port 1.
+    let store = PostgresStore::new("host=127.0.0.1 port=1 user=postgres connect_timeout=1");
+    let received = Utc::now();
+    assert!(store.ingest("USDM_FUT", &[], received).await.is_err());
+    // The next attempt waits for the backoff.
+    let e = store.ingest("USDM_FUT", &[], received).await.unwrap_err();
+    assert!(e
+        .to_string()
+        .starts_with("Not connecting to postgres again"));
+}
+
+#[tokio::test]
+async fn test_postgres_sink_keeps_records_of_closed_windows() {
+    let store = Arc::new(PostgresStore::new(
+        "host=127.0.0.1 port=1 user=postgres connect_timeout=1",
+    ));
+    let bus = EventBus::new(
+        BinanceAssetType::Futures(FuturesType::USDMargined),
+        PipelineConfig::default(),
+    )
+    .with_sink(Arc::new(PostgresSinkFactory::new(store.clone())));
+    let text = FRAMES[0];
+    bus.on_frame(&RawFrame {
+        received: Utc.timestamp_millis_opt(1_676_214_000_200).unwrap(),
+        connection_id: 1,
+        frame: Some(&route(text).unwrap()),
+        text,
+    })
+    .await;
+    // The window closes while the database is down.
+    bus.rotate(Utc::now()).await;
+    assert_eq!(store.buffered(), 1);
+    bus.shutdown().await;
+    assert_eq!(store.buffered(), 1);
+}
+
+/// Needs a database it can drop the tables of, given by `POSTGRES_TEST_URL`.
+/// Run with `POSTGRES_TEST_URL="host=localhost user=postgres" cargo test postgres -- --ignored`.
+#[tokio::test]
+#[ignore]
+async fn test_postgres_sink_is_idempotent() {
+    let url = std::env::var("POSTGRES_TEST_URL").unwrap();
+    let (client, connection) = tokio_postgres::connect(&url, tokio_postgres::NoTls)
+        .await
+        .unwrap();
+    tokio::spawn(connection);
+    client
+        .batch_execute(
+            "DROP VIEW IF EXISTS trade_bars_1m;
+            DROP TABLE IF EXISTS trades, depth_updates, book_tickers, schema_migrations;",
+        )
+        .await
+        .unwrap();
+    let store = Arc::new(PostgresStore::connect(&url).await.unwrap());
+    // Migrations are only applied once.
+    let store_again = PostgresStore::connect(&url).await.unwrap();
+    drop(store_again);
+    let received = Utc.timestamp_millis_opt(1_676_214_002_000).unwrap();
+    // Frames are replayed twice, like after a crash, without duplicating rows.
+    for _ in 0..2 {
+        let bus = EventBus::new(
+            BinanceAssetType::Futures(FuturesType::USDMargined),
+            PipelineConfig::default(),
+        )
+        .with_sink(Arc::new(PostgresSinkFactory::new(store.clone())));
+        for text in FRAMES {
+            bus.on_frame(&RawFrame {
+                received,
+                connection_id: 1,
+                frame: Some(&route(text).unwrap()),
+                text,
+            })
+            .await;
+        }
+        bus.shutdown().await;
+    }
+    let count = |table: &'static str| {
+        let client = &client;
+        async move {
+            let row = client
+                .query_one(&format!("SELECT count(*) FROM {}", table), &[])
+                .await
+                .unwrap();
+            row.get::<_, i64>(0)
+        }
+    };
+    assert_eq!(count("schema_migrations").await, MIGRATIONS.len() as i64);
+    assert_eq!(count("trades").await, 2);
+    assert_eq!(count("depth_updates").await, 1);
+    assert_eq!(count("book_tickers").await, 1);
+    let row = client
+        .query_one(
+            "SELECT ask_prices[2]::text, received, received_corrected FROM depth_updates WHERE last_update_id = 12",
+            &[],
+        )
+        .await
+        .unwrap();
+    assert_eq!(row.get::<_, String>(0), "21803.60");
+    assert_eq!(row.get::<_, chrono::DateTime<Utc>>(1), received);
+    assert_eq!(row.get::<_, Option<chrono::DateTime<Utc>>>(2), None);
+    let bar = client
+        .query_one(
+            "SELECT open::text, close::text, trades FROM trade_bars_1m WHERE symbol = 'BTCUSDT'",
+            &[],
+        )
+        .await
+        .unwrap();
+    assert_eq!(
+        (
+            bar.get::<_, String>(0),
+            bar.get::<_, String>(1),
+            bar.get::<_, i64>(2)
+        ),
+        ("21803.40".to_string(), "21803.50".to_string(), 2)
+    );
+}
@@ -0,0 +1,93 @@
+use std::sync::Arc;
+
+use chrono::Utc;
+use redis::{streams::StreamRangeReply, AsyncCommands};
+
+use crate::{
+    binance::{
+        models::trades::Trade,
+        websocket::requests::{BinanceAssetType, FuturesType},
+    },
+    sinks::{
+        bus::{EventBus, PipelineConfig},
+        nats::{NatsConfig, NatsPublisher, NatsSinkFactory},
+        redis_streams::{RedisStreamsConfig, RedisStreamsPublisher, RedisStreamsSinkFactory},
+        Record, SinkFactory,
+    },
+    tests::fixtures::TRADE_DATA,
+};
+
+async fn publish_trade(factory: Arc<dyn SinkFactory>) {
+    let bus = EventBus::new(
+        BinanceAssetType::Futures(FuturesType::USDMargined),
+        PipelineConfig::default(),
+    )
+    .with_sink(factory);
+    // The publishers take bare records, not frames.
+    let trade: Trade = serde_json::from_str(TRADE_DATA).unwrap();
+    bus.publish(Record::Trade(trade)).await;
+    bus.shutdown().await;
+}
+
+/// Needs a NATS server with JetStream enabled, given by `NATS_TEST_URL`.
+/// Run with `NATS_TEST_URL=nats://localhost:4222 cargo test nats -- --ignored`,
+/// e.g. against the services of `docker-compose.test.yml`, as CI does.
+#[tokio::test]
+#[ignore]
+async fn test_nats_sink_deduplicates_replays() {
+    let url = std::env::var("NATS_TEST_URL").unwrap();
+    let prefix = format!("test{}", Utc::now().timestamp_millis());
+    let config = NatsConfig {
+        url: url.clone(),
+        subject_template: format!("{}.{{asset_type}}.{{stream}}.{{symbol}}", prefix),
+        stream: prefix.to_uppercase(),
+        stream_subjects: vec![format!("{}.>", prefix)],
+        ..Default::default()
+    };
+    let publisher = Arc::new(NatsPublisher::connect(config.clone()).await.unwrap());
+    // The same trade is published twice, like after a journal replay.
+    for _ in 0..2 {
+        publish_trade(Arc::new(NatsSinkFactory::new(publisher.clone()))).await;
+    }
+
+    let jetstream = async_nats::jetstream::new(async_nats::connect(&url).await.unwrap());
+    let mut stream = jetstream.get_stream(&config.stream).await.unwrap();
+    assert_eq!(stream.info().await.unwrap().state.messages, 1);
+    let message = stream
+        .get_last_raw_message_by_subject(&format!("{}.usdm_fut.trade.BTCUSDT", prefix))
+        .await
+        .unwrap();
+    assert_eq!(message.sequence, 1);
+    jetstream.delete_stream(&config.stream).await.unwrap();
+}
+
+/// Needs a Redis server it can write a test key to, given by `REDIS_TEST_URL`.
+/// Run with `REDIS_TEST_URL=redis://localhost:6379 cargo test redis -- --ignored`,
+/// e.g. against the services of `docker-compose.test.yml`, as CI does.
+#[tokio::test]
+#[ignore]
+async fn test_redis_streams_sink_appends_by_symbol() {
+    let url = std::env::var("REDIS_TEST_URL").unwrap();
+    let prefix = format!("test{}", Utc::now().timestamp_millis());
+    let publisher = RedisStreamsPublisher::connect(RedisStreamsConfig {
+        url: url.clone(),
+        key_template: format!("{}.{{asset_type}}.{{stream}}.{{symbol}}", prefix),
+        ..Default::default()
+    })
+    .await
+    .unwrap();
+    publish_trade(Arc::new(RedisStreamsSinkFactory::new(Arc::new(publisher)))).await;
+
+    let mut connection = redis::Client::open(url.as_str())
+        .unwrap()
+        .get_async_connection()
+        .await
+        .unwrap();
+    let key = format!("{}.usdm_fut.trade.BTCUSDT", prefix);
+    let reply: StreamRangeReply = connection.xrange_all(&key).await.unwrap();
+    assert_eq!(reply.ids.len(), 1);
+    let data: Vec<u8> = reply.ids[0].get("data").unwrap();
+    let trade: serde_json::Value = serde_json::from_slice(&data).unwrap();
+    assert_eq!(trade["tradeId"], 1);
+    let _: () = connection.del(&key).await.unwrap();
+}
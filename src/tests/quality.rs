@@ -0,0 +1,192 @@
+use std::sync::{Arc, Mutex};
+
+use async_trait::async_trait;
+use chrono::{TimeZone, Utc};
+
+use crate::{
+    binance::{
+        models::{orderbook::OrderbookMessage, trades::Trade},
+        websocket::{
+            handlers::{EventHandler, RawFrame},
+            requests::{BinanceAssetType, FuturesType},
+            router::route,
+        },
+    },
+    quality::{
+        check_file, describe, AlertHook, Issue, IssueKind, QualityConfig, QualityMonitor,
+        QualityReport,
+    },
+    sinks::{
+        bus::{EventBus, PipelineConfig},
+        csv_file::CsvFileSinkFactory,
+        json_lines::{JsonLinesMode, JsonLinesSinkFactory},
+        manifest::write_manifest,
+        Dataset, Record,
+    },
+    tests::fixtures::TRADE_DATA,
+};
+
+const DEPTH_UPDATE: &str = r#"{"e":"depthUpdate","E":1676214000123,"s":"BTCUSDT","U":10,"u":12,"pu":9,"b":[["21800.00","1.204"]],"a":[["21803.50","3.019"]]}"#;
+
+fn trade(id: i64, time: i64) -> Trade {
+    let trade = TRADE_DATA.replace(r#""t":1"#, &format!(r#""t":{id}"#));
+    serde_json::from_str(&trade.replace("1676214000123", &time.to_string())).unwrap()
+}
+
+fn depth_update(first: i64, last: i64, previous: i64) -> OrderbookMessage {
+    let mut update: OrderbookMessage = serde_json::from_str(DEPTH_UPDATE).unwrap();
+    (
+        update.first_update_id,
+        update.last_update_id,
+        update.prev_last_update_id,
+    ) = (first, last, Some(previous));
+    update
+}
+
+#[derive(Default)]
+struct Collector(Mutex<Vec<Issue>>);
+#[async_trait]
+impl AlertHook for Collector {
+    async fn alert(&self, issues: &[Issue]) {
+        self.0.lock().unwrap().extend(issues.iter().cloned());
+    }
+}
+
+#[tokio::test]
+async fn test_live_and_file_issues() {
+    let folder = std::env::temp_dir().join("binance_data_gatherer_quality_test");
+    _ = std::fs::remove_dir_all(&folder);
+    let collector = Arc::new(Collector::default());
+    let monitor = QualityMonitor::new(QualityConfig::default()).with_hook(collector.clone());
+    let bus = EventBus::new(
+        BinanceAssetType::Futures(FuturesType::USDMargined),
+        PipelineConfig::default(),
+    )
+    .with_sink(Arc::new(CsvFileSinkFactory::new(&folder)))
+    .with_sink(Arc::new(JsonLinesSinkFactory::new(
+        folder.join("jsonl"),
+        JsonLinesMode::Raw,
+    )));
+    // 3 is missing, 5 comes twice then 4 late, and the last trade is a millisecond earlier.
+    for (id, time) in [
+        (1, 1_676_214_000_100),
+        (2, 1_676_214_000_100),
+        (5, 1_676_214_000_200),
+        (5, 1_676_214_000_200),
+        (4, 1_676_214_000_200),
+        (6, 1_676_214_000_199),
+    ] {
+        let trade = trade(id, time);
+        monitor.on_trade(&trade).await;
+        bus.publish(Record::Trade(trade)).await;
+    }
+    // The third update does not chain from the second one.
+    for (first, last, previous) in [(10, 12, 9), (13, 15, 12), (18, 20, 16)] {
+        let update = depth_update(first, last, previous);
+        monitor.on_depth(&update).await;
+        let text = format!(
+            r#"{{"stream":"btcusdt@depth@100ms","data":{}}}"#,
+            serde_json::to_string(&update).unwrap()
+        );
+        bus.on_frame(&RawFrame {
+            received: Utc.timestamp_millis_opt(1_676_214_000_200).unwrap(),
+            connection_id: 1,
+            frame: Some(&route(&text).unwrap()),
+            text: &text,
+        })
+        .await;
+    }
+    bus.shutdown().await;
+
+    let kinds = |issues: &[Issue], dataset: Dataset| {
+        issues
+            .iter()
+            .filter(|issue| issue.dataset == dataset)
+            .map(|issue| issue.kind.clone())
+            .collect::<Vec<_>>()
+    };
+    let expected_trades = vec![
+        IssueKind::Gap { last: 2, next: 5 },
+        IssueKind::Duplicate { id: 5 },
+        IssueKind::OutOfOrder { last: 5, id: 4 },
+        IssueKind::TimeRegression {
+            last: trade(5, 1_676_214_000_200).event_time,
+            time: trade(6, 1_676_214_000_199).event_time,
+        },
+    ];
+    let expected_depth = vec![IssueKind::Gap { last: 15, next: 16 }];
+    assert_eq!(monitor.alert().await, 5);
+    let alerted = collector.0.lock().unwrap().clone();
+    assert_eq!(kinds(&alerted, Dataset::Trades), expected_trades);
+    assert_eq!(kinds(&alerted, Dataset::BookHistory), expected_depth);
+    assert!(alerted
+        .iter()
+        .all(|issue| issue.symbol == "BTCUSDT" && issue.file.is_none()));
+
+    // Both files hold the ids.
+    let path = monitor
+        .write_report(&folder, "USDM_FUT_QUALITY_20230212T160000Z")
+        .await
+        .unwrap()
+        .unwrap();
+    let report: QualityReport =
+        serde_json::from_reader(std::fs::File::open(path).unwrap()).unwrap();
+    assert_eq!(report.live.len(), 5);
+    assert_eq!(report.files.len(), 2);
+    let (depth, trades) = (&report.files[0], &report.files[1]);
+    assert_eq!(
+        (
+            depth.dataset,
+            depth.ids_checked,
+            depth.records,
+            depth.issues.len()
+        ),
+        (Dataset::BookHistory, true, 6, 1)
+    );
+    assert_eq!(kinds(&depth.issues, Dataset::BookHistory), expected_depth);
+    assert_eq!(
+        (trades.dataset, trades.ids_checked, trades.records),
+        (Dataset::Trades, true, 6)
+    );
+    assert_eq!(kinds(&trades.issues, Dataset::Trades), expected_trades);
+    assert_eq!(
+        trades.issues[0].file.as_deref(),
+        Some("USDM_FUT_BTCUSDT_TRADES_20230212T150000Z.csv")
+    );
+    // The file issues are alerted in turn, and the live ones are only reported once.
+    assert_eq!(monitor.alert().await, 5);
+    write_manifest(&folder, "USDM_FUT_MANIFEST").unwrap();
+
+    // The file of the next window is checked from the last trade of the previous one.
+    let bus = EventBus::new(
+        BinanceAssetType::Futures(FuturesType::USDMargined),
+        PipelineConfig::default(),
+    )
+    .with_sink(Arc::new(CsvFileSinkFactory::new(&folder)));
+    bus.publish(Record::Trade(trade(8, 1_676_217_600_100)))
+        .await;
+    bus.shutdown().await;
+    let path = monitor
+        .write_report(&folder, "USDM_FUT_QUALITY_NEXT")
+        .await
+        .unwrap()
+        .unwrap();
+    let report: QualityReport =
+        serde_json::from_reader(std::fs::File::open(path).unwrap()).unwrap();
+    assert!(report.live.is_empty());
+    assert_eq!(report.files.len(), 1);
+    assert_eq!(
+        report.files[0].issues[0].kind,
+        IssueKind::Gap { last: 6, next: 8 }
+    );
+
+    let jsonl = folder.join("jsonl/USDM_FUT_BTCUSDT_BOOK_HISTORY_20230212T150000Z.jsonl");
+    assert_eq!(
+        describe(&jsonl),
+        Some((Dataset::BookHistory, "BTCUSDT".to_string()))
+    );
+    let depth = check_file(&jsonl, Dataset::BookHistory, "BTCUSDT", None).unwrap();
+    assert_eq!((depth.ids_checked, depth.records), (true, 3));
+    assert_eq!(kinds(&depth.issues, Dataset::BookHistory), expected_depth);
+    std::fs::remove_dir_all(&folder).unwrap();
+}
@@ -0,0 +1,103 @@
+use std::sync::{Arc, Mutex};
+
+use async_trait::async_trait;
+use chrono::{Duration, TimeZone, Utc};
+
+use crate::binance::{
+    models::{orderbook::new_orderbooks_rwl, trades::Trade},
+    websocket::{
+        handlers::{depth_update::OrderBookMaintainer, EventHandler, EventHandlers, RawFrame},
+        recorder::{replay_file, FrameRecorder, ReplaySpeed},
+    },
+};
+use crate::tests::fixtures::{trade, DEPTH};
+
+#[derive(Default)]
+struct Seen {
+    trades: Mutex<Vec<i64>>,
+    frames: Mutex<Vec<(i64, Option<String>)>>,
+}
+
+#[async_trait]
+impl EventHandler for Seen {
+    async fn on_trade(&self, trade: &Trade) {
+        self.trades.lock().unwrap().push(trade.trade_id);
+    }
+    async fn on_frame(&self, frame: &RawFrame<'_>) {
+        let received = frame.received.timestamp_nanos();
+        let stream = frame.stream().map(str::to_string);
+        self.frames.lock().unwrap().push((received, stream));
+    }
+}
+
+/// Records two trades, a depth update and an unparsable frame, one second apart.
+async fn record(name: &str) -> std::path::PathBuf {
+    let folder = std::env::temp_dir().join(name);
+    _ = std::fs::remove_dir_all(&folder);
+    let recorder = FrameRecorder::new(&folder);
+    let start = Utc.timestamp_nanos(1_676_214_000_123_456_789);
+    let frames = [
+        trade(1),
+        DEPTH.to_string(),
+        trade(2),
+        "not json".to_string(),
+    ];
+    for (i, text) in frames.iter().enumerate() {
+        let frame = RawFrame {
+            received: start + Duration::seconds(i as i64),
+            connection_id: 7,
+            frame: None,
+            text,
+        };
+        recorder.on_frame(&frame).await;
+    }
+    recorder.on_disconnect(7, "wss://fstream.binance.com").await;
+    let file = std::fs::read_dir(&folder)
+        .unwrap()
+        .next()
+        .unwrap()
+        .unwrap()
+        .path();
+    assert!(file.to_string_lossy().ends_with("_000007.frames.bz2"));
+    file
+}
+
+#[tokio::test]
+async fn test_replay_as_fast_as_possible() {
+    let file = record("binance_data_gatherer_recorder_fast").await;
+    let seen = Arc::new(Seen::default());
+    let orderbooks_rwl = new_orderbooks_rwl();
+    let maintainer = Arc::new(OrderBookMaintainer::new(orderbooks_rwl.clone()));
+    let handlers: EventHandlers = vec![seen.clone(), maintainer];
+    assert_eq!(
+        replay_file(&file, &handlers, ReplaySpeed::AsFastAsPossible)
+            .await
+            .unwrap(),
+        4
+    );
+    assert_eq!(*seen.trades.lock().unwrap(), vec![1, 2]);
+    let frames = seen.frames.lock().unwrap().clone();
+    assert_eq!(
+        frames[0],
+        (1_676_214_000_123_456_789, Some("btcusdt@trade".to_string()))
+    );
+    assert_eq!(frames[1].1.as_deref(), Some("btcusdt@depth@100ms"));
+    assert_eq!(frames[3].1, None);
+    // The replay ends like a connection does, so the book is dropped.
+    assert!(orderbooks_rwl.read().await.is_empty());
+    std::fs::remove_dir_all(file.parent().unwrap()).unwrap();
+}
+
+#[tokio::test(start_paused = true)]
+async fn test_replay_accelerated() {
+    let file = record("binance_data_gatherer_recorder_accelerated").await;
+    let handlers: EventHandlers = vec![Arc::new(Seen::default())];
+    let started = tokio::time::Instant::now();
+    replay_file(&file, &handlers, ReplaySpeed::Accelerated(10.0))
+        .await
+        .unwrap();
+    assert_eq!(started.elapsed(), std::time::Duration::from_millis(300));
+    assert_eq!("max".parse(), Ok(ReplaySpeed::AsFastAsPossible));
+    assert!("0".parse::<ReplaySpeed>().is_err());
+    std::fs::remove_dir_all(file.parent().unwrap()).unwrap();
+}
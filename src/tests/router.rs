@@ -0,0 +1,101 @@
+use crate::binance::websocket::router::{route, Frame, StreamEvent, StreamKind};
+
+const TRADE: &str = r#"{"stream":"btcusdt@trade","data":{"e":"trade","E":1676214000123,"T":1676214000120,"s":"BTCUSDT","t":3156843171,"p":"21803.40","q":"0.015","X":"MARKET","m":true}}"#;
+const DEPTH_UPDATE: &str = r#"{"stream":"btcusdt@depth@100ms","data":{"e":"depthUpdate","E":1676214000123,"T":1676214000119,"s":"BTCUSDT","U":2425684330841,"u":2425684338437,"pu":2425684330562,"b":[["21800.00","1.204"],["21799.90","0.000"]],"a":[["21803.50","3.019"]]}}"#;
+const FUTURES_PARTIAL_DEPTH: &str = r#"{"stream":"btcusdt@depth5@100ms","data":{"e":"depthUpdate","E":1676214000123,"T":1676214000119,"s":"BTCUSDT","U":150,"u":160,"pu":149,"b":[["21800.00","1.204"]],"a":[["21803.50","3.019"]]}}"#;
+const SPOT_PARTIAL_DEPTH: &str = r#"{"stream":"btcusdt@depth5","data":{"lastUpdateId":160,"bids":[["0.0024","10"]],"asks":[["0.0026","100"]]}}"#;
+
+#[test]
+fn test_stream_kind() {
+    assert_eq!(StreamKind::from_stream_name("btcusdt@trade"), StreamKind::Trade);
+    assert_eq!(StreamKind::from_stream_name("btcusdt@depth"), StreamKind::DepthUpdate);
+    assert_eq!(StreamKind::from_stream_name("btcusdt@depth@100ms"), StreamKind::DepthUpdate);
+    assert_eq!(StreamKind::from_stream_name("btcusdt@depth20@100ms"), StreamKind::PartialDepth);
+    assert_eq!(StreamKind::from_stream_name("btcusdt@bookTicker"), StreamKind::BookTicker);
+    assert_eq!(StreamKind::from_stream_name("btcusdt@markPrice@1s"), StreamKind::Unknown);
+}
+
+#[test]
+fn test_route_events() {
+    match route(TRADE).unwrap() {
+        Frame::Event { stream, event: StreamEvent::Trade(trade), .. } => {
+            assert_eq!(stream, "btcusdt@trade");
+            assert_eq!(trade.trade_id, 3156843171);
+            assert!(trade.buyer_is_the_market_maker);
+        }
+        frame => panic!("Unexpected frame {frame:?}"),
+    }
+    match route(DEPTH_UPDATE).unwrap() {
+        Frame::Event { event: StreamEvent::DepthUpdate(update), .. } => {
+            assert_eq!(update.prev_last_update_id, Some(2425684330562));
+            assert_eq!(update.bids.len(), 2);
+        }
+        frame => panic!("Unexpected frame {frame:?}"),
+    }
+    // Spot partial depth payloads have no event type, they used to panic the connection.
+    match route(SPOT_PARTIAL_DEPTH).unwrap() {
+        Frame::Event { event: StreamEvent::PartialDepth { symbol, book }, .. } => {
+            assert_eq!(symbol, "BTCUSDT");
+            assert_eq!(book.last_update_id, 160);
+            assert_eq!(book.asks[0].size.to_string(), "100");
+        }
+        frame => panic!("Unexpected frame {frame:?}"),
+    }
+    // Futures partial depth keeps the event time of the exchange.
+    match route(FUTURES_PARTIAL_DEPTH).unwrap() {
+        Frame::Event { event: StreamEvent::PartialDepth { symbol, book }, .. } => {
+            assert_eq!(symbol, "BTCUSDT");
+            assert_eq!(book.last_update_id, 160);
+            assert_eq!(book.received_ts.timestamp_millis(), 1676214000123);
+        }
+        frame => panic!("Unexpected frame {frame:?}"),
+    }
+}
+
+#[test]
+fn test_route_responses_and_errors() {
+    assert!(matches!(
+        route(r#"{"result":null,"id":1}"#).unwrap(),
+        Frame::Response { id: Some(1), error: None }
+    ));
+    assert!(matches!(
+        route(r#"{"error":{"code":2,"msg":"Invalid request"},"id":3}"#).unwrap(),
+        Frame::Response { id: Some(3), error: Some(_) }
+    ));
+    assert!(matches!(
+        route(r#"{"stream":"btcusdt@markPrice@1s","data":{"e":"markPriceUpdate"}}"#).unwrap(),
+        Frame::Unrouted { .. }
+    ));
+    assert!(route(r#"{"stream":"btcusdt@trade","data":{"e":"trade","p":1}}"#).is_err());
+    assert!(route("not json").is_err());
+    // Malformed levels are errors rather than panics.
+    for level in [r#"["21800.00"]"#, r#"["21800.00","1.204","1"]"#, r#"["price","1.204"]"#] {
+        let frame = DEPTH_UPDATE.replace(r#"["21799.90","0.000"]"#, level);
+        assert!(route(&frame).is_err());
+    }
+}
+
+/// Single threaded routing throughput, i.e. per core.
+/// Run with `cargo test --release router_throughput -- --ignored --nocapture`.
+#[test]
+#[ignore]
+fn router_throughput() {
+    let frames = [TRADE, DEPTH_UPDATE, SPOT_PARTIAL_DEPTH];
+    let iterations = 300_000;
+    let bytes = frames.iter().map(|f| f.len()).sum::<usize>() * iterations;
+    let start = std::time::Instant::now();
+    for _ in 0..iterations {
+        for frame in frames {
+            std::hint::black_box(route(std::hint::black_box(frame)).unwrap());
+        }
+    }
+    let elapsed = start.elapsed().as_secs_f64();
+    let routed = (frames.len() * iterations) as f64;
+    println!(
+        "routed {} frames in {:.3}s: {:.0} frames/s/core, {:.1} MB/s/core",
+        routed,
+        elapsed,
+        routed / elapsed,
+        bytes as f64 / elapsed / 1_000_000.0
+    );
+}
@@ -0,0 +1,120 @@
+use std::sync::Arc;
+
+use chrono::{TimeZone, Utc};
+use prost::Message;
+
+use crate::{
+    binance::{
+        mock::{MockConfig, MockExchange},
+        time_sync::{ServerClock, TimeSyncConfig},
+        websocket::{
+            handlers::{EventHandler, RawFrame},
+            requests::{BinanceAssetType, FuturesType},
+            router::route,
+        },
+    },
+    metrics::Metrics,
+    proto,
+    sinks::{
+        bus::{EventBus, PipelineConfig},
+        csv_file::CsvFileSinkFactory,
+        encoding::Encoding,
+        json_lines::{JsonLinesMode, JsonLinesSinkFactory},
+        manifest::ManifestEntry,
+        FrameRecord, Record,
+    },
+    tests::fixtures::{TRADE, TRADE_DATA},
+};
+
+#[tokio::test]
+async fn test_clock_offset_is_estimated_and_recorded() {
+    let mock = MockExchange::start(MockConfig {
+        clock_offset_ms: 5_000,
+        ..Default::default()
+    })
+    .await
+    .unwrap();
+    let asset_type = BinanceAssetType::Futures(FuturesType::USDMargined);
+    let config = TimeSyncConfig::new(&asset_type);
+    assert_eq!(config.url, "https://fapi.binance.com/fapi/v1/time");
+    let clock = Arc::new(ServerClock::new(
+        config.with_base_url(&mock.http_base_url()),
+    ));
+    assert!(clock.estimate().is_none());
+    for _ in 0..3 {
+        clock.sync().await.unwrap();
+    }
+    let estimate = clock.estimate().unwrap();
+    // The server time has a millisecond resolution, and the error is bounded by half the round trip.
+    let error = (estimate.offset_ms - 5_000.0).abs();
+    assert!(error <= 1.0 + estimate.rtt_ms / 2.0, "{estimate:?}");
+
+    let folder = std::env::temp_dir().join("binance_data_gatherer_time_sync_test");
+    _ = std::fs::remove_dir_all(&folder);
+    let bus = EventBus::new(asset_type, PipelineConfig::default())
+        .with_clock(clock.clone())
+        .with_sink(Arc::new(
+            JsonLinesSinkFactory::new(&folder, JsonLinesMode::Raw).with_clock(clock.clone()),
+        ))
+        .with_sink(Arc::new(CsvFileSinkFactory::new(folder.join("csv"))));
+    bus.on_frame(&RawFrame {
+        received: Utc.timestamp_millis_opt(1_676_214_000_200).unwrap(),
+        connection_id: 1,
+        frame: Some(&route(TRADE).unwrap()),
+        text: TRADE,
+    })
+    .await;
+    bus.shutdown().await;
+    let file = folder.join("USDM_FUT_BTCUSDT_TRADES_20230212T150000Z.jsonl");
+    let line: serde_json::Value =
+        serde_json::from_str(&std::fs::read_to_string(&file).unwrap()).unwrap();
+    let corrected = 1_676_214_000_200 + estimate.offset().num_milliseconds();
+    assert_eq!(line["recv_ts"], 1_676_214_000_200i64);
+    assert_eq!(line["recv_ts_corrected"], corrected);
+    let sidecar = std::fs::File::open(format!("{}.manifest.json", file.display())).unwrap();
+    let manifest: ManifestEntry = serde_json::from_reader(sidecar).unwrap();
+    assert_eq!(manifest.clock, Some(estimate.clone()));
+    let csv = folder.join("csv/USDM_FUT_BTCUSDT_TRADES_20230212T150000Z.csv");
+    let csv = std::fs::read_to_string(csv).unwrap();
+    assert!(csv
+        .lines()
+        .next()
+        .unwrap()
+        .ends_with(",recv_ts,recv_ts_corrected"));
+    assert!(csv.ends_with(&format!(",1676214000200,{corrected}\n")));
+    std::fs::remove_dir_all(&folder).unwrap();
+
+    // Messages carry the receive times of the frames too.
+    let frame = Record::Frame(FrameRecord {
+        stream: "btcusdt@trade".to_string(),
+        received: Utc.timestamp_millis_opt(1_676_214_000_200).unwrap(),
+        corrected: Some(Utc.timestamp_millis_opt(corrected).unwrap()),
+        payload: serde_json::value::RawValue::from_string("{}".to_string()).unwrap(),
+        record: Box::new(Record::Trade(serde_json::from_str(TRADE_DATA).unwrap())),
+    });
+    let trade = proto::Trade::decode(&*Encoding::Protobuf.encode(&frame).unwrap()).unwrap();
+    assert_eq!(
+        (trade.recv_ts, trade.recv_ts_corrected),
+        (Some(1_676_214_000_200), Some(corrected))
+    );
+    let avro = Encoding::Avro.encode(&frame).unwrap();
+    // Both times are the last fields, as `["null", "long"]` unions of index 1.
+    let mut ending = Vec::new();
+    for time in [1_676_214_000_200i64, corrected] {
+        ending.push(2);
+        let mut zigzag = (time << 1) as u64;
+        while zigzag >= 0x80 {
+            ending.push((zigzag as u8) | 0x80);
+            zigzag >>= 7;
+        }
+        ending.push(zigzag as u8);
+    }
+    assert!(avro.ends_with(&ending));
+
+    let metrics = Metrics::new().with_clock(clock);
+    let rendered = metrics.render().await;
+    assert!(rendered.contains(&format!(
+        "binance_clock_offset_seconds {}",
+        estimate.offset_ms / 1e3
+    )));
+}
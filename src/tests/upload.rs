@@ -0,0 +1,100 @@
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::Duration;
+
+use crate::{
+    bucket_utils::mock::MockS3,
+    upload_queue::{UploadConfig, UploadQueue, SHA256_METADATA_KEY},
+};
+
+fn config(name: &str) -> (PathBuf, UploadConfig) {
+    let folder = std::env::temp_dir().join(name);
+    _ = std::fs::remove_dir_all(&folder);
+    std::fs::create_dir_all(&folder).unwrap();
+    let config = UploadConfig {
+        folder: folder.join("uploads"),
+        prefix: "usdm/".to_string(),
+        multipart_threshold: 1000,
+        part_size: 400,
+        initial_backoff: Duration::ZERO,
+        max_backoff: Duration::ZERO,
+        archive: None,
+    };
+    (folder, config)
+}
+
+fn write_file(path: &Path, size: usize) -> Vec<u8> {
+    let data = (0..size).map(|i| (i % 251) as u8).collect::<Vec<_>>();
+    std::fs::write(path, &data).unwrap();
+    data
+}
+
+#[tokio::test]
+async fn test_uploads_survive_failures_and_restarts() {
+    let s3 = MockS3::start().await.unwrap();
+    let (folder, config) = config("binance_data_gatherer_upload_test");
+    let small = folder.join("small.csv.zst");
+    let large = folder.join("large.csv.zst");
+    let small_data = write_file(&small, 100);
+    let large_data = write_file(&large, 1500);
+    let queue = UploadQueue::open(config.clone(), Arc::new(s3.bucket("data"))).unwrap();
+    queue.enqueue(&small).unwrap();
+    queue.enqueue(&large).unwrap();
+    queue.enqueue(&small).unwrap();
+    assert_eq!(queue.pending(), 2);
+    s3.fail_after(0, 2);
+    assert_eq!(queue.process_due().await, 0);
+    assert!(small.exists() && large.exists());
+    drop(queue);
+
+    // Both tasks were persisted and are picked up again.
+    let queue = UploadQueue::open(config, Arc::new(s3.bucket("data"))).unwrap();
+    assert_eq!(queue.pending(), 2);
+    assert_eq!(queue.process_due().await, 2);
+    assert_eq!(queue.pending(), 0);
+    assert!(!small.exists() && !large.exists());
+    let object = s3.object("data", "usdm/small.csv.zst").unwrap();
+    assert_eq!(object.data, small_data);
+    let object = s3.object("data", "usdm/large.csv.zst").unwrap();
+    assert_eq!(object.data, large_data);
+    assert!(object.etag.ends_with("-4\""));
+    assert_eq!(object.metadata[SHA256_METADATA_KEY].len(), 64);
+    assert_eq!(std::fs::read_dir(folder.join("uploads")).unwrap().count(), 0);
+}
+
+#[tokio::test]
+async fn test_multipart_upload_resumes() {
+    let s3 = MockS3::start().await.unwrap();
+    let (folder, config) = config("binance_data_gatherer_resume_test");
+    let file = folder.join("book.csv.zst");
+    let data = write_file(&file, 1500);
+    let queue = UploadQueue::open(config.clone(), Arc::new(s3.bucket("data"))).unwrap();
+    queue.enqueue(&file).unwrap();
+    // Creating the upload and the first two parts go through, the third part fails.
+    s3.fail_after(3, 1);
+    assert_eq!(queue.process_due().await, 0);
+    assert_eq!(s3.parts_received(), 2);
+    drop(queue);
+
+    let queue = UploadQueue::open(config, Arc::new(s3.bucket("data"))).unwrap();
+    assert_eq!(queue.process_due().await, 1);
+    assert_eq!(s3.parts_received(), 4);
+    assert_eq!(s3.object("data", "usdm/book.csv.zst").unwrap().data, data);
+    assert!(!file.exists());
+}
+
+#[tokio::test]
+async fn test_missing_files_are_skipped() {
+    let s3 = MockS3::start().await.unwrap();
+    let (folder, config) = config("binance_data_gatherer_upload_missing_test");
+    let gone = folder.join("gone.csv.zst");
+    write_file(&gone, 100);
+    let queue = UploadQueue::open(config, Arc::new(s3.bucket("data"))).unwrap();
+    queue.enqueue(&gone).unwrap();
+    std::fs::remove_file(&gone).unwrap();
+    assert_eq!(queue.process_due().await, 0);
+    assert_eq!(queue.pending(), 0);
+    let stats = queue.stats();
+    assert_eq!((stats.succeeded, stats.skipped), (0, 1));
+    assert!(s3.object("data", "usdm/gone.csv.zst").is_none());
+}
@@ -0,0 +1,48 @@
+use crate::binance::websocket::{
+    requests::{BinanceAssetType, DataRequest, FuturesType, Stream},
+    watchdog::{LivenessConfig, Stall, Watchdog},
+};
+
+fn request() -> DataRequest {
+    DataRequest::new(
+        BinanceAssetType::Futures(FuturesType::USDMargined),
+        vec![
+            Stream::Depth("BTCUSDT".to_string(), 100),
+            Stream::Depth("ETHUSDT".to_string(), 1000),
+            Stream::Trade("BTCUSDT".to_string()),
+        ],
+    )
+}
+
+#[tokio::test(start_paused = true)]
+async fn test_stream_stall_uses_cadence() {
+    let watchdog = Watchdog::new(&request(), LivenessConfig::default());
+    // 100ms depth is floored at the minimum timeout, 1000ms depth waits 20 intervals.
+    tokio::time::advance(std::time::Duration::from_secs(11)).await;
+    watchdog.record(Some("ethusdt@depth@1000ms"));
+    assert_eq!(
+        watchdog.check(),
+        Some(Stall::Streams(vec![Stream::Depth("BTCUSDT".to_string(), 100)]))
+    );
+    watchdog.record(Some("btcusdt@depth@100ms"));
+    assert_eq!(watchdog.check(), None);
+    tokio::time::advance(std::time::Duration::from_secs(15)).await;
+    watchdog.record(Some("btcusdt@depth@100ms"));
+    assert_eq!(watchdog.check(), None);
+}
+
+#[tokio::test(start_paused = true)]
+async fn test_connection_stall() {
+    let watchdog = Watchdog::new(&request(), LivenessConfig::default());
+    tokio::time::advance(std::time::Duration::from_secs(61)).await;
+    assert!(matches!(watchdog.check(), Some(Stall::Connection(_))));
+    // Pongs keep the connection alive but do not count as stream activity.
+    watchdog.record(None);
+    match watchdog.monitor().await {
+        Stall::Streams(streams) => {
+            assert_eq!(streams.len(), 2);
+            assert!(streams.contains(&Stream::Depth("ETHUSDT".to_string(), 1000)));
+        }
+        stall => panic!("Unexpected stall {stall:?}"),
+    }
+}
@@ -0,0 +1,439 @@
+//! A durable queue of files to upload to an [`ObjectStore`].
+//!
+//! Every queued file is recorded in its own task file, so uploads survive restarts, and is retried with an
+//! exponential backoff until it succeeds. Large files are uploaded in parts, and an interrupted multipart
+//! upload is resumed from the parts the store already has. Every body is sent with its MD5, and once uploaded
+//! the object is checked against the size, ETag and SHA256 of the local file, which is only deleted,
+//! or moved to the archive, then.
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::{BufReader, Read, Seek, SeekFrom};
+use std::path::{Path, PathBuf};
+use std::sync::{
+    atomic::{AtomicU64, Ordering},
+    Arc, Mutex,
+};
+use std::time::Duration;
+
+use base64::{engine::general_purpose::STANDARD, Engine};
+use log::{error, info, warn};
+use md5::{Digest, Md5};
+use serde::{Deserialize, Serialize};
+use sha2::Sha256;
+use tokio::{sync::Notify, time::Instant};
+
+use crate::{
+    bucket_utils::{ObjectStore, StoreError, UploadedPart},
+    settings::UPLOAD_QUEUE_FOLDER_NAME,
+};
+
+const TASK_EXTENSION: &str = "upload.json";
+/// Metadata key holding the hex SHA256 of the uploaded file.
+pub const SHA256_METADATA_KEY: &str = "sha256";
+
+#[derive(Debug, Clone)]
+pub struct UploadConfig {
+    /// Where the queued tasks are kept.
+    pub folder: PathBuf,
+    /// Prepended to the file names to build the object keys.
+    pub prefix: String,
+    /// Files larger than this are uploaded in parts.
+    pub multipart_threshold: u64,
+    /// S3 requires parts of at least 5 MiB, except for the last one.
+    pub part_size: u64,
+    pub initial_backoff: Duration,
+    pub max_backoff: Duration,
+    /// Where verified files are moved instead of being deleted, pruned by the [`crate::disk_guard`].
+    pub archive: Option<PathBuf>,
+}
+impl Default for UploadConfig {
+    fn default() -> Self {
+        Self {
+            folder: PathBuf::from(UPLOAD_QUEUE_FOLDER_NAME),
+            prefix: String::new(),
+            multipart_threshold: 64 * 1024 * 1024,
+            part_size: 16 * 1024 * 1024,
+            initial_backoff: Duration::from_secs(1),
+            max_backoff: Duration::from_secs(300),
+            archive: None,
+        }
+    }
+}
+
+/// A file waiting to be uploaded, as persisted in the queue folder.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct UploadTask {
+    pub file: PathBuf,
+    pub key: String,
+    pub attempts: u32,
+    /// Set once a multipart upload was started, so it can be resumed.
+    pub upload_id: Option<String>,
+}
+
+/// Outcomes of the upload attempts since the queue was opened.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct UploadStats {
+    pub succeeded: u64,
+    pub failed: u64,
+    /// Tasks dropped because their file was gone.
+    pub skipped: u64,
+    /// Size of the files uploaded.
+    pub bytes: u64,
+}
+
+struct Pending {
+    path: PathBuf,
+    task: UploadTask,
+    due: Instant,
+    in_flight: bool,
+}
+
+pub struct UploadQueue {
+    config: UploadConfig,
+    store: Arc<dyn ObjectStore>,
+    pending: Mutex<Vec<Pending>>,
+    next_id: AtomicU64,
+    notify: Notify,
+    succeeded: AtomicU64,
+    failed: AtomicU64,
+    skipped: AtomicU64,
+    bytes: AtomicU64,
+}
+impl UploadQueue {
+    /// Opens the queue in `config.folder`, picking up the tasks left by a previous run.
+    pub fn open(config: UploadConfig, store: Arc<dyn ObjectStore>) -> std::io::Result<Self> {
+        std::fs::create_dir_all(&config.folder)?;
+        let mut pending = Vec::new();
+        let mut last_id = 0;
+        for file in std::fs::read_dir(&config.folder)? {
+            let path = file?.path();
+            let name = path.file_name().unwrap().to_string_lossy().to_string();
+            let id = match name.strip_suffix(&format!(".{}", TASK_EXTENSION)) {
+                Some(id) => id.parse::<u64>().unwrap_or(0),
+                None => continue,
+            };
+            last_id = last_id.max(id);
+            match serde_json::from_reader::<_, UploadTask>(BufReader::new(File::open(&path)?)) {
+                Ok(task) => pending.push(Pending {
+                    path,
+                    task,
+                    due: Instant::now(),
+                    in_flight: false,
+                }),
+                Err(e) => error!("Error reading upload task {}: {}", path.display(), e),
+            }
+        }
+        pending.sort_by(|a, b| a.path.cmp(&b.path));
+        if !pending.is_empty() {
+            info!("Resuming {} queued uploads", pending.len());
+        }
+        Ok(Self {
+            config,
+            store,
+            pending: Mutex::new(pending),
+            next_id: AtomicU64::new(last_id + 1),
+            notify: Notify::new(),
+            succeeded: AtomicU64::new(0),
+            failed: AtomicU64::new(0),
+            skipped: AtomicU64::new(0),
+            bytes: AtomicU64::new(0),
+        })
+    }
+    /// Queues `file` for upload under the configured prefix followed by its name, unless it is already queued.
+    pub fn enqueue(&self, file: &Path) -> std::io::Result<()> {
+        let mut pending = self.pending.lock().unwrap();
+        if pending.iter().any(|pending| pending.task.file == file) {
+            return Ok(());
+        }
+        let name = file.file_name().unwrap_or_default().to_string_lossy();
+        let task = UploadTask {
+            file: file.to_path_buf(),
+            key: format!("{}{}", self.config.prefix, name),
+            attempts: 0,
+            upload_id: None,
+        };
+        let id = self.next_id.fetch_add(1, Ordering::SeqCst);
+        let path = self
+            .config
+            .folder
+            .join(format!("{:020}.{}", id, TASK_EXTENSION));
+        persist(&path, &task)?;
+        pending.push(Pending {
+            path,
+            task,
+            due: Instant::now(),
+            in_flight: false,
+        });
+        self.notify.notify_one();
+        Ok(())
+    }
+    /// Counts the files not uploaded yet.
+    pub fn pending(&self) -> usize {
+        self.pending.lock().unwrap().len()
+    }
+    pub fn stats(&self) -> UploadStats {
+        UploadStats {
+            succeeded: self.succeeded.load(Ordering::Relaxed),
+            failed: self.failed.load(Ordering::Relaxed),
+            skipped: self.skipped.load(Ordering::Relaxed),
+            bytes: self.bytes.load(Ordering::Relaxed),
+        }
+    }
+    /// Attempts every task that is due once, and returns how many were uploaded.
+    /// Failed tasks are scheduled again after their backoff, those whose file is gone are dropped.
+    pub async fn process_due(&self) -> usize {
+        let now = Instant::now();
+        let due = self
+            .pending
+            .lock()
+            .unwrap()
+            .iter_mut()
+            .filter(|pending| !pending.in_flight && pending.due <= now)
+            .map(|pending| {
+                pending.in_flight = true;
+                (pending.path.clone(), pending.task.clone())
+            })
+            .collect::<Vec<_>>();
+        let mut uploaded = 0;
+        for (path, mut task) in due {
+            let result = self.upload(&path, &mut task).await;
+            let mut pending = self.pending.lock().unwrap();
+            let index = pending
+                .iter()
+                .position(|pending| pending.path == path)
+                .unwrap();
+            match result {
+                Ok(size) => {
+                    match size {
+                        Some(size) => {
+                            uploaded += 1;
+                            self.succeeded.fetch_add(1, Ordering::Relaxed);
+                            self.bytes.fetch_add(size, Ordering::Relaxed);
+                        }
+                        None => _ = self.skipped.fetch_add(1, Ordering::Relaxed),
+                    }
+                    pending.remove(index);
+                    if let Err(e) = std::fs::remove_file(&path) {
+                        error!("Error deleting upload task {}: {}", path.display(), e);
+                    }
+                }
+                Err(e) => {
+                    self.failed.fetch_add(1, Ordering::Relaxed);
+                    task.attempts += 1;
+                    let backoff = self.backoff(task.attempts);
+                    error!(
+                        "Error uploading {} (attempt {}), retrying in {:?}: {}",
+                        task.file.display(),
+                        task.attempts,
+                        backoff,
+                        e
+                    );
+                    if let Err(e) = persist(&path, &task) {
+                        error!("Error saving upload task {}: {}", path.display(), e);
+                    }
+                    pending[index] = Pending {
+                        path,
+                        task,
+                        due: Instant::now() + backoff,
+                        in_flight: false,
+                    };
+                }
+            }
+        }
+        uploaded
+    }
+    /// Uploads queued files as they come, forever.
+    pub async fn run(&self) {
+        loop {
+            self.process_due().await;
+            let next_due = self
+                .pending
+                .lock()
+                .unwrap()
+                .iter()
+                .map(|pending| pending.due)
+                .min();
+            match next_due {
+                Some(due) => {
+                    tokio::select! {
+                        _ = tokio::time::sleep_until(due) => {}
+                        _ = self.notify.notified() => {}
+                    }
+                }
+                None => self.notify.notified().await,
+            }
+        }
+    }
+    fn backoff(&self, attempts: u32) -> Duration {
+        let factor = 2u32.saturating_pow(attempts.saturating_sub(1));
+        self.config
+            .initial_backoff
+            .saturating_mul(factor)
+            .min(self.config.max_backoff)
+    }
+    /// Uploads and verifies the file of `task`, and returns its size, or `None` if the file is gone.
+    async fn upload(&self, path: &Path, task: &mut UploadTask) -> Result<Option<u64>, StoreError> {
+        if !task.file.exists() {
+            // Deleted after a verified upload, right before the task.
+            warn!("{} is gone, dropping its upload", task.file.display());
+            return Ok(None);
+        }
+        let size = std::fs::metadata(&task.file)?.len();
+        let file = task.file.clone();
+        let sha256 = blocking(move || file_sha256(&file)).await?;
+        let metadata = HashMap::from([(SHA256_METADATA_KEY.to_string(), sha256.clone())]);
+        let expected_etag = if size > self.config.multipart_threshold {
+            self.upload_parts(path, task, size, &metadata).await?
+        } else {
+            let file = task.file.clone();
+            let body = blocking(move || std::fs::read(file)).await?;
+            let digest = Md5::digest(&body);
+            self.store
+                .put_object(&task.key, body, &STANDARD.encode(digest), &metadata)
+                .await?;
+            hex::encode(digest)
+        };
+        let object = self
+            .store
+            .head_object(&task.key)
+            .await?
+            .ok_or_else(|| format!("{} is missing after its upload", task.key))?;
+        if object.size != size
+            || object.etag.trim_matches('"') != expected_etag
+            || object.metadata.get(SHA256_METADATA_KEY) != Some(&sha256)
+        {
+            return Err(format!(
+                "{} does not match {}: size {} etag {} sha256 {:?}, expected size {} etag {} sha256 {}",
+                task.key,
+                task.file.display(),
+                object.size,
+                object.etag,
+                object.metadata.get(SHA256_METADATA_KEY),
+                size,
+                expected_etag,
+                sha256
+            )
+            .into());
+        }
+        match &self.config.archive {
+            Some(archive) => {
+                std::fs::create_dir_all(archive)?;
+                std::fs::rename(&task.file, archive.join(task.file.file_name().unwrap()))?;
+            }
+            None => std::fs::remove_file(&task.file)?,
+        }
+        info!(
+            "File {} uploaded to {} and verified",
+            task.file.display(),
+            task.key
+        );
+        Ok(Some(size))
+    }
+    /// Uploads the parts the store does not have yet and completes the upload. Returns the expected ETag.
+    async fn upload_parts(
+        &self,
+        path: &Path,
+        task: &mut UploadTask,
+        size: u64,
+        metadata: &HashMap<String, String>,
+    ) -> Result<String, StoreError> {
+        let uploaded = match &task.upload_id {
+            Some(upload_id) => self.store.list_parts(&task.key, upload_id).await?,
+            None => None,
+        };
+        let (upload_id, uploaded) = match (task.upload_id.clone(), uploaded) {
+            (Some(upload_id), Some(uploaded)) => {
+                info!(
+                    "Resuming upload of {} with {} parts already uploaded",
+                    task.file.display(),
+                    uploaded.len()
+                );
+                (upload_id, uploaded)
+            }
+            _ => {
+                let upload_id = self
+                    .store
+                    .create_multipart_upload(&task.key, metadata)
+                    .await?;
+                task.upload_id = Some(upload_id.clone());
+                persist(path, task)?;
+                (upload_id, Vec::new())
+            }
+        };
+        let mut parts = Vec::new();
+        let mut digests = Vec::new();
+        for (index, offset) in (0..size)
+            .step_by(self.config.part_size as usize)
+            .enumerate()
+        {
+            let number = index as i32 + 1;
+            let length = self.config.part_size.min(size - offset) as usize;
+            let file = task.file.clone();
+            let body = blocking(move || read_part(&file, offset, length)).await?;
+            let digest = Md5::digest(&body);
+            digests.extend_from_slice(&digest);
+            let etag = format!("\"{}\"", hex::encode(digest));
+            let etag = match uploaded
+                .iter()
+                .find(|part| part.number == number && part.etag == etag)
+            {
+                Some(part) => part.etag.clone(),
+                None => {
+                    self.store
+                        .upload_part(
+                            &task.key,
+                            &upload_id,
+                            number,
+                            body,
+                            &STANDARD.encode(digest),
+                        )
+                        .await?
+                }
+            };
+            parts.push(UploadedPart { number, etag });
+        }
+        self.store
+            .complete_multipart_upload(&task.key, &upload_id, &parts)
+            .await?;
+        Ok(format!(
+            "{}-{}",
+            hex::encode(Md5::digest(&digests)),
+            parts.len()
+        ))
+    }
+}
+
+fn persist(path: &Path, task: &UploadTask) -> std::io::Result<()> {
+    let tmp = path.with_extension("tmp");
+    std::fs::write(&tmp, serde_json::to_vec(task)?)?;
+    std::fs::rename(tmp, path)
+}
+
+/// Runs `read` on the blocking pool, so reading files does not hold up the runtime.
+async fn blocking<T: Send + 'static>(
+    read: impl FnOnce() -> std::io::Result<T> + Send + 'static,
+) -> std::io::Result<T> {
+    tokio::task::spawn_blocking(read)
+        .await
+        .map_err(std::io::Error::other)?
+}
+
+fn read_part(path: &Path, offset: u64, length: usize) -> std::io::Result<Vec<u8>> {
+    let mut file = File::open(path)?;
+    let mut body = vec![0; length];
+    file.seek(SeekFrom::Start(offset))?;
+    file.read_exact(&mut body)?;
+    Ok(body)
+}
+
+fn file_sha256(path: &Path) -> std::io::Result<String> {
+    let mut reader = BufReader::new(File::open(path)?);
+    let mut hasher = Sha256::new();
+    let mut buffer = vec![0; 64 * 1024];
+    loop {
+        match reader.read(&mut buffer)? {
+            0 => return Ok(hex::encode(hasher.finalize())),
+            read => hasher.update(&buffer[..read]),
+        }
+    }
+}